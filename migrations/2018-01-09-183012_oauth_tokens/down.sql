@@ -0,0 +1,5 @@
+-- Remove the OAuth access tokens table.
+DROP TABLE oauth_access_tokens;
+
+-- Remove the OAuth refresh tokens table.
+DROP TABLE oauth_refresh_tokens;
@@ -0,0 +1,25 @@
+-- Create the OAuth refresh tokens table.
+--
+-- Tokens are never stored, only their SHA-256 hash, so that a database leak does not allow
+-- impersonating users.
+CREATE TABLE oauth_refresh_tokens (
+    id SERIAL NOT NULL PRIMARY KEY,
+    token_hash BYTEA NOT NULL UNIQUE,
+    creation TIMESTAMP(3) WITH TIME ZONE NOT NULL DEFAULT current_timestamp,
+    expiration TIMESTAMP(3) WITH TIME ZONE NOT NULL,
+    user_id INTEGER NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+    app_id UUID NOT NULL REFERENCES oauth_apps(id) ON DELETE CASCADE
+);
+
+-- Create the OAuth access tokens table.
+--
+-- Access tokens are derived from a refresh token, and get removed with it.
+CREATE TABLE oauth_access_tokens (
+    id SERIAL NOT NULL PRIMARY KEY,
+    token_hash BYTEA NOT NULL UNIQUE,
+    creation TIMESTAMP(3) WITH TIME ZONE NOT NULL DEFAULT current_timestamp,
+    expiration TIMESTAMP(3) WITH TIME ZONE NOT NULL,
+    refresh_token INTEGER NOT NULL REFERENCES oauth_refresh_tokens(id) ON DELETE CASCADE,
+    user_id INTEGER NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+    app_id UUID NOT NULL REFERENCES oauth_apps(id) ON DELETE CASCADE
+);
@@ -0,0 +1,5 @@
+-- Remove the scopes from access tokens.
+ALTER TABLE oauth_access_tokens DROP COLUMN scopes;
+
+-- Remove the scopes from refresh tokens.
+ALTER TABLE oauth_refresh_tokens DROP COLUMN scopes;
@@ -0,0 +1,7 @@
+-- Add the granted scopes to refresh tokens.
+--
+-- Access tokens derived from a refresh token can only have a subset of these scopes.
+ALTER TABLE oauth_refresh_tokens ADD COLUMN scopes TEXT[] NOT NULL DEFAULT '{}';
+
+-- Add the scopes to access tokens.
+ALTER TABLE oauth_access_tokens ADD COLUMN scopes TEXT[] NOT NULL DEFAULT '{}';
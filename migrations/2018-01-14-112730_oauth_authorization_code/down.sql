@@ -0,0 +1,5 @@
+-- Remove the OAuth application redirect URIs table.
+DROP TABLE oauth_redirect_uris;
+
+-- Remove the first party flag from OAuth applications.
+ALTER TABLE oauth_apps DROP COLUMN first_party;
@@ -0,0 +1,16 @@
+-- Add the first party flag to OAuth applications.
+--
+-- Only first party applications are allowed to use the password grant, third party applications
+-- must use the authorization code grant.
+ALTER TABLE oauth_apps ADD COLUMN first_party BOOLEAN NOT NULL DEFAULT FALSE;
+
+-- Create the OAuth application redirect URIs table.
+--
+-- Users can only be redirected to the registered URIs of an application after an authorization
+-- request.
+CREATE TABLE oauth_redirect_uris (
+    id SERIAL NOT NULL PRIMARY KEY,
+    app_id UUID NOT NULL REFERENCES oauth_apps(id) ON DELETE CASCADE,
+    uri TEXT NOT NULL,
+    UNIQUE (app_id, uri)
+);
@@ -0,0 +1,9 @@
+-- Remove the access tokens issued to applications.
+DELETE FROM oauth_access_tokens WHERE user_id IS NULL OR refresh_token IS NULL;
+
+-- Require a user and a refresh token for all access tokens.
+ALTER TABLE oauth_access_tokens ALTER COLUMN user_id SET NOT NULL;
+ALTER TABLE oauth_access_tokens ALTER COLUMN refresh_token SET NOT NULL;
+
+-- Remove the client scopes from OAuth applications.
+ALTER TABLE oauth_apps DROP COLUMN client_scopes;
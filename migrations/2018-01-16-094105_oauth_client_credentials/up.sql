@@ -0,0 +1,6 @@
+-- Add the scopes an application can request for itself with the client credentials grant.
+ALTER TABLE oauth_apps ADD COLUMN client_scopes TEXT[] NOT NULL DEFAULT '{}';
+
+-- Allow access tokens that are issued to an application, without user or refresh token.
+ALTER TABLE oauth_access_tokens ALTER COLUMN refresh_token DROP NOT NULL;
+ALTER TABLE oauth_access_tokens ALTER COLUMN user_id DROP NOT NULL;
@@ -0,0 +1,2 @@
+-- Remove the JWT signing keys table.
+DROP TABLE oauth_signing_keys;
@@ -0,0 +1,11 @@
+-- Create the JWT signing keys table.
+--
+-- Only the newest key is used to sign tokens. Retired keys are still published until all the
+-- tokens they signed have expired.
+CREATE TABLE oauth_signing_keys (
+    id TEXT NOT NULL PRIMARY KEY, -- Key ID, sent in the `kid` JWT header
+    creation TIMESTAMP(3) WITH TIME ZONE NOT NULL DEFAULT current_timestamp,
+    retirement TIMESTAMP(3) WITH TIME ZONE DEFAULT NULL, -- When a newer key replaced it
+    private_key BYTEA NOT NULL, -- PKCS#8 encoded Ed25519 key pair
+    public_key BYTEA NOT NULL
+);
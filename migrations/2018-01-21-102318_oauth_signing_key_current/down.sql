@@ -0,0 +1,2 @@
+-- Remove the single current signing key constraint.
+DROP INDEX oauth_signing_keys_current;
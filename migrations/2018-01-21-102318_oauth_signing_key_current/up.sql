@@ -0,0 +1,8 @@
+-- Only allow one current signing key, retiring all but the newest one.
+UPDATE oauth_signing_keys SET retirement = current_timestamp
+    WHERE retirement IS NULL AND id NOT IN (
+        SELECT id FROM oauth_signing_keys WHERE retirement IS NULL ORDER BY creation DESC LIMIT 1
+    );
+
+CREATE UNIQUE INDEX oauth_signing_keys_current ON oauth_signing_keys ((retirement IS NULL))
+    WHERE retirement IS NULL;
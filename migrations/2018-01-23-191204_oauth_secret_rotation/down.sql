@@ -0,0 +1,3 @@
+-- Remove the previous API secret of OAuth applications.
+ALTER TABLE oauth_apps DROP COLUMN previous_secret_expiration;
+ALTER TABLE oauth_apps DROP COLUMN previous_api_secret;
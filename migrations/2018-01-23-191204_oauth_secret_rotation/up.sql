@@ -0,0 +1,6 @@
+-- Keep the previous API secret of OAuth applications after a rotation.
+--
+-- Both the current and the previous secret are accepted when verifying signed requests until the
+-- previous secret expires, so that deployed clients can be updated without downtime.
+ALTER TABLE oauth_apps ADD COLUMN previous_api_secret BYTEA DEFAULT NULL;
+ALTER TABLE oauth_apps ADD COLUMN previous_secret_expiration TIMESTAMP(3) WITH TIME ZONE DEFAULT NULL;
@@ -0,0 +1,13 @@
+-- Refuse to remove the encryption keys while there are encrypted secrets, since they could not be
+-- decrypted anymore.
+DO $$
+BEGIN
+    IF EXISTS (SELECT 1 FROM oauth_apps WHERE master_key_id IS NOT NULL) THEN
+        RAISE EXCEPTION 'there are encrypted application secrets';
+    END IF;
+END
+$$;
+
+-- Remove the envelope encryption keys of the OAuth application secrets.
+ALTER TABLE oauth_apps DROP COLUMN master_key_id;
+ALTER TABLE oauth_apps DROP COLUMN secret_key;
@@ -0,0 +1,6 @@
+-- Add the envelope encryption keys of the OAuth application secrets.
+--
+-- Existing secrets are kept in plain text, without data key or master key ID, until they are
+-- encrypted with `web_admin reencrypt-secrets`.
+ALTER TABLE oauth_apps ADD COLUMN secret_key BYTEA DEFAULT NULL; -- Data key, encrypted
+ALTER TABLE oauth_apps ADD COLUMN master_key_id TEXT DEFAULT NULL; -- Key encrypting the data key
@@ -0,0 +1,9 @@
+-- Remove the audit log table.
+DROP TABLE audit_log;
+
+-- Remove the failed login attempts table.
+DROP TABLE user_login_failures;
+
+-- Remove the failed login tracking of users.
+ALTER TABLE users DROP COLUMN last_failed_login;
+ALTER TABLE users DROP COLUMN failed_logins;
@@ -0,0 +1,35 @@
+-- Track the failed login attempts of each user.
+--
+-- Consecutive failures of the whole account, from any address, delay every new attempt
+-- exponentially after a generous threshold, so that attackers spreading their guesses over many
+-- addresses are slowed down too.
+ALTER TABLE users ADD COLUMN failed_logins INTEGER NOT NULL DEFAULT 0;
+ALTER TABLE users ADD COLUMN last_failed_login TIMESTAMP(3) WITH TIME ZONE DEFAULT NULL;
+
+-- Track the failed login attempts of each user per client address.
+--
+-- Consecutive failures from the same address delay its next attempt exponentially, and too many
+-- of them lock that address out of the account temporarily. Counting them per address keeps a
+-- remote attacker from locking the owner out of the account.
+CREATE TABLE user_login_failures (
+    user_id INTEGER NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+    ip_address TEXT NOT NULL, -- Client IP address, its /64 network for IPv6, or empty if unknown
+    failed_logins INTEGER NOT NULL DEFAULT 0,
+    last_failed_login TIMESTAMP(3) WITH TIME ZONE NOT NULL DEFAULT current_timestamp,
+    locked_until TIMESTAMP(3) WITH TIME ZONE DEFAULT NULL,
+    PRIMARY KEY (user_id, ip_address)
+);
+
+-- Create the audit log table.
+--
+-- Security related events of user accounts are recorded here. Events of unknown users, such as
+-- failed logins with a username that does not exist, don't have a user.
+CREATE TABLE audit_log (
+    id BIGSERIAL NOT NULL PRIMARY KEY,
+    creation TIMESTAMP(3) WITH TIME ZONE NOT NULL DEFAULT current_timestamp,
+    user_id INTEGER REFERENCES users(id) ON DELETE CASCADE, -- Optional
+    event TEXT NOT NULL,
+    ip_address TEXT -- Optional, the client IP address of the request, if any
+);
+
+CREATE INDEX audit_log_user_id ON audit_log (user_id, creation);
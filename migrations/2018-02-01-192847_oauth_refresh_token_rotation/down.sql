@@ -0,0 +1,7 @@
+-- Remove the rotated refresh tokens, that would be valid again.
+DELETE FROM oauth_refresh_tokens WHERE rotated IS NOT NULL;
+
+-- Remove the refresh token families.
+DROP INDEX oauth_refresh_tokens_family;
+ALTER TABLE oauth_refresh_tokens DROP COLUMN rotated;
+ALTER TABLE oauth_refresh_tokens DROP COLUMN family;
@@ -0,0 +1,9 @@
+-- Rotate OAuth refresh tokens on each use.
+--
+-- All the refresh tokens that come from the same login belong to the same family. Rotated
+-- tokens are kept to detect their reuse, that revokes the whole family. Existing tokens get a
+-- family each.
+ALTER TABLE oauth_refresh_tokens ADD COLUMN family UUID NOT NULL DEFAULT uuid_generate_v4();
+ALTER TABLE oauth_refresh_tokens ADD COLUMN rotated TIMESTAMP(3) WITH TIME ZONE DEFAULT NULL;
+
+CREATE INDEX oauth_refresh_tokens_family ON oauth_refresh_tokens (family);
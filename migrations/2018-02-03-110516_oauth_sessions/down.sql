@@ -0,0 +1,4 @@
+-- Remove the session information of OAuth refresh tokens.
+DROP INDEX oauth_refresh_tokens_user_id;
+ALTER TABLE oauth_refresh_tokens DROP COLUMN user_agent;
+ALTER TABLE oauth_refresh_tokens DROP COLUMN session_creation;
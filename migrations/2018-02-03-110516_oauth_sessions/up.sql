@@ -0,0 +1,10 @@
+-- Keep the session information in OAuth refresh tokens.
+--
+-- Each refresh token family is a session of the user. The session creation is copied to the new
+-- token on each rotation, and the user agent is the one of the last request that used it.
+ALTER TABLE oauth_refresh_tokens ADD COLUMN session_creation TIMESTAMP(3) WITH TIME ZONE NOT NULL DEFAULT current_timestamp;
+ALTER TABLE oauth_refresh_tokens ADD COLUMN user_agent TEXT DEFAULT NULL;
+
+UPDATE oauth_refresh_tokens SET session_creation = creation;
+
+CREATE INDEX oauth_refresh_tokens_user_id ON oauth_refresh_tokens (user_id);
@@ -0,0 +1,7 @@
+-- Remove the two-factor authentication of the users.
+DROP TABLE user_recovery_codes;
+
+ALTER TABLE users DROP COLUMN totp_last_step;
+ALTER TABLE users DROP COLUMN totp_master_key_id;
+ALTER TABLE users DROP COLUMN totp_secret_key;
+ALTER TABLE users DROP COLUMN totp_secret;
@@ -0,0 +1,20 @@
+-- Add optional TOTP two-factor authentication to the users.
+--
+-- The TOTP secret is stored with envelope encryption, like the API secrets of the applications.
+-- The last used time step is stored so that the same code cannot be used twice.
+ALTER TABLE users ADD COLUMN totp_secret BYTEA DEFAULT NULL;
+ALTER TABLE users ADD COLUMN totp_secret_key BYTEA DEFAULT NULL;
+ALTER TABLE users ADD COLUMN totp_master_key_id TEXT DEFAULT NULL;
+ALTER TABLE users ADD COLUMN totp_last_step BIGINT DEFAULT NULL;
+
+-- Create the recovery codes table.
+--
+-- Recovery codes can be used instead of a TOTP code if the user loses the authenticator. Each
+-- one can only be used once, and only its SHA-256 hash is stored.
+CREATE TABLE user_recovery_codes (
+    id BIGSERIAL NOT NULL PRIMARY KEY,
+    user_id INTEGER NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+    code_hash BYTEA NOT NULL
+);
+
+CREATE UNIQUE INDEX user_recovery_codes_user_id ON user_recovery_codes (user_id, code_hash);
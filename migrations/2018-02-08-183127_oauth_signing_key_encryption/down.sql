@@ -0,0 +1,13 @@
+-- Refuse to remove the encryption keys while there are encrypted signing keys, since they could
+-- not be decrypted anymore.
+DO $$
+BEGIN
+    IF EXISTS (SELECT 1 FROM oauth_signing_keys WHERE master_key_id IS NOT NULL) THEN
+        RAISE EXCEPTION 'there are encrypted signing keys';
+    END IF;
+END
+$$;
+
+-- Remove the envelope encryption keys of the JWT signing keys.
+ALTER TABLE oauth_signing_keys DROP COLUMN master_key_id;
+ALTER TABLE oauth_signing_keys DROP COLUMN secret_key;
@@ -0,0 +1,7 @@
+-- Encrypt the JWT signing keys with envelope encryption, like the API secrets of the
+-- applications.
+--
+-- Existing keys are kept in plain text, without data key or master key ID, until they are
+-- encrypted with `web_admin reencrypt-secrets`.
+ALTER TABLE oauth_signing_keys ADD COLUMN secret_key BYTEA DEFAULT NULL; -- Data key, encrypted
+ALTER TABLE oauth_signing_keys ADD COLUMN master_key_id TEXT DEFAULT NULL; -- Key encrypting it
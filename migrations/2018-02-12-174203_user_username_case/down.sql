@@ -0,0 +1,4 @@
+-- Make usernames unique only with the same case.
+DROP INDEX users_username_lower_key;
+
+ALTER TABLE users ADD CONSTRAINT users_username_key UNIQUE (username);
@@ -0,0 +1,7 @@
+-- Make usernames unique regardless of their case.
+--
+-- Existing usernames that only differ in their case must be renamed before running this
+-- migration.
+ALTER TABLE users DROP CONSTRAINT users_username_key;
+
+CREATE UNIQUE INDEX users_username_lower_key ON users (lower(username));
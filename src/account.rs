@@ -0,0 +1,1161 @@
+//! Account security module.
+//!
+//! Failed login attempts are counted for each account and client address. After a few
+//! consecutive failures, every new attempt from the address has to wait exponentially longer, and
+//! too many of them lock the address out of the account for a while, so that an attacker cannot
+//! lock the owner out. Failures are also counted for the whole account, so that attackers that
+//! spread their guesses over many addresses are slowed down: after many more of them, every new
+//! attempt on the account has to wait exponentially longer, whatever its address. In both cases,
+//! the user receives an email with a link to unlock the account right away. Password logins of
+//! unknown users, of locked accounts and of accounts that must wait fail with the same error and
+//! the same work, so that they cannot be told apart. Logins, failures, lockouts and unlocks are
+//! recorded in the audit log.
+//!
+//! New users register with a username, an email address and a password, and their account is
+//! activated when they follow the signed verification link sent to their email.
+//!
+//! Users that forgot their password can request a single-use reset link by email. Resetting the
+//! password logs the user out of all its sessions.
+//!
+//! When enabled, users can also log in without their password, with a single-use, short-lived
+//! sign-in link sent to their email. It goes through the same lockout and two-factor checks as
+//! password logins.
+//!
+//! Users can enable two-factor authentication with an authenticator app. Logging in then
+//! requires a TOTP code, or one of the single-use recovery codes given when enabling it. Wrong
+//! codes count as failed login attempts.
+//!
+//! The account pages don't keep the user logged in. After logging in with the password, their
+//! forms carry a short-lived page token that authenticates the user.
+
+use std::cmp;
+use std::net::IpAddr;
+use std::sync::{Arc, Mutex};
+use std::sync::mpsc::{self, Receiver, SyncSender, TrySendError};
+use std::thread;
+
+use chrono::{DateTime, Duration, Utc};
+use diesel::Connection;
+use failure::Error;
+use hex;
+use uuid::Uuid;
+
+use crypto;
+use db;
+use db::cache::user::MagicLink;
+use db::models::audit::{AuditEvent, NewAuditEntry};
+use db::models::user::{NewUser, TotpSecret, User};
+use db::user::UserConflict;
+use api::v1::{oauth, sessions};
+use mail::MailService;
+use rate_limit::{throttled_address, RateLimit};
+use totp;
+
+/// Consecutive failed login attempts allowed without any delay.
+const FREE_FAILED_LOGINS: i32 = 3;
+/// Consecutive failed login attempts from a client address that lock it out of the account.
+const MAX_FAILED_LOGINS: i32 = 10;
+/// Consecutive failed login attempts of the whole account, from any address, allowed before
+/// delaying every new attempt on it.
+const ACCOUNT_FREE_FAILED_LOGINS: i32 = 20;
+/// Maximum delay between failed login attempts, in seconds.
+const MAX_LOGIN_DELAY_SECONDS: i64 = 300;
+/// Time an account stays locked, in minutes.
+const LOCKOUT_MINUTES: i64 = 30;
+/// Hours after which failed login attempts are no longer counted.
+const FAILED_LOGIN_RESET_HOURS: i64 = 24;
+/// Lifetime of the account page tokens, in minutes.
+const PAGE_TOKEN_MINUTES: i64 = 10;
+/// Lifetime of the email verification links, in hours.
+const VERIFICATION_LINK_HOURS: i64 = 48;
+/// Minimum length of usernames, in characters.
+const MIN_USERNAME_LEN: usize = 3;
+/// Maximum length of usernames, in characters.
+const MAX_USERNAME_LEN: usize = 32;
+/// Minimum length of passwords, in characters.
+const MIN_PASSWORD_LEN: usize = 10;
+/// Lifetime of the password reset links, in minutes.
+const RESET_TOKEN_MINUTES: i64 = 60;
+/// Time to confirm a two-factor enrolment, in minutes.
+const TOTP_ENROLMENT_MINUTES: i64 = 10;
+/// Number of recovery codes given when enabling two-factor authentication.
+const RECOVERY_CODES: usize = 10;
+/// Minutes a sign-in link is valid for.
+const MAGIC_LINK_MINUTES: i64 = 15;
+/// Hourly limit of the emails with links requested by each client for each email address.
+const LINK_EMAILS_HOURLY_LIMIT: i32 = 5;
+/// Hourly limit of the emails with links requested by each client for all the email addresses.
+const LINK_EMAILS_REQUESTER_HOURLY_LIMIT: i32 = 20;
+/// Emails with links that can be waiting to be sent, beyond which new ones are dropped.
+const LINK_EMAIL_QUEUE_SIZE: usize = 100;
+/// Background threads sending the emails with links.
+const LINK_EMAIL_WORKERS: usize = 2;
+
+/// Reason why a login attempt was rejected.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LoginError {
+    /// The username or the password are not valid, or the user is not active.
+    ///
+    /// Password logins that are delayed or locked out also get this error.
+    InvalidCredentials,
+    /// The attempt came too soon after the last failure from the client address, or of the whole
+    /// account, it can be retried at the given time.
+    Delayed(DateTime<Utc>),
+    /// The client address is locked out of the account until the given time.
+    Locked(DateTime<Utc>),
+    /// The password is valid, but the user has two-factor authentication enabled, and no code
+    /// was given.
+    SecondFactorRequired,
+    /// The two-factor authentication code is not valid.
+    InvalidSecondFactor,
+    /// The sign-in link is not valid, has expired or has already been used.
+    InvalidMagicLink,
+}
+
+impl LoginError {
+    /// Gets the description of the error, to show it to the user.
+    pub fn description(&self) -> &'static str {
+        match *self {
+            LoginError::InvalidCredentials => "Invalid username or password",
+            LoginError::Delayed(_) => {
+                "Too many failed login attempts, please wait a few seconds and try again"
+            }
+            LoginError::Locked(_) => {
+                "The account has been locked after too many failed login attempts, check your \
+                 email to unlock it"
+            }
+            LoginError::SecondFactorRequired => {
+                "Enter the code of your authenticator app, or one of your recovery codes"
+            }
+            LoginError::InvalidSecondFactor => "Invalid two-factor authentication code",
+            LoginError::InvalidMagicLink => "The sign-in link is not valid or has expired",
+        }
+    }
+}
+
+/// Gets the time the user has to wait after the given number of consecutive failed logins, when
+/// the given number of them are allowed without any delay.
+fn login_delay(failed_logins: i32, free_failed_logins: i32) -> Duration {
+    if failed_logins < free_failed_logins {
+        Duration::zero()
+    } else {
+        let exponent = cmp::min(failed_logins - free_failed_logins, 16) as u32;
+        Duration::seconds(cmp::min(1 << exponent, MAX_LOGIN_DELAY_SECONDS))
+    }
+}
+
+/// Records an event in the audit log.
+fn audit(
+    db_con: &db::Connection,
+    user_id: Option<i32>,
+    event: AuditEvent,
+    ip: Option<IpAddr>,
+) -> Result<(), Error> {
+    db::audit::insert_entry(db_con, &NewAuditEntry::new(user_id, event, ip))
+}
+
+/// Authenticates a user with its username and password, and its two-factor authentication code
+/// if it has it enabled.
+///
+/// Attempts from client addresses that are locked out of the account, or that come too soon after
+/// a failure of the address or of the whole account, are rejected as if the password was wrong,
+/// without counting them. The address gets locked out after too many consecutive failures, and
+/// the whole account gets delayed after many more, sending an unlock email to the user. The
+/// client IP address is recorded in the audit log.
+pub fn authenticate_user(
+    db_con: &db::Connection,
+    mail: &MailService,
+    username: &str,
+    password: &str,
+    second_factor: Option<&str>,
+    ip: Option<IpAddr>,
+) -> Result<Result<User, LoginError>, Error> {
+    let user = match db::user::get_user_by_username(db_con, username)? {
+        Some(user) => user,
+        None => {
+            // Hash the password anyway, so that the response time does not reveal if the user
+            // exists.
+            let _ = crypto::hash_password(password)?;
+            audit(db_con, None, AuditEvent::LoginFailed, ip)?;
+            return Ok(Err(LoginError::InvalidCredentials));
+        }
+    };
+
+    if login_blocked(db_con, &user, ip)?.is_some() {
+        // Check the password anyway, so that the response time does not reveal if the user
+        // exists.
+        let _ = crypto::verify_password(user.password(), password)?;
+        audit(db_con, Some(user.id()), AuditEvent::LoginFailed, ip)?;
+        return Ok(Err(LoginError::InvalidCredentials));
+    }
+
+    let error = if !crypto::verify_password(user.password(), password)? || !user.is_active() {
+        LoginError::InvalidCredentials
+    } else if !user.has_two_factor() {
+        return login_succeeded(db_con, user, ip).map(Ok);
+    } else {
+        match second_factor {
+            None => return Ok(Err(LoginError::SecondFactorRequired)),
+            Some(code) => if verify_second_factor(db_con, &user, code, ip)? {
+                return login_succeeded(db_con, user, ip).map(Ok);
+            } else {
+                LoginError::InvalidSecondFactor
+            },
+        }
+    };
+
+    Ok(Err(login_failed(db_con, mail, &user, error, ip)?))
+}
+
+/// Gets the address the failed login attempts of a client are counted for: its IP address, its
+/// /64 network for IPv6, or an empty string if it's unknown.
+fn login_address(ip: Option<IpAddr>) -> String {
+    ip.map_or_else(String::new, |ip| throttled_address(ip).to_string())
+}
+
+/// Checks if the client is locked out of the account of the user, or if it has to wait after
+/// the last failed login attempt of the address or of the whole account.
+fn login_blocked(
+    db_con: &db::Connection,
+    user: &User,
+    ip: Option<IpAddr>,
+) -> Result<Option<LoginError>, Error> {
+    let now = Utc::now();
+    if let Some(last_failed_login) = user.last_failed_login() {
+        let retry_at =
+            last_failed_login + login_delay(user.failed_logins(), ACCOUNT_FREE_FAILED_LOGINS);
+        if retry_at > now {
+            return Ok(Some(LoginError::Delayed(retry_at)));
+        }
+    }
+
+    let failures = match db::user::get_login_failures(db_con, user.id(), &login_address(ip))? {
+        Some(failures) => failures,
+        None => return Ok(None),
+    };
+    if let Some(locked_until) = failures.locked_until() {
+        if locked_until > now {
+            return Ok(Some(LoginError::Locked(locked_until)));
+        }
+    }
+    let retry_at = failures.last_failed_login()
+        + login_delay(failures.failed_logins(), FREE_FAILED_LOGINS);
+    if retry_at > now {
+        return Ok(Some(LoginError::Delayed(retry_at)));
+    }
+    Ok(None)
+}
+
+/// Records a failed login attempt of a user, locking the client address out of the account, or
+/// delaying the whole account, after too many of them.
+///
+/// Returns the given error. Lockouts are only notified to the user by email, so that they don't
+/// reveal that the account exists.
+fn login_failed(
+    db_con: &db::Connection,
+    mail: &MailService,
+    user: &User,
+    error: LoginError,
+    ip: Option<IpAddr>,
+) -> Result<LoginError, Error> {
+    let (address_locked, account_failures) = db::user::record_failed_login(
+        db_con,
+        user.id(),
+        &login_address(ip),
+        Utc::now() - Duration::hours(FAILED_LOGIN_RESET_HOURS),
+        MAX_FAILED_LOGINS,
+        Duration::minutes(LOCKOUT_MINUTES),
+    )?;
+    audit(db_con, Some(user.id()), AuditEvent::LoginFailed, ip)?;
+
+    // Only the attempt that reaches the threshold of the account sends an email, so that the
+    // attempts that come after it don't flood the user.
+    let lockout = if account_failures == ACCOUNT_FREE_FAILED_LOGINS {
+        audit(db_con, Some(user.id()), AuditEvent::AccountThrottled, ip)?;
+        Some(Lockout::Account)
+    } else if address_locked {
+        audit(db_con, Some(user.id()), AuditEvent::AccountLocked, ip)?;
+        Some(Lockout::Address)
+    } else {
+        None
+    };
+    if let Some(lockout) = lockout {
+        if send_unlock_email(mail, user, lockout).is_err() {
+            // TODO log error.
+        }
+    }
+    Ok(error)
+}
+
+/// Records a successful login of a user.
+fn login_succeeded(
+    db_con: &db::Connection,
+    user: User,
+    ip: Option<IpAddr>,
+) -> Result<User, Error> {
+    db::user::reset_failed_logins(db_con, user.id(), &login_address(ip))?;
+    audit(db_con, Some(user.id()), AuditEvent::LoginSucceeded, ip)?;
+    Ok(user)
+}
+
+/// Verifies a two-factor authentication code of a user, either a TOTP code or a recovery code.
+///
+/// Each TOTP code and each recovery code can only be used once.
+fn verify_second_factor(
+    db_con: &db::Connection,
+    user: &User,
+    code: &str,
+    ip: Option<IpAddr>,
+) -> Result<bool, Error> {
+    let code = code.trim();
+    if totp::is_code(code) {
+        let secret = match user.totp_secret()? {
+            Some(secret) => secret,
+            None => return Ok(false),
+        };
+        return match totp::verify(&secret, code, Utc::now()) {
+            Some(step) => db::user::use_totp_step(db_con, user.id(), step),
+            None => Ok(false),
+        };
+    }
+
+    let used = db::user::use_recovery_code(db_con, user.id(), &recovery_code_hash(code))?;
+    if used {
+        audit(db_con, Some(user.id()), AuditEvent::RecoveryCodeUsed, ip)?;
+    }
+    Ok(used)
+}
+
+/// Lockout notified to the user with the unlock email.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Lockout {
+    /// A client address has been locked out of the account.
+    Address,
+    /// Every login attempt on the account is being delayed.
+    Account,
+}
+
+/// Context of the account unlock email.
+#[derive(Debug, Serialize)]
+struct UnlockEmail<'a> {
+    /// Username of the user.
+    username: &'a str,
+    /// Wether every login attempt on the account is delayed, instead of a client address being
+    /// locked out.
+    whole_account: bool,
+    /// Minutes the account is locked for.
+    minutes: i64,
+    /// Failed login attempts that locked the account.
+    attempts: i32,
+    /// Unlock link.
+    link: String,
+}
+
+/// Sends an email to a user whose account has just been locked, with a link to unlock it.
+///
+/// The link is valid while the account stays locked.
+fn send_unlock_email(mail: &MailService, user: &User, lockout: Lockout) -> Result<(), Error> {
+    let token = crypto::random_token()?;
+    db::cache::user::set_unlock_token(
+        &crypto::token_hash(&token),
+        user.id(),
+        Duration::minutes(LOCKOUT_MINUTES),
+    )?;
+
+    let context = UnlockEmail {
+        username: user.username(),
+        whole_account: lockout == Lockout::Account,
+        minutes: LOCKOUT_MINUTES,
+        attempts: match lockout {
+            Lockout::Address => MAX_FAILED_LOGINS,
+            Lockout::Account => ACCOUNT_FREE_FAILED_LOGINS,
+        },
+        link: format!("{}/unlock?token={}", mail.base_url(), hex::encode(token)),
+    };
+    mail.send(user.email(), "Your account has been locked", "unlock", &context)
+}
+
+/// Unlocks the account with the given unlock token, from the unlock email.
+///
+/// Returns `false` if the token is not valid, or if it has already been used.
+pub fn unlock_account(
+    db_con: &db::Connection,
+    token: &str,
+    ip: Option<IpAddr>,
+) -> Result<bool, Error> {
+    let token = match hex::decode(token) {
+        Ok(token) => token,
+        Err(_) => return Ok(false),
+    };
+
+    match db::cache::user::take_unlock_token(&crypto::token_hash(&token))? {
+        Some(user_id) => {
+            if db::user::unlock_user(db_con, user_id, ACCOUNT_FREE_FAILED_LOGINS)? {
+                audit(db_con, Some(user_id), AuditEvent::AccountUnlocked, ip)?;
+            }
+            Ok(true)
+        }
+        None => Ok(false),
+    }
+}
+
+/// Creates a new account page token for a user, after logging in with the password.
+///
+/// The token is valid for a few minutes, and must be sent with the forms of the account pages.
+pub fn create_page_token(user_id: i32) -> Result<String, Error> {
+    let token = crypto::random_token()?;
+    db::cache::user::set_page_token(
+        &crypto::token_hash(&token),
+        user_id,
+        Duration::minutes(PAGE_TOKEN_MINUTES),
+    )?;
+    Ok(hex::encode(token))
+}
+
+/// Gets the ID of the user of an account page token.
+///
+/// Returns `None` if the token is not valid or has expired.
+pub fn page_token_user(token: &str) -> Result<Option<i32>, Error> {
+    match hex::decode(token) {
+        Ok(token) => db::cache::user::get_page_token(&crypto::token_hash(&token)),
+        Err(_) => Ok(None),
+    }
+}
+
+/// Reason why a registration was rejected.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RegistrationError {
+    /// The username is too short or too long, or has invalid characters.
+    InvalidUsername,
+    /// The email address is not valid.
+    InvalidEmail,
+    /// The password is too short.
+    WeakPassword,
+    /// There is already a user with the username.
+    UsernameTaken,
+    /// There is already a user with the email address.
+    EmailTaken,
+}
+
+impl RegistrationError {
+    /// Gets the description of the error, to show it to the user.
+    pub fn description(&self) -> &'static str {
+        match *self {
+            RegistrationError::InvalidUsername => {
+                "The username must have between 3 and 32 ASCII letters, numbers, dots, dashes \
+                 or underscores"
+            }
+            RegistrationError::InvalidEmail => "The email address is not valid",
+            RegistrationError::WeakPassword => "The password must have at least 10 characters",
+            RegistrationError::UsernameTaken => "The username is already taken",
+            RegistrationError::EmailTaken => "The email address is already registered",
+        }
+    }
+
+    /// Checks if the error is a conflict with an existing user.
+    pub fn is_conflict(&self) -> bool {
+        match *self {
+            RegistrationError::UsernameTaken | RegistrationError::EmailTaken => true,
+            _ => false,
+        }
+    }
+}
+
+/// Checks if a username is valid.
+///
+/// Only ASCII characters are allowed, so that usernames that look the same cannot be registered
+/// with different characters.
+fn is_valid_username(username: &str) -> bool {
+    username.len() >= MIN_USERNAME_LEN && username.len() <= MAX_USERNAME_LEN
+        && username
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '.' || c == '-' || c == '_')
+}
+
+/// Checks if a password is long enough.
+fn is_strong_password(password: &str) -> bool {
+    password.chars().count() >= MIN_PASSWORD_LEN
+}
+
+/// Checks if an email address looks valid.
+///
+/// The only way to really validate it is sending an email, so this only rejects obvious
+/// mistakes.
+fn is_valid_email(email: &str) -> bool {
+    let mut parts = email.splitn(2, '@');
+    match (parts.next(), parts.next()) {
+        (Some(local), Some(domain)) => {
+            !local.is_empty() && domain.contains('.') && !domain.starts_with('.')
+                && !domain.ends_with('.') && !email.chars().any(char::is_whitespace)
+        }
+        _ => false,
+    }
+}
+
+/// Registers a new user, sending the email verification link to its email address.
+///
+/// The user is not active until the email address is verified. Email addresses are stored in
+/// lowercase, and usernames are unique regardless of their case.
+pub fn register_user(
+    db_con: &db::Connection,
+    mail: &MailService,
+    username: &str,
+    email: &str,
+    password: &str,
+    ip: Option<IpAddr>,
+) -> Result<Result<User, RegistrationError>, Error> {
+    let email = email.trim().to_lowercase();
+    if !is_valid_username(username) {
+        return Ok(Err(RegistrationError::InvalidUsername));
+    }
+    if !is_valid_email(&email) {
+        return Ok(Err(RegistrationError::InvalidEmail));
+    }
+    if !is_strong_password(password) {
+        return Ok(Err(RegistrationError::WeakPassword));
+    }
+
+    let user = db_con.transaction(|| {
+        if db::user::username_exists(db_con, username)? {
+            return Ok(Err(RegistrationError::UsernameTaken));
+        }
+        if db::user::email_exists(db_con, &email)? {
+            return Ok(Err(RegistrationError::EmailTaken));
+        }
+
+        let user = match db::user::insert_user(
+            db_con,
+            &NewUser::new(&email, username, crypto::hash_password(password)?),
+        )? {
+            Ok(user) => user,
+            // A concurrent registration took it after the checks.
+            Err(UserConflict::Username) => return Ok(Err(RegistrationError::UsernameTaken)),
+            Err(UserConflict::Email) => return Ok(Err(RegistrationError::EmailTaken)),
+        };
+        audit(db_con, Some(user.id()), AuditEvent::UserRegistered, ip)?;
+        Ok(Ok(user))
+    })?;
+
+    // The email is sent once the user is stored, so that the transaction is not kept open while
+    // sending it. If it cannot be sent, the user is removed, so that it can try again.
+    if let Ok(ref user) = user {
+        if let Err(e) = send_verification_email(mail, user) {
+            let _ = db::user::delete_inactive_user(db_con, user.id())?;
+            return Err(e);
+        }
+    }
+    Ok(user)
+}
+
+/// Gets the signed data of an email verification link.
+///
+/// The email address is signed too, so that the link is not valid if it changes.
+fn verification_link_data(user_id: i32, email: &str, expires: i64) -> String {
+    format!("verify_email:{}:{}:{}", user_id, email, expires)
+}
+
+/// Context of the email verification email.
+#[derive(Debug, Serialize)]
+struct VerificationEmail<'a> {
+    /// Username of the user.
+    username: &'a str,
+    /// Hours the link is valid for.
+    hours: i64,
+    /// Email verification link.
+    link: String,
+}
+
+/// Sends the email verification link to a new user.
+fn send_verification_email(mail: &MailService, user: &User) -> Result<(), Error> {
+    let expires = (Utc::now() + Duration::hours(VERIFICATION_LINK_HOURS)).timestamp();
+    let signature = crypto::sign_link(&verification_link_data(user.id(), user.email(), expires));
+
+    let context = VerificationEmail {
+        username: user.username(),
+        hours: VERIFICATION_LINK_HOURS,
+        link: format!(
+            "{}/verify_email?user={}&expires={}&signature={}",
+            mail.base_url(),
+            user.id(),
+            expires,
+            signature
+        ),
+    };
+    mail.send(user.email(), "Verify your email address", "verify_email", &context)
+}
+
+/// Verifies the email address of a user with the signed link sent to it, activating the user.
+///
+/// Returns `false` if the link is not valid or has expired.
+pub fn verify_email(
+    db_con: &db::Connection,
+    user_id: i32,
+    expires: i64,
+    signature: &str,
+    ip: Option<IpAddr>,
+) -> Result<bool, Error> {
+    if expires <= Utc::now().timestamp() {
+        return Ok(false);
+    }
+    let user = match db::user::get_user(db_con, user_id)? {
+        Some(user) => user,
+        None => return Ok(false),
+    };
+    if !crypto::verify_link(&verification_link_data(user_id, user.email(), expires), signature) {
+        return Ok(false);
+    }
+
+    if db::user::activate_user(db_con, user_id, user.email())? {
+        audit(db_con, Some(user_id), AuditEvent::EmailVerified, ip)?;
+    }
+    Ok(true)
+}
+
+/// Context of the password reset email.
+#[derive(Debug, Serialize)]
+struct ResetPasswordEmail<'a> {
+    /// Username of the user.
+    username: &'a str,
+    /// Minutes the link is valid for.
+    minutes: i64,
+    /// Password reset link.
+    link: String,
+}
+
+/// Sends a password reset link to the user with the given email address, if it exists.
+///
+/// The response is the same whether the user exists or not (see `send_link_email()`), so that
+/// email addresses cannot be enumerated.
+pub fn request_password_reset(
+    mail: &MailService,
+    email: &str,
+    ip: Option<IpAddr>,
+) -> Result<(), Error> {
+    send_link_email(mail, email, ip, "password reset", move |db_con, mail, user| {
+        let token = crypto::random_token()?;
+        db::cache::user::set_reset_token(
+            &crypto::token_hash(&token),
+            user.id(),
+            Duration::minutes(RESET_TOKEN_MINUTES),
+        )?;
+        audit(db_con, Some(user.id()), AuditEvent::PasswordResetRequested, ip)?;
+
+        let context = ResetPasswordEmail {
+            username: user.username(),
+            minutes: RESET_TOKEN_MINUTES,
+            link: format!("{}/reset_password?token={}", mail.base_url(), hex::encode(token)),
+        };
+        mail.send(user.email(), "Reset your password", "reset_password", &context)
+    })
+}
+
+/// Function that sends an email with a link to a user.
+type LinkEmailSender =
+    Box<FnMut(&db::Connection, &MailService, &User) -> Result<(), Error> + Send>;
+
+/// Email with a link, waiting to be sent by the link email workers.
+struct LinkEmail {
+    /// Email service to send it with.
+    mail: MailService,
+    /// Email address of the user.
+    email: String,
+    /// Description of the email, for the error logs.
+    description: &'static str,
+    /// Function that sends the email.
+    send: LinkEmailSender,
+}
+
+impl LinkEmail {
+    /// Looks up the active user with the email address, and sends it the email if it exists.
+    ///
+    /// Errors are only logged, since the client has already got its response.
+    fn send(mut self) {
+        let result = db::CONNECTION_POOL
+            .get()
+            .map_err(Error::from)
+            .and_then(|db_con| match db::user::get_user_by_email(&db_con, &self.email)? {
+                Some(ref user) if user.is_active() => (self.send)(&db_con, &self.mail, user),
+                _ => Ok(()),
+            });
+        if let Err(e) = result {
+            error!("Error sending the {} email: {}", self.description, e);
+        }
+    }
+}
+
+lazy_static!{
+    /// Queue of the emails with links, sent by `LINK_EMAIL_WORKERS` background threads.
+    static ref LINK_EMAIL_QUEUE: Mutex<SyncSender<LinkEmail>> = {
+        let (sender, receiver) = mpsc::sync_channel(LINK_EMAIL_QUEUE_SIZE);
+        let receiver = Arc::new(Mutex::new(receiver));
+        for worker in 0..LINK_EMAIL_WORKERS {
+            let receiver = receiver.clone();
+            if let Err(e) = thread::Builder::new()
+                .name(format!("link-email-{}", worker))
+                .spawn(move || run_link_email_worker(&receiver))
+            {
+                error!("Error starting the link email worker: {}", e);
+            }
+        }
+        Mutex::new(sender)
+    };
+}
+
+/// Sends the emails with links from the queue, until it's closed.
+fn run_link_email_worker(receiver: &Mutex<Receiver<LinkEmail>>) {
+    loop {
+        // The lock is only held while waiting for the next email, not while sending it.
+        let link_email = match receiver.lock() {
+            Ok(receiver) => receiver.recv(),
+            Err(_) => return,
+        };
+        match link_email {
+            Ok(link_email) => link_email.send(),
+            Err(_) => return,
+        }
+    }
+}
+
+/// Sends an email with a link to the active user with the given email address, if it exists.
+///
+/// The user is looked up and the email is sent with the given function by a background worker,
+/// where errors are only logged, so that the response does not reveal if the user exists, not
+/// even by its timing. The emails each client can request are limited per hour for each address
+/// (`LINK_EMAILS_HOURLY_LIMIT`) and for all of them (`LINK_EMAILS_REQUESTER_HOURLY_LIMIT`), and
+/// emails beyond the limits or the capacity of the queue are silently dropped.
+fn send_link_email<F>(
+    mail: &MailService,
+    email: &str,
+    ip: Option<IpAddr>,
+    description: &'static str,
+    send: F,
+) -> Result<(), Error>
+where
+    F: FnOnce(&db::Connection, &MailService, &User) -> Result<(), Error> + Send + 'static,
+{
+    let email = email.trim().to_lowercase();
+    let rate_limit = RateLimit::consume_email(
+        &email,
+        ip,
+        LINK_EMAILS_HOURLY_LIMIT,
+        LINK_EMAILS_REQUESTER_HOURLY_LIMIT,
+    )?;
+    if rate_limit.is_exceeded() {
+        return Ok(());
+    }
+
+    // The queue needs a `FnMut`, but each email is only sent once.
+    let mut send = Some(send);
+    let link_email = LinkEmail {
+        mail: mail.clone(),
+        email,
+        description,
+        send: Box::new(move |db_con, mail, user| match send.take() {
+            Some(send) => send(db_con, mail, user),
+            None => Ok(()),
+        }),
+    };
+    let queue = LINK_EMAIL_QUEUE
+        .lock()
+        .map_err(|_| format_err!("the link email queue mutex was poisoned"))?
+        .clone();
+    match queue.try_send(link_email) {
+        Ok(()) => {}
+        Err(TrySendError::Full(link_email)) => {
+            error!("The link email queue is full, dropping a {} email", link_email.description);
+        }
+        Err(TrySendError::Disconnected(link_email)) => {
+            error!("No link email worker is running, dropping a {} email", link_email.description);
+        }
+    }
+    Ok(())
+}
+
+/// Reason why a password reset was rejected.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PasswordResetError {
+    /// The reset link is not valid, has expired or has already been used.
+    InvalidToken,
+    /// The new password is too short.
+    WeakPassword,
+}
+
+impl PasswordResetError {
+    /// Gets the description of the error, to show it to the user.
+    pub fn description(&self) -> &'static str {
+        match *self {
+            PasswordResetError::InvalidToken => "The reset link is not valid or has expired",
+            PasswordResetError::WeakPassword => RegistrationError::WeakPassword.description(),
+        }
+    }
+}
+
+/// Checks if a password reset token is valid, without using it.
+pub fn is_valid_reset_token(token: &str) -> Result<bool, Error> {
+    match hex::decode(token) {
+        Ok(token) => Ok(db::cache::user::get_reset_token(&crypto::token_hash(&token))?.is_some()),
+        Err(_) => Ok(false),
+    }
+}
+
+/// Resets the password of a user with the token sent in the password reset email.
+///
+/// The token can only be used once, and all the sessions of the user are revoked.
+pub fn reset_password(
+    db_con: &db::Connection,
+    token: &str,
+    password: &str,
+    ip: Option<IpAddr>,
+) -> Result<Result<(), PasswordResetError>, Error> {
+    let token = match hex::decode(token) {
+        Ok(token) => token,
+        Err(_) => return Ok(Err(PasswordResetError::InvalidToken)),
+    };
+    // The password is checked first, so that the token is not used if it must be repeated.
+    if !is_strong_password(password) {
+        return Ok(Err(PasswordResetError::WeakPassword));
+    }
+
+    let token_hash = crypto::token_hash(&token);
+    let user_id = match db::cache::user::get_reset_token(&token_hash)? {
+        Some(user_id) => user_id,
+        None => return Ok(Err(PasswordResetError::InvalidToken)),
+    };
+    let password_hash = crypto::hash_password(password)?;
+    db_con.transaction(|| {
+        db::user::set_password(db_con, user_id, &password_hash)?;
+        sessions::revoke_all_sessions(db_con, user_id)?;
+        audit(db_con, Some(user_id), AuditEvent::PasswordReset, ip)
+    })?;
+
+    // The token is only used once the password has been changed, so that it can be tried again
+    // if that fails. Any other link sent before must not be able to reset the new password.
+    let _ = db::cache::user::take_reset_token(&token_hash)?;
+    db::cache::user::remove_reset_tokens(user_id)?;
+    Ok(Ok(()))
+}
+
+/// Context of the sign-in link email.
+#[derive(Debug, Serialize)]
+struct MagicLinkEmail<'a> {
+    /// Username of the user.
+    username: &'a str,
+    /// Minutes the link is valid for.
+    minutes: i64,
+    /// Sign-in link.
+    link: String,
+}
+
+/// Sends a sign-in link to the user with the given email address, if it exists.
+///
+/// Links requested through the API are bound to the requesting application, given with the
+/// registered redirect URI the link points to, and to the scopes it asked for. The application
+/// gets the token in the `token` query parameter and exchanges it for a refresh token. Links
+/// requested from the login page point to the sign-in link page, that logs in to the account
+/// pages. The response is the same whether the user exists or not (see `send_link_email()`), so
+/// that email addresses cannot be enumerated.
+pub fn send_magic_link(
+    mail: &MailService,
+    email: &str,
+    app: Option<(Uuid, String)>,
+    scopes: Vec<String>,
+    ip: Option<IpAddr>,
+) -> Result<(), Error> {
+    send_link_email(mail, email, ip, "sign-in link", move |db_con, mail, user| {
+        let token = crypto::random_token()?;
+        db::cache::user::set_magic_link(
+            &crypto::token_hash(&token),
+            &MagicLink::new(user.id(), app.as_ref().map(|&(app_id, _)| app_id), scopes),
+            Duration::minutes(MAGIC_LINK_MINUTES),
+        )?;
+        audit(db_con, Some(user.id()), AuditEvent::MagicLinkRequested, ip)?;
+
+        let token = hex::encode(token);
+        let link = match app {
+            Some((_, ref redirect_uri)) => {
+                oauth::redirect_url(redirect_uri, &[("token", Some(&token))])
+            }
+            None => format!("{}/magic_link?token={}", mail.base_url(), token),
+        };
+        let context = MagicLinkEmail {
+            username: user.username(),
+            minutes: MAGIC_LINK_MINUTES,
+            link,
+        };
+        mail.send(user.email(), "Your sign-in link", "magic_link", &context)
+    })
+}
+
+/// Checks if the token of a sign-in link requested from the login page is valid, without
+/// using it.
+pub fn is_valid_magic_link(token: &str) -> Result<bool, Error> {
+    match hex::decode(token) {
+        Ok(token) => Ok(db::cache::user::get_magic_link(&crypto::token_hash(&token))?
+            .map_or(false, |link| link.app_id().is_none())),
+        Err(_) => Ok(false),
+    }
+}
+
+/// Logs a user in with the token of a sign-in link, and its two-factor authentication code if
+/// it has it enabled.
+///
+/// Links requested through the API can only be used by the same application, given in `app_id`,
+/// and links requested from the login page only without it. The token is only used once the
+/// login succeeds, so that a missing two-factor code can be given in a new attempt. Wrong codes
+/// count as failed login attempts, as with password logins, and attempts from locked out or
+/// delayed clients are rejected as invalid links.
+pub fn magic_link_login(
+    db_con: &db::Connection,
+    mail: &MailService,
+    token: &str,
+    second_factor: Option<&str>,
+    app_id: Option<Uuid>,
+    ip: Option<IpAddr>,
+) -> Result<Result<(User, MagicLink), LoginError>, Error> {
+    let token_hash = match hex::decode(token) {
+        Ok(token) => crypto::token_hash(&token),
+        Err(_) => return Ok(Err(LoginError::InvalidMagicLink)),
+    };
+    let link = match db::cache::user::get_magic_link(&token_hash)? {
+        Some(ref link) if link.app_id() != app_id => return Ok(Err(LoginError::InvalidMagicLink)),
+        Some(link) => link,
+        None => return Ok(Err(LoginError::InvalidMagicLink)),
+    };
+    let user = match db::user::get_user(db_con, link.user_id())? {
+        Some(ref user) if !user.is_active() => return Ok(Err(LoginError::InvalidMagicLink)),
+        Some(user) => user,
+        None => return Ok(Err(LoginError::InvalidMagicLink)),
+    };
+
+    // Unlike password logins, the link proves that the user exists, so blocked attempts get the
+    // same response as unknown links, not to reveal the lockout to whoever got hold of it.
+    if login_blocked(db_con, &user, ip)?.is_some() {
+        return Ok(Err(LoginError::InvalidMagicLink));
+    }
+    if user.has_two_factor() {
+        match second_factor {
+            None => return Ok(Err(LoginError::SecondFactorRequired)),
+            Some(code) => if !verify_second_factor(db_con, &user, code, ip)? {
+                let error = LoginError::InvalidSecondFactor;
+                return Ok(Err(login_failed(db_con, mail, &user, error, ip)?));
+            },
+        }
+    }
+
+    // Another request could have used the link in the meantime.
+    let link = match db::cache::user::take_magic_link(&token_hash)? {
+        Some(link) => link,
+        None => return Ok(Err(LoginError::InvalidMagicLink)),
+    };
+    let user = login_succeeded(db_con, user, ip)?;
+    Ok(Ok((user, link)))
+}
+
+/// Generates a new recovery code, formatted in groups of four hexadecimal characters.
+fn generate_recovery_code() -> Result<String, Error> {
+    let mut code = [0u8; 8];
+    crypto::fill_random(&mut code)?;
+    let code = hex::encode(code);
+    Ok(format!("{}-{}-{}-{}", &code[..4], &code[4..8], &code[8..12], &code[12..]))
+}
+
+/// Gets the hash of a recovery code, as it's stored in the database.
+///
+/// Dashes, spaces and the case of the code are ignored.
+fn recovery_code_hash(code: &str) -> Vec<u8> {
+    let normalized = code.chars()
+        .filter(|&c| c != '-' && !c.is_whitespace())
+        .collect::<String>()
+        .to_lowercase();
+    crypto::token_hash(normalized.as_bytes())
+}
+
+/// Pending two-factor authentication enrolment, to show it to the user.
+#[derive(Debug, Clone, Serialize)]
+pub struct TwoFactorEnrolment {
+    /// TOTP secret, in base 32, to enter it manually in the authenticator app.
+    secret: String,
+    /// Provisioning URI of the secret, to show it as a QR code.
+    provisioning_uri: String,
+}
+
+/// Gets the context the pending TOTP secret of a user is encrypted with in the cache, so that it
+/// cannot be used for another user.
+fn enrolment_context(user_id: i32) -> String {
+    format!("totp_enrolment:{}", user_id)
+}
+
+/// Starts the two-factor authentication enrolment of a user, generating a new TOTP secret.
+///
+/// The secret is not enabled until the user confirms it with a code from the authenticator app.
+/// Returns `None` if the user already has two-factor authentication enabled.
+pub fn start_two_factor_enrolment(
+    user: &User,
+    issuer: &str,
+) -> Result<Option<TwoFactorEnrolment>, Error> {
+    if user.has_two_factor() {
+        return Ok(None);
+    }
+
+    let secret = totp::generate_secret()?;
+    db::cache::user::set_totp_enrolment(
+        user.id(),
+        &crypto::seal(crypto::cache_key(), &secret, enrolment_context(user.id()).as_bytes())?,
+        Duration::minutes(TOTP_ENROLMENT_MINUTES),
+    )?;
+
+    Ok(Some(TwoFactorEnrolment {
+        secret: totp::base32(&secret),
+        provisioning_uri: totp::provisioning_uri(issuer, user.username(), &secret),
+    }))
+}
+
+/// Confirms the pending two-factor authentication enrolment of a user with a TOTP code, enabling
+/// it.
+///
+/// Returns the new recovery codes, that will not be shown again, or `None` if there is no
+/// pending enrolment or the code is not valid.
+pub fn confirm_two_factor_enrolment(
+    db_con: &db::Connection,
+    user_id: i32,
+    code: &str,
+    ip: Option<IpAddr>,
+) -> Result<Option<Vec<String>>, Error> {
+    // Enrolments that cannot be decrypted, started before changing the master key, are
+    // considered expired.
+    let secret = match db::cache::user::get_totp_enrolment(user_id)?
+        .and_then(|sealed| {
+            crypto::open(crypto::cache_key(), &sealed, enrolment_context(user_id).as_bytes()).ok()
+        })
+    {
+        Some(secret) => secret,
+        None => return Ok(None),
+    };
+    let step = match totp::verify(&secret, code.trim(), Utc::now()) {
+        Some(step) => step,
+        None => return Ok(None),
+    };
+
+    let recovery_codes = (0..RECOVERY_CODES)
+        .map(|_| generate_recovery_code())
+        .collect::<Result<Vec<_>, _>>()?;
+    let hashes = recovery_codes
+        .iter()
+        .map(|code| recovery_code_hash(code))
+        .collect::<Vec<_>>();
+    let totp_secret = TotpSecret::new(user_id, &secret)?;
+    if !db::user::enable_two_factor(db_con, user_id, &totp_secret, step, &hashes)? {
+        return Ok(None);
+    }
+    db::cache::user::remove_totp_enrolment(user_id)?;
+    audit(db_con, Some(user_id), AuditEvent::TwoFactorEnabled, ip)?;
+
+    Ok(Some(recovery_codes))
+}
+
+/// Disables the two-factor authentication of a user, after checking one of its codes.
+///
+/// Returns `false` if the code is not valid.
+pub fn disable_two_factor(
+    db_con: &db::Connection,
+    user: &User,
+    code: &str,
+    ip: Option<IpAddr>,
+) -> Result<bool, Error> {
+    if !user.has_two_factor() || !verify_second_factor(db_con, user, code, ip)? {
+        return Ok(false);
+    }
+
+    if db::user::disable_two_factor(db_con, user.id())? {
+        audit(db_con, Some(user.id()), AuditEvent::TwoFactorDisabled, ip)?;
+    }
+    Ok(true)
+}
+
+/// Disables the two-factor authentication of a user on behalf of an administrator, when the
+/// user has lost both the authenticator and the recovery codes.
+///
+/// Returns `false` if the user did not have two-factor authentication enabled.
+pub fn reset_two_factor(db_con: &db::Connection, user_id: i32) -> Result<bool, Error> {
+    let reset = db::user::disable_two_factor(db_con, user_id)?;
+    if reset {
+        audit(db_con, Some(user_id), AuditEvent::TwoFactorReset, None)?;
+    }
+    Ok(reset)
+}
+
+/// Re-encrypts the TOTP secrets that are not encrypted with the current master key, returning
+/// the number of re-encrypted secrets.
+pub fn reencrypt_totp_secrets(db_con: &db::Connection) -> Result<usize, Error> {
+    let users = db::user::get_users_to_reencrypt(db_con, crypto::master_key_id())?;
+    for user in &users {
+        if let Some(secret) = user.totp_secret()? {
+            db::user::update_totp_secret(db_con, user.id(), &TotpSecret::new(user.id(), &secret)?)?;
+        }
+    }
+    Ok(users.len())
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::Duration;
+
+    use super::{is_valid_email, is_valid_username, login_delay, ACCOUNT_FREE_FAILED_LOGINS,
+                FREE_FAILED_LOGINS, MAX_LOGIN_DELAY_SECONDS};
+
+    #[test]
+    fn delays_logins_exponentially() {
+        assert_eq!(login_delay(0, FREE_FAILED_LOGINS), Duration::zero());
+        assert_eq!(login_delay(2, FREE_FAILED_LOGINS), Duration::zero());
+        assert_eq!(login_delay(3, FREE_FAILED_LOGINS), Duration::seconds(1));
+        assert_eq!(login_delay(4, FREE_FAILED_LOGINS), Duration::seconds(2));
+        assert_eq!(login_delay(8, FREE_FAILED_LOGINS), Duration::seconds(32));
+        assert_eq!(
+            login_delay(1000, FREE_FAILED_LOGINS),
+            Duration::seconds(MAX_LOGIN_DELAY_SECONDS)
+        );
+
+        assert_eq!(login_delay(19, ACCOUNT_FREE_FAILED_LOGINS), Duration::zero());
+        assert_eq!(login_delay(20, ACCOUNT_FREE_FAILED_LOGINS), Duration::seconds(1));
+        assert_eq!(login_delay(25, ACCOUNT_FREE_FAILED_LOGINS), Duration::seconds(32));
+    }
+
+    #[test]
+    fn accepts_valid_emails() {
+        assert!(is_valid_email("user@example.com"));
+        assert!(is_valid_email("first.last+tag@mail.example.co.uk"));
+        assert!(is_valid_email("a@b.c"));
+    }
+
+    #[test]
+    fn rejects_invalid_emails() {
+        assert!(!is_valid_email(""));
+        assert!(!is_valid_email("user"));
+        assert!(!is_valid_email("user@"));
+        assert!(!is_valid_email("@example.com"));
+        assert!(!is_valid_email("user@localhost"));
+        assert!(!is_valid_email("user@.example.com"));
+        assert!(!is_valid_email("user@example.com."));
+        assert!(!is_valid_email("user name@example.com"));
+        assert!(!is_valid_email("user@example.com\n"));
+    }
+
+    #[test]
+    fn accepts_valid_usernames() {
+        assert!(is_valid_username("abc"));
+        assert!(is_valid_username("John.Doe-42_x"));
+        assert!(is_valid_username(&"a".repeat(32)));
+    }
+
+    #[test]
+    fn rejects_invalid_usernames() {
+        assert!(!is_valid_username("ab"));
+        assert!(!is_valid_username(&"a".repeat(33)));
+        assert!(!is_valid_username("john doe"));
+        assert!(!is_valid_username("john@doe"));
+        // Non-ASCII letters, and look-alikes of ASCII ones.
+        assert!(!is_valid_username("jöhn"));
+        assert!(!is_valid_username("jоhn"));
+        assert!(!is_valid_username("ｊｏｈｎ"));
+    }
+}
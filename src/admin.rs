@@ -0,0 +1,150 @@
+//! Administration command line tool.
+//!
+//! It runs maintenance tasks that don't belong to the web server, using the same configuration as
+//! the launcher:
+//!
+//! ```text
+//! web_admin rotate-secret <app-id>
+//! web_admin reencrypt-secrets
+//! web_admin set-first-party <app-id> <true|false>
+//! web_admin reset-2fa <username>
+//! ```
+
+#![cfg_attr(feature = "cargo-clippy", deny(clippy))]
+#![forbid(anonymous_parameters)]
+//#![cfg_attr(feature = "cargo-clippy", warn(clippy_pedantic))]
+#![deny(variant_size_differences, unused_results, unused_qualifications, unused_import_braces,
+        unsafe_code, trivial_numeric_casts, trivial_casts, missing_docs,
+        missing_debug_implementations, missing_copy_implementations, box_pointers,
+        unused_extern_crates)]
+
+extern crate dotenv;
+#[macro_use]
+extern crate failure;
+extern crate rocket;
+extern crate uuid;
+extern crate web_core;
+
+use std::{env, process};
+
+use failure::Error;
+use uuid::Uuid;
+use web_core::api;
+use web_core::api::v1::apps::SecretRotationError;
+use web_core::config::OAuthConfig;
+
+/// Usage of the tool.
+const USAGE: &str = "Usage: web_admin <command> [arguments]
+
+Commands:
+    rotate-secret <app-id>    Rotates the API secret of an application and prints the new one
+    reencrypt-secrets         Re-encrypts the stored secrets with the current master key
+    set-first-party <app-id> <true|false>
+                              Sets wether an application is a first party application
+    reset-2fa <username>      Disables the two-factor authentication of a user";
+
+/// Program entry point.
+fn main() {
+    let _ = dotenv::dotenv().ok();
+
+    let args = env::args().skip(1).collect::<Vec<_>>();
+    if let Err(e) = run(&args) {
+        eprintln!("Error: {}", e);
+        process::exit(1);
+    }
+}
+
+/// Runs the command given in the arguments.
+fn run(args: &[String]) -> Result<(), Error> {
+    web_core::load_master_keys()?;
+
+    match args.first().map(String::as_str) {
+        Some("rotate-secret") if args.len() == 2 => rotate_secret(&args[1]),
+        Some("reencrypt-secrets") if args.len() == 1 => reencrypt_secrets(),
+        Some("set-first-party") if args.len() == 3 => set_first_party(&args[1], &args[2]),
+        Some("reset-2fa") if args.len() == 2 => reset_two_factor(&args[1]),
+        _ => {
+            eprintln!("{}", USAGE);
+            process::exit(2);
+        }
+    }
+}
+
+/// Loads the OAuth configuration of the current environment from `Rocket.toml`.
+fn load_config() -> Result<OAuthConfig, Error> {
+    // Don't print the launch information of Rocket, only the output of the command.
+    if env::var_os("ROCKET_LOG").is_none() {
+        env::set_var("ROCKET_LOG", "critical");
+    }
+
+    OAuthConfig::from_config(rocket::ignite().config())
+}
+
+/// Rotates the API secret of an application, printing the new secret.
+fn rotate_secret(app_id: &str) -> Result<(), Error> {
+    let app_id = app_id
+        .parse::<Uuid>()
+        .map_err(|_| format_err!("invalid application ID `{}`", app_id))?;
+    let config = load_config()?;
+
+    match api::v1::apps::rotate_api_secret(&config, app_id)? {
+        Ok(secret) => {
+            println!("New API secret: {}", secret.api_secret());
+            println!(
+                "The previous secret will be accepted until {} (UNIX timestamp).",
+                secret.previous_secret_expiration()
+            );
+            println!("This secret will not be shown again, store it securely.");
+            Ok(())
+        }
+        Err(SecretRotationError::NotFound) => bail!("application `{}` not found", app_id),
+        Err(SecretRotationError::OverlapActive(expiration)) => bail!(
+            "the previous secret of application `{}` is still accepted until {}, the secret \
+             cannot be rotated again yet",
+            app_id,
+            expiration.timestamp()
+        ),
+    }
+}
+
+/// Re-encrypts the application secrets, the TOTP secrets of the users and the JWT signing keys
+/// that are not encrypted with the current master key.
+fn reencrypt_secrets() -> Result<(), Error> {
+    let count = api::v1::apps::reencrypt_api_secrets()?;
+    println!("Re-encrypted the secrets of {} applications.", count);
+    let count = api::v1::two_factor::reencrypt_totp_secrets()?;
+    println!("Re-encrypted the two-factor authentication secrets of {} users.", count);
+    let count = web_core::jwt::reencrypt_signing_keys()?;
+    println!("Re-encrypted {} JWT signing keys.", count);
+    Ok(())
+}
+
+/// Sets wether an application is a first party application, allowed to use the password grant.
+fn set_first_party(app_id: &str, first_party: &str) -> Result<(), Error> {
+    let app_id = app_id
+        .parse::<Uuid>()
+        .map_err(|_| format_err!("invalid application ID `{}`", app_id))?;
+    let first_party = first_party
+        .parse::<bool>()
+        .map_err(|_| format_err!("expected `true` or `false`, found `{}`", first_party))?;
+
+    if api::v1::apps::set_first_party(app_id, first_party)? {
+        println!("Application `{}` first party: {}.", app_id, first_party);
+        Ok(())
+    } else {
+        bail!("application `{}` not found", app_id)
+    }
+}
+
+/// Disables the two-factor authentication of a user that lost its authenticator and its
+/// recovery codes.
+fn reset_two_factor(username: &str) -> Result<(), Error> {
+    match api::v1::two_factor::reset_two_factor(username)? {
+        Some(true) => {
+            println!("Two-factor authentication disabled for `{}`.", username);
+            Ok(())
+        }
+        Some(false) => bail!("user `{}` does not have two-factor authentication", username),
+        None => bail!("user `{}` not found", username),
+    }
+}
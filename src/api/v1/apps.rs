@@ -0,0 +1,525 @@
+//! Application management module.
+//!
+//! Users can register the applications they manage, edit them and deactivate them. Every route
+//! only gives access to the applications whose manager is the user the access token belongs to,
+//! and responds with `404 Not Found` for any other application.
+//!
+//! Managers can register the redirect URIs of the authorization code grant and the application
+//! scopes of the client credentials grant. Only administrators can make an application first
+//! party, with `web_admin set-first-party`.
+
+use chrono::{DateTime, Utc};
+use diesel::Connection;
+use failure::Error;
+use hex;
+use rocket::State;
+use rocket::response::status::Created;
+use rocket_contrib::Json;
+use serde::{Deserialize, Deserializer};
+use uuid::Uuid;
+
+use compress::CompressedJson;
+use config::OAuthConfig;
+use crypto;
+use db::{self, CONNECTION_POOL};
+use db::models::oauth::{Application, ApplicationChanges, ApplicationSecrets, NewApplication};
+use super::auth::{AppsRead, AppsWrite, Bearer};
+use super::error::ApiError;
+use super::scope::Scope;
+
+/// Hourly request limit of new applications, if none is requested.
+const DEFAULT_HOURLY_LIMIT: i32 = 1_000;
+/// Maximum hourly request limit managers can set for their applications.
+const MAX_HOURLY_LIMIT: i32 = 10_000;
+/// Maximum number of redirect URIs of an application.
+const MAX_REDIRECT_URIS: usize = 10;
+/// Hosts that can be used in plain HTTP redirect URIs, for native applications.
+const LOOPBACK_HOSTS: &[&str] = &["localhost", "127.0.0.1", "[::1]"];
+
+/// Application information structure.
+#[derive(Debug, Serialize)]
+pub struct ApplicationInfo {
+    id: Uuid,
+    active: bool,
+    creation: i64,
+    last_update: i64,
+    name: String,
+    description: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    url: Option<String>,
+    hourly_limit: i32,
+    first_party: bool,
+    redirect_uris: Vec<String>,
+    client_scopes: Vec<Scope>,
+}
+
+impl ApplicationInfo {
+    /// Creates the information structure of an application, with its redirect URIs.
+    fn new(app: &Application, redirect_uris: Vec<String>) -> ApplicationInfo {
+        ApplicationInfo {
+            id: app.id(),
+            active: app.is_active(),
+            creation: app.creation().timestamp(),
+            last_update: app.last_update().timestamp(),
+            name: app.name().to_owned(),
+            description: app.description().to_owned(),
+            url: app.url().map(str::to_owned),
+            hourly_limit: app.hourly_limit(),
+            first_party: app.is_first_party(),
+            redirect_uris,
+            client_scopes: Scope::from_names(app.client_scopes()),
+        }
+    }
+
+    /// Loads the redirect URIs of an application and creates its information structure.
+    fn load(db_con: &db::Connection, app: &Application) -> Result<ApplicationInfo, Error> {
+        let redirect_uris = db::oauth::get_redirect_uris(db_con, &[app.id()])?
+            .into_iter()
+            .map(|(_, uri)| uri)
+            .collect();
+        Ok(ApplicationInfo::new(app, redirect_uris))
+    }
+}
+
+/// Newly registered application, with its API secret.
+///
+/// The API secret is only shown once, when the application is registered.
+#[derive(Debug, Serialize)]
+pub struct NewApplicationInfo {
+    application: ApplicationInfo,
+    api_secret: String,
+}
+
+/// Application registration request.
+#[derive(Debug, Deserialize)]
+pub struct RegistrationRequest {
+    /// Application name.
+    name: String,
+    /// Application description.
+    description: String,
+    /// Optional URL of the application.
+    url: Option<String>,
+    /// Requested hourly request limit.
+    hourly_limit: Option<i32>,
+    /// Redirect URIs for the authorization code grant.
+    #[serde(default)]
+    redirect_uris: Vec<String>,
+    /// Application scopes for the client credentials grant.
+    #[serde(default)]
+    client_scopes: Vec<Scope>,
+}
+
+/// Application update request.
+///
+/// Only the present fields are updated. The URL can be removed by setting it to `null`.
+#[derive(Debug, Deserialize)]
+pub struct UpdateRequest {
+    /// New application name.
+    name: Option<String>,
+    /// New application description.
+    description: Option<String>,
+    /// New URL of the application.
+    #[serde(default, deserialize_with = "nullable")]
+    url: Option<Option<String>>,
+    /// New hourly request limit.
+    hourly_limit: Option<i32>,
+    /// New redirect URIs, replacing the current ones.
+    redirect_uris: Option<Vec<String>>,
+    /// New application scopes, replacing the current ones.
+    client_scopes: Option<Vec<Scope>>,
+}
+
+/// Deserializes a field that can be missing or `null`, to distinguish between both cases.
+fn nullable<'de, D, T>(deserializer: D) -> Result<Option<Option<T>>, D::Error>
+where
+    D: Deserializer<'de>,
+    T: Deserialize<'de>,
+{
+    Ok(Some(Option::deserialize(deserializer)?))
+}
+
+/// Gets the ID of the user the access token belongs to, the manager of the applications.
+fn manager_id<S>(token: &Bearer<S>) -> Result<i32, ApiError> {
+    token.user_id().ok_or_else(|| {
+        ApiError::invalid_token(Some("The access token does not belong to a user"))
+    })
+}
+
+/// Parses an application ID from the route.
+fn parse_app_id(id: &str) -> Result<Uuid, ApiError> {
+    id.parse().map_err(|_| ApiError::not_found("Application not found"))
+}
+
+/// Validates the application name.
+fn validate_name(name: &str) -> Result<(), ApiError> {
+    if name.trim().is_empty() {
+        Err(ApiError::invalid_request("The application name cannot be empty"))
+    } else {
+        Ok(())
+    }
+}
+
+/// Validates the application description.
+fn validate_description(description: &str) -> Result<(), ApiError> {
+    if description.trim().is_empty() {
+        Err(ApiError::invalid_request("The application description cannot be empty"))
+    } else {
+        Ok(())
+    }
+}
+
+/// Validates the application URL, that must be an HTTP or HTTPS URL.
+fn validate_url(url: &str) -> Result<(), ApiError> {
+    if url.starts_with("https://") || url.starts_with("http://") {
+        Ok(())
+    } else {
+        Err(ApiError::invalid_request("The application URL must be an HTTP or HTTPS URL"))
+    }
+}
+
+/// Validates the hourly request limit.
+fn validate_hourly_limit(hourly_limit: i32) -> Result<(), ApiError> {
+    if hourly_limit > 0 && hourly_limit <= MAX_HOURLY_LIMIT {
+        Ok(())
+    } else {
+        Err(ApiError::invalid_request("The hourly limit must be between 1 and 10000 requests"))
+    }
+}
+
+/// Validates the redirect URIs.
+///
+/// They must be absolute HTTPS URIs without fragment. Plain HTTP is only allowed for loopback
+/// hosts, used by native applications.
+fn validate_redirect_uris(redirect_uris: &[String]) -> Result<(), ApiError> {
+    if redirect_uris.len() > MAX_REDIRECT_URIS {
+        return Err(ApiError::invalid_request(
+            "An application cannot have more than 10 redirect URIs",
+        ));
+    }
+
+    for (i, uri) in redirect_uris.iter().enumerate() {
+        if redirect_uris[..i].contains(uri) {
+            return Err(ApiError::invalid_request("The redirect URIs must be unique"));
+        }
+        if uri.contains('#') {
+            return Err(ApiError::invalid_request("Redirect URIs cannot have a fragment"));
+        }
+
+        let valid = if uri.starts_with("https://") {
+            uri.len() > "https://".len()
+        } else if uri.starts_with("http://") {
+            let host = uri["http://".len()..]
+                .split(|c| c == '/' || c == '?')
+                .next()
+                .unwrap_or("");
+            let host = match host.rfind(':') {
+                Some(i) if !host[i..].contains(']') => &host[..i],
+                _ => host,
+            };
+            LOOPBACK_HOSTS.contains(&host)
+        } else {
+            false
+        };
+        if !valid {
+            return Err(ApiError::invalid_request(
+                "Redirect URIs must be HTTPS URIs, or HTTP URIs of a loopback host",
+            ));
+        }
+    }
+
+    Ok(())
+}
+
+/// Validates the client scopes, that must be application scopes.
+fn validate_client_scopes(client_scopes: &[Scope]) -> Result<(), ApiError> {
+    if client_scopes.iter().all(Scope::is_application_scope) {
+        Ok(())
+    } else {
+        Err(ApiError::invalid_scope(
+            "Only application scopes can be granted with the client credentials grant",
+        ))
+    }
+}
+
+/// Lists the applications managed by the user.
+#[get("/apps")]
+pub fn list(token: Bearer<AppsRead>) -> Result<CompressedJson<Vec<ApplicationInfo>>, ApiError> {
+    let manager_id = manager_id(&token)?;
+
+    let db_con = CONNECTION_POOL.get()?;
+    let apps = db::oauth::get_managed_applications(&db_con, manager_id)?;
+    let app_ids = apps.iter().map(Application::id).collect::<Vec<_>>();
+    let redirect_uris = db::oauth::get_redirect_uris(&db_con, &app_ids)?;
+
+    Ok(CompressedJson::new(
+        apps.iter()
+            .map(|app| {
+                let app_redirect_uris = redirect_uris
+                    .iter()
+                    .filter(|&&(app_id, _)| app_id == app.id())
+                    .map(|&(_, ref uri)| uri.clone())
+                    .collect();
+                ApplicationInfo::new(app, app_redirect_uris)
+            })
+            .collect(),
+    ))
+}
+
+/// Registers a new application managed by the user.
+#[post("/apps", data = "<request>")]
+pub fn register(
+    token: Bearer<AppsWrite>,
+    request: Json<RegistrationRequest>,
+) -> Result<Created<CompressedJson<NewApplicationInfo>>, ApiError> {
+    let manager_id = manager_id(&token)?;
+    let request = request.into_inner();
+
+    validate_name(&request.name)?;
+    validate_description(&request.description)?;
+    if let Some(ref url) = request.url {
+        validate_url(url)?;
+    }
+    let hourly_limit = request.hourly_limit.unwrap_or(DEFAULT_HOURLY_LIMIT);
+    validate_hourly_limit(hourly_limit)?;
+    validate_redirect_uris(&request.redirect_uris)?;
+    validate_client_scopes(&request.client_scopes)?;
+
+    let api_secret = crypto::generate_api_secret()?;
+    let db_con = CONNECTION_POOL.get()?;
+    let app = db::oauth::insert_application(
+        &db_con,
+        &NewApplication::new(
+            request.name,
+            request.description,
+            request.url,
+            &api_secret,
+            hourly_limit,
+            manager_id,
+            Scope::to_names(&request.client_scopes),
+        )?,
+        &request.redirect_uris,
+    )?;
+
+    Ok(Created(
+        format!("/api/v1/apps/{}", app.id()),
+        Some(CompressedJson::new(NewApplicationInfo {
+            application: ApplicationInfo::new(&app, request.redirect_uris),
+            api_secret: hex::encode(api_secret),
+        })),
+    ))
+}
+
+/// Gets an application managed by the user.
+#[get("/apps/<id>")]
+pub fn get(
+    token: Bearer<AppsRead>,
+    id: String,
+) -> Result<CompressedJson<ApplicationInfo>, ApiError> {
+    let manager_id = manager_id(&token)?;
+    let app_id = parse_app_id(&id)?;
+
+    let db_con = CONNECTION_POOL.get()?;
+    match db::oauth::get_managed_application(&db_con, app_id, manager_id)? {
+        Some(app) => Ok(CompressedJson::new(ApplicationInfo::load(&db_con, &app)?)),
+        None => Err(ApiError::not_found("Application not found")),
+    }
+}
+
+/// Updates the name, description, URL, hourly request limit, redirect URIs or client scopes of
+/// an application managed by the user.
+#[patch("/apps/<id>", data = "<request>")]
+pub fn update(
+    token: Bearer<AppsWrite>,
+    id: String,
+    request: Json<UpdateRequest>,
+) -> Result<CompressedJson<ApplicationInfo>, ApiError> {
+    let manager_id = manager_id(&token)?;
+    let app_id = parse_app_id(&id)?;
+    let request = request.into_inner();
+
+    if let Some(ref name) = request.name {
+        validate_name(name)?;
+    }
+    if let Some(ref description) = request.description {
+        validate_description(description)?;
+    }
+    if let Some(Some(ref url)) = request.url {
+        validate_url(url)?;
+    }
+    if let Some(hourly_limit) = request.hourly_limit {
+        validate_hourly_limit(hourly_limit)?;
+    }
+    if let Some(ref redirect_uris) = request.redirect_uris {
+        validate_redirect_uris(redirect_uris)?;
+    }
+    if let Some(ref client_scopes) = request.client_scopes {
+        validate_client_scopes(client_scopes)?;
+    }
+
+    let db_con = CONNECTION_POOL.get()?;
+    match db::oauth::update_application(
+        &db_con,
+        app_id,
+        manager_id,
+        &ApplicationChanges::new(
+            request.name,
+            request.description,
+            request.url,
+            request.hourly_limit,
+            request.client_scopes.as_ref().map(|scopes| Scope::to_names(scopes)),
+        ),
+        request.redirect_uris.as_ref().map(Vec::as_slice),
+    )? {
+        Some(app) => Ok(CompressedJson::new(ApplicationInfo::load(&db_con, &app)?)),
+        None => Err(ApiError::not_found("Application not found")),
+    }
+}
+
+/// Deactivates an application managed by the user.
+///
+/// All the tokens issued for the application are revoked, and it will no longer be able to make
+/// signed requests.
+#[delete("/apps/<id>")]
+pub fn deactivate(token: Bearer<AppsWrite>, id: String) -> Result<(), ApiError> {
+    let manager_id = manager_id(&token)?;
+    let app_id = parse_app_id(&id)?;
+
+    let db_con = CONNECTION_POOL.get()?;
+    match db::oauth::deactivate_application(&db_con, app_id, manager_id)? {
+        Some(access_token_hashes) => {
+            db::cache::oauth::remove_access_tokens(&access_token_hashes)?;
+            Ok(())
+        }
+        None => Err(ApiError::not_found("Application not found")),
+    }
+}
+
+/// New API secret of an application, after a rotation.
+///
+/// The new secret is only shown once, when it's generated.
+#[derive(Debug, Serialize)]
+pub struct RotatedSecret {
+    api_secret: String,
+    previous_secret_expiration: i64,
+}
+
+impl RotatedSecret {
+    /// Gets the new API secret, encoded as hexadecimal.
+    pub fn api_secret(&self) -> &str {
+        &self.api_secret
+    }
+
+    /// Gets the timestamp until which the previous secret is still accepted.
+    pub fn previous_secret_expiration(&self) -> i64 {
+        self.previous_secret_expiration
+    }
+}
+
+/// Reason why the API secret of an application could not be rotated.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SecretRotationError {
+    /// The application does not exist or is not active.
+    NotFound,
+    /// The previous secret of the last rotation is still accepted until the given time.
+    OverlapActive(DateTime<Utc>),
+}
+
+/// Rotates the API secret of an application.
+///
+/// The previous secret is still accepted for the configured overlap period, so that deployed
+/// clients can be updated. The secret cannot be rotated again until that period ends, since
+/// only one previous secret is kept.
+pub fn rotate_api_secret(
+    config: &OAuthConfig,
+    app_id: Uuid,
+) -> Result<Result<RotatedSecret, SecretRotationError>, Error> {
+    let db_con = CONNECTION_POOL.get()?;
+    db_con.transaction(|| {
+        // Lock the application, so that concurrent rotations don't lose a secret.
+        let app = match db::oauth::get_application_for_update(&db_con, app_id)? {
+            Some(app) => app,
+            None => return Ok(Err(SecretRotationError::NotFound)),
+        };
+        if let Some(expiration) = app.previous_secret_expiration() {
+            if expiration > Utc::now() {
+                return Ok(Err(SecretRotationError::OverlapActive(expiration)));
+            }
+        }
+
+        let api_secret = crypto::generate_api_secret()?;
+        let previous_expiration = Utc::now() + config.secret_overlap();
+        let secrets = ApplicationSecrets::new(
+            app_id,
+            &api_secret,
+            Some(&app.api_secret()?),
+            Some(previous_expiration),
+        )?;
+        let _ = db::oauth::update_application_secrets(&db_con, app_id, &secrets)?;
+
+        Ok(Ok(RotatedSecret {
+            api_secret: hex::encode(&api_secret),
+            previous_secret_expiration: previous_expiration.timestamp(),
+        }))
+    })
+}
+
+/// Sets wether an application is a first party application, that can use the password grant.
+///
+/// Returns `false` if the application does not exist or is not active.
+pub fn set_first_party(app_id: Uuid, first_party: bool) -> Result<bool, Error> {
+    let db_con = CONNECTION_POOL.get()?;
+    if db::oauth::get_application(&db_con, app_id)?.is_none() {
+        return Ok(false);
+    }
+
+    Ok(db::oauth::update_application_first_party(&db_con, app_id, first_party)?.is_some())
+}
+
+/// Re-encrypts the secrets of all the applications that are not encrypted with the current
+/// master key, returning the number of re-encrypted applications.
+///
+/// Secrets encrypted with an older master key can only be re-encrypted if it's configured as the
+/// previous master key. Secrets stored in plain text get encrypted.
+pub fn reencrypt_api_secrets() -> Result<usize, Error> {
+    let db_con = CONNECTION_POOL.get()?;
+    let apps = db::oauth::get_applications_to_reencrypt(&db_con, crypto::master_key_id())?;
+
+    for app in &apps {
+        let previous_api_secret = app.previous_api_secret()?;
+        let secrets = ApplicationSecrets::new(
+            app.id(),
+            &app.api_secret()?,
+            previous_api_secret.as_ref().map(Vec::as_slice),
+            app.previous_secret_expiration(),
+        )?;
+        let _ = db::oauth::update_application_secrets(&db_con, app.id(), &secrets)?;
+    }
+
+    Ok(apps.len())
+}
+
+/// Rotates the API secret of an application managed by the user.
+///
+/// Responds with `409 Conflict` while the previous secret of the last rotation is still accepted.
+#[post("/apps/<id>/secret")]
+pub fn rotate_secret(
+    config: State<OAuthConfig>,
+    token: Bearer<AppsWrite>,
+    id: String,
+) -> Result<CompressedJson<RotatedSecret>, ApiError> {
+    let manager_id = manager_id(&token)?;
+    let app_id = parse_app_id(&id)?;
+
+    let db_con = CONNECTION_POOL.get()?;
+    if db::oauth::get_managed_application(&db_con, app_id, manager_id)?.is_none() {
+        return Err(ApiError::not_found("Application not found"));
+    }
+
+    match rotate_api_secret(&config, app_id)? {
+        Ok(secret) => Ok(CompressedJson::new(secret)),
+        Err(SecretRotationError::NotFound) => Err(ApiError::not_found("Application not found")),
+        Err(SecretRotationError::OverlapActive(_)) => Err(ApiError::conflict(
+            "The previous API secret is still accepted, the secret cannot be rotated again yet",
+        )),
+    }
+}
@@ -0,0 +1,194 @@
+//! End user authorization module.
+//!
+//! Routes that act on behalf of a user should use the `Bearer` request guard, parameterized by
+//! the scopes they require:
+//!
+//! ```ignore
+//! #[get("/profile")]
+//! pub fn profile(token: Bearer<ProfileRead>) -> ... { ... }
+//! ```
+//!
+//! New sets of required scopes can be defined with the `required_scopes!` macro.
+
+use std::marker::PhantomData;
+
+use rocket::{Outcome, Request};
+use rocket::http::Status;
+use rocket::request::{self, FromRequest};
+use uuid::Uuid;
+
+use db::cache::oauth::AccessTokenInfo;
+use super::error::{ApiError, GuardError};
+use super::oauth::validate_access_token;
+use super::scope::Scope;
+
+/// Realm sent in the `WWW-Authenticate` challenges.
+const REALM: &str = "api";
+
+/// Set of scopes required by a route.
+pub trait RequiredScopes {
+    /// Scopes that the access token must have.
+    const SCOPES: &'static [Scope];
+}
+
+/// Defines a set of required scopes to be used with the `Bearer` request guard.
+macro_rules! required_scopes {
+    ($(#[$attr:meta])* $name:ident => [$($scope:ident),*]) => {
+        $(#[$attr])*
+        #[derive(Debug, Clone, Copy)]
+        pub struct $name;
+
+        impl $crate::api::v1::auth::RequiredScopes for $name {
+            const SCOPES: &'static [$crate::api::v1::scope::Scope] =
+                &[$($crate::api::v1::scope::Scope::$scope),*];
+        }
+    };
+}
+
+required_scopes!(
+    /// Only requires a valid access token, without any scope.
+    Authenticated => []
+);
+required_scopes!(
+    /// Requires the `profile:read` scope.
+    ProfileRead => [ProfileRead]
+);
+required_scopes!(
+    /// Requires the `profile:write` scope.
+    ProfileWrite => [ProfileWrite]
+);
+required_scopes!(
+    /// Requires the `apps:read` scope.
+    AppsRead => [AppsRead]
+);
+required_scopes!(
+    /// Requires the `apps:write` scope.
+    AppsWrite => [AppsWrite]
+);
+required_scopes!(
+    /// Requires the `sessions:read` scope.
+    SessionsRead => [SessionsRead]
+);
+required_scopes!(
+    /// Requires the `sessions:write` scope.
+    SessionsWrite => [SessionsWrite]
+);
+required_scopes!(
+    /// Requires the `two_factor:write` scope.
+    TwoFactorWrite => [TwoFactorWrite]
+);
+required_scopes!(
+    /// Requires the `openid` scope.
+    OpenId => [OpenId]
+);
+
+/// Bearer access token request guard.
+///
+/// It validates the access token in the `Authorization: Bearer <token>` header, and checks that
+/// it has all the scopes required by `S`. It fails with a `401 Unauthorized` status if the token
+/// is missing or invalid, and with a `403 Forbidden` status if it lacks any required scope. The
+/// responses have the bearer token challenge (RFC 6750) in the `WWW-Authenticate` header.
+///
+/// Application tokens, without a user, are rejected if any of the required scopes is a user
+/// scope, so that handlers requiring user scopes always get a user.
+#[derive(Debug)]
+pub struct Bearer<S> {
+    /// Information of the access token.
+    info: AccessTokenInfo,
+    /// Required scopes.
+    required: PhantomData<S>,
+}
+
+impl<S> Bearer<S> {
+    /// Gets the ID of the user the token was issued to.
+    ///
+    /// Application tokens, issued with the client credentials grant, don't have a user.
+    pub fn user_id(&self) -> Option<i32> {
+        self.info.user_id()
+    }
+
+    /// Gets the ID of the application the token was issued for.
+    pub fn app_id(&self) -> Uuid {
+        self.info.app_id()
+    }
+
+    /// Gets the scopes of the token.
+    pub fn scopes(&self) -> Vec<Scope> {
+        Scope::from_names(self.info.scopes())
+    }
+}
+
+/// Builds the challenge of tokens that lack any of the given required scopes, listing them in
+/// the `scope` attribute.
+fn insufficient_scope_challenge(scopes: &[Scope]) -> String {
+    format!(
+        "Bearer realm=\"{}\", error=\"insufficient_scope\", scope=\"{}\"",
+        REALM,
+        Scope::to_names(scopes).join(" ")
+    )
+}
+
+impl<'a, 'r, S> FromRequest<'a, 'r> for Bearer<S>
+where
+    S: RequiredScopes,
+{
+    type Error = &'static str;
+
+    fn from_request(request: &'a Request<'r>) -> request::Outcome<Self, Self::Error> {
+        let token = match request.headers().get_one("Authorization") {
+            Some(header) if header.starts_with("Bearer ") => &header[7..],
+            _ => {
+                // Failure: Invalid request.
+                return GuardError::new(ApiError::invalid_token(None))
+                    .with_challenge(format!("Bearer realm=\"{}\"", REALM))
+                    .fail("Valid bearer token not found");
+            }
+        };
+
+        match validate_access_token(token) {
+            Ok(Some(info)) => {
+                let granted = Scope::from_names(info.scopes());
+                // User scopes also require a token issued to a user.
+                let has_user = info.user_id().is_some()
+                    || S::SCOPES.iter().all(Scope::is_application_scope);
+                if has_user && S::SCOPES.iter().all(|scope| granted.contains(scope)) {
+                    Outcome::Success(Bearer {
+                        info,
+                        required: PhantomData,
+                    })
+                } else {
+                    // Failure: the token does not have the required scopes.
+                    GuardError::new(ApiError::insufficient_scope())
+                        .with_challenge(insufficient_scope_challenge(S::SCOPES))
+                        .fail("Insufficient scope")
+                }
+            }
+            Ok(None) => {
+                // Failure: the token does not exist, or it has expired.
+                let error =
+                    ApiError::invalid_token(Some("The access token is invalid or has expired"));
+                GuardError::new(error)
+                    .with_challenge(format!("Bearer realm=\"{}\", error=\"invalid_token\"", REALM))
+                    .fail("Invalid access token")
+            }
+            Err(_) => {
+                // TODO log error.
+                Outcome::Failure((Status::InternalServerError, "Unknown error"))
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn lists_required_scopes_in_challenge() {
+        assert_eq!(
+            insufficient_scope_challenge(&[Scope::ProfileRead, Scope::ProfileWrite]),
+            "Bearer realm=\"api\", error=\"insufficient_scope\", \
+             scope=\"profile:read profile:write\""
+        );
+    }
+}
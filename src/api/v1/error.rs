@@ -0,0 +1,251 @@
+//! API error module.
+//!
+//! Rocket catchers only get the request, not the error of the request guard that failed. Guards
+//! that need a specific error response store it with `GuardError::fail()`, and the catchers
+//! registered in this module respond with it. Each request is handled in a single thread, so the
+//! error is kept in a thread-local, that the `fairing()` clears when a new request arrives.
+
+use std::cell::RefCell;
+
+use failure::Error;
+use r2d2;
+use rocket::{Outcome, Request};
+use rocket::fairing::AdHoc;
+use rocket::http::Status;
+use rocket::response::{Responder, Response};
+
+use compress::CompressedJson;
+use rate_limit::{RateLimit, RateLimited};
+
+/// API error response.
+///
+/// It follows the error response format of the OAuth 2.0 specification (RFC 6749, section 5.2),
+/// so that the same format can be used for all the API errors.
+#[derive(Debug, Serialize)]
+pub struct ApiError {
+    /// HTTP status of the response.
+    #[serde(skip)]
+    status: Status,
+    /// Error code.
+    error: &'static str,
+    /// Human readable description of the error.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error_description: Option<&'static str>,
+}
+
+impl ApiError {
+    /// Creates a new API error.
+    pub fn new(
+        status: Status,
+        error: &'static str,
+        error_description: Option<&'static str>,
+    ) -> ApiError {
+        ApiError {
+            status,
+            error,
+            error_description,
+        }
+    }
+
+    /// The request is missing a parameter or is otherwise malformed.
+    pub fn invalid_request(description: &'static str) -> ApiError {
+        ApiError::new(Status::BadRequest, "invalid_request", Some(description))
+    }
+
+    /// The provided grant (credentials, refresh token...) is invalid, expired or revoked.
+    pub fn invalid_grant(description: &'static str) -> ApiError {
+        ApiError::new(Status::BadRequest, "invalid_grant", Some(description))
+    }
+
+    /// The application is not allowed to use the requested grant.
+    pub fn unauthorized_client(description: &'static str) -> ApiError {
+        ApiError::new(Status::BadRequest, "unauthorized_client", Some(description))
+    }
+
+    /// The requested scope is invalid, unknown, or exceeds the granted scope.
+    pub fn invalid_scope(description: &'static str) -> ApiError {
+        ApiError::new(Status::BadRequest, "invalid_scope", Some(description))
+    }
+
+    /// The credentials are valid, but the user has two-factor authentication enabled, and the
+    /// request must be repeated with a code.
+    pub fn second_factor_required() -> ApiError {
+        ApiError::new(
+            Status::Forbidden,
+            "second_factor_required",
+            Some("A two-factor authentication code is required"),
+        )
+    }
+
+    /// The application ID is unknown, or the application is not active.
+    pub fn invalid_client(description: &'static str) -> ApiError {
+        ApiError::new(Status::Unauthorized, "invalid_client", Some(description))
+    }
+
+    /// The request signature, or the body hash it covers, does not match the request.
+    pub fn invalid_signature(description: &'static str) -> ApiError {
+        ApiError::new(Status::Unauthorized, "invalid_signature", Some(description))
+    }
+
+    /// The access token is missing, invalid, expired or revoked.
+    pub fn invalid_token(description: Option<&'static str>) -> ApiError {
+        ApiError::new(Status::Unauthorized, "invalid_token", description)
+    }
+
+    /// The access token does not have the scopes required by the resource.
+    pub fn insufficient_scope() -> ApiError {
+        ApiError::new(
+            Status::Forbidden,
+            "insufficient_scope",
+            Some("The access token does not have the required scope"),
+        )
+    }
+
+    /// The requested resource does not exist, or the user cannot access it.
+    pub fn not_found(description: &'static str) -> ApiError {
+        ApiError::new(Status::NotFound, "not_found", Some(description))
+    }
+
+    /// The request conflicts with an existing resource.
+    pub fn conflict(description: &'static str) -> ApiError {
+        ApiError::new(Status::Conflict, "conflict", Some(description))
+    }
+
+    /// The rate limit has been exceeded.
+    pub fn too_many_requests() -> ApiError {
+        ApiError::new(
+            Status::TooManyRequests,
+            "too_many_requests",
+            Some("The rate limit has been exceeded, try again later"),
+        )
+    }
+
+    /// Unexpected server error.
+    pub fn server_error() -> ApiError {
+        ApiError::new(Status::InternalServerError, "server_error", None)
+    }
+
+    /// Gets the HTTP status of the error.
+    pub fn status(&self) -> Status {
+        self.status
+    }
+}
+
+impl From<Error> for ApiError {
+    fn from(_error: Error) -> ApiError {
+        // TODO log error.
+        ApiError::server_error()
+    }
+}
+
+impl From<r2d2::Error> for ApiError {
+    fn from(_error: r2d2::Error) -> ApiError {
+        // TODO log error.
+        ApiError::server_error()
+    }
+}
+
+impl<'r> Responder<'r> for ApiError {
+    fn respond_to(self, request: &Request) -> Result<Response<'r>, Status> {
+        let status = self.status;
+        let mut response = CompressedJson::new(self).respond_to(request)?;
+        response.set_status(status);
+        Ok(response)
+    }
+}
+
+thread_local! {
+    /// Error response of the request guard that failed in the current request, if any.
+    static GUARD_ERROR: RefCell<Option<GuardError>> = RefCell::new(None);
+}
+
+/// Error response of a failed request guard, with its extra headers.
+#[derive(Debug)]
+pub struct GuardError {
+    /// Error to respond with.
+    error: ApiError,
+    /// Value of the `WWW-Authenticate` header, if any.
+    challenge: Option<String>,
+    /// Rate limit of the request, if it was exceeded.
+    rate_limit: Option<RateLimit>,
+}
+
+impl GuardError {
+    /// Creates a new guard error response.
+    pub fn new(error: ApiError) -> GuardError {
+        GuardError {
+            error,
+            challenge: None,
+            rate_limit: None,
+        }
+    }
+
+    /// Adds a `WWW-Authenticate` challenge to the response.
+    pub fn with_challenge<S: Into<String>>(mut self, challenge: S) -> GuardError {
+        self.challenge = Some(challenge.into());
+        self
+    }
+
+    /// Adds the rate limit headers of the exceeded rate limit to the response.
+    pub fn with_rate_limit(mut self, rate_limit: RateLimit) -> GuardError {
+        self.rate_limit = Some(rate_limit);
+        self
+    }
+
+    /// Stores the error response for the catcher of its status, and returns the failure outcome
+    /// of the guard.
+    pub fn fail<S, E, F>(self, error: E) -> Outcome<S, (Status, E), F> {
+        let status = self.error.status();
+        GUARD_ERROR.with(|guard_error| *guard_error.borrow_mut() = Some(self));
+        Outcome::Failure((status, error))
+    }
+
+    /// Takes the error response stored by the failed guard of the current request, if it has the
+    /// given status.
+    pub fn take(status: Status) -> Option<GuardError> {
+        GUARD_ERROR
+            .with(|guard_error| guard_error.borrow_mut().take())
+            .and_then(|guard_error| {
+                if guard_error.error.status() == status {
+                    Some(guard_error)
+                } else {
+                    None
+                }
+            })
+    }
+}
+
+impl<'r> Responder<'r> for GuardError {
+    fn respond_to(self, request: &Request) -> Result<Response<'r>, Status> {
+        let mut response = match self.rate_limit {
+            Some(rate_limit) => RateLimited::new(self.error, rate_limit).respond_to(request)?,
+            None => self.error.respond_to(request)?,
+        };
+        if let Some(challenge) = self.challenge {
+            let _ = response.set_raw_header("WWW-Authenticate", challenge);
+        }
+        Ok(response)
+    }
+}
+
+/// Fairing that clears the guard error of the previous request handled by the thread.
+pub fn fairing() -> AdHoc {
+    AdHoc::on_request(|_, _| {
+        GUARD_ERROR.with(|guard_error| *guard_error.borrow_mut() = None);
+    })
+}
+
+/// Catcher for `401 Unauthorized` errors.
+#[error(401)]
+pub fn unauthorized(_request: &Request) -> GuardError {
+    GuardError::take(Status::Unauthorized).unwrap_or_else(|| {
+        GuardError::new(ApiError::new(Status::Unauthorized, "unauthorized", None))
+    })
+}
+
+/// Catcher for `403 Forbidden` errors.
+#[error(403)]
+pub fn forbidden(_request: &Request) -> GuardError {
+    GuardError::take(Status::Forbidden)
+        .unwrap_or_else(|| GuardError::new(ApiError::new(Status::Forbidden, "forbidden", None)))
+}
@@ -1,3 +1,11 @@
 //! V1 REST API.
 
+#[macro_use]
+pub mod auth;
+pub mod apps;
+pub mod error;
 pub mod oauth;
+pub mod scope;
+pub mod sessions;
+pub mod two_factor;
+pub mod user;
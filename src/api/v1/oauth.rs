@@ -1,21 +1,57 @@
 //! OAuth module.
 
+use std::cmp;
+use std::io::Read;
+use std::marker::PhantomData;
+use std::net::IpAddr;
+use std::ops::Deref;
+
+use base64;
 use failure::Error;
 use chrono::{DateTime, Duration, NaiveDateTime, Utc};
-use rocket_contrib::Json;
-use rocket::Outcome;
-use rocket::request::{self, FromRequest, Request};
+use diesel::Connection;
+use hex;
+use ring::{constant_time, digest, hmac};
+use rocket::{Data, Outcome, State};
+use rocket::data::{self, FromData};
+use rocket::request::{self, Form, FromRequest, Request};
 use rocket::http::Status;
+use rocket::http::uri::URI;
+use rocket::response::{Responder, Response};
+use rocket::response::status::Accepted;
+use serde::{Deserialize, Deserializer, Serializer};
+use serde::de::{self, DeserializeOwned};
+use serde_json;
 use uuid::Uuid;
 
+use account::{self, LoginError};
 use compress::CompressedJson;
+use config::{AccessTokenFormat, OAuthConfig, RateLimitConfig};
+use crypto;
 use db::{self, CONNECTION_POOL};
+use db::cache::oauth::{AccessTokenInfo, AuthorizationCode};
+use db::models::audit::{AuditEvent, NewAuditEntry};
+use db::models::oauth::{self as models, NewAccessToken, NewRefreshToken};
+use jwt::{self, AccessTokenClaims};
+use mail::MailService;
+use oidc;
+use rate_limit::{throttle_ip, ClientIp, RateLimit, RateLimitTier, RateLimited, Standard};
+use super::error::{ApiError, GuardError};
+use super::scope::Scope;
+
+/// Lifetime of refresh tokens, in days.
+const REFRESH_TOKEN_LIFETIME_DAYS: i64 = 30;
+/// Lifetime of access tokens, in minutes.
+pub const ACCESS_TOKEN_LIFETIME_MINUTES: i64 = 60;
 
 /// Refresh token response structure.
 #[derive(Debug, Serialize)]
 pub struct RefreshResponse {
     refresh_token: RefreshToken,
-    access_token: AccessTokenResponse,
+    access_token: AccessToken,
+    /// OpenID Connect ID token, if the `openid` scope was granted in the authorization code flow.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    id_token: Option<String>,
 }
 
 /// Refresh token information structure.
@@ -26,81 +62,216 @@ pub struct RefreshToken {
     expiration: i64,
 }
 
+/// Serializes a byte array as a hexadecimal string.
+fn hex_str<T, S>(bytes: &T, serializer: S) -> Result<S::Ok, S::Error>
+where
+    T: AsRef<[u8]>,
+    S: Serializer,
+{
+    serializer.serialize_str(&hex::encode(bytes))
+}
+
+/// Deserializes a 16-byte token from a hexadecimal string.
+fn hex_token<'de, D>(deserializer: D) -> Result<[u8; 16], D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let bytes = hex::decode(String::deserialize(deserializer)?).map_err(de::Error::custom)?;
+    if bytes.len() != 16 {
+        return Err(de::Error::invalid_length(bytes.len(), &"a 16-byte token"));
+    }
+
+    let mut token = [0u8; 16];
+    token.copy_from_slice(&bytes);
+    Ok(token)
+}
+
 /// Token refresh request credentials.
 #[derive(Debug, Deserialize)]
 pub struct RefreshCredentials {
     username: String,
     password: String,
+    /// Two-factor authentication code, TOTP or recovery code, if the user has it enabled.
+    second_factor: Option<String>,
+    /// Requested scopes. Only `Scope::DEFAULT` will be granted if not present.
+    scope: Option<Vec<Scope>>,
+}
+
+/// Sign-in link request structure.
+#[derive(Debug, Deserialize)]
+pub struct MagicLinkRequest {
+    email: String,
+    /// Redirect URI registered for the application, that the link in the email points to, with
+    /// the token in its `token` query parameter.
+    redirect_uri: String,
+    /// Requested scopes. Only `Scope::DEFAULT` will be granted if not present.
+    scope: Option<Vec<Scope>>,
 }
 
+/// Sign-in link login credentials.
+#[derive(Debug, Deserialize)]
+pub struct MagicLinkCredentials {
+    /// Token of the sign-in link.
+    token: String,
+    /// Two-factor authentication code, TOTP or recovery code, if the user has it enabled.
+    second_factor: Option<String>,
+}
+
+/// Maximum time introspection responses can be cached by clients, in seconds.
+const INTROSPECTION_CACHE_SECONDS: i64 = 30;
+
+/// Maximum time a signed request timestamp can be in the future, in seconds.
+const MAX_CLOCK_SKEW_SECONDS: i64 = 10;
+
+/// Maximum size of a signed JSON request body, in bytes.
+const SIGNED_BODY_LIMIT: u64 = 1 << 20;
+
+/// Maximum length of the stored user agents, in characters.
+const MAX_USER_AGENT_LEN: usize = 256;
+
+rate_limit_tier!(
+    /// Password authentication or registration, that must hash the password with Argon2.
+    PasswordLogin => Tokens, cost 5
+);
+rate_limit_tier!(
+    /// Token issuance, that stores and possibly signs new tokens.
+    TokenIssuance => Tokens, cost 1
+);
+
 /// OAuth application request guard.
+///
+/// Every application request must be signed with the application's API secret. The signature
+/// is an HMAC-SHA256 of the canonical request (see `canonical_request()`), encoded as hexadecimal
+/// in the `X-Signature` header. Each request must have a unique `X-Nonce` header, so that signed
+/// requests cannot be replayed. Requests with a body must also send the hexadecimal SHA-256 hash
+/// of the body in the `X-Content-SHA256` header, and routes must read the body with the
+/// `SignedBody` data guard (or `SignedJson`, that uses it), that checks it.
+///
+/// The request is counted in the hourly rate limit of the application, with the bucket and cost
+/// of the rate limit tier `T`.
 #[derive(Debug, Clone, Copy)]
-pub struct Application {
+pub struct Application<T = Standard> {
     /// Application ID.
     id: Uuid,
-    /// Number of requests left for the rest of the hour.
-    requests_left: i32,
+    /// Hourly rate limit of the application, after counting the request.
+    rate_limit: RateLimit,
+    /// Wether the application is a first party application or not.
+    first_party: bool,
+    /// Rate limit tier.
+    tier: PhantomData<T>,
+}
+
+impl<T> Application<T> {
+    /// Gets the application ID.
+    pub fn id(&self) -> Uuid {
+        self.id
+    }
+
+    /// Gets the hourly rate limit of the application, after counting the request.
+    pub fn rate_limit(&self) -> RateLimit {
+        self.rate_limit
+    }
+
+    /// Checks if the application is a first party application.
+    pub fn is_first_party(&self) -> bool {
+        self.first_party
+    }
 }
 
-impl<'a, 'r> FromRequest<'a, 'r> for Application {
+impl<'a, 'r, T> FromRequest<'a, 'r> for Application<T>
+where
+    T: RateLimitTier,
+{
     type Error = &'static str;
 
     fn from_request(request: &'a Request<'r>) -> request::Outcome<Self, Self::Error> {
-        if let (Some(Ok(app_id)), Some(Ok(timestamp)), Some(signature)) = (
+        let config = match request.guard::<State<OAuthConfig>>() {
+            Outcome::Success(config) => config,
+            _ => return Outcome::Failure((Status::InternalServerError, "Unknown error")),
+        };
+
+        if let (
+            Some(Ok(app_id)),
+            Some(Ok(timestamp)),
+            Some(nonce),
+            Some(Ok(signature)),
+            Some(body_hash),
+        ) = (
             request
                 .headers()
                 .get("X-App-Id")
                 .next()
-                .map(|str_id| str_id.parse::<u64>()),
+                .map(|str_id| str_id.parse::<Uuid>()),
             request
                 .headers()
                 .get("X-Timestamp")
                 .next()
                 .map(|str_time| str_time.parse::<i64>()),
-            request.headers().get("X-Signature").next(),
+            request
+                .headers()
+                .get("X-Nonce")
+                .next()
+                .and_then(|nonce| if is_valid_nonce(nonce) { Some(nonce) } else { None }),
+            request.headers().get("X-Signature").next().map(hex::decode),
+            body_hash(request),
         ) {
             let date_time = DateTime::from_utc(NaiveDateTime::from_timestamp(timestamp, 0), Utc);
-            if date_time > Utc::now() - Duration::minutes(5)
-                && date_time <= Utc::now() + Duration::seconds(10)
+            if date_time > Utc::now() - config.signature_window()
+                && date_time <= Utc::now() + Duration::seconds(MAX_CLOCK_SKEW_SECONDS)
             {
                 if let Ok(db_con) = CONNECTION_POOL.get() {
                     // Valid date time, check request.
                     match db::oauth::get_application(&db_con, app_id) {
                         Ok(Some(app)) => {
                             // It's ok, continue validating.
-                            if let Ok(last_hour_count) =
-                                db::cache::oauth::get_request_count(app.id())
-                            {
-                                if last_hour_count < app.hourly_limit() {
-                                    // Everything ok, return structure.
-
-                                    if let Err(_) = db::cache::oauth::add_request(app.id()) {
+                            let secrets = match app.valid_api_secrets() {
+                                Ok(secrets) => secrets,
+                                Err(_) => {
+                                    // TODO log error.
+                                    return Outcome::Failure((
+                                        Status::InternalServerError,
+                                        "Unknown error",
+                                    ));
+                                }
+                            };
+                            let canonical_request =
+                                canonical_request(request, timestamp, nonce, &body_hash);
+                            if !secrets.iter().any(|secret| {
+                                verify_signature(secret, &canonical_request, &signature)
+                            }) {
+                                // Failure: the signature does not match.
+                                GuardError::new(ApiError::invalid_signature(
+                                    "The request signature is not valid",
+                                )).fail("Invalid signature")
+                            } else {
+                                // The nonce must be remembered while the timestamp is valid.
+                                match db::cache::oauth::use_nonce(
+                                    app.id(),
+                                    nonce,
+                                    config.signature_window()
+                                        + Duration::seconds(MAX_CLOCK_SKEW_SECONDS),
+                                ) {
+                                    Ok(true) => rate_limit(&app),
+                                    Ok(false) => {
+                                        // Failure: the request has already been received.
+                                        GuardError::new(ApiError::invalid_signature(
+                                            "The request nonce has already been used",
+                                        )).fail("Replayed request")
+                                    }
+                                    Err(_) => {
                                         // TODO log error.
                                         Outcome::Failure((
                                             Status::InternalServerError,
                                             "Unknown error",
                                         ))
-                                    } else {
-                                        Outcome::Success(Application {
-                                            id: app.id(),
-                                            requests_left: app.hourly_limit() - last_hour_count - 1,
-                                        })
                                     }
-                                } else {
-                                    // Failure: too many requests.
-                                    Outcome::Failure((
-                                        Status::TooManyRequests,
-                                        "Hourly request limit reached",
-                                    ))
                                 }
-                            } else {
-                                // TODO log error.
-                                Outcome::Failure((Status::InternalServerError, "Unknown error"))
                             }
                         }
                         Ok(None) => {
                             // Failure: Invalid APP ID.
-                            Outcome::Failure((Status::Unauthorized, "Invalid application ID"))
+                            GuardError::new(ApiError::invalid_client("Unknown application ID"))
+                                .fail("Invalid application ID")
                         }
                         Err(_) => {
                             // TODO log error.
@@ -119,30 +290,1608 @@ impl<'a, 'r> FromRequest<'a, 'r> for Application {
             // Failure: Invalid request.
             Outcome::Failure((
                 Status::BadRequest,
-                "Valid X-App-Id, X-Timestamp or X-Signature headers not found",
+                "Valid X-App-Id, X-Timestamp, X-Nonce, X-Signature or X-Content-SHA256 headers \
+                 not found",
             ))
         }
     }
 }
 
+/// Checks the hourly request limit of an authenticated application, and counts the request.
+///
+/// The check and the count are atomic, so that concurrent requests cannot exceed the limit.
+fn rate_limit<T>(app: &models::Application) -> request::Outcome<Application<T>, &'static str>
+where
+    T: RateLimitTier,
+{
+    match RateLimit::consume_application::<T>(app.id(), app.hourly_limit()) {
+        Ok(rate_limit) if rate_limit.is_exceeded() => {
+            // Failure: too many requests.
+            GuardError::new(ApiError::too_many_requests())
+                .with_rate_limit(rate_limit)
+                .fail("Hourly request limit reached")
+        }
+        Ok(rate_limit) => Outcome::Success(Application {
+            id: app.id(),
+            rate_limit,
+            first_party: app.is_first_party(),
+            tier: PhantomData,
+        }),
+        Err(_) => {
+            // TODO log error.
+            Outcome::Failure((Status::InternalServerError, "Unknown error"))
+        }
+    }
+}
+
+/// OAuth application authenticated with its API secret in HTTP Basic authentication
+/// (`client_secret_basic`, RFC 6749, section 2.3.1).
+///
+/// Standard OAuth and OpenID Connect clients cannot sign their requests, so they can use this
+/// guard in the token endpoint instead of `Application`. The user name is the application ID and
+/// the password is the hexadecimal API secret. The request is counted in the hourly rate limit
+/// of the application, with the bucket and cost of the rate limit tier `T`.
+#[derive(Debug, Clone, Copy)]
+pub struct BasicApplication<T = Standard>(Application<T>);
+
+impl<T> BasicApplication<T> {
+    /// Consumes the guard, returning the authenticated application.
+    pub fn into_inner(self) -> Application<T> {
+        self.0
+    }
+}
+
+impl<'a, 'r, T> FromRequest<'a, 'r> for BasicApplication<T>
+where
+    T: RateLimitTier,
+{
+    type Error = &'static str;
+
+    fn from_request(request: &'a Request<'r>) -> request::Outcome<Self, Self::Error> {
+        let invalid_client = |description| {
+            GuardError::new(ApiError::invalid_client(description))
+                .with_challenge("Basic realm=\"token\"")
+                .fail("Invalid client credentials")
+        };
+
+        let (app_id, secret) = match request
+            .headers()
+            .get_one("Authorization")
+            .and_then(basic_credentials)
+        {
+            Some(credentials) => credentials,
+            None => {
+                return invalid_client(
+                    "The application ID and API secret must be sent with HTTP Basic \
+                     authentication",
+                )
+            }
+        };
+
+        let db_con = match CONNECTION_POOL.get() {
+            Ok(db_con) => db_con,
+            Err(_) => {
+                // TODO log error.
+                return Outcome::Failure((Status::InternalServerError, "Unknown error"));
+            }
+        };
+        let app = match db::oauth::get_application(&db_con, app_id) {
+            Ok(Some(app)) => app,
+            Ok(None) => return invalid_client("Invalid application ID or API secret"),
+            Err(_) => {
+                // TODO log error.
+                return Outcome::Failure((Status::InternalServerError, "Unknown error"));
+            }
+        };
+        let secrets = match app.valid_api_secrets() {
+            Ok(secrets) => secrets,
+            Err(_) => {
+                // TODO log error.
+                return Outcome::Failure((Status::InternalServerError, "Unknown error"));
+            }
+        };
+
+        if secrets
+            .iter()
+            .any(|valid| constant_time::verify_slices_are_equal(valid, &secret).is_ok())
+        {
+            rate_limit(&app).map(BasicApplication)
+        } else {
+            invalid_client("Invalid application ID or API secret")
+        }
+    }
+}
+
+/// Parses the application ID and the API secret of an HTTP Basic `Authorization` header.
+fn basic_credentials(header: &str) -> Option<(Uuid, Vec<u8>)> {
+    if !header.starts_with("Basic ") {
+        return None;
+    }
+    let decoded = base64::decode(header["Basic ".len()..].trim()).ok()?;
+    let credentials = String::from_utf8(decoded).ok()?;
+
+    let mut parts = credentials.splitn(2, ':');
+    let app_id = parts.next()?.parse().ok()?;
+    let secret = hex::decode(parts.next()?).ok()?;
+    Some((app_id, secret))
+}
+
+/// Checks if a request nonce is valid.
+///
+/// Nonces must be between 16 and 64 characters long, and can only contain ASCII alphanumeric
+/// characters, `-` and `_`.
+fn is_valid_nonce(nonce: &str) -> bool {
+    nonce.len() >= 16 && nonce.len() <= 64
+        && nonce
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+}
+
+/// Gets the SHA-256 hash of the request body declared in the `X-Content-SHA256` header.
+///
+/// The header can only be omitted if the request has no body, and the hash of an empty body is
+/// used then. Returns `None` if the header is missing or invalid.
+fn body_hash(request: &Request) -> Option<Vec<u8>> {
+    match request.headers().get_one("X-Content-SHA256") {
+        Some(hash) => hex::decode(hash).ok(),
+        None if has_body(request) => None,
+        None => Some(digest::digest(&digest::SHA256, &[]).as_ref().to_vec()),
+    }
+}
+
+/// Checks if the request has a body, from its `Content-Length` and `Transfer-Encoding` headers.
+fn has_body(request: &Request) -> bool {
+    request.headers().contains("Transfer-Encoding")
+        || request
+            .headers()
+            .get_one("Content-Length")
+            .map_or(false, |len| len.trim() != "0")
+}
+
+/// Builds the canonical representation of a request, that applications must sign.
+///
+/// It's composed by the request method, path, query string (empty if there is none), timestamp,
+/// nonce and hexadecimal SHA-256 hash of the body, separated by new lines.
+fn canonical_request(
+    request: &Request,
+    timestamp: i64,
+    nonce: &str,
+    body_hash: &[u8],
+) -> String {
+    format!(
+        "{}\n{}\n{}\n{}\n{}\n{}",
+        request.method().as_str(),
+        request.uri().path(),
+        request.uri().query().unwrap_or(""),
+        timestamp,
+        nonce,
+        hex::encode(body_hash)
+    )
+}
+
+/// Checks the HMAC-SHA256 signature of the given canonical request in constant time.
+fn verify_signature(secret: &[u8], canonical_request: &str, signature: &[u8]) -> bool {
+    let key = hmac::SigningKey::new(&digest::SHA256, secret);
+    hmac::verify_with_own_key(&key, canonical_request.as_bytes(), signature).is_ok()
+}
+
+/// Request body signed by an application.
+///
+/// The SHA-256 hash of the body must match the one in the `X-Content-SHA256` header, that is
+/// part of the signature verified by the `Application` request guard. Every route with that
+/// guard must read its body through this data guard.
+#[derive(Debug)]
+pub struct SignedBody(Vec<u8>);
+
+impl SignedBody {
+    /// Gets the bytes of the body.
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.0
+    }
+
+    /// Consumes the signed body wrapper, returning the bytes of the body.
+    pub fn into_inner(self) -> Vec<u8> {
+        self.0
+    }
+}
+
+impl FromData for SignedBody {
+    type Error = Error;
+
+    fn from_data(request: &Request, data: Data) -> data::Outcome<Self, Self::Error> {
+        let mut body = Vec::new();
+        if let Err(e) = data.open().take(SIGNED_BODY_LIMIT).read_to_end(&mut body) {
+            return Outcome::Failure((Status::BadRequest, e.into()));
+        }
+
+        let body_matches = body_hash(request).map_or(false, |expected| {
+            constant_time::verify_slices_are_equal(
+                digest::digest(&digest::SHA256, &body).as_ref(),
+                &expected,
+            ).is_ok()
+        });
+        if body_matches {
+            Outcome::Success(SignedBody(body))
+        } else {
+            GuardError::new(ApiError::invalid_signature(
+                "The request body does not match the X-Content-SHA256 header",
+            )).fail(format_err!("the request body does not match the X-Content-SHA256 header"))
+        }
+    }
+}
+
+/// JSON request body signed by an application.
+///
+/// The body is checked by the `SignedBody` data guard before deserializing it.
+#[derive(Debug)]
+pub struct SignedJson<T>(pub T);
+
+impl<T> SignedJson<T> {
+    /// Consumes the signed JSON wrapper, returning the wrapped value.
+    pub fn into_inner(self) -> T {
+        self.0
+    }
+}
+
+impl<T> Deref for SignedJson<T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.0
+    }
+}
+
+impl<T> FromData for SignedJson<T>
+where
+    T: DeserializeOwned,
+{
+    type Error = Error;
+
+    fn from_data(request: &Request, data: Data) -> data::Outcome<Self, Self::Error> {
+        if !request.content_type().map_or(false, |ct| ct.is_json()) {
+            return Outcome::Forward(data);
+        }
+
+        let body = match SignedBody::from_data(request, data) {
+            Outcome::Success(body) => body,
+            Outcome::Failure(failure) => return Outcome::Failure(failure),
+            Outcome::Forward(data) => return Outcome::Forward(data),
+        };
+
+        match serde_json::from_slice(body.as_bytes()) {
+            Ok(value) => Outcome::Success(SignedJson(value)),
+            Err(e) => Outcome::Failure((Status::BadRequest, e.into())),
+        }
+    }
+}
+
+/// End user throttle request guard, for the routes that first party applications call on behalf
+/// of their users.
+///
+/// First party backends can send the IP address of the user in the `X-End-User-IP` header, and
+/// the request is counted in the hourly limit of that address, as `IpThrottle` does with the
+/// client IP address. If they don't send it, the request is only counted in the limit of the
+/// application, so that a backend is not throttled as a single client. Requests of other
+/// applications are throttled by client IP address.
+///
+/// The `X-App-Id` header is only authenticated by the `Application` guard, so this guard must
+/// come after it in the route.
+#[derive(Debug, Clone, Copy)]
+pub struct UserThrottle<T = Standard> {
+    /// IP address of the end user, or of the client if it's not known.
+    ip: IpAddr,
+    /// Hourly rate limit of the IP address, after counting the request, if it was throttled.
+    rate_limit: Option<RateLimit>,
+    /// Rate limit tier.
+    tier: PhantomData<T>,
+}
+
+impl<T> UserThrottle<T> {
+    /// Gets the IP address of the end user, or of the client if it's not known.
+    pub fn ip(&self) -> IpAddr {
+        self.ip
+    }
+
+    /// Gets the hourly rate limit of the IP address, after counting the request, if it was
+    /// throttled.
+    pub fn rate_limit(&self) -> Option<RateLimit> {
+        self.rate_limit
+    }
+}
+
+impl<'a, 'r, T> FromRequest<'a, 'r> for UserThrottle<T>
+where
+    T: RateLimitTier,
+{
+    type Error = &'static str;
+
+    fn from_request(request: &'a Request<'r>) -> request::Outcome<Self, Self::Error> {
+        let config = match request.guard::<State<RateLimitConfig>>() {
+            Outcome::Success(config) => config,
+            _ => return Outcome::Failure((Status::InternalServerError, "Unknown error")),
+        };
+        let client_ip = match request.guard::<ClientIp>() {
+            Outcome::Success(client_ip) => client_ip.ip(),
+            Outcome::Failure(failure) => return Outcome::Failure(failure),
+            Outcome::Forward(()) => return Outcome::Forward(()),
+        };
+
+        let app_id = request
+            .headers()
+            .get_one("X-App-Id")
+            .and_then(|app_id| app_id.parse::<Uuid>().ok());
+        let first_party = match app_id {
+            Some(app_id) => match CONNECTION_POOL
+                .get()
+                .map_err(Error::from)
+                .and_then(|db_con| db::oauth::get_application(&db_con, app_id))
+            {
+                Ok(app) => app.map_or(false, |app| app.is_first_party()),
+                Err(_) => {
+                    // TODO log error.
+                    return Outcome::Failure((Status::InternalServerError, "Unknown error"));
+                }
+            },
+            None => false,
+        };
+
+        let ip = if first_party {
+            match request.headers().get_one("X-End-User-IP") {
+                Some(ip) => match ip.trim().parse() {
+                    Ok(ip) => ip,
+                    Err(_) => {
+                        // Failure: invalid end user address.
+                        return Outcome::Failure((Status::BadRequest, "Invalid X-End-User-IP"));
+                    }
+                },
+                None => {
+                    return Outcome::Success(UserThrottle {
+                        ip: client_ip,
+                        rate_limit: None,
+                        tier: PhantomData,
+                    })
+                }
+            }
+        } else {
+            client_ip
+        };
+
+        throttle_ip::<T>(ip, &config).map(|rate_limit| UserThrottle {
+            ip,
+            rate_limit: Some(rate_limit),
+            tier: PhantomData,
+        })
+    }
+}
+
+/// User agent of the request, from the `User-Agent` header.
+///
+/// It's stored with the refresh tokens, so that users can recognize their sessions. Long user
+/// agents are truncated.
+#[derive(Debug, Clone)]
+pub struct UserAgent(Option<String>);
+
+impl UserAgent {
+    /// Gets the user agent, if it was sent.
+    pub fn as_str(&self) -> Option<&str> {
+        self.0.as_ref().map(String::as_str)
+    }
+}
+
+impl<'a, 'r> FromRequest<'a, 'r> for UserAgent {
+    type Error = ();
+
+    fn from_request(request: &'a Request<'r>) -> request::Outcome<Self, Self::Error> {
+        Outcome::Success(UserAgent(request.headers().get_one("User-Agent").map(
+            |user_agent| user_agent.chars().take(MAX_USER_AGENT_LEN).collect(),
+        )))
+    }
+}
+
 /// Authenticate user with username and password.
+///
+/// Password attempts are also throttled by the IP address of the end user, so that a single
+/// client cannot use the whole limit of the application (see `UserThrottle`). If the user has
+/// two-factor authentication enabled and no code is given, a `second_factor_required` error is
+/// returned, and the request must be repeated with the code.
 #[post("/refresh_token", data = "<credentials>")]
 pub fn refresh_token(
-    application: Application,
-    credentials: Json<RefreshCredentials>,
-) -> Result<CompressedJson<RefreshResponse>, Error> {
-    unimplemented!()
+    config: State<OAuthConfig>,
+    mail: State<MailService>,
+    application: Application<PasswordLogin>,
+    throttle: UserThrottle<PasswordLogin>,
+    user_agent: UserAgent,
+    credentials: SignedJson<RefreshCredentials>,
+) -> Result<RateLimited<CompressedJson<RefreshResponse>>, ApiError> {
+    if !application.first_party {
+        return Err(ApiError::unauthorized_client(
+            "Only first party applications can use the password grant",
+        ));
+    }
+
+    let db_con = CONNECTION_POOL.get()?;
+    let user = match account::authenticate_user(
+        &db_con,
+        &mail,
+        &credentials.username,
+        &credentials.password,
+        credentials.second_factor.as_ref().map(String::as_str),
+        Some(throttle.ip()),
+    )? {
+        Ok(user) => user,
+        Err(LoginError::SecondFactorRequired) => return Err(ApiError::second_factor_required()),
+        Err(e) => return Err(ApiError::invalid_grant(e.description())),
+    };
+
+    let scope = requested_user_scope(credentials.scope.clone())?;
+
+    let response = issue_tokens(
+        &db_con,
+        &config,
+        user.id(),
+        application.id,
+        scope,
+        user_agent.as_str(),
+    )?;
+    Ok(RateLimited::new(CompressedJson::new(response), application.rate_limit))
+}
+
+/// Send a single-use sign-in link to the email address of a user, for passwordless login.
+///
+/// The link points to the given redirect URI of the application, with the token in its `token`
+/// query parameter, and the application must exchange it in the `/magic_link/token` endpoint.
+/// Only the same application can use it, to get the requested scopes. The response does not
+/// reveal if a user with the given email address exists.
+#[post("/magic_link", data = "<request>")]
+pub fn request_magic_link(
+    config: State<OAuthConfig>,
+    mail: State<MailService>,
+    application: Application<PasswordLogin>,
+    throttle: UserThrottle<PasswordLogin>,
+    request: SignedJson<MagicLinkRequest>,
+) -> Result<RateLimited<Accepted<()>>, ApiError> {
+    if !config.magic_link_login() {
+        return Err(ApiError::unauthorized_client("Sign-in links are not enabled"));
+    }
+    if !application.first_party {
+        return Err(ApiError::unauthorized_client(
+            "Only first party applications can request sign-in links",
+        ));
+    }
+
+    let request = request.into_inner();
+    let scope = requested_user_scope(request.scope)?;
+    let db_con = CONNECTION_POOL.get()?;
+    if !db::oauth::is_redirect_uri_registered(&db_con, application.id, &request.redirect_uri)? {
+        return Err(ApiError::invalid_request(
+            "The redirect URI is not registered for the application",
+        ));
+    }
+
+    account::send_magic_link(
+        &mail,
+        &request.email,
+        Some((application.id, request.redirect_uri)),
+        Scope::to_names(&scope),
+        Some(throttle.ip()),
+    )?;
+    Ok(RateLimited::new(Accepted(None), application.rate_limit))
+}
+
+/// Authenticate user with the token of a sign-in link.
+///
+/// This works as the password grant, with the same throttling and two-factor authentication
+/// handling. The token is only used when the login succeeds.
+#[post("/magic_link/token", data = "<credentials>")]
+pub fn magic_link_token(
+    config: State<OAuthConfig>,
+    mail: State<MailService>,
+    application: Application<PasswordLogin>,
+    throttle: UserThrottle<PasswordLogin>,
+    user_agent: UserAgent,
+    credentials: SignedJson<MagicLinkCredentials>,
+) -> Result<RateLimited<CompressedJson<RefreshResponse>>, ApiError> {
+    if !config.magic_link_login() {
+        return Err(ApiError::unauthorized_client("Sign-in links are not enabled"));
+    }
+    if !application.first_party {
+        return Err(ApiError::unauthorized_client(
+            "Only first party applications can use sign-in links",
+        ));
+    }
+
+    let db_con = CONNECTION_POOL.get()?;
+    let (user, link) = match account::magic_link_login(
+        &db_con,
+        &mail,
+        &credentials.token,
+        credentials.second_factor.as_ref().map(String::as_str),
+        Some(application.id),
+        Some(throttle.ip()),
+    )? {
+        Ok(login) => login,
+        Err(LoginError::SecondFactorRequired) => return Err(ApiError::second_factor_required()),
+        Err(e) => return Err(ApiError::invalid_grant(e.description())),
+    };
+
+    let response = issue_tokens(
+        &db_con,
+        &config,
+        user.id(),
+        application.id,
+        Scope::from_names(link.scopes()),
+        user_agent.as_str(),
+    )?;
+    Ok(RateLimited::new(CompressedJson::new(response), application.rate_limit))
+}
+
+/// Gets the scopes requested in a user grant, the default ones if none were requested.
+///
+/// Application scopes cannot be granted to users.
+fn requested_user_scope(requested: Option<Vec<Scope>>) -> Result<Vec<Scope>, ApiError> {
+    match requested {
+        Some(ref scope) if scope.iter().any(Scope::is_application_scope) => Err(
+            ApiError::invalid_scope("Application scopes cannot be requested in user grants"),
+        ),
+        Some(scope) => Ok(scope),
+        None => Ok(Scope::DEFAULT.to_vec()),
+    }
+}
+
+/// Issues a new refresh token for the given user and application, with its first access token.
+fn issue_tokens(
+    db_con: &db::Connection,
+    config: &OAuthConfig,
+    user_id: i32,
+    app_id: Uuid,
+    scope: Vec<Scope>,
+    user_agent: Option<&str>,
+) -> Result<RefreshResponse, Error> {
+    db_con.transaction(|| {
+        let refresh_token = crypto::random_token()?;
+        let refresh_token_hash = crypto::token_hash(&refresh_token);
+        let refresh_model = db::oauth::insert_refresh_token(
+            db_con,
+            &NewRefreshToken::new(
+                &refresh_token_hash,
+                Utc::now() + Duration::days(REFRESH_TOKEN_LIFETIME_DAYS),
+                user_id,
+                app_id,
+                Scope::to_names(&scope),
+                user_agent,
+            ),
+        )?;
+
+        Ok(RefreshResponse {
+            refresh_token: RefreshToken {
+                token: refresh_token,
+                expiration: refresh_model.expiration().timestamp(),
+            },
+            access_token: issue_access_token(db_con, config, &refresh_model, scope)?,
+            id_token: None,
+        })
+    })
+}
+
+/// Rotates a refresh token, issuing a new one in the same family with a new access token.
+///
+/// The new refresh token keeps the granted scopes, while the access token gets the given scopes.
+/// The user agent of the session gets updated if the request has one.
+/// Returns `None` if the refresh token had already been rotated.
+fn rotate_tokens(
+    db_con: &db::Connection,
+    config: &OAuthConfig,
+    refresh_token: &models::RefreshToken,
+    scope: Vec<Scope>,
+    user_agent: Option<&str>,
+) -> Result<Option<RefreshResponse>, Error> {
+    db_con.transaction(|| {
+        let new_token = crypto::random_token()?;
+        let new_token_hash = crypto::token_hash(&new_token);
+        let new_model = match db::oauth::rotate_refresh_token(
+            db_con,
+            refresh_token.id(),
+            &NewRefreshToken::rotation(
+                &new_token_hash,
+                Utc::now() + Duration::days(REFRESH_TOKEN_LIFETIME_DAYS),
+                refresh_token,
+                user_agent.or_else(|| refresh_token.user_agent()),
+            ),
+        )? {
+            Some(model) => model,
+            None => return Ok(None),
+        };
+
+        Ok(Some(RefreshResponse {
+            refresh_token: RefreshToken {
+                token: new_token,
+                expiration: new_model.expiration().timestamp(),
+            },
+            access_token: issue_access_token(db_con, config, &new_model, scope)?,
+            id_token: None,
+        }))
+    })
+}
+
+/// Revokes the whole family of a rotated refresh token that has been used again.
+///
+/// Either the application or an attacker is using a stolen token, and there is no way to know
+/// which one, so all the tokens of the family get revoked, as the OAuth 2.0 Security Best
+/// Current Practice recommends. The event is recorded in the audit log.
+fn revoke_reused_token(
+    db_con: &db::Connection,
+    refresh_token: &models::RefreshToken,
+    ip: Option<IpAddr>,
+) -> Result<(), Error> {
+    let access_token_hashes =
+        db::oauth::revoke_refresh_token_family(db_con, refresh_token.family())?;
+    db::cache::oauth::remove_access_tokens(&access_token_hashes)?;
+    db::audit::insert_entry(
+        db_con,
+        &NewAuditEntry::new(
+            Some(refresh_token.user_id()),
+            AuditEvent::RefreshTokenReused,
+            ip,
+        ),
+    )
+}
+
+/// Issues a new access token derived from the given refresh token.
+fn issue_access_token(
+    db_con: &db::Connection,
+    config: &OAuthConfig,
+    refresh_token: &models::RefreshToken,
+    scope: Vec<Scope>,
+) -> Result<AccessToken, Error> {
+    let expiration = Utc::now() + Duration::minutes(ACCESS_TOKEN_LIFETIME_MINUTES);
+    let token = new_access_token(
+        config,
+        refresh_token.app_id(),
+        Some(refresh_token.user_id()),
+        &scope,
+        expiration,
+    )?;
+    let model = db::oauth::insert_access_token(
+        db_con,
+        &NewAccessToken::new(
+            &access_token_hash(&token)
+                .ok_or_else(|| format_err!("invalid generated access token"))?,
+            expiration,
+            refresh_token,
+            Scope::to_names(&scope),
+        ),
+    )?;
+
+    Ok(AccessToken {
+        token,
+        scope,
+        expiration: model.expiration().timestamp(),
+    })
+}
+
+/// Issues a new access token for an application, without a user.
+fn issue_application_token(
+    db_con: &db::Connection,
+    config: &OAuthConfig,
+    app_id: Uuid,
+    scope: Vec<Scope>,
+) -> Result<AccessToken, Error> {
+    let expiration = Utc::now() + Duration::minutes(ACCESS_TOKEN_LIFETIME_MINUTES);
+    let token = new_access_token(config, app_id, None, &scope, expiration)?;
+    let model = db::oauth::insert_access_token(
+        db_con,
+        &NewAccessToken::for_application(
+            &access_token_hash(&token)
+                .ok_or_else(|| format_err!("invalid generated access token"))?,
+            expiration,
+            app_id,
+            Scope::to_names(&scope),
+        ),
+    )?;
+
+    Ok(AccessToken {
+        token,
+        scope,
+        expiration: model.expiration().timestamp(),
+    })
+}
+
+/// Generates a new access token in the configured format.
+///
+/// Opaque tokens are random hexadecimal strings, while JWT tokens are signed with the current
+/// signing key.
+fn new_access_token(
+    config: &OAuthConfig,
+    app_id: Uuid,
+    user_id: Option<i32>,
+    scope: &[Scope],
+    expiration: DateTime<Utc>,
+) -> Result<String, Error> {
+    match config.access_token_format() {
+        AccessTokenFormat::Opaque => Ok(hex::encode(crypto::random_token()?)),
+        AccessTokenFormat::Jwt => {
+            let scopes = Scope::to_names(scope);
+            jwt::encode(
+                config,
+                &AccessTokenClaims::new(config, app_id, user_id, &scopes, expiration.timestamp())?,
+            )
+        }
+    }
+}
+
+/// Gets the hash of a hexadecimal 16-byte token, as it's stored in the database.
+///
+/// Returns `None` if the token is not well formed.
+fn hex_token_hash(token: &str) -> Option<Vec<u8>> {
+    match hex::decode(token) {
+        Ok(ref bytes) if bytes.len() == 16 => Some(crypto::token_hash(bytes)),
+        _ => None,
+    }
+}
+
+/// Gets the hash of an access token, as it's stored in the database.
+///
+/// JWT access tokens are hashed as they are, while opaque tokens are decoded first. Returns
+/// `None` if the token is not well formed.
+pub fn access_token_hash(token: &str) -> Option<Vec<u8>> {
+    if token.contains('.') {
+        Some(crypto::token_hash(token.as_bytes()))
+    } else {
+        hex_token_hash(token)
+    }
+}
+
+/// Validates an access token, returning its information if it's valid.
+///
+/// Valid tokens are cached for a short period of time, so that most requests don't need to
+/// reach the database.
+pub fn validate_access_token(token: &str) -> Result<Option<AccessTokenInfo>, Error> {
+    let token_hash = match access_token_hash(token) {
+        Some(hash) => hash,
+        None => return Ok(None),
+    };
+
+    if let Some(info) = db::cache::oauth::get_access_token(&token_hash)? {
+        return Ok(if info.is_expired() { None } else { Some(info) });
+    }
+
+    let db_con = CONNECTION_POOL.get()?;
+    match db::oauth::get_access_token(&db_con, &token_hash)? {
+        Some(ref token) if token.expiration() > Utc::now() => {
+            // The token could have been revoked after reading it.
+            if db::cache::oauth::is_access_token_revoked(&token_hash)? {
+                return Ok(None);
+            }
+            let info = AccessTokenInfo::from(token);
+            db::cache::oauth::set_access_token(&token_hash, &info)?;
+            Ok(Some(info))
+        }
+        _ => Ok(None),
+    }
 }
 
 /// Access token information structure.
+///
+/// The token is either an opaque hexadecimal string or a signed JWT, depending on the
+/// configuration.
 #[derive(Debug, Serialize)]
 pub struct AccessToken {
+    token: String,
     scope: Vec<Scope>,
     expiration: i64,
 }
 
-/// Get access token.
-#[get("/access_token")]
-pub fn access_token() -> Result<CompressedJson<AccessToken>, Error> {
-    unimplemented!()
+/// Access token request.
+#[derive(Debug, Deserialize)]
+pub struct AccessTokenRequest {
+    /// Refresh token to derive the access token from.
+    #[serde(deserialize_with = "hex_token")]
+    refresh_token: [u8; 16],
+    /// Requested scopes. All the scopes of the refresh token will be used if not present.
+    scope: Option<Vec<Scope>>,
+}
+
+/// Get a short-lived access token from a refresh token.
+///
+/// The refresh token is rotated: the response contains a new refresh token that replaces it, and
+/// it cannot be used again. Using it again revokes all the refresh tokens that come from the same
+/// login.
+#[post("/access_token", data = "<request>")]
+pub fn access_token(
+    config: State<OAuthConfig>,
+    application: Application<TokenIssuance>,
+    client_ip: Option<ClientIp>,
+    user_agent: UserAgent,
+    request: SignedJson<AccessTokenRequest>,
+) -> Result<RateLimited<CompressedJson<RefreshResponse>>, ApiError> {
+    let db_con = CONNECTION_POOL.get()?;
+
+    let refresh_token =
+        match db::oauth::get_refresh_token(&db_con, &crypto::token_hash(&request.refresh_token))? {
+            Some(token) => token,
+            None => return Err(ApiError::invalid_grant("Invalid refresh token")),
+        };
+    if refresh_token.app_id() != application.id || refresh_token.expiration() <= Utc::now() {
+        return Err(ApiError::invalid_grant("Invalid refresh token"));
+    }
+    let ip = client_ip.map(|client_ip| client_ip.ip());
+    if refresh_token.is_rotated() {
+        revoke_reused_token(&db_con, &refresh_token, ip)?;
+        return Err(ApiError::invalid_grant("Invalid refresh token"));
+    }
+
+    let granted = Scope::from_names(refresh_token.scopes());
+    let scope = match request.scope {
+        Some(ref requested) if requested.iter().all(|scope| granted.contains(scope)) => {
+            requested.clone()
+        }
+        Some(_) => {
+            return Err(ApiError::invalid_scope(
+                "The requested scope exceeds the granted scope",
+            ))
+        }
+        None => granted,
+    };
+
+    match rotate_tokens(&db_con, &config, &refresh_token, scope, user_agent.as_str())? {
+        Some(response) => Ok(RateLimited::new(
+            CompressedJson::new(response),
+            application.rate_limit,
+        )),
+        None => {
+            // The token was rotated by a concurrent request.
+            revoke_reused_token(&db_con, &refresh_token, ip)?;
+            Err(ApiError::invalid_grant("Invalid refresh token"))
+        }
+    }
+}
+
+/// Authorization request parameters (RFC 6749, section 4.1.1).
+///
+/// The PKCE code challenge (RFC 7636, section 4.3) is mandatory, and only the `S256` method is
+/// accepted.
+#[derive(Debug, FromForm)]
+pub struct AuthorizationRequest {
+    response_type: String,
+    client_id: String,
+    redirect_uri: String,
+    scope: Option<String>,
+    state: Option<String>,
+    code_challenge: Option<String>,
+    code_challenge_method: Option<String>,
+    nonce: Option<String>,
+}
+
+/// Authorization request error.
+#[derive(Debug)]
+pub enum AuthorizationError {
+    /// The client ID or the redirect URI are not valid, so the user must not be redirected.
+    InvalidClient(&'static str),
+    /// The request is not valid, and the user must be redirected to the given URL.
+    Redirect(String),
+    /// Unexpected server error.
+    Server(Error),
+}
+
+impl From<Error> for AuthorizationError {
+    fn from(error: Error) -> AuthorizationError {
+        AuthorizationError::Server(error)
+    }
+}
+
+impl AuthorizationRequest {
+    /// Validates the authorization request.
+    pub fn validate(&self) -> Result<Authorization, AuthorizationError> {
+        let app_id = self.client_id
+            .parse::<Uuid>()
+            .map_err(|_| AuthorizationError::InvalidClient("Invalid client ID"))?;
+        let db_con = CONNECTION_POOL.get().map_err(Error::from)?;
+        let app = match db::oauth::get_application(&db_con, app_id)? {
+            Some(app) => app,
+            None => return Err(AuthorizationError::InvalidClient("Invalid client ID")),
+        };
+        if !db::oauth::is_redirect_uri_registered(&db_con, app_id, &self.redirect_uri)? {
+            return Err(AuthorizationError::InvalidClient("Invalid redirect URI"));
+        }
+
+        // From now on, errors are sent to the redirect URI.
+        let error = |code: &str, description: &str| {
+            AuthorizationError::Redirect(redirect_url(
+                &self.redirect_uri,
+                &[
+                    ("error", Some(code)),
+                    ("error_description", Some(description)),
+                    ("state", self.state.as_ref().map(String::as_str)),
+                ],
+            ))
+        };
+
+        if self.response_type != "code" {
+            return Err(error(
+                "unsupported_response_type",
+                "Only the code response type is supported",
+            ));
+        }
+
+        let code_challenge = match (&self.code_challenge, &self.code_challenge_method) {
+            (&Some(ref challenge), &Some(ref method)) if method == "S256" => challenge.clone(),
+            _ => {
+                return Err(error(
+                    "invalid_request",
+                    "A PKCE code challenge with the S256 method is required",
+                ))
+            }
+        };
+
+        let scope = match self.scope {
+            Some(ref scope) => scope
+                .split_whitespace()
+                .map(str::parse)
+                .collect::<Result<Vec<Scope>, _>>()
+                .map_err(|_| error("invalid_scope", "Unknown scope requested"))?,
+            None => Vec::new(),
+        };
+        if scope.is_empty() {
+            return Err(error("invalid_scope", "No scope requested"));
+        }
+        if scope.iter().any(Scope::is_application_scope) {
+            return Err(error(
+                "invalid_scope",
+                "Application scopes cannot be requested in user grants",
+            ));
+        }
+
+        Ok(Authorization {
+            app,
+            redirect_uri: self.redirect_uri.clone(),
+            scope,
+            state: self.state.clone(),
+            code_challenge,
+            nonce: self.nonce.clone(),
+        })
+    }
+}
+
+/// Valid authorization request, waiting for the user consent.
+#[derive(Debug)]
+pub struct Authorization {
+    /// Application requesting the authorization.
+    app: models::Application,
+    /// Redirect URI of the request.
+    redirect_uri: String,
+    /// Requested scopes.
+    scope: Vec<Scope>,
+    /// Opaque client state, that must be sent back to the redirect URI.
+    state: Option<String>,
+    /// PKCE code challenge.
+    code_challenge: String,
+    /// OpenID Connect nonce, that must be sent back in the ID token.
+    nonce: Option<String>,
+}
+
+impl Authorization {
+    /// Gets the application requesting the authorization.
+    pub fn application(&self) -> &models::Application {
+        &self.app
+    }
+
+    /// Gets the requested scopes.
+    pub fn scope(&self) -> &[Scope] {
+        &self.scope
+    }
+
+    /// Denies the authorization, returning the URL where the user must be redirected.
+    pub fn deny(&self) -> String {
+        redirect_url(
+            &self.redirect_uri,
+            &[
+                ("error", Some("access_denied")),
+                ("state", self.state.as_ref().map(String::as_str)),
+            ],
+        )
+    }
+
+    /// Grants the authorization to the given user, returning the URL where the user must be
+    /// redirected.
+    ///
+    /// This issues a single use authorization code that the application can exchange for a
+    /// refresh token in the token endpoint.
+    pub fn grant(self, user_id: i32) -> Result<String, Error> {
+        let code = crypto::random_token()?;
+        db::cache::oauth::set_authorization_code(
+            &crypto::token_hash(&code),
+            &AuthorizationCode::new(
+                user_id,
+                self.app.id(),
+                self.redirect_uri.clone(),
+                Scope::to_names(&self.scope),
+                self.code_challenge,
+                self.nonce,
+            ),
+        )?;
+
+        let code = hex::encode(code);
+        Ok(redirect_url(
+            &self.redirect_uri,
+            &[
+                ("code", Some(code.as_str())),
+                ("state", self.state.as_ref().map(String::as_str)),
+            ],
+        ))
+    }
+}
+
+/// Builds a redirect URL, adding the given query parameters to the redirect URI.
+pub fn redirect_url(redirect_uri: &str, params: &[(&str, Option<&str>)]) -> String {
+    let mut url = redirect_uri.to_owned();
+    let mut separator = if redirect_uri.contains('?') { '&' } else { '?' };
+    for &(name, value) in params {
+        if let Some(value) = value {
+            url.push(separator);
+            url.push_str(name);
+            url.push('=');
+            url.push_str(&URI::percent_encode(value));
+            separator = '&';
+        }
+    }
+    url
+}
+
+/// Checks a PKCE code verifier against the `S256` code challenge (RFC 7636, section 4.6).
+fn verify_code_challenge(code_challenge: &str, code_verifier: &str) -> bool {
+    if code_verifier.len() < 43 || code_verifier.len() > 128 {
+        return false;
+    }
+
+    let computed = base64::encode_config(
+        digest::digest(&digest::SHA256, code_verifier.as_bytes()).as_ref(),
+        base64::URL_SAFE_NO_PAD,
+    );
+    constant_time::verify_slices_are_equal(computed.as_bytes(), code_challenge.as_bytes()).is_ok()
+}
+
+/// Token endpoint request, for the grants that are not specific to first party applications.
+#[derive(Debug, Deserialize)]
+#[serde(tag = "grant_type", rename_all = "snake_case")]
+pub enum TokenRequest {
+    /// Authorization code grant (RFC 6749, section 4.1.3).
+    AuthorizationCode {
+        /// Authorization code received in the redirect URI.
+        #[serde(deserialize_with = "hex_token")]
+        code: [u8; 16],
+        /// Redirect URI used in the authorization request.
+        redirect_uri: String,
+        /// PKCE code verifier.
+        code_verifier: String,
+    },
+    /// Client credentials grant (RFC 6749, section 4.4.2).
+    ClientCredentials {
+        /// Requested scopes. All the client scopes of the application will be used if not
+        /// present.
+        scope: Option<Vec<Scope>>,
+    },
+}
+
+/// Token endpoint request sent as a form, as in the OAuth specification, by the applications
+/// that authenticate with HTTP Basic authentication.
+#[derive(Debug, FromForm)]
+pub struct TokenForm {
+    grant_type: String,
+    /// Application ID, that must match the authenticated application if present.
+    client_id: Option<String>,
+    code: Option<String>,
+    redirect_uri: Option<String>,
+    code_verifier: Option<String>,
+    /// Space separated list of requested scopes.
+    scope: Option<String>,
+}
+
+impl TokenForm {
+    /// Validates the form of the given application, converting it to a token request.
+    pub fn into_request(self, app_id: Uuid) -> Result<TokenRequest, ApiError> {
+        if let Some(client_id) = self.client_id {
+            if client_id.parse::<Uuid>().ok() != Some(app_id) {
+                return Err(ApiError::invalid_client(
+                    "The client ID does not match the authenticated application",
+                ));
+            }
+        }
+
+        match self.grant_type.as_str() {
+            "authorization_code" => {
+                let code = match self.code.as_ref().and_then(|code| hex::decode(code).ok()) {
+                    Some(ref code) if code.len() == 16 => {
+                        let mut token = [0u8; 16];
+                        token.copy_from_slice(code);
+                        token
+                    }
+                    _ => return Err(ApiError::invalid_grant("Invalid authorization code")),
+                };
+                match (self.redirect_uri, self.code_verifier) {
+                    (Some(redirect_uri), Some(code_verifier)) => {
+                        Ok(TokenRequest::AuthorizationCode {
+                            code,
+                            redirect_uri,
+                            code_verifier,
+                        })
+                    }
+                    _ => Err(ApiError::invalid_request(
+                        "The redirect URI and the PKCE code verifier are required",
+                    )),
+                }
+            }
+            "client_credentials" => {
+                let scope = match self.scope {
+                    Some(scope) => Some(scope
+                        .split_whitespace()
+                        .map(str::parse)
+                        .collect::<Result<Vec<Scope>, _>>()
+                        .map_err(|_| ApiError::invalid_scope("Unknown scope requested"))?),
+                    None => None,
+                };
+                Ok(TokenRequest::ClientCredentials { scope })
+            }
+            _ => Err(ApiError::new(
+                Status::BadRequest,
+                "unsupported_grant_type",
+                Some("Only the authorization code and client credentials grants are supported"),
+            )),
+        }
+    }
+}
+
+/// Token endpoint response.
+#[derive(Debug, Serialize)]
+#[serde(untagged)]
+pub enum TokenResponse {
+    /// User grants respond with a refresh token and its first access token.
+    Refresh(RefreshResponse),
+    /// Application grants only respond with an access token.
+    Access(AccessToken),
+}
+
+/// Token endpoint.
+#[post("/token", format = "application/json", data = "<request>")]
+pub fn token(
+    config: State<OAuthConfig>,
+    application: Application<TokenIssuance>,
+    user_agent: UserAgent,
+    request: SignedJson<TokenRequest>,
+) -> Result<RateLimited<CompressedJson<TokenResponse>>, ApiError> {
+    token_response(&config, &application, &user_agent, request.into_inner())
+}
+
+/// Token endpoint, for the applications that authenticate with HTTP Basic authentication.
+#[post("/token", format = "application/x-www-form-urlencoded", data = "<form>", rank = 2)]
+#[cfg_attr(feature = "cargo-clippy", allow(needless_pass_by_value))]
+pub fn token_form(
+    config: State<OAuthConfig>,
+    application: BasicApplication<TokenIssuance>,
+    user_agent: UserAgent,
+    form: Form<TokenForm>,
+) -> Result<RateLimited<CompressedJson<TokenResponse>>, ApiError> {
+    let application = application.into_inner();
+    let request = form.into_inner().into_request(application.id)?;
+    token_response(&config, &application, &user_agent, request)
+}
+
+/// Handles a token request of an authenticated application.
+fn token_response(
+    config: &OAuthConfig,
+    application: &Application<TokenIssuance>,
+    user_agent: &UserAgent,
+    request: TokenRequest,
+) -> Result<RateLimited<CompressedJson<TokenResponse>>, ApiError> {
+    let response = match request {
+        TokenRequest::AuthorizationCode {
+            code,
+            redirect_uri,
+            code_verifier,
+        } => {
+            let code_hash = crypto::token_hash(&code);
+            let code = match db::cache::oauth::take_authorization_code(&code_hash)? {
+                Some(code) => code,
+                None => return Err(ApiError::invalid_grant("Invalid authorization code")),
+            };
+            if code.app_id() != application.id || code.redirect_uri() != redirect_uri
+                || !verify_code_challenge(code.code_challenge(), &code_verifier)
+            {
+                return Err(ApiError::invalid_grant("Invalid authorization code"));
+            }
+
+            let scope = Scope::from_names(code.scopes());
+            let openid = scope.contains(&Scope::OpenId);
+            let db_con = CONNECTION_POOL.get()?;
+            let mut response = issue_tokens(
+                &db_con,
+                config,
+                code.user_id(),
+                application.id,
+                scope,
+                user_agent.as_str(),
+            )?;
+            if openid {
+                response.id_token = Some(oidc::id_token(
+                    config,
+                    code.user_id(),
+                    application.id,
+                    code.auth_time(),
+                    code.nonce(),
+                )?);
+            }
+            TokenResponse::Refresh(response)
+        }
+        TokenRequest::ClientCredentials { scope } => {
+            let db_con = CONNECTION_POOL.get()?;
+            let app = match db::oauth::get_application(&db_con, application.id)? {
+                Some(app) => app,
+                None => return Err(ApiError::server_error()),
+            };
+
+            // Only application scopes can be granted without a user.
+            let allowed = Scope::from_names(app.client_scopes())
+                .into_iter()
+                .filter(Scope::is_application_scope)
+                .collect::<Vec<_>>();
+            let scope = match scope {
+                Some(requested) => {
+                    if requested.iter().all(|scope| allowed.contains(scope)) {
+                        requested
+                    } else {
+                        return Err(ApiError::invalid_scope(
+                            "The requested scope is not an application scope allowed for the \
+                             application",
+                        ));
+                    }
+                }
+                None => allowed,
+            };
+            if scope.is_empty() {
+                return Err(ApiError::unauthorized_client(
+                    "The application is not allowed to use the client credentials grant",
+                ));
+            }
+
+            TokenResponse::Access(issue_application_token(
+                &db_con,
+                config,
+                application.id,
+                scope,
+            )?)
+        }
+    };
+
+    Ok(RateLimited::new(CompressedJson::new(response), application.rate_limit))
+}
+
+/// Token revocation request (RFC 7009, section 2.1).
+#[derive(Debug, Deserialize)]
+pub struct RevocationRequest {
+    /// Token to revoke.
+    token: String,
+    /// Type of the token, `refresh_token` or `access_token`, to speed up the lookup.
+    token_type_hint: Option<String>,
+}
+
+/// Revokes a refresh or access token issued for the application.
+///
+/// Revoking a refresh token revokes all the access tokens derived from it. As the specification
+/// requires, the response is successful even if the token was not valid.
+#[post("/revoke", data = "<request>")]
+pub fn revoke(
+    application: Application,
+    request: SignedJson<RevocationRequest>,
+) -> Result<RateLimited<()>, ApiError> {
+    let db_con = CONNECTION_POOL.get()?;
+
+    let revoke_refresh = || -> Result<bool, Error> {
+        let token_hash = match hex_token_hash(&request.token) {
+            Some(hash) => hash,
+            None => return Ok(false),
+        };
+        match db::oauth::revoke_refresh_token(&db_con, &token_hash, application.id)? {
+            Some(access_token_hashes) => {
+                db::cache::oauth::remove_access_tokens(&access_token_hashes)?;
+                Ok(true)
+            }
+            None => Ok(false),
+        }
+    };
+    let revoke_access = || -> Result<bool, Error> {
+        let token_hash = match access_token_hash(&request.token) {
+            Some(hash) => hash,
+            None => return Ok(false),
+        };
+        if db::oauth::revoke_access_token(&db_con, &token_hash, application.id)? {
+            db::cache::oauth::remove_access_tokens(&[&token_hash])?;
+            Ok(true)
+        } else {
+            Ok(false)
+        }
+    };
+
+    if request.token_type_hint.as_ref().map(String::as_str) == Some("access_token") {
+        let _ = revoke_access()? || revoke_refresh()?;
+    } else {
+        let _ = revoke_refresh()? || revoke_access()?;
+    }
+
+    Ok(RateLimited::new((), application.rate_limit))
+}
+
+/// Token introspection request (RFC 7662, section 2.1).
+#[derive(Debug, Deserialize)]
+pub struct IntrospectionRequest {
+    /// Token to introspect.
+    token: String,
+    /// Type of the token, `refresh_token` or `access_token`, to speed up the lookup.
+    token_type_hint: Option<String>,
+}
+
+/// Token introspection response (RFC 7662, section 2.2).
+#[derive(Debug, Default, Serialize)]
+pub struct IntrospectionResponse {
+    active: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    scope: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    client_id: Option<Uuid>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    sub: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    exp: Option<i64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    token_type: Option<&'static str>,
+}
+
+impl IntrospectionResponse {
+    /// Creates the response for an active token.
+    fn active<S: AsRef<str>>(
+        scopes: &[S],
+        client_id: Uuid,
+        user_id: Option<i32>,
+        expiration: i64,
+        token_type: Option<&'static str>,
+    ) -> IntrospectionResponse {
+        IntrospectionResponse {
+            active: true,
+            scope: Some(
+                scopes
+                    .iter()
+                    .map(AsRef::as_ref)
+                    .collect::<Vec<_>>()
+                    .join(" "),
+            ),
+            client_id: Some(client_id),
+            sub: user_id.map(|id| id.to_string()),
+            exp: Some(expiration),
+            token_type,
+        }
+    }
+
+    /// Gets the time the response can be cached, in seconds.
+    fn max_age(&self) -> i64 {
+        match self.exp {
+            Some(exp) if self.active => {
+                cmp::min(INTROSPECTION_CACHE_SECONDS, exp - Utc::now().timestamp())
+            }
+            _ => 0,
+        }
+    }
+}
+
+/// Response that clients can cache for a limited amount of time.
+#[derive(Debug)]
+pub struct Cacheable<R> {
+    /// Inner response.
+    response: R,
+    /// Maximum time the response can be cached, in seconds.
+    max_age: i64,
+}
+
+impl<R> Cacheable<R> {
+    /// Creates a new response that can be cached for the given number of seconds.
+    ///
+    /// A non-positive `max_age` forbids clients from storing the response.
+    pub fn new(response: R, max_age: i64) -> Cacheable<R> {
+        Cacheable { response, max_age }
+    }
+}
+
+impl<'r, R> Responder<'r> for Cacheable<R>
+where
+    R: Responder<'r>,
+{
+    fn respond_to(self, request: &Request) -> Result<Response<'r>, Status> {
+        let mut response = self.response.respond_to(request)?;
+        let cache_control = if self.max_age > 0 {
+            format!("private, max-age={}", self.max_age)
+        } else {
+            "no-store".to_owned()
+        };
+        let _ = response.set_raw_header("Cache-Control", cache_control);
+        Ok(response)
+    }
+}
+
+/// Introspects a token.
+///
+/// Applications can introspect the tokens issued for them. Access tokens of other applications
+/// can also be introspected by first party applications and by resource servers, that are
+/// allowed the `tokens:introspect` client scope. Otherwise, the token is reported as inactive.
+#[post("/introspect", data = "<request>")]
+pub fn introspect(
+    application: Application,
+    request: SignedJson<IntrospectionRequest>,
+) -> Result<RateLimited<Cacheable<CompressedJson<IntrospectionResponse>>>, ApiError> {
+    let can_introspect_others = || -> Result<bool, Error> {
+        if application.first_party {
+            return Ok(true);
+        }
+        let db_con = CONNECTION_POOL.get()?;
+        Ok(
+            db::oauth::get_application(&db_con, application.id)?.map_or(false, |app| {
+                Scope::from_names(app.client_scopes()).contains(&Scope::TokensIntrospect)
+            }),
+        )
+    };
+    let introspect_access = || -> Result<Option<IntrospectionResponse>, Error> {
+        let info = match validate_access_token(&request.token)? {
+            Some(info) => info,
+            None => return Ok(None),
+        };
+        if info.app_id() != application.id && !can_introspect_others()? {
+            return Ok(None);
+        }
+        Ok(Some(IntrospectionResponse::active(
+            info.scopes(),
+            info.app_id(),
+            info.user_id(),
+            info.expiration(),
+            Some("bearer"),
+        )))
+    };
+    let introspect_refresh = || -> Result<Option<IntrospectionResponse>, Error> {
+        let token_hash = match hex_token_hash(&request.token) {
+            Some(hash) => hash,
+            None => return Ok(None),
+        };
+        let db_con = CONNECTION_POOL.get()?;
+        Ok(
+            match db::oauth::get_refresh_token(&db_con, &token_hash)? {
+                // Rotated tokens are no longer valid, as in the refresh token grant.
+                Some(ref token)
+                    if token.app_id() == application.id && token.is_active(Utc::now()) =>
+                {
+                    Some(IntrospectionResponse::active(
+                        token.scopes(),
+                        token.app_id(),
+                        Some(token.user_id()),
+                        token.expiration().timestamp(),
+                        None,
+                    ))
+                }
+                _ => None,
+            },
+        )
+    };
+
+    let response = if request.token_type_hint.as_ref().map(String::as_str)
+        == Some("refresh_token")
+    {
+        match introspect_refresh()? {
+            Some(response) => Some(response),
+            None => introspect_access()?,
+        }
+    } else {
+        match introspect_access()? {
+            Some(response) => Some(response),
+            None => introspect_refresh()?,
+        }
+    }.unwrap_or_default();
+
+    let max_age = response.max_age();
+    Ok(RateLimited::new(
+        Cacheable::new(CompressedJson::new(response), max_age),
+        application.rate_limit,
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use base64;
+    use ring::digest;
+    use rocket;
+    use rocket::local::Client;
+    use uuid::Uuid;
+
+    use super::{basic_credentials, canonical_request, verify_code_challenge, verify_signature};
+
+    #[test]
+    fn builds_the_canonical_request() {
+        let client = Client::new(rocket::ignite()).unwrap();
+        let body_hash = digest::digest(&digest::SHA256, b"{}");
+
+        let request = client.post("/api/v1/oauth/token?grant_type=refresh_token");
+        assert_eq!(
+            canonical_request(request.inner(), 1_518_000_000, "abc", body_hash.as_ref()),
+            "POST\n/api/v1/oauth/token\ngrant_type=refresh_token\n1518000000\nabc\n\
+             44136fa355b3678a1146ad16f7e8649e94fb4fc21fe77e8310c060f61caaff8a"
+        );
+
+        let request = client.get("/api/v1/user");
+        let body_hash = digest::digest(&digest::SHA256, b"");
+        assert_eq!(
+            canonical_request(request.inner(), 1_518_000_000, "def", body_hash.as_ref()),
+            "GET\n/api/v1/user\n\n1518000000\ndef\n\
+             e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855"
+        );
+    }
+
+    #[test]
+    fn verifies_signatures() {
+        // RFC 4231, test case 2.
+        let signature = [
+            0x5b, 0xdc, 0xc1, 0x46, 0xbf, 0x60, 0x75, 0x4e, 0x6a, 0x04, 0x24, 0x26, 0x08, 0x95,
+            0x75, 0xc7, 0x5a, 0x00, 0x3f, 0x08, 0x9d, 0x27, 0x39, 0x83, 0x9d, 0xec, 0x58, 0xb9,
+            0x64, 0xec, 0x38, 0x43,
+        ];
+        assert!(verify_signature(b"Jefe", "what do ya want for nothing?", &signature));
+        assert!(!verify_signature(b"Jefe", "what do ya want for nothing!", &signature));
+        assert!(!verify_signature(b"jefe", "what do ya want for nothing?", &signature));
+        assert!(!verify_signature(b"Jefe", "what do ya want for nothing?", &signature[..31]));
+    }
+
+    #[test]
+    fn verifies_s256_code_challenges() {
+        // RFC 7636, appendix B.
+        let verifier = "dBjftJeZ4CVP-mB92K27uhbUJU1p1r_wW1gFWFOEjXk";
+        let challenge = "E9Melhoa2OwvFrEMTJguCHaoeK1t8URWbuGJSstw-cM";
+        assert!(verify_code_challenge(challenge, verifier));
+        assert!(!verify_code_challenge(challenge, "dBjftJeZ4CVP-mB92K27uhbUJU1p1r_wW1gFWFOEjXK"));
+        assert!(!verify_code_challenge(
+            "E9Melhoa2OwvFrEMTJguCHaoeK1t8URWbuGJSstw-cM=",
+            verifier
+        ));
+        assert!(!verify_code_challenge(verifier, verifier));
+    }
+
+    #[test]
+    fn rejects_code_verifiers_of_invalid_length() {
+        let short = "a".repeat(42);
+        let challenge = base64::encode_config(
+            digest::digest(&digest::SHA256, short.as_bytes()).as_ref(),
+            base64::URL_SAFE_NO_PAD,
+        );
+        assert!(!verify_code_challenge(&challenge, &short));
+
+        let long = "a".repeat(129);
+        let challenge = base64::encode_config(
+            digest::digest(&digest::SHA256, long.as_bytes()).as_ref(),
+            base64::URL_SAFE_NO_PAD,
+        );
+        assert!(!verify_code_challenge(&challenge, &long));
+    }
+
+    #[test]
+    fn parses_basic_credentials() {
+        let app_id = Uuid::parse_str("7c5ea6d1-8e0d-4bb7-9a64-3a0f6c1d2e4b").unwrap();
+        let header = format!(
+            "Basic {}",
+            base64::encode(&format!("{}:{}", app_id, "00ff10"))
+        );
+        assert_eq!(
+            basic_credentials(&header),
+            Some((app_id, vec![0x00, 0xff, 0x10]))
+        );
+
+        let no_secret = format!("Basic {}", base64::encode(&app_id.to_string()));
+        assert_eq!(basic_credentials(&no_secret), None);
+        let invalid_id = format!("Basic {}", base64::encode("app:00ff10"));
+        assert_eq!(basic_credentials(&invalid_id), None);
+        let invalid_secret = format!("Basic {}", base64::encode(&format!("{}:xyz", app_id)));
+        assert_eq!(basic_credentials(&invalid_secret), None);
+        assert_eq!(basic_credentials(&header.replace("Basic", "Bearer")), None);
+        assert_eq!(basic_credentials("Basic !!!"), None);
+    }
 }
@@ -0,0 +1,151 @@
+//! OAuth scopes module.
+
+use std::fmt;
+use std::str::FromStr;
+
+use failure::Error;
+
+/// OAuth scope.
+///
+/// Scopes define what an access token gives access to. They are serialized with their
+/// `resource:action` name, such as `profile:read`, except the OpenID Connect scopes, that have
+/// the names of the specification. To add a new scope, add its variant here and register it in
+/// `Scope::ALL`, `Scope::as_str()`, `Scope::description()` and, if it's an application scope,
+/// `Scope::is_application_scope()`.
+///
+/// User scopes give access to the resources of a user, and can only be granted with the user
+/// grants. Application scopes give access to resources of the application itself, and can only
+/// be granted with the client credentials grant. User grants that don't request any scope only
+/// get `Scope::DEFAULT`; the scopes that modify the account must always be requested explicitly.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum Scope {
+    /// Read the user profile.
+    #[serde(rename = "profile:read")]
+    ProfileRead,
+    /// Modify the user profile.
+    #[serde(rename = "profile:write")]
+    ProfileWrite,
+    /// Read the applications managed by the user.
+    #[serde(rename = "apps:read")]
+    AppsRead,
+    /// Register, modify and deactivate the applications managed by the user.
+    #[serde(rename = "apps:write")]
+    AppsWrite,
+    /// Read the sessions of the user.
+    #[serde(rename = "sessions:read")]
+    SessionsRead,
+    /// Revoke the sessions of the user.
+    #[serde(rename = "sessions:write")]
+    SessionsWrite,
+    /// Enable and disable two-factor authentication.
+    #[serde(rename = "two_factor:write")]
+    TwoFactorWrite,
+    /// Sign in with OpenID Connect, getting an ID token and access to the user info endpoint.
+    #[serde(rename = "openid")]
+    OpenId,
+    /// Get the email address of the user, with OpenID Connect.
+    #[serde(rename = "email")]
+    Email,
+    /// Get the basic profile of the user, with OpenID Connect.
+    #[serde(rename = "profile")]
+    Profile,
+    /// Introspect access tokens issued to other applications, as a resource server.
+    #[serde(rename = "tokens:introspect")]
+    TokensIntrospect,
+}
+
+impl Scope {
+    /// All the available scopes.
+    pub const ALL: &'static [Scope] = &[
+        Scope::ProfileRead,
+        Scope::ProfileWrite,
+        Scope::AppsRead,
+        Scope::AppsWrite,
+        Scope::SessionsRead,
+        Scope::SessionsWrite,
+        Scope::TwoFactorWrite,
+        Scope::OpenId,
+        Scope::Email,
+        Scope::Profile,
+        Scope::TokensIntrospect,
+    ];
+
+    /// Scopes granted in the user grants that don't request any scope.
+    pub const DEFAULT: &'static [Scope] = &[Scope::ProfileRead];
+
+    /// Gets the name of the scope.
+    pub fn as_str(&self) -> &'static str {
+        match *self {
+            Scope::ProfileRead => "profile:read",
+            Scope::ProfileWrite => "profile:write",
+            Scope::AppsRead => "apps:read",
+            Scope::AppsWrite => "apps:write",
+            Scope::SessionsRead => "sessions:read",
+            Scope::SessionsWrite => "sessions:write",
+            Scope::TwoFactorWrite => "two_factor:write",
+            Scope::OpenId => "openid",
+            Scope::Email => "email",
+            Scope::Profile => "profile",
+            Scope::TokensIntrospect => "tokens:introspect",
+        }
+    }
+
+    /// Gets a human readable description of what the scope gives access to.
+    pub fn description(&self) -> &'static str {
+        match *self {
+            Scope::ProfileRead => "Read your profile information",
+            Scope::ProfileWrite => "Modify your profile information",
+            Scope::AppsRead => "See the applications you manage",
+            Scope::AppsWrite => "Register, modify and deactivate the applications you manage",
+            Scope::SessionsRead => "See the devices and applications where you are logged in",
+            Scope::SessionsWrite => "Log you out of your devices and applications",
+            Scope::TwoFactorWrite => "Enable and disable two-factor authentication",
+            Scope::OpenId => "Sign you in with your account",
+            Scope::Email => "See your email address",
+            Scope::Profile => "See your username",
+            Scope::TokensIntrospect => "Validate the access tokens issued to other applications",
+        }
+    }
+
+    /// Checks if the scope is an application scope, that can only be granted to applications
+    /// without a user.
+    pub fn is_application_scope(&self) -> bool {
+        match *self {
+            Scope::TokensIntrospect => true,
+            _ => false,
+        }
+    }
+
+    /// Parses a list of scope names, as stored in the database.
+    ///
+    /// Unknown scopes are ignored, so that removing a scope does not invalidate existing tokens.
+    pub fn from_names<S: AsRef<str>>(names: &[S]) -> Vec<Scope> {
+        names
+            .iter()
+            .filter_map(|name| name.as_ref().parse().ok())
+            .collect()
+    }
+
+    /// Gets the names of a list of scopes, to store them in the database.
+    pub fn to_names(scopes: &[Scope]) -> Vec<String> {
+        scopes.iter().map(|scope| scope.as_str().to_owned()).collect()
+    }
+}
+
+impl FromStr for Scope {
+    type Err = Error;
+
+    fn from_str(name: &str) -> Result<Scope, Error> {
+        Scope::ALL
+            .iter()
+            .find(|scope| scope.as_str() == name)
+            .cloned()
+            .ok_or_else(|| format_err!("unknown scope `{}`", name))
+    }
+}
+
+impl fmt::Display for Scope {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
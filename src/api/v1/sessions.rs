@@ -0,0 +1,139 @@
+//! Session management module.
+//!
+//! Each login of a user in an application is a session: a family of refresh tokens, that get
+//! rotated on each use. Users can list their sessions and revoke them, which logs them out of the
+//! application.
+
+use failure::Error;
+use uuid::Uuid;
+
+use compress::CompressedJson;
+use db::{self, CONNECTION_POOL};
+use super::auth::{Bearer, SessionsRead, SessionsWrite};
+use super::error::ApiError;
+
+/// Session information structure.
+#[derive(Debug, Serialize)]
+pub struct SessionInfo {
+    id: Uuid,
+    app_id: Uuid,
+    app_name: String,
+    creation: i64,
+    last_use: i64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    user_agent: Option<String>,
+}
+
+impl SessionInfo {
+    /// Gets the session ID.
+    pub fn id(&self) -> Uuid {
+        self.id
+    }
+
+    /// Gets the name of the application of the session.
+    pub fn app_name(&self) -> &str {
+        &self.app_name
+    }
+
+    /// Gets the creation timestamp of the session.
+    pub fn creation(&self) -> i64 {
+        self.creation
+    }
+
+    /// Gets the timestamp of the last use of the session.
+    pub fn last_use(&self) -> i64 {
+        self.last_use
+    }
+
+    /// Gets the user agent of the last request that used the session, if it was sent.
+    pub fn user_agent(&self) -> Option<&str> {
+        self.user_agent.as_ref().map(String::as_str)
+    }
+}
+
+/// Gets the ID of the user the access token belongs to.
+fn user_id<S>(token: &Bearer<S>) -> Result<i32, ApiError> {
+    token.user_id().ok_or_else(|| {
+        ApiError::invalid_token(Some("The access token does not belong to a user"))
+    })
+}
+
+/// Gets the current sessions of a user, newest first.
+///
+/// The last use of a session is the last time its refresh token was rotated, or its creation.
+pub fn user_sessions(db_con: &db::Connection, user_id: i32) -> Result<Vec<SessionInfo>, Error> {
+    Ok(db::oauth::get_user_sessions(db_con, user_id)?
+        .into_iter()
+        .map(|(token, app_name)| SessionInfo {
+            id: token.family(),
+            app_id: token.app_id(),
+            app_name,
+            creation: token.session_creation().timestamp(),
+            last_use: token.creation().timestamp(),
+            user_agent: token.user_agent().map(str::to_owned),
+        })
+        .collect())
+}
+
+/// Revokes a session of a user.
+///
+/// Returns `false` if the user does not have the session.
+pub fn revoke_session(
+    db_con: &db::Connection,
+    user_id: i32,
+    session_id: Uuid,
+) -> Result<bool, Error> {
+    match db::oauth::revoke_user_session(db_con, user_id, session_id)? {
+        Some(access_token_hashes) => {
+            db::cache::oauth::remove_access_tokens(&access_token_hashes)?;
+            Ok(true)
+        }
+        None => Ok(false),
+    }
+}
+
+/// Revokes all the sessions of a user.
+///
+/// The account page tokens of the user are removed too, the account pages must be logged in to
+/// again.
+pub fn revoke_all_sessions(db_con: &db::Connection, user_id: i32) -> Result<(), Error> {
+    let access_token_hashes = db::oauth::revoke_user_sessions(db_con, user_id)?;
+    db::cache::oauth::remove_access_tokens(&access_token_hashes)?;
+    db::cache::user::remove_page_tokens(user_id)
+}
+
+/// Lists the current sessions of the user.
+#[get("/sessions")]
+pub fn list(token: Bearer<SessionsRead>) -> Result<CompressedJson<Vec<SessionInfo>>, ApiError> {
+    let user_id = user_id(&token)?;
+
+    let db_con = CONNECTION_POOL.get()?;
+    Ok(CompressedJson::new(user_sessions(&db_con, user_id)?))
+}
+
+/// Revokes a session of the user, logging it out of the application.
+#[delete("/sessions/<id>")]
+pub fn revoke(token: Bearer<SessionsWrite>, id: String) -> Result<(), ApiError> {
+    let user_id = user_id(&token)?;
+    let session_id = id.parse::<Uuid>()
+        .map_err(|_| ApiError::not_found("Session not found"))?;
+
+    let db_con = CONNECTION_POOL.get()?;
+    if revoke_session(&db_con, user_id, session_id)? {
+        Ok(())
+    } else {
+        Err(ApiError::not_found("Session not found"))
+    }
+}
+
+/// Revokes all the sessions of the user, logging it out of all the applications.
+///
+/// This includes the session of the access token used in the request.
+#[delete("/sessions")]
+pub fn revoke_all(token: Bearer<SessionsWrite>) -> Result<(), ApiError> {
+    let user_id = user_id(&token)?;
+
+    let db_con = CONNECTION_POOL.get()?;
+    revoke_all_sessions(&db_con, user_id)?;
+    Ok(())
+}
@@ -0,0 +1,126 @@
+//! Two-factor authentication module.
+//!
+//! Users enable two-factor authentication in two steps: they start the enrolment, getting a new
+//! TOTP secret to add to their authenticator app, and they confirm it with a code from the app.
+//! Only then is it enabled, and the user gets the recovery codes.
+
+use failure::Error;
+use rocket::State;
+use rocket_contrib::Json;
+
+use account::{self, TwoFactorEnrolment};
+use compress::CompressedJson;
+use config::OAuthConfig;
+use db::{self, CONNECTION_POOL};
+use db::models::user::User;
+use rate_limit::IpThrottle;
+use super::auth::{Bearer, TwoFactorWrite};
+use super::error::ApiError;
+
+/// Two-factor authentication code request structure.
+#[derive(Debug, Deserialize)]
+pub struct CodeRequest {
+    /// TOTP code, or recovery code when disabling two-factor authentication.
+    code: String,
+}
+
+/// Recovery codes response structure.
+#[derive(Debug, Serialize)]
+pub struct RecoveryCodes {
+    recovery_codes: Vec<String>,
+}
+
+/// Gets the user the access token belongs to.
+fn token_user<S>(db_con: &db::Connection, token: &Bearer<S>) -> Result<User, ApiError> {
+    let user_id = token.user_id().ok_or_else(|| {
+        ApiError::invalid_token(Some("The access token does not belong to a user"))
+    })?;
+    db::user::get_user(db_con, user_id)?
+        .ok_or_else(|| ApiError::invalid_token(Some("The user no longer exists")))
+}
+
+/// Starts the two-factor authentication enrolment, returning a new TOTP secret and its
+/// provisioning URI.
+///
+/// Starting a new enrolment replaces the previous one, if it has not been confirmed.
+#[post("/two_factor/enrolment")]
+pub fn start_enrolment(
+    config: State<OAuthConfig>,
+    token: Bearer<TwoFactorWrite>,
+) -> Result<CompressedJson<TwoFactorEnrolment>, ApiError> {
+    let db_con = CONNECTION_POOL.get()?;
+    let user = token_user(&db_con, &token)?;
+
+    match account::start_two_factor_enrolment(&user, config.totp_issuer())? {
+        Some(enrolment) => Ok(CompressedJson::new(enrolment)),
+        None => Err(ApiError::conflict("Two-factor authentication is already enabled")),
+    }
+}
+
+/// Confirms the two-factor authentication enrolment with a code from the authenticator app,
+/// enabling it.
+///
+/// The recovery codes are returned only once, so the user must store them.
+#[post("/two_factor", data = "<request>")]
+pub fn enable(
+    token: Bearer<TwoFactorWrite>,
+    throttle: IpThrottle,
+    request: Json<CodeRequest>,
+) -> Result<CompressedJson<RecoveryCodes>, ApiError> {
+    let db_con = CONNECTION_POOL.get()?;
+    let user = token_user(&db_con, &token)?;
+
+    match account::confirm_two_factor_enrolment(
+        &db_con,
+        user.id(),
+        &request.code,
+        Some(throttle.ip()),
+    )? {
+        Some(recovery_codes) => Ok(CompressedJson::new(RecoveryCodes { recovery_codes })),
+        None => Err(ApiError::invalid_request(
+            "The code is not valid, or there is no pending enrolment",
+        )),
+    }
+}
+
+/// Disables two-factor authentication, after checking a TOTP code or a recovery code.
+#[delete("/two_factor", data = "<request>")]
+pub fn disable(
+    token: Bearer<TwoFactorWrite>,
+    throttle: IpThrottle,
+    request: Json<CodeRequest>,
+) -> Result<(), ApiError> {
+    let db_con = CONNECTION_POOL.get()?;
+    let user = token_user(&db_con, &token)?;
+
+    if account::disable_two_factor(&db_con, &user, &request.code, Some(throttle.ip()))? {
+        Ok(())
+    } else {
+        Err(ApiError::invalid_request(
+            "The code is not valid, or two-factor authentication is not enabled",
+        ))
+    }
+}
+
+/// Disables the two-factor authentication of the user with the given username, for users that
+/// lost both their authenticator and their recovery codes.
+///
+/// Returns `None` if the user does not exist, and `Some(false)` if it did not have two-factor
+/// authentication enabled.
+pub fn reset_two_factor(username: &str) -> Result<Option<bool>, Error> {
+    let db_con = CONNECTION_POOL.get()?;
+    match db::user::get_user_by_username(&db_con, username)? {
+        Some(user) => Ok(Some(account::reset_two_factor(&db_con, user.id())?)),
+        None => Ok(None),
+    }
+}
+
+/// Re-encrypts the TOTP secrets that are not encrypted with the current master key, returning
+/// the number of re-encrypted secrets.
+///
+/// Secrets encrypted with an older master key can only be re-encrypted if it's configured as the
+/// previous master key.
+pub fn reencrypt_totp_secrets() -> Result<usize, Error> {
+    let db_con = CONNECTION_POOL.get()?;
+    account::reencrypt_totp_secrets(&db_con)
+}
@@ -0,0 +1,182 @@
+//! User module.
+
+use rocket::State;
+use rocket::response::status::{Accepted, Created};
+
+use account;
+use compress::CompressedJson;
+use db::{self, CONNECTION_POOL};
+use db::models::user::User;
+use mail::MailService;
+use rate_limit::RateLimited;
+use super::auth::{Bearer, OpenId, ProfileRead};
+use super::error::ApiError;
+use super::oauth::{Application, PasswordLogin, SignedJson, UserThrottle};
+use super::scope::Scope;
+
+/// User profile response structure.
+#[derive(Debug, Serialize)]
+pub struct Profile {
+    id: i32,
+    username: String,
+    email: String,
+    creation: i64,
+}
+
+impl<'a> From<&'a User> for Profile {
+    fn from(user: &User) -> Profile {
+        Profile {
+            id: user.id(),
+            username: user.username().to_owned(),
+            email: user.email().to_owned(),
+            creation: user.creation().timestamp(),
+        }
+    }
+}
+
+/// User registration request structure.
+#[derive(Debug, Deserialize)]
+pub struct RegistrationRequest {
+    username: String,
+    email: String,
+    password: String,
+}
+
+/// Registers a new user.
+///
+/// The user will not be able to log in until the email address is verified with the link sent
+/// to it. Only first party applications can register users.
+#[post("/users", data = "<request>")]
+pub fn register(
+    mail: State<MailService>,
+    application: Application<PasswordLogin>,
+    throttle: UserThrottle<PasswordLogin>,
+    request: SignedJson<RegistrationRequest>,
+) -> Result<RateLimited<Created<CompressedJson<Profile>>>, ApiError> {
+    if !application.is_first_party() {
+        return Err(ApiError::unauthorized_client(
+            "Only first party applications can register users",
+        ));
+    }
+
+    let db_con = CONNECTION_POOL.get()?;
+    let user = match account::register_user(
+        &db_con,
+        &mail,
+        &request.username,
+        &request.email,
+        &request.password,
+        Some(throttle.ip()),
+    )? {
+        Ok(user) => user,
+        Err(e) if e.is_conflict() => return Err(ApiError::conflict(e.description())),
+        Err(e) => return Err(ApiError::invalid_request(e.description())),
+    };
+
+    Ok(RateLimited::new(
+        Created(
+            "/api/v1/profile".to_owned(),
+            Some(CompressedJson::new(Profile::from(&user))),
+        ),
+        application.rate_limit(),
+    ))
+}
+
+/// Password reset request structure.
+#[derive(Debug, Deserialize)]
+pub struct PasswordResetRequest {
+    email: String,
+}
+
+/// Requests a password reset link for the user with the given email address.
+///
+/// The response is the same whether the user exists or not, so that email addresses cannot be
+/// enumerated. Only first party applications can request password resets.
+#[post("/password_reset", data = "<request>")]
+pub fn request_password_reset(
+    mail: State<MailService>,
+    application: Application,
+    throttle: UserThrottle,
+    request: SignedJson<PasswordResetRequest>,
+) -> Result<RateLimited<Accepted<()>>, ApiError> {
+    if !application.is_first_party() {
+        return Err(ApiError::unauthorized_client(
+            "Only first party applications can request password resets",
+        ));
+    }
+
+    account::request_password_reset(&mail, &request.email, Some(throttle.ip()))?;
+    Ok(RateLimited::new(Accepted(None), application.rate_limit()))
+}
+
+/// Get the profile of the authenticated user.
+#[get("/profile")]
+pub fn profile(token: Bearer<ProfileRead>) -> Result<CompressedJson<Profile>, ApiError> {
+    let user_id = match token.user_id() {
+        Some(user_id) => user_id,
+        None => {
+            return Err(ApiError::invalid_token(Some(
+                "The access token does not belong to a user",
+            )))
+        }
+    };
+
+    let db_con = CONNECTION_POOL.get()?;
+    match db::user::get_user(&db_con, user_id)? {
+        Some(user) => Ok(CompressedJson::new(Profile::from(&user))),
+        None => Err(ApiError::invalid_token(Some("The user no longer exists"))),
+    }
+}
+
+/// OpenID Connect user information response structure (OpenID Connect Core, section 5.3.2).
+///
+/// The subject is always present, and the rest of the claims depend on the granted scopes.
+#[derive(Debug, Serialize)]
+pub struct UserInfo {
+    sub: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    email: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    email_verified: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    preferred_username: Option<String>,
+}
+
+/// OpenID Connect user info endpoint.
+///
+/// The `email` scope gives access to the email address, and the `profile` scope to the username.
+/// Users are activated by email, so the email address of active users is verified.
+#[get("/userinfo")]
+pub fn userinfo(token: Bearer<OpenId>) -> Result<CompressedJson<UserInfo>, ApiError> {
+    let user_id = match token.user_id() {
+        Some(user_id) => user_id,
+        None => {
+            return Err(ApiError::invalid_token(Some(
+                "The access token does not belong to a user",
+            )))
+        }
+    };
+
+    let db_con = CONNECTION_POOL.get()?;
+    let user = match db::user::get_user(&db_con, user_id)? {
+        Some(user) => user,
+        None => return Err(ApiError::invalid_token(Some("The user no longer exists"))),
+    };
+
+    let scopes = token.scopes();
+    let email = scopes.contains(&Scope::Email);
+    Ok(CompressedJson::new(UserInfo {
+        sub: user.id().to_string(),
+        email: if email {
+            Some(user.email().to_owned())
+        } else {
+            None
+        },
+        email_verified: if email { Some(user.is_active()) } else { None },
+        preferred_username: if scopes.contains(&Scope::Profile) {
+            Some(user.username().to_owned())
+        } else {
+            None
+        },
+    }))
+}
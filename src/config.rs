@@ -0,0 +1,655 @@
+//! Configuration module.
+//!
+//! The configuration is read from the extra parameters of the current environment in
+//! `Rocket.toml`, and it's available to request guards and handlers as managed state.
+
+use std::{env, fmt};
+use std::net::IpAddr;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+use chrono::Duration;
+use failure::Error;
+use rocket::Config;
+use rocket::config::{ConfigError, Environment};
+use rocket::fairing::AdHoc;
+
+use crypto::{self, RsaSigningKey};
+use mail::MailService;
+
+/// Default time a signed application request is accepted for, in seconds.
+const DEFAULT_SIGNATURE_WINDOW: i64 = 300;
+/// Default issuer identifier of the tokens.
+const DEFAULT_ISSUER: &str = "http://localhost:8000";
+/// Default number of days a JWT signing key is used before rotating it.
+const DEFAULT_SIGNING_KEY_DAYS: i64 = 30;
+/// Default number of hours the previous API secret is accepted after a rotation.
+const DEFAULT_SECRET_OVERLAP_HOURS: i64 = 24;
+/// Default issuer name shown by authenticator apps for the TOTP secrets.
+const DEFAULT_TOTP_ISSUER: &str = "Web Template";
+/// Default hourly request limit for each client IP address.
+const DEFAULT_IP_HOURLY_LIMIT: i32 = 100;
+/// Default sender of the emails.
+const DEFAULT_MAIL_FROM: &str = "no-reply@localhost";
+/// Default SMTP server to send the emails, only in the development environment.
+const DEFAULT_SMTP_SERVER: &str = "localhost";
+/// Default port of the SMTP server with `STARTTLS`, the submission port.
+const DEFAULT_SMTP_SUBMISSION_PORT: u16 = 587;
+/// Default port of the SMTP server without encryption.
+const DEFAULT_SMTP_PORT: u16 = 25;
+/// Default directory where the file mailer writes the emails.
+const DEFAULT_MAIL_DIR: &str = "target/mail";
+/// Default directory of the templates.
+const DEFAULT_TEMPLATE_DIR: &str = "templates";
+/// Default base URL of the links in the emails.
+const DEFAULT_MAIL_BASE_URL: &str = "http://localhost:8000";
+
+/// Format of the issued access tokens.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AccessTokenFormat {
+    /// Random tokens, that can only be validated by this server.
+    Opaque,
+    /// Signed JSON Web Tokens, that resource servers can verify offline.
+    Jwt,
+}
+
+/// OAuth configuration.
+#[derive(Debug, Clone)]
+pub struct OAuthConfig {
+    /// Time a signed application request is accepted for after its timestamp, in seconds.
+    signature_window: i64,
+    /// Format of the issued access tokens.
+    access_token_format: AccessTokenFormat,
+    /// Issuer identifier, the base URL of the server.
+    issuer: String,
+    /// Number of days a JWT signing key is used before rotating it.
+    signing_key_days: i64,
+    /// Number of hours the previous API secret of an application is accepted after a rotation.
+    secret_overlap_hours: i64,
+    /// Audience of the JWT access tokens, identifying the API that accepts them.
+    audience: String,
+    /// Issuer name shown by authenticator apps for the TOTP secrets.
+    totp_issuer: String,
+    /// Whether users can log in with a link sent by email, without their password.
+    magic_link_login: bool,
+    /// RSA key to sign the ID tokens with `RS256`, instead of the Ed25519 JWT signing keys.
+    rsa_key: Option<RsaSigningKey>,
+}
+
+impl OAuthConfig {
+    /// Loads the OAuth configuration from the Rocket configuration.
+    ///
+    /// Parameters that are not present will get their default value.
+    pub fn from_config(config: &Config) -> Result<OAuthConfig, Error> {
+        let signature_window = match config.get_int("oauth_signature_window") {
+            Ok(window) if window > 0 => window,
+            Ok(_) => bail!("`oauth_signature_window` must be a positive number of seconds"),
+            Err(ConfigError::NotFound) => DEFAULT_SIGNATURE_WINDOW,
+            Err(e) => bail!("invalid `oauth_signature_window` parameter: {:?}", e),
+        };
+
+        let access_token_format = match config.get_str("oauth_access_token_format") {
+            Ok("opaque") | Err(ConfigError::NotFound) => AccessTokenFormat::Opaque,
+            Ok("jwt") => AccessTokenFormat::Jwt,
+            Ok(format) => bail!("unknown access token format `{}`", format),
+            Err(e) => bail!("invalid `oauth_access_token_format` parameter: {:?}", e),
+        };
+
+        let issuer = match config.get_str("oauth_issuer") {
+            Ok(issuer) => issuer.trim_right_matches('/').to_owned(),
+            Err(ConfigError::NotFound) => DEFAULT_ISSUER.to_owned(),
+            Err(e) => bail!("invalid `oauth_issuer` parameter: {:?}", e),
+        };
+
+        let audience = match config.get_str("oauth_audience") {
+            Ok(audience) => audience.to_owned(),
+            Err(ConfigError::NotFound) => issuer.clone(),
+            Err(e) => bail!("invalid `oauth_audience` parameter: {:?}", e),
+        };
+
+        let signing_key_days = match config.get_int("oauth_signing_key_days") {
+            Ok(days) if days > 0 => days,
+            Ok(_) => bail!("`oauth_signing_key_days` must be a positive number of days"),
+            Err(ConfigError::NotFound) => DEFAULT_SIGNING_KEY_DAYS,
+            Err(e) => bail!("invalid `oauth_signing_key_days` parameter: {:?}", e),
+        };
+
+        let secret_overlap_hours = match config.get_int("oauth_secret_overlap_hours") {
+            Ok(hours) if hours >= 0 => hours,
+            Ok(_) => bail!("`oauth_secret_overlap_hours` cannot be negative"),
+            Err(ConfigError::NotFound) => DEFAULT_SECRET_OVERLAP_HOURS,
+            Err(e) => bail!("invalid `oauth_secret_overlap_hours` parameter: {:?}", e),
+        };
+
+        let totp_issuer = match config.get_str("totp_issuer") {
+            Ok(issuer) => issuer.to_owned(),
+            Err(ConfigError::NotFound) => DEFAULT_TOTP_ISSUER.to_owned(),
+            Err(e) => bail!("invalid `totp_issuer` parameter: {:?}", e),
+        };
+
+        let magic_link_login = match config.get_bool("magic_link_login") {
+            Ok(enabled) => enabled,
+            Err(ConfigError::NotFound) => false,
+            Err(e) => bail!("invalid `magic_link_login` parameter: {:?}", e),
+        };
+
+        let rsa_key = match config.get_str("oauth_rsa_key_file") {
+            Ok(path) => Some(
+                RsaSigningKey::from_file(config.root().join(path))
+                    .map_err(|e| format_err!("error loading the RSA signing key: {}", e))?,
+            ),
+            Err(ConfigError::NotFound) => None,
+            Err(e) => bail!("invalid `oauth_rsa_key_file` parameter: {:?}", e),
+        };
+
+        Ok(OAuthConfig {
+            signature_window,
+            access_token_format,
+            issuer,
+            audience,
+            signing_key_days,
+            secret_overlap_hours,
+            totp_issuer,
+            magic_link_login,
+            rsa_key,
+        })
+    }
+
+    /// Gets the time a signed application request is accepted for after its timestamp.
+    ///
+    /// Nonces of signed requests are stored for this time, to prevent replays.
+    pub fn signature_window(&self) -> Duration {
+        Duration::seconds(self.signature_window)
+    }
+
+    /// Gets the format of the issued access tokens.
+    pub fn access_token_format(&self) -> AccessTokenFormat {
+        self.access_token_format
+    }
+
+    /// Gets the issuer identifier, the base URL of the server without a trailing slash.
+    pub fn issuer(&self) -> &str {
+        &self.issuer
+    }
+
+    /// Gets the audience of the JWT access tokens, the issuer unless configured otherwise.
+    pub fn audience(&self) -> &str {
+        &self.audience
+    }
+
+    /// Gets the time a JWT signing key is used before rotating it.
+    pub fn signing_key_lifetime(&self) -> Duration {
+        Duration::days(self.signing_key_days)
+    }
+
+    /// Gets the time the previous API secret of an application is accepted after a rotation.
+    pub fn secret_overlap(&self) -> Duration {
+        Duration::hours(self.secret_overlap_hours)
+    }
+
+    /// Gets the issuer name shown by authenticator apps for the TOTP secrets.
+    pub fn totp_issuer(&self) -> &str {
+        &self.totp_issuer
+    }
+
+    /// Checks if users can log in with a link sent by email, without their password.
+    pub fn magic_link_login(&self) -> bool {
+        self.magic_link_login
+    }
+
+    /// Gets the RSA key to sign the ID tokens with `RS256`, if it's configured.
+    pub fn rsa_key(&self) -> Option<&RsaSigningKey> {
+        self.rsa_key.as_ref()
+    }
+}
+
+/// Network of IP addresses, in CIDR notation, such as `10.0.0.0/8`.
+///
+/// A single IP address is a network with only that address.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct IpNetwork {
+    /// Address of the network.
+    address: IpAddr,
+    /// Length of the network prefix, in bits.
+    prefix_len: u8,
+}
+
+impl IpNetwork {
+    /// Checks if the network contains the given IP address.
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        match (self.address, ip) {
+            (IpAddr::V4(network), IpAddr::V4(ip)) => {
+                prefix_matches(&network.octets(), &ip.octets(), self.prefix_len)
+            }
+            (IpAddr::V6(network), IpAddr::V6(ip)) => {
+                prefix_matches(&network.octets(), &ip.octets(), self.prefix_len)
+            }
+            _ => false,
+        }
+    }
+}
+
+/// Checks if the first `prefix_len` bits of two addresses are the same.
+fn prefix_matches(network: &[u8], ip: &[u8], prefix_len: u8) -> bool {
+    let full_bytes = usize::from(prefix_len / 8);
+    let remaining_bits = prefix_len % 8;
+
+    network[..full_bytes] == ip[..full_bytes]
+        && (remaining_bits == 0 || {
+            let mask = 0xffu8 << (8 - remaining_bits);
+            network[full_bytes] & mask == ip[full_bytes] & mask
+        })
+}
+
+impl FromStr for IpNetwork {
+    type Err = Error;
+
+    fn from_str(network: &str) -> Result<IpNetwork, Error> {
+        let mut parts = network.splitn(2, '/');
+        let address = parts.next().unwrap_or("").parse::<IpAddr>()?;
+        let max_prefix_len = if address.is_ipv4() { 32 } else { 128 };
+        let prefix_len = match parts.next() {
+            Some(prefix_len) => prefix_len.parse::<u8>()?,
+            None => max_prefix_len,
+        };
+        if prefix_len > max_prefix_len {
+            bail!("invalid prefix length in network `{}`", network);
+        }
+
+        Ok(IpNetwork {
+            address,
+            prefix_len,
+        })
+    }
+}
+
+/// Rate limiting configuration.
+#[derive(Debug, Clone)]
+pub struct RateLimitConfig {
+    /// Hourly request limit for each client IP address.
+    ip_hourly_limit: i32,
+    /// Proxies trusted to set the `X-Forwarded-For` header.
+    trusted_proxies: Vec<IpNetwork>,
+}
+
+impl RateLimitConfig {
+    /// Loads the rate limiting configuration from the Rocket configuration.
+    ///
+    /// Parameters that are not present will get their default value.
+    pub fn from_config(config: &Config) -> Result<RateLimitConfig, Error> {
+        let ip_hourly_limit = match config.get_int("ip_hourly_limit") {
+            Ok(limit) if limit > 0 && limit <= i64::from(i32::max_value()) => limit as i32,
+            Ok(_) => bail!("`ip_hourly_limit` must be a positive number of requests"),
+            Err(ConfigError::NotFound) => DEFAULT_IP_HOURLY_LIMIT,
+            Err(e) => bail!("invalid `ip_hourly_limit` parameter: {:?}", e),
+        };
+
+        let trusted_proxies = match config.get_slice("trusted_proxies") {
+            Ok(proxies) => proxies
+                .iter()
+                .map(|proxy| match proxy.as_str() {
+                    Some(network) => network.parse::<IpNetwork>(),
+                    None => bail!("`trusted_proxies` must be a list of strings"),
+                })
+                .collect::<Result<Vec<_>, Error>>()?,
+            Err(ConfigError::NotFound) => Vec::new(),
+            Err(e) => bail!("invalid `trusted_proxies` parameter: {:?}", e),
+        };
+
+        Ok(RateLimitConfig {
+            ip_hourly_limit,
+            trusted_proxies,
+        })
+    }
+
+    /// Gets the hourly request limit for each client IP address.
+    pub fn ip_hourly_limit(&self) -> i32 {
+        self.ip_hourly_limit
+    }
+
+    /// Checks if the given IP address belongs to a trusted proxy.
+    pub fn is_trusted_proxy(&self, ip: IpAddr) -> bool {
+        self.trusted_proxies.iter().any(|network| network.contains(ip))
+    }
+}
+
+/// Security of the connection to the SMTP server.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SmtpSecurity {
+    /// The connection is encrypted with `STARTTLS`, and it fails if the server does not support
+    /// it.
+    StartTls,
+    /// The connection is not encrypted, only suitable for a local server.
+    Unencrypted,
+}
+
+/// SMTP server configuration.
+#[derive(Clone, PartialEq, Eq)]
+pub struct SmtpConfig {
+    /// Host name of the server.
+    server: String,
+    /// Port of the server.
+    port: u16,
+    /// Security of the connection.
+    security: SmtpSecurity,
+    /// Username and password to authenticate with the server, if any.
+    credentials: Option<(String, String)>,
+}
+
+impl SmtpConfig {
+    /// Loads the SMTP configuration from the Rocket configuration.
+    ///
+    /// The server must be set outside the development environment, where it defaults to the
+    /// local one. The credentials are read from the `SMTP_USERNAME` and `SMTP_PASSWORD`
+    /// environment variables, so that they are not stored in `Rocket.toml`, and they can only be
+    /// sent through an encrypted connection.
+    fn from_config(config: &Config) -> Result<SmtpConfig, Error> {
+        let server = match config.get_str("smtp_server") {
+            Ok(server) => server.to_owned(),
+            Err(ConfigError::NotFound) if config.environment == Environment::Development => {
+                DEFAULT_SMTP_SERVER.to_owned()
+            }
+            Err(ConfigError::NotFound) => {
+                bail!("`smtp_server` must be set outside the development environment")
+            }
+            Err(e) => bail!("invalid `smtp_server` parameter: {:?}", e),
+        };
+
+        let security = match config.get_str("smtp_security") {
+            Ok("starttls") | Err(ConfigError::NotFound) => SmtpSecurity::StartTls,
+            Ok("none") => SmtpSecurity::Unencrypted,
+            Ok(security) => bail!(
+                "`smtp_security` must be \"starttls\" or \"none\", found \"{}\"",
+                security
+            ),
+            Err(e) => bail!("invalid `smtp_security` parameter: {:?}", e),
+        };
+
+        let port = match config.get_int("smtp_port") {
+            Ok(port) if port > 0 && port <= i64::from(u16::max_value()) => port as u16,
+            Ok(_) => bail!("`smtp_port` must be a valid port number"),
+            Err(ConfigError::NotFound) => match security {
+                SmtpSecurity::StartTls => DEFAULT_SMTP_SUBMISSION_PORT,
+                SmtpSecurity::Unencrypted => DEFAULT_SMTP_PORT,
+            },
+            Err(e) => bail!("invalid `smtp_port` parameter: {:?}", e),
+        };
+
+        let credentials = match (env::var("SMTP_USERNAME"), env::var("SMTP_PASSWORD")) {
+            (Ok(_), Ok(_)) if security == SmtpSecurity::Unencrypted => {
+                bail!("the SMTP credentials cannot be sent without encryption")
+            }
+            (Ok(username), Ok(password)) => Some((username, password)),
+            (Err(_), Err(_)) => None,
+            _ => bail!("SMTP_USERNAME and SMTP_PASSWORD must be set together"),
+        };
+
+        Ok(SmtpConfig {
+            server,
+            port,
+            security,
+            credentials,
+        })
+    }
+
+    /// Gets the host name of the server.
+    pub fn server(&self) -> &str {
+        &self.server
+    }
+
+    /// Gets the port of the server.
+    pub fn port(&self) -> u16 {
+        self.port
+    }
+
+    /// Gets the security of the connection.
+    pub fn security(&self) -> SmtpSecurity {
+        self.security
+    }
+
+    /// Gets the username and password to authenticate with the server, if any.
+    pub fn credentials(&self) -> Option<(&str, &str)> {
+        self.credentials
+            .as_ref()
+            .map(|&(ref username, ref password)| (username.as_str(), password.as_str()))
+    }
+}
+
+impl fmt::Debug for SmtpConfig {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        // Never print the password.
+        f.debug_struct("SmtpConfig")
+            .field("server", &self.server)
+            .field("port", &self.port)
+            .field("security", &self.security)
+            .field(
+                "username",
+                &self.credentials.as_ref().map(|&(ref username, _)| username),
+            )
+            .finish()
+    }
+}
+
+/// Backend used to send the emails.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum MailBackend {
+    /// Sends the emails through the configured SMTP server.
+    Smtp(SmtpConfig),
+    /// Writes the emails to files in the given directory.
+    File(PathBuf),
+    /// Keeps the emails in memory.
+    Memory,
+}
+
+/// Email configuration.
+#[derive(Debug, Clone)]
+pub struct MailConfig {
+    /// Sender of the emails.
+    from: String,
+    /// Backend used to send the emails.
+    backend: MailBackend,
+    /// Base URL of the links in the emails.
+    base_url: String,
+    /// Directory of the email templates.
+    template_dir: PathBuf,
+}
+
+impl MailConfig {
+    /// Loads the email configuration from the Rocket configuration.
+    ///
+    /// Parameters that are not present will get their default value. Relative directories are
+    /// relative to the directory of `Rocket.toml`.
+    pub fn from_config(config: &Config) -> Result<MailConfig, Error> {
+        let from = match config.get_str("mail_from") {
+            Ok(from) => from.to_owned(),
+            Err(ConfigError::NotFound) => DEFAULT_MAIL_FROM.to_owned(),
+            Err(e) => bail!("invalid `mail_from` parameter: {:?}", e),
+        };
+
+        let backend = match config.get_str("mail_backend") {
+            Ok("smtp") | Err(ConfigError::NotFound) => {
+                MailBackend::Smtp(SmtpConfig::from_config(config)?)
+            }
+            Ok("file") => MailBackend::File(match config.get_str("mail_dir") {
+                Ok(dir) => config.root().join(dir),
+                Err(ConfigError::NotFound) => config.root().join(DEFAULT_MAIL_DIR),
+                Err(e) => bail!("invalid `mail_dir` parameter: {:?}", e),
+            }),
+            Ok("memory") => MailBackend::Memory,
+            Ok(backend) => bail!(
+                "`mail_backend` must be \"smtp\", \"file\" or \"memory\", found \"{}\"",
+                backend
+            ),
+            Err(e) => bail!("invalid `mail_backend` parameter: {:?}", e),
+        };
+
+        let base_url = match config.get_str("mail_base_url") {
+            Ok(url) => url.trim_right_matches('/').to_owned(),
+            Err(ConfigError::NotFound) => DEFAULT_MAIL_BASE_URL.to_owned(),
+            Err(e) => bail!("invalid `mail_base_url` parameter: {:?}", e),
+        };
+
+        let template_dir = match config.get_str("template_dir") {
+            Ok(dir) => config.root().join(dir),
+            Err(ConfigError::NotFound) => config.root().join(DEFAULT_TEMPLATE_DIR),
+            Err(e) => bail!("invalid `template_dir` parameter: {:?}", e),
+        }.join("email");
+
+        Ok(MailConfig {
+            from,
+            backend,
+            base_url,
+            template_dir,
+        })
+    }
+
+    /// Gets the sender of the emails.
+    pub fn from(&self) -> &str {
+        &self.from
+    }
+
+    /// Gets the backend used to send the emails.
+    pub fn backend(&self) -> &MailBackend {
+        &self.backend
+    }
+
+    /// Gets the base URL of the links in the emails, without a trailing slash.
+    pub fn base_url(&self) -> &str {
+        &self.base_url
+    }
+
+    /// Gets the directory of the email templates.
+    pub fn template_dir(&self) -> &Path {
+        &self.template_dir
+    }
+}
+
+/// Fairing that loads the configuration and adds it, with the email service, to the managed
+/// state.
+///
+/// It also loads the master keys, so that the server does not launch without them.
+pub fn fairing() -> AdHoc {
+    AdHoc::on_attach(|rocket| {
+        let configs = crypto::load_master_keys().and_then(|_| {
+            Ok((
+                OAuthConfig::from_config(rocket.config())?,
+                RateLimitConfig::from_config(rocket.config())?,
+                MailService::new(&MailConfig::from_config(rocket.config())?)?,
+            ))
+        });
+        match configs {
+            Ok((oauth_config, rate_limit_config, mail_service)) => Ok(rocket
+                .manage(oauth_config)
+                .manage(rate_limit_config)
+                .manage(mail_service)),
+            Err(e) => {
+                error!("Error loading the configuration: {}", e);
+                Err(rocket)
+            }
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use rocket::Config;
+    use rocket::config::Environment;
+
+    use super::{IpNetwork, SmtpConfig, SmtpSecurity};
+
+    #[test]
+    fn parses_ipv4_networks() {
+        let network = "10.0.0.0/8".parse::<IpNetwork>().unwrap();
+        assert!(network.contains("10.0.0.1".parse().unwrap()));
+        assert!(network.contains("10.255.255.255".parse().unwrap()));
+        assert!(!network.contains("11.0.0.1".parse().unwrap()));
+        assert!(!network.contains("::ffff:10.0.0.1".parse().unwrap()));
+    }
+
+    #[test]
+    fn parses_unaligned_prefixes() {
+        let network = "192.168.16.0/20".parse::<IpNetwork>().unwrap();
+        assert!(network.contains("192.168.16.1".parse().unwrap()));
+        assert!(network.contains("192.168.31.255".parse().unwrap()));
+        assert!(!network.contains("192.168.32.0".parse().unwrap()));
+        assert!(!network.contains("192.168.15.255".parse().unwrap()));
+    }
+
+    #[test]
+    fn parses_ipv6_networks() {
+        let network = "2001:db8::/32".parse::<IpNetwork>().unwrap();
+        assert!(network.contains("2001:db8::1".parse().unwrap()));
+        assert!(network.contains("2001:db8:ffff::1".parse().unwrap()));
+        assert!(!network.contains("2001:db9::1".parse().unwrap()));
+        assert!(!network.contains("10.0.0.1".parse().unwrap()));
+    }
+
+    #[test]
+    fn parses_single_addresses() {
+        let network = "127.0.0.1".parse::<IpNetwork>().unwrap();
+        assert_eq!(network, "127.0.0.1/32".parse().unwrap());
+        assert!(network.contains("127.0.0.1".parse().unwrap()));
+        assert!(!network.contains("127.0.0.2".parse().unwrap()));
+
+        let network = "::1".parse::<IpNetwork>().unwrap();
+        assert_eq!(network, "::1/128".parse().unwrap());
+        assert!(network.contains("::1".parse().unwrap()));
+    }
+
+    #[test]
+    fn matches_everything_with_an_empty_prefix() {
+        let network = "0.0.0.0/0".parse::<IpNetwork>().unwrap();
+        assert!(network.contains("1.2.3.4".parse().unwrap()));
+        assert!(network.contains("255.255.255.255".parse().unwrap()));
+    }
+
+    #[test]
+    fn rejects_invalid_networks() {
+        assert!("10.0.0.0/33".parse::<IpNetwork>().is_err());
+        assert!("::/129".parse::<IpNetwork>().is_err());
+        assert!("10.0.0.0/".parse::<IpNetwork>().is_err());
+        assert!("10.0.0.0/a".parse::<IpNetwork>().is_err());
+        assert!("10.0.0/8".parse::<IpNetwork>().is_err());
+        assert!("example.com".parse::<IpNetwork>().is_err());
+        assert!("".parse::<IpNetwork>().is_err());
+    }
+
+    #[test]
+    fn requires_the_smtp_server_outside_development() {
+        let config = Config::build(Environment::Production).finalize().unwrap();
+        assert!(SmtpConfig::from_config(&config).is_err());
+
+        let config = Config::build(Environment::Development).finalize().unwrap();
+        let smtp_config = SmtpConfig::from_config(&config).unwrap();
+        assert_eq!(smtp_config.server(), "localhost");
+        assert_eq!(smtp_config.port(), 587);
+        assert_eq!(smtp_config.security(), SmtpSecurity::StartTls);
+    }
+
+    #[test]
+    fn reads_the_smtp_settings() {
+        let config = Config::build(Environment::Production)
+            .extra("smtp_server", "mail.example.com")
+            .extra("smtp_security", "none")
+            .finalize()
+            .unwrap();
+        let smtp_config = SmtpConfig::from_config(&config).unwrap();
+        assert_eq!(smtp_config.server(), "mail.example.com");
+        assert_eq!(smtp_config.port(), 25);
+        assert_eq!(smtp_config.security(), SmtpSecurity::Unencrypted);
+
+        let config = Config::build(Environment::Production)
+            .extra("smtp_server", "mail.example.com")
+            .extra("smtp_port", 2525)
+            .finalize()
+            .unwrap();
+        assert_eq!(SmtpConfig::from_config(&config).unwrap().port(), 2525);
+
+        let config = Config::build(Environment::Production)
+            .extra("smtp_server", "mail.example.com")
+            .extra("smtp_security", "tls")
+            .finalize()
+            .unwrap();
+        assert!(SmtpConfig::from_config(&config).is_err());
+    }
+}
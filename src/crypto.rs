@@ -0,0 +1,540 @@
+//! Cryptography helpers module.
+//!
+//! This module contains the primitives used to generate and store secrets, so that the rest of
+//! the code does not need to deal with the underlying cryptographic libraries.
+//!
+//! Secrets that must be recovered, such as the API secrets of the applications, are stored with
+//! envelope encryption: each secret is encrypted with its own data key, and the data key is
+//! stored encrypted with the master key. The master key is read as hexadecimal from the
+//! `MASTER_KEY` environment variable, or from the file in the `MASTER_KEY_FILE` environment
+//! variable. When changing the master key, the old one must be set in `PREVIOUS_MASTER_KEY` or
+//! `PREVIOUS_MASTER_KEY_FILE` until all the secrets have been re-encrypted. Both keys are loaded
+//! and validated with `load_master_keys()` at launch, before any secret is used. Data keys and
+//! secrets are encrypted along with a context naming the row and column they are stored in, so
+//! that they cannot be copied to another row or column without being detected.
+//!
+//! Links sent by email are signed with HMAC-SHA256, with a key derived from the master key, so
+//! that they don't need to be stored. Secrets kept temporarily in the cache are encrypted with
+//! `seal()`, with another key derived from the master key. Changing the master key invalidates
+//! the links sent before and the secrets in the cache.
+
+use std::{env, fmt, str};
+use std::fs::File;
+use std::io::Read;
+use std::path::Path;
+use std::sync::Arc;
+
+use argon2::{self, Config};
+use failure::Error;
+use hex;
+use ring::{aead, constant_time, digest, hmac};
+use ring::rand::{SecureRandom, SystemRandom};
+use ring::signature::{self, Ed25519KeyPair, RSAKeyPair, RSASigningState};
+use untrusted;
+use uuid::Uuid;
+
+/// Length of the random salt used for password hashes, in bytes.
+const SALT_LEN: usize = 16;
+/// Length of the application API secrets, in bytes.
+const API_SECRET_LEN: usize = 32;
+/// Length of the master and data keys, in bytes.
+const KEY_LEN: usize = 32;
+/// Length of the master key IDs, in bytes.
+const KEY_ID_LEN: usize = 8;
+/// Length of the RSA signing key IDs, in bytes.
+const RSA_KEY_ID_LEN: usize = 16;
+/// DER tag of integers.
+const DER_INTEGER: u8 = 0x02;
+/// DER tag of sequences.
+const DER_SEQUENCE: u8 = 0x30;
+
+lazy_static!{
+    /// System secure random number generator.
+    static ref RANDOM: SystemRandom = SystemRandom::new();
+
+    /// Master keys, or the reason why they could not be loaded.
+    static ref MASTER_KEYS: Result<MasterKeys, String> = MasterKeys::from_env()
+        .map_err(|e| e.to_string());
+
+    /// Key to sign the links sent by email, derived from the master key.
+    static ref LINK_SIGNING_KEY: hmac::SigningKey = {
+        let master_key = hmac::SigningKey::new(&digest::SHA256, &master_keys().current.key);
+        hmac::SigningKey::new(&digest::SHA256, hmac::sign(&master_key, b"link signing").as_ref())
+    };
+
+    /// Key to encrypt the secrets stored in the cache, derived from the master key.
+    static ref CACHE_KEY: Vec<u8> = {
+        let master_key = hmac::SigningKey::new(&digest::SHA256, &master_keys().current.key);
+        hmac::sign(&master_key, b"cache encryption").as_ref().to_vec()
+    };
+}
+
+/// Loads and validates the master keys from the environment.
+///
+/// It must be called at launch, so that a missing or invalid key is reported before serving any
+/// request.
+pub fn load_master_keys() -> Result<(), Error> {
+    match *MASTER_KEYS {
+        Ok(_) => Ok(()),
+        Err(ref e) => bail!("{}", e),
+    }
+}
+
+/// Gets the master keys, that have been validated by `load_master_keys()`.
+fn master_keys() -> &'static MasterKeys {
+    MASTER_KEYS
+        .as_ref()
+        .expect("the master keys must be loaded at launch")
+}
+
+/// Fills the given buffer with secure random bytes.
+pub fn fill_random(buf: &mut [u8]) -> Result<(), Error> {
+    RANDOM
+        .fill(buf)
+        .map_err(|_| format_err!("could not generate secure random bytes"))
+}
+
+/// Generates a new random 16-byte token.
+pub fn random_token() -> Result<[u8; 16], Error> {
+    let mut token = [0u8; 16];
+    fill_random(&mut token)?;
+    Ok(token)
+}
+
+/// Gets the hash of a token, as it should be stored in the database.
+pub fn token_hash(token: &[u8]) -> Vec<u8> {
+    digest::digest(&digest::SHA256, token).as_ref().to_vec()
+}
+
+/// Generates a new random (version 4) UUID.
+pub fn random_uuid() -> Result<Uuid, Error> {
+    let mut bytes = random_token()?;
+    bytes[6] = (bytes[6] & 0x0f) | 0x40;
+    bytes[8] = (bytes[8] & 0x3f) | 0x80;
+    Ok(Uuid::from_bytes(&bytes)?)
+}
+
+/// Generates a new random API secret for an application.
+pub fn generate_api_secret() -> Result<Vec<u8>, Error> {
+    let mut secret = vec![0u8; API_SECRET_LEN];
+    fill_random(&mut secret)?;
+    Ok(secret)
+}
+
+/// Generates a new Ed25519 key pair, PKCS#8 encoded.
+pub fn generate_signing_key() -> Result<Vec<u8>, Error> {
+    Ok(Ed25519KeyPair::generate_pkcs8(&*RANDOM)
+        .map_err(|_| format_err!("could not generate a signing key"))?
+        .to_vec())
+}
+
+/// Hashes a password with Argon2, returning the encoded hash with its parameters and salt.
+pub fn hash_password(password: &str) -> Result<Vec<u8>, Error> {
+    let mut salt = [0u8; SALT_LEN];
+    fill_random(&mut salt)?;
+
+    Ok(argon2::hash_encoded(password.as_bytes(), &salt, &Config::default())?.into_bytes())
+}
+
+/// Verifies a password against an encoded Argon2 hash.
+pub fn verify_password(hash: &[u8], password: &str) -> Result<bool, Error> {
+    Ok(argon2::verify_encoded(
+        str::from_utf8(hash)?,
+        password.as_bytes(),
+    )?)
+}
+
+/// Current and previous master keys.
+struct MasterKeys {
+    /// Master key used to encrypt new data keys.
+    current: MasterKey,
+    /// Previous master key, to decrypt the data keys that have not been re-encrypted yet.
+    previous: Option<MasterKey>,
+}
+
+impl MasterKeys {
+    /// Loads the current and previous master keys from the environment.
+    fn from_env() -> Result<MasterKeys, Error> {
+        let current = match MasterKey::from_env("MASTER_KEY") {
+            Ok(Some(key)) => key,
+            Ok(None) => bail!("MASTER_KEY or MASTER_KEY_FILE environment variable not found"),
+            Err(e) => bail!("error loading the master key: {}", e),
+        };
+        let previous = MasterKey::from_env("PREVIOUS_MASTER_KEY")
+            .map_err(|e| format_err!("error loading the previous master key: {}", e))?;
+
+        Ok(MasterKeys { current, previous })
+    }
+}
+
+/// Master key, used to encrypt the data keys.
+struct MasterKey {
+    /// Key ID, derived from the key, stored along with the data keys it encrypts.
+    id: String,
+    /// AES-256 key.
+    key: Vec<u8>,
+}
+
+impl MasterKey {
+    /// Loads a master key from the given environment variable, or from the file in the
+    /// environment variable with the `_FILE` suffix.
+    fn from_env(var: &str) -> Result<Option<MasterKey>, Error> {
+        let hex_key = if let Ok(hex_key) = env::var(var) {
+            hex_key
+        } else if let Ok(path) = env::var(format!("{}_FILE", var)) {
+            let mut hex_key = String::new();
+            let _ = File::open(path)?.read_to_string(&mut hex_key)?;
+            hex_key
+        } else {
+            return Ok(None);
+        };
+
+        let key = hex::decode(hex_key.trim())?;
+        if key.len() != KEY_LEN {
+            bail!("the master key must be {} bytes long", KEY_LEN);
+        }
+
+        Ok(Some(MasterKey {
+            id: hex::encode(&token_hash(&key)[..KEY_ID_LEN]),
+            key,
+        }))
+    }
+}
+
+/// Signs the given link data, returning the signature as hexadecimal.
+pub fn sign_link(data: &str) -> String {
+    hex::encode(hmac::sign(&LINK_SIGNING_KEY, data.as_bytes()))
+}
+
+/// Verifies the hexadecimal signature of the given link data, in constant time.
+pub fn verify_link(data: &str, signature: &str) -> bool {
+    constant_time::verify_slices_are_equal(sign_link(data).as_bytes(), signature.as_bytes())
+        .is_ok()
+}
+
+/// Gets the ID of the current master key.
+pub fn master_key_id() -> &'static str {
+    &master_keys().current.id
+}
+
+/// Data key, used to encrypt secrets.
+///
+/// Data keys are stored encrypted with the master key, along with the secrets they encrypt.
+pub struct DataKey {
+    /// AES-256 key.
+    key: Vec<u8>,
+}
+
+impl DataKey {
+    /// Generates a new random data key.
+    pub fn generate() -> Result<DataKey, Error> {
+        let mut key = vec![0u8; KEY_LEN];
+        fill_random(&mut key)?;
+        Ok(DataKey { key })
+    }
+
+    /// Decrypts a data key with the master key with the given ID.
+    ///
+    /// The context must be the same one given to `wrap()`.
+    pub fn unwrap(
+        master_key_id: &str,
+        wrapped_key: &[u8],
+        context: &str,
+    ) -> Result<DataKey, Error> {
+        let master_keys = master_keys();
+        let master_key = if master_keys.current.id == master_key_id {
+            &master_keys.current
+        } else {
+            match master_keys.previous {
+                Some(ref key) if key.id == master_key_id => key,
+                _ => bail!("master key `{}` not found", master_key_id),
+            }
+        };
+
+        Ok(DataKey {
+            key: open(&master_key.key, wrapped_key, context.as_bytes())?,
+        })
+    }
+
+    /// Encrypts the data key with the current master key, returning the master key ID and the
+    /// encrypted data key.
+    ///
+    /// The context identifies where the data key is stored, and it's needed to decrypt it.
+    pub fn wrap(&self, context: &str) -> Result<(String, Vec<u8>), Error> {
+        let master_key = &master_keys().current;
+        Ok((master_key.id.clone(), seal(&master_key.key, &self.key, context.as_bytes())?))
+    }
+
+    /// Encrypts a secret.
+    ///
+    /// The context identifies where the secret is stored, and it's needed to decrypt it.
+    pub fn encrypt(&self, secret: &[u8], context: &str) -> Result<Vec<u8>, Error> {
+        seal(&self.key, secret, context.as_bytes())
+    }
+
+    /// Decrypts a secret encrypted with the given context.
+    pub fn decrypt(&self, encrypted: &[u8], context: &str) -> Result<Vec<u8>, Error> {
+        open(&self.key, encrypted, context.as_bytes())
+    }
+}
+
+impl fmt::Debug for DataKey {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        // Never print the key.
+        f.write_str("DataKey")
+    }
+}
+
+/// RSA signing key, to sign ID tokens with `RS256`.
+///
+/// Keys cannot be generated by the server, so the key is read from a DER encoded `RSAPrivateKey`
+/// (PKCS#1) file, that can be generated with
+/// `openssl genrsa 2048 | openssl rsa -outform DER -out rsa_key.der`.
+#[derive(Clone)]
+pub struct RsaSigningKey {
+    /// Key ID, derived from the modulus.
+    id: String,
+    /// Key pair.
+    key_pair: Arc<RSAKeyPair>,
+    /// Modulus of the public key, as a big-endian unsigned integer.
+    modulus: Vec<u8>,
+    /// Public exponent, as a big-endian unsigned integer.
+    public_exponent: Vec<u8>,
+}
+
+impl RsaSigningKey {
+    /// Loads an RSA signing key from the given DER file.
+    pub fn from_file<P: AsRef<Path>>(path: P) -> Result<RsaSigningKey, Error> {
+        let mut der = Vec::new();
+        let _ = File::open(path)?.read_to_end(&mut der)?;
+        RsaSigningKey::from_der(&der)
+    }
+
+    /// Parses a DER encoded `RSAPrivateKey`.
+    pub fn from_der(der: &[u8]) -> Result<RsaSigningKey, Error> {
+        let key_pair = RSAKeyPair::from_der(untrusted::Input::from(der))
+            .map_err(|_| format_err!("invalid RSA private key"))?;
+        let (modulus, public_exponent) =
+            rsa_public_key(der).ok_or_else(|| format_err!("invalid RSA private key"))?;
+
+        Ok(RsaSigningKey {
+            id: hex::encode(&token_hash(&modulus)[..RSA_KEY_ID_LEN]),
+            key_pair: Arc::new(key_pair),
+            modulus,
+            public_exponent,
+        })
+    }
+
+    /// Gets the ID of the key.
+    pub fn id(&self) -> &str {
+        &self.id
+    }
+
+    /// Gets the modulus of the public key, as a big-endian unsigned integer.
+    pub fn modulus(&self) -> &[u8] {
+        &self.modulus
+    }
+
+    /// Gets the public exponent, as a big-endian unsigned integer.
+    pub fn public_exponent(&self) -> &[u8] {
+        &self.public_exponent
+    }
+
+    /// Signs a message with RSASSA-PKCS1-v1_5 and SHA-256.
+    pub fn sign(&self, message: &[u8]) -> Result<Vec<u8>, Error> {
+        let mut signing_state = RSASigningState::new(Arc::clone(&self.key_pair))
+            .map_err(|_| format_err!("invalid RSA private key"))?;
+        let mut signature = vec![0u8; self.key_pair.public_modulus_len()];
+        signing_state
+            .sign(
+                &signature::RSA_PKCS1_SHA256,
+                &*RANDOM,
+                message,
+                &mut signature,
+            )
+            .map_err(|_| format_err!("could not sign the message"))?;
+        Ok(signature)
+    }
+}
+
+impl fmt::Debug for RsaSigningKey {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        // Never print the private key.
+        write!(f, "RsaSigningKey({})", self.id)
+    }
+}
+
+/// Reads the modulus and the public exponent of a DER encoded `RSAPrivateKey`.
+///
+/// The key must have been validated already, only the structure is checked here.
+fn rsa_public_key(der: &[u8]) -> Option<(Vec<u8>, Vec<u8>)> {
+    let (key, _) = der_element(der, DER_SEQUENCE)?;
+    let (_version, rest) = der_element(key, DER_INTEGER)?;
+    let (modulus, rest) = der_element(rest, DER_INTEGER)?;
+    let (public_exponent, _) = der_element(rest, DER_INTEGER)?;
+
+    let unsigned = |integer: &[u8]| {
+        let start = integer.iter().position(|&b| b != 0).unwrap_or(integer.len());
+        integer[start..].to_vec()
+    };
+    Some((unsigned(modulus), unsigned(public_exponent)))
+}
+
+/// Reads a DER element with the given tag, returning its contents and the rest of the input.
+///
+/// Only lengths of up to two bytes are supported, enough for RSA keys of up to 16384 bits.
+fn der_element(der: &[u8], tag: u8) -> Option<(&[u8], &[u8])> {
+    if der.len() < 2 || der[0] != tag {
+        return None;
+    }
+    let (len, header_len) = match der[1] {
+        len if len < 0x80 => (len as usize, 2),
+        0x81 => (*der.get(2)? as usize, 3),
+        0x82 => ((*der.get(2)? as usize) << 8 | *der.get(3)? as usize, 4),
+        _ => return None,
+    };
+    if der.len() < header_len + len {
+        return None;
+    }
+
+    let (element, rest) = der.split_at(header_len + len);
+    Some((&element[header_len..], rest))
+}
+
+/// Gets the key to encrypt the secrets stored in the cache, with `seal()` and `open()`.
+pub fn cache_key() -> &'static [u8] {
+    &CACHE_KEY
+}
+
+/// Encrypts data with AES-256-GCM, prepending the random nonce to the result.
+///
+/// The additional data is authenticated, but not encrypted, and it must be the same to decrypt
+/// the result.
+pub fn seal(key: &[u8], plaintext: &[u8], additional_data: &[u8]) -> Result<Vec<u8>, Error> {
+    let key = aead::SealingKey::new(&aead::AES_256_GCM, key)
+        .map_err(|_| format_err!("invalid encryption key"))?;
+    let nonce_len = aead::AES_256_GCM.nonce_len();
+    let tag_len = aead::AES_256_GCM.tag_len();
+
+    let mut output = vec![0u8; nonce_len + plaintext.len() + tag_len];
+    let (nonce, in_out) = output.split_at_mut(nonce_len);
+    fill_random(nonce)?;
+    in_out[..plaintext.len()].copy_from_slice(plaintext);
+    let _ = aead::seal_in_place(&key, nonce, additional_data, in_out, tag_len)
+        .map_err(|_| format_err!("could not encrypt the data"))?;
+
+    Ok(output)
+}
+
+/// Decrypts data encrypted with `seal()` and the given additional data.
+pub fn open(key: &[u8], encrypted: &[u8], additional_data: &[u8]) -> Result<Vec<u8>, Error> {
+    let key = aead::OpeningKey::new(&aead::AES_256_GCM, key)
+        .map_err(|_| format_err!("invalid encryption key"))?;
+    let nonce_len = aead::AES_256_GCM.nonce_len();
+    if encrypted.len() < nonce_len {
+        bail!("the encrypted data is too short");
+    }
+
+    let (nonce, sealed) = encrypted.split_at(nonce_len);
+    let mut in_out = sealed.to_vec();
+    Ok(aead::open_in_place(&key, nonce, additional_data, 0, &mut in_out)
+        .map_err(|_| format_err!("could not decrypt the data"))?
+        .to_vec())
+}
+
+#[cfg(test)]
+mod tests {
+    use ring::aead;
+
+    use super::{der_element, open, random_uuid, rsa_public_key, seal, DER_INTEGER, KEY_LEN};
+
+    #[test]
+    fn opens_sealed_data() {
+        let key = [7u8; KEY_LEN];
+        let sealed = seal(&key, b"secret", b"context").unwrap();
+        assert_eq!(
+            sealed.len(),
+            aead::AES_256_GCM.nonce_len() + b"secret".len() + aead::AES_256_GCM.tag_len()
+        );
+        assert_eq!(open(&key, &sealed, b"context").unwrap(), b"secret");
+
+        let sealed = seal(&key, b"", b"").unwrap();
+        assert_eq!(open(&key, &sealed, b"").unwrap(), b"");
+    }
+
+    #[test]
+    fn uses_random_nonces() {
+        let key = [7u8; KEY_LEN];
+        assert_ne!(
+            seal(&key, b"secret", b"context").unwrap(),
+            seal(&key, b"secret", b"context").unwrap()
+        );
+    }
+
+    #[test]
+    fn rejects_other_keys() {
+        let sealed = seal(&[7u8; KEY_LEN], b"secret", b"context").unwrap();
+        assert!(open(&[8u8; KEY_LEN], &sealed, b"context").is_err());
+        assert!(seal(&[7u8; 16], b"secret", b"context").is_err());
+    }
+
+    #[test]
+    fn rejects_other_contexts() {
+        let key = [7u8; KEY_LEN];
+        let sealed = seal(&key, b"secret", b"oauth_apps.api_secret:1").unwrap();
+        assert!(open(&key, &sealed, b"oauth_apps.api_secret:2").is_err());
+        assert!(open(&key, &sealed, b"oauth_apps.previous_api_secret:1").is_err());
+        assert!(open(&key, &sealed, b"").is_err());
+    }
+
+    #[test]
+    fn rejects_tampered_data() {
+        let key = [7u8; KEY_LEN];
+        let sealed = seal(&key, b"secret", b"context").unwrap();
+        for i in 0..sealed.len() {
+            let mut tampered = sealed.clone();
+            tampered[i] ^= 1;
+            assert!(open(&key, &tampered, b"context").is_err());
+        }
+        assert!(open(&key, &sealed[..sealed.len() - 1], b"context").is_err());
+        assert!(open(&key, &sealed[..aead::AES_256_GCM.nonce_len() - 1], b"context").is_err());
+    }
+
+    #[test]
+    fn generates_random_uuids() {
+        let uuid = random_uuid().unwrap();
+        assert_eq!(uuid.get_version_num(), 4);
+        assert_ne!(uuid, random_uuid().unwrap());
+    }
+
+    #[test]
+    fn reads_der_elements() {
+        let der = [0x02, 0x01, 0x05, 0x02, 0x00];
+        let (integer, rest) = der_element(&der, DER_INTEGER).unwrap();
+        assert_eq!(integer, &[0x05]);
+        assert_eq!(der_element(rest, DER_INTEGER).unwrap(), (&[][..], &[][..]));
+
+        let mut long = vec![0x02, 0x82, 0x01, 0x00];
+        long.extend(vec![0xff; 256]);
+        assert_eq!(der_element(&long, DER_INTEGER).unwrap().0.len(), 256);
+
+        assert!(der_element(&der, 0x30).is_none());
+        assert!(der_element(&[0x02, 0x02, 0x05], DER_INTEGER).is_none());
+        assert!(der_element(&[0x02, 0x83, 0x00, 0x00, 0x01], DER_INTEGER).is_none());
+    }
+
+    #[test]
+    fn reads_rsa_public_keys() {
+        // Sequence with the version, a modulus with a sign byte and the public exponent.
+        let der = [
+            0x30, 0x0c, 0x02, 0x01, 0x00, 0x02, 0x03, 0x00, 0xc5, 0x0f, 0x02, 0x02, 0x01, 0x00,
+            0x02, 0x00,
+        ];
+        let (modulus, public_exponent) = rsa_public_key(&der).unwrap();
+        assert_eq!(modulus, vec![0xc5, 0x0f]);
+        assert_eq!(public_exponent, vec![0x01, 0x00]);
+
+        assert!(rsa_public_key(&der[2..]).is_none());
+        assert!(rsa_public_key(&[0x30, 0x03, 0x02, 0x01, 0x00]).is_none());
+    }
+}
@@ -0,0 +1,18 @@
+//! Audit log related database methods.
+
+use diesel;
+use diesel::prelude::*;
+use failure::Error;
+
+use super::models::audit::NewAuditEntry;
+use super::schema::audit_log;
+use super::Connection;
+
+/// Records a new entry in the audit log.
+pub fn insert_entry(db_con: &Connection, entry: &NewAuditEntry) -> Result<(), Error> {
+    let _ = diesel::insert_into(audit_log::table)
+        .values(entry)
+        .execute(db_con)?;
+    Ok(())
+}
+
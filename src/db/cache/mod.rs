@@ -1,6 +1,8 @@
 //! Database cache module
 
 pub mod oauth;
+pub mod rate_limit;
+pub mod user;
 
 use std::env;
 
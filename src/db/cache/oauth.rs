@@ -1,17 +1,282 @@
 //! OAuth cache module.
 
+use std::cmp;
+
+use chrono::{Duration, Utc};
+use hex;
+use redis::{self, Commands};
+use serde_json;
 use uuid::Uuid;
 use failure::Error;
 
-/// Gets the hourly request count for the given application ID.
-pub fn get_request_count(app_id: Uuid) -> Result<i32, Error> {
-    unimplemented!()
+use super::CONNECTION_POOL;
+use super::super::models::oauth::AccessToken;
+
+/// Maximum time an access token will stay in the cache, in seconds.
+const ACCESS_TOKEN_CACHE_SECONDS: i64 = 300;
+/// Time a revoked access token is remembered, in seconds.
+///
+/// It's longer than the cache time, so that it outlives any entry cached by a validation that
+/// read the token from the database just before it was revoked.
+const REVOKED_ACCESS_TOKEN_SECONDS: usize = 2 * ACCESS_TOKEN_CACHE_SECONDS as usize;
+/// Lifetime of authorization codes, in seconds.
+const AUTHORIZATION_CODE_SECONDS: usize = 300;
+
+/// Records the nonce of a signed application request.
+///
+/// Returns `false` if the nonce had already been used by the application in the given lifetime,
+/// which means that the request is a replay.
+pub fn use_nonce(app_id: Uuid, nonce: &str, lifetime: Duration) -> Result<bool, Error> {
+    let cache_con = CONNECTION_POOL.get()?;
+    let result: Option<String> = redis::cmd("SET")
+        .arg(format!("oauth:nonce:{}:{}", app_id, nonce))
+        .arg(1)
+        .arg("NX")
+        .arg("EX")
+        .arg(lifetime.num_seconds())
+        .query(&*cache_con)?;
+
+    Ok(result.is_some())
 }
 
-/// Adds a request to the given application ID request counter.
+/// Access token information, as stored in the cache.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AccessTokenInfo {
+    /// ID of the user the token was issued to, if any.
+    user_id: Option<i32>,
+    /// ID of the application the token was issued for.
+    app_id: Uuid,
+    /// Scopes of the token.
+    scopes: Vec<String>,
+    /// Expiration timestamp.
+    expiration: i64,
+}
+
+impl AccessTokenInfo {
+    /// Gets the ID of the user the token was issued to.
+    ///
+    /// Application tokens, issued with the client credentials grant, don't have a user.
+    pub fn user_id(&self) -> Option<i32> {
+        self.user_id
+    }
+
+    /// Gets the ID of the application the token was issued for.
+    pub fn app_id(&self) -> Uuid {
+        self.app_id
+    }
+
+    /// Gets the scopes of the token.
+    pub fn scopes(&self) -> &[String] {
+        &self.scopes
+    }
+
+    /// Gets the expiration timestamp.
+    pub fn expiration(&self) -> i64 {
+        self.expiration
+    }
+
+    /// Checks if the token has expired.
+    pub fn is_expired(&self) -> bool {
+        self.expiration <= Utc::now().timestamp()
+    }
+}
+
+impl<'a> From<&'a AccessToken> for AccessTokenInfo {
+    fn from(token: &AccessToken) -> AccessTokenInfo {
+        AccessTokenInfo {
+            user_id: token.user_id(),
+            app_id: token.app_id(),
+            scopes: token.scopes().to_vec(),
+            expiration: token.expiration().timestamp(),
+        }
+    }
+}
+
+/// Gets the cache key of an access token.
+fn access_token_key(token_hash: &[u8]) -> String {
+    format!("oauth:access_token:{}", hex::encode(token_hash))
+}
+
+/// Gets the cache key of the revocation tombstone of an access token.
+fn revoked_access_token_key(token_hash: &[u8]) -> String {
+    format!("oauth:revoked_access_token:{}", hex::encode(token_hash))
+}
+
+/// Gets the cached information of the access token with the given hash.
 ///
-/// If the application ID does not exist, it will create a new record with 1 request and with a
-/// lifetime of 1 hour.
-pub fn add_request(app_id: Uuid) -> Result<(), Error> {
-    unimplemented!()
+/// Tokens that have been revoked recently are never returned, even if a concurrent validation
+/// cached them again.
+pub fn get_access_token(token_hash: &[u8]) -> Result<Option<AccessTokenInfo>, Error> {
+    let cache_con = CONNECTION_POOL.get()?;
+    let (cached, revoked): (Option<String>, bool) = redis::pipe()
+        .get(access_token_key(token_hash))
+        .exists(revoked_access_token_key(token_hash))
+        .query(&*cache_con)?;
+
+    match cached {
+        Some(json) if !revoked => Ok(Some(serde_json::from_str(&json)?)),
+        _ => Ok(None),
+    }
+}
+
+/// Checks if the access token with the given hash has been revoked recently.
+pub fn is_access_token_revoked(token_hash: &[u8]) -> Result<bool, Error> {
+    let cache_con = CONNECTION_POOL.get()?;
+    Ok(cache_con.exists(revoked_access_token_key(token_hash))?)
+}
+
+/// Caches the information of the access token with the given hash.
+///
+/// The information will be cached for 5 minutes at most, and never after the token expires.
+pub fn set_access_token(token_hash: &[u8], info: &AccessTokenInfo) -> Result<(), Error> {
+    let ttl = cmp::min(
+        info.expiration - Utc::now().timestamp(),
+        ACCESS_TOKEN_CACHE_SECONDS,
+    );
+    if ttl > 0 {
+        let cache_con = CONNECTION_POOL.get()?;
+        let _: () = cache_con.set_ex(
+            access_token_key(token_hash),
+            serde_json::to_string(info)?,
+            ttl as usize,
+        )?;
+    }
+    Ok(())
+}
+
+/// Removes the access tokens with the given hashes from the cache, and stores a revocation
+/// tombstone for each of them.
+///
+/// This must be called after revoking access tokens, so that the revocation takes effect
+/// immediately. The tombstones prevent a validation that read a token from the database just
+/// before its revocation from caching it again.
+pub fn remove_access_tokens<H: AsRef<[u8]>>(token_hashes: &[H]) -> Result<(), Error> {
+    if !token_hashes.is_empty() {
+        let mut pipe = redis::pipe();
+        for hash in token_hashes {
+            let _ = pipe
+                .del(access_token_key(hash.as_ref()))
+                .ignore()
+                .set_ex(revoked_access_token_key(hash.as_ref()), 1, REVOKED_ACCESS_TOKEN_SECONDS)
+                .ignore();
+        }
+
+        let cache_con = CONNECTION_POOL.get()?;
+        let _: () = pipe.atomic().query(&*cache_con)?;
+    }
+    Ok(())
+}
+
+/// Authorization code information, as stored in the cache.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AuthorizationCode {
+    /// ID of the user that authorized the application.
+    user_id: i32,
+    /// ID of the authorized application.
+    app_id: Uuid,
+    /// Redirect URI used in the authorization request.
+    redirect_uri: String,
+    /// Authorized scopes.
+    scopes: Vec<String>,
+    /// PKCE code challenge, using the `S256` method.
+    code_challenge: String,
+    /// OpenID Connect nonce of the authorization request, if any.
+    #[serde(default)]
+    nonce: Option<String>,
+    /// Timestamp of the authentication of the user.
+    #[serde(default)]
+    auth_time: i64,
+}
+
+impl AuthorizationCode {
+    /// Creates a new authorization code information structure.
+    ///
+    /// The user is considered to be authenticated when the code is created.
+    pub fn new(
+        user_id: i32,
+        app_id: Uuid,
+        redirect_uri: String,
+        scopes: Vec<String>,
+        code_challenge: String,
+        nonce: Option<String>,
+    ) -> AuthorizationCode {
+        AuthorizationCode {
+            user_id,
+            app_id,
+            redirect_uri,
+            scopes,
+            code_challenge,
+            nonce,
+            auth_time: Utc::now().timestamp(),
+        }
+    }
+
+    /// Gets the ID of the user that authorized the application.
+    pub fn user_id(&self) -> i32 {
+        self.user_id
+    }
+
+    /// Gets the ID of the authorized application.
+    pub fn app_id(&self) -> Uuid {
+        self.app_id
+    }
+
+    /// Gets the redirect URI used in the authorization request.
+    pub fn redirect_uri(&self) -> &str {
+        &self.redirect_uri
+    }
+
+    /// Gets the authorized scopes.
+    pub fn scopes(&self) -> &[String] {
+        &self.scopes
+    }
+
+    /// Gets the PKCE code challenge.
+    pub fn code_challenge(&self) -> &str {
+        &self.code_challenge
+    }
+
+    /// Gets the OpenID Connect nonce of the authorization request, if any.
+    pub fn nonce(&self) -> Option<&str> {
+        self.nonce.as_ref().map(String::as_str)
+    }
+
+    /// Gets the timestamp of the authentication of the user.
+    pub fn auth_time(&self) -> i64 {
+        self.auth_time
+    }
+}
+
+/// Gets the cache key of an authorization code.
+fn authorization_code_key(code_hash: &[u8]) -> String {
+    format!("oauth:authorization_code:{}", hex::encode(code_hash))
+}
+
+/// Stores a new authorization code with the given hash, that will expire in 5 minutes.
+pub fn set_authorization_code(code_hash: &[u8], code: &AuthorizationCode) -> Result<(), Error> {
+    let cache_con = CONNECTION_POOL.get()?;
+    let _: () = cache_con.set_ex(
+        authorization_code_key(code_hash),
+        serde_json::to_string(code)?,
+        AUTHORIZATION_CODE_SECONDS,
+    )?;
+    Ok(())
+}
+
+/// Gets and removes the authorization code with the given hash.
+///
+/// This is done atomically, so that a code can only be used once, even with concurrent requests.
+pub fn take_authorization_code(code_hash: &[u8]) -> Result<Option<AuthorizationCode>, Error> {
+    let cache_con = CONNECTION_POOL.get()?;
+    let key = authorization_code_key(code_hash);
+    let (cached, _): (Option<String>, i32) = redis::pipe()
+        .atomic()
+        .get(&key)
+        .del(&key)
+        .query(&*cache_con)?;
+
+    match cached {
+        Some(json) => Ok(Some(serde_json::from_str(&json)?)),
+        None => Ok(None),
+    }
 }
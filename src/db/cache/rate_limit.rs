@@ -0,0 +1,324 @@
+//! Rate limiting cache module.
+//!
+//! Rate limits use the generic cell rate algorithm (GCRA), a sliding window that only needs to
+//! store one timestamp per key: the theoretical arrival time (TAT) of the next request. Requests
+//! can be made in bursts up to the limit, and then the capacity is restored gradually along the
+//! period. The TAT is updated in an optimistic transaction that watches the key, so that it's
+//! atomic between server instances, and it uses the Redis clock, so that instances don't need
+//! synchronized clocks.
+
+use std::cmp;
+
+use chrono::Duration;
+use failure::Error;
+use redis;
+
+use super::CONNECTION_POOL;
+
+/// State of a rate limit after checking a request.
+#[derive(Debug, Clone, Copy)]
+pub struct RateLimitState {
+    /// Wether the request is allowed.
+    allowed: bool,
+    /// Requests that can still be made right away.
+    remaining: i32,
+    /// Milliseconds until the limit is fully restored.
+    reset_after: i64,
+    /// Milliseconds until the request would be allowed, if it's not allowed.
+    retry_after: i64,
+}
+
+impl RateLimitState {
+    /// Gets wether the request is allowed.
+    pub fn is_allowed(&self) -> bool {
+        self.allowed
+    }
+
+    /// Gets the number of requests that can still be made right away.
+    pub fn remaining(&self) -> i32 {
+        self.remaining
+    }
+
+    /// Gets the time until the limit is fully restored.
+    pub fn reset_after(&self) -> Duration {
+        Duration::milliseconds(self.reset_after)
+    }
+
+    /// Gets the time until the request would be allowed.
+    pub fn retry_after(&self) -> Duration {
+        Duration::milliseconds(self.retry_after)
+    }
+}
+
+/// Checks a request with the given cost against a rate limit with the given TAT, at the given
+/// time.
+///
+/// Times are in milliseconds, and the limit is the number of requests allowed in each period.
+/// Returns the state of the limit, and the new TAT to store if the request is allowed and
+/// consumed.
+fn gcra(
+    tat: Option<i64>,
+    now: i64,
+    limit: i32,
+    period: i64,
+    cost: i32,
+    consume: bool,
+) -> (RateLimitState, Option<i64>) {
+    let interval = period as f64 / f64::from(limit);
+    let remaining = |tat: f64| ((period as f64 - (tat - now as f64)) / interval).floor() as i32;
+    let tat = tat.map_or(now, |tat| cmp::max(tat, now)) as f64;
+
+    let new_tat = tat + interval * f64::from(cost);
+    let allowed_at = new_tat - period as f64;
+    if allowed_at > now as f64 {
+        let state = RateLimitState {
+            allowed: false,
+            remaining: remaining(tat),
+            reset_after: (tat - now as f64).ceil() as i64,
+            retry_after: (allowed_at - now as f64).ceil() as i64,
+        };
+        return (state, None);
+    }
+
+    let tat = if consume { new_tat } else { tat };
+    let state = RateLimitState {
+        allowed: true,
+        remaining: remaining(tat),
+        reset_after: (tat - now as f64).ceil() as i64,
+        retry_after: 0,
+    };
+    (state, if consume { Some(tat.ceil() as i64) } else { None })
+}
+
+/// Checks a request against the rate limit with the given key, storing its new TAT if it's
+/// consumed.
+fn check(
+    key: &str,
+    limit: i32,
+    period: Duration,
+    cost: i32,
+    consume: bool,
+) -> Result<RateLimitState, Error> {
+    let cache_con = CONNECTION_POOL.get()?;
+    Ok(redis::transaction(&*cache_con, &[key], |pipe| {
+        let (seconds, microseconds): (i64, i64) = redis::cmd("TIME").query(&*cache_con)?;
+        let now = seconds * 1000 + microseconds / 1000;
+        let tat: Option<i64> = redis::cmd("GET").arg(key).query(&*cache_con)?;
+
+        match gcra(tat, now, limit, period.num_milliseconds(), cost, consume) {
+            (state, Some(tat)) => {
+                let stored: Option<()> = pipe.cmd("SET")
+                    .arg(key)
+                    .arg(tat)
+                    .arg("PX")
+                    .arg(cmp::max(tat - now, 1))
+                    .ignore()
+                    .query(&*cache_con)?;
+                // The transaction is retried if the key changed since reading it.
+                Ok(stored.map(|_| state))
+            }
+            (state, None) => Ok(Some(state)),
+        }
+    })?)
+}
+
+/// Counts a request with the given cost against the rate limit with the given key, if it's
+/// allowed.
+///
+/// The limit is the number of requests allowed in each period.
+pub fn consume(
+    key: &str,
+    limit: i32,
+    period: Duration,
+    cost: i32,
+) -> Result<RateLimitState, Error> {
+    check(key, limit, period, cost, true)
+}
+
+/// Checks the rate limit with the given key, without counting any request.
+pub fn peek(key: &str, limit: i32, period: Duration) -> Result<RateLimitState, Error> {
+    check(key, limit, period, 1, false)
+}
+
+#[cfg(test)]
+mod tests {
+    //! The tests of the stored limits run in the Redis server of the `REDIS_DATABASE`
+    //! environment variable, so they are ignored by default. Run them with
+    //! `cargo test -- --ignored`.
+
+    use chrono::Duration;
+    use hex;
+    use redis;
+
+    use crypto;
+    use super::{consume, gcra, peek, CONNECTION_POOL};
+
+    /// One hour, in milliseconds.
+    const HOUR: i64 = 3_600_000;
+
+    #[test]
+    fn gcra_allows_bursts_up_to_the_limit() {
+        let mut tat = None;
+        for remaining in (0..5).rev() {
+            let (state, new_tat) = gcra(tat, 0, 5, HOUR, 1, true);
+            assert!(state.is_allowed());
+            assert_eq!(state.remaining(), remaining);
+            assert_eq!(state.retry_after(), Duration::zero());
+            tat = new_tat;
+        }
+        assert_eq!(tat, Some(HOUR));
+
+        let (state, new_tat) = gcra(tat, 0, 5, HOUR, 1, true);
+        assert!(!state.is_allowed());
+        assert_eq!(state.remaining(), 0);
+        assert_eq!(state.reset_after(), Duration::hours(1));
+        assert_eq!(state.retry_after(), Duration::minutes(12));
+        assert_eq!(new_tat, None);
+    }
+
+    #[test]
+    fn gcra_counts_the_cost_of_the_request() {
+        let (state, tat) = gcra(None, 0, 10, HOUR, 4, true);
+        assert!(state.is_allowed());
+        assert_eq!(state.remaining(), 6);
+        assert_eq!(tat, Some(HOUR * 4 / 10));
+
+        let (state, new_tat) = gcra(tat, 0, 10, HOUR, 7, true);
+        assert!(!state.is_allowed());
+        assert_eq!(state.remaining(), 6);
+        assert_eq!(state.retry_after(), Duration::minutes(6));
+        assert_eq!(new_tat, None);
+
+        let (state, tat) = gcra(tat, 0, 10, HOUR, 6, true);
+        assert!(state.is_allowed());
+        assert_eq!(state.remaining(), 0);
+        assert_eq!(tat, Some(HOUR));
+    }
+
+    #[test]
+    fn gcra_never_allows_a_cost_over_the_limit() {
+        let (state, tat) = gcra(None, 0, 3, HOUR, 4, true);
+        assert!(!state.is_allowed());
+        assert_eq!(state.remaining(), 3);
+        assert_eq!(tat, None);
+    }
+
+    #[test]
+    fn gcra_peeking_does_not_consume() {
+        let (state, tat) = gcra(None, 0, 2, HOUR, 1, false);
+        assert!(state.is_allowed());
+        assert_eq!(state.remaining(), 2);
+        assert_eq!(state.reset_after(), Duration::zero());
+        assert_eq!(tat, None);
+
+        let (state, tat) = gcra(Some(HOUR / 2), 0, 2, HOUR, 1, false);
+        assert!(state.is_allowed());
+        assert_eq!(state.remaining(), 1);
+        assert_eq!(state.reset_after(), Duration::minutes(30));
+        assert_eq!(tat, None);
+    }
+
+    #[test]
+    fn gcra_restores_the_capacity_along_the_period() {
+        let (_, tat) = gcra(None, 0, 10, 1000, 10, true);
+        assert_eq!(tat, Some(1000));
+        assert!(!gcra(tat, 0, 10, 1000, 1, true).0.is_allowed());
+
+        let (state, new_tat) = gcra(tat, 250, 10, 1000, 1, true);
+        assert!(state.is_allowed());
+        assert_eq!(state.remaining(), 1);
+        assert_eq!(new_tat, Some(1100));
+
+        // Old TATs don't give more capacity than the limit.
+        let (state, new_tat) = gcra(tat, 5000, 10, 1000, 1, true);
+        assert!(state.is_allowed());
+        assert_eq!(state.remaining(), 9);
+        assert_eq!(new_tat, Some(5100));
+    }
+
+    /// Runs a test with a new random rate limit key, removing it afterwards.
+    fn with_key<F: FnOnce(&str)>(test: F) {
+        let key = format!("test:rate_limit:{}", hex::encode(crypto::random_token().unwrap()));
+        test(&key);
+
+        let cache_con = CONNECTION_POOL.get().unwrap();
+        let _: i32 = redis::cmd("DEL").arg(&key).query(&*cache_con).unwrap();
+    }
+
+    #[test]
+    #[ignore]
+    fn allows_bursts_up_to_the_limit() {
+        with_key(|key| {
+            for remaining in (0..5).rev() {
+                let state = consume(key, 5, Duration::hours(1), 1).unwrap();
+                assert!(state.is_allowed());
+                assert_eq!(state.remaining(), remaining);
+            }
+
+            let state = consume(key, 5, Duration::hours(1), 1).unwrap();
+            assert!(!state.is_allowed());
+            assert_eq!(state.remaining(), 0);
+            assert!(state.retry_after() > Duration::minutes(11));
+            assert!(state.retry_after() <= Duration::minutes(12));
+            assert!(state.reset_after() > Duration::minutes(59));
+        });
+    }
+
+    #[test]
+    #[ignore]
+    fn counts_the_cost_of_the_request() {
+        with_key(|key| {
+            let state = consume(key, 10, Duration::hours(1), 4).unwrap();
+            assert!(state.is_allowed());
+            assert_eq!(state.remaining(), 6);
+
+            let state = consume(key, 10, Duration::hours(1), 7).unwrap();
+            assert!(!state.is_allowed());
+            assert_eq!(state.remaining(), 6);
+
+            let state = consume(key, 10, Duration::hours(1), 6).unwrap();
+            assert!(state.is_allowed());
+            assert_eq!(state.remaining(), 0);
+        });
+    }
+
+    #[test]
+    #[ignore]
+    fn never_allows_a_cost_over_the_limit() {
+        with_key(|key| {
+            let state = consume(key, 3, Duration::hours(1), 4).unwrap();
+            assert!(!state.is_allowed());
+            assert_eq!(state.remaining(), 3);
+        });
+    }
+
+    #[test]
+    #[ignore]
+    fn peeking_does_not_consume() {
+        with_key(|key| {
+            let state = peek(key, 2, Duration::hours(1)).unwrap();
+            assert!(state.is_allowed());
+            assert_eq!(state.remaining(), 2);
+            assert_eq!(state.reset_after(), Duration::zero());
+
+            let _ = consume(key, 2, Duration::hours(1), 1).unwrap();
+            let state = peek(key, 2, Duration::hours(1)).unwrap();
+            assert!(state.is_allowed());
+            assert_eq!(state.remaining(), 1);
+        });
+    }
+
+    #[test]
+    #[ignore]
+    fn restores_the_capacity_along_the_period() {
+        with_key(|key| {
+            assert!(consume(key, 10, Duration::seconds(1), 10).unwrap().is_allowed());
+            assert!(!consume(key, 10, Duration::seconds(1), 1).unwrap().is_allowed());
+
+            ::std::thread::sleep(::std::time::Duration::from_millis(250));
+            let state = consume(key, 10, Duration::seconds(1), 1).unwrap();
+            assert!(state.is_allowed());
+            assert!(state.remaining() >= 1);
+        });
+    }
+}
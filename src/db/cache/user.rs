@@ -0,0 +1,293 @@
+//! User cache module.
+
+use chrono::Duration;
+use failure::Error;
+use hex;
+use redis::{self, Commands};
+use serde_json;
+use uuid::Uuid;
+
+use super::CONNECTION_POOL;
+
+/// Gets the cache key of an account unlock token.
+fn unlock_token_key(token_hash: &[u8]) -> String {
+    format!("user:unlock_token:{}", hex::encode(token_hash))
+}
+
+/// Stores a new account unlock token with the given hash, for the given user.
+pub fn set_unlock_token(token_hash: &[u8], user_id: i32, lifetime: Duration) -> Result<(), Error> {
+    let cache_con = CONNECTION_POOL.get()?;
+    let _: () = cache_con.set_ex(
+        unlock_token_key(token_hash),
+        user_id,
+        lifetime.num_seconds() as usize,
+    )?;
+    Ok(())
+}
+
+/// Gets and removes the account unlock token with the given hash, returning the ID of its user.
+///
+/// This is done atomically, so that a token can only be used once.
+pub fn take_unlock_token(token_hash: &[u8]) -> Result<Option<i32>, Error> {
+    let cache_con = CONNECTION_POOL.get()?;
+    let key = unlock_token_key(token_hash);
+    let (user_id, _): (Option<i32>, i32) = redis::pipe()
+        .atomic()
+        .get(&key)
+        .del(&key)
+        .query(&*cache_con)?;
+    Ok(user_id)
+}
+
+/// Gets the cache key of a password reset token.
+fn reset_token_key(token_hash: &[u8]) -> String {
+    format!("user:reset_token:{}", hex::encode(token_hash))
+}
+
+/// Gets the cache key of the set of password reset token hashes of a user.
+fn user_reset_tokens_key(user_id: i32) -> String {
+    format!("user:reset_tokens:{}", user_id)
+}
+
+/// Stores a new password reset token with the given hash, for the given user.
+///
+/// The hash is also added to the reset tokens of the user, so that they can all be removed.
+pub fn set_reset_token(token_hash: &[u8], user_id: i32, lifetime: Duration) -> Result<(), Error> {
+    set_user_token(
+        &reset_token_key(token_hash),
+        &user_reset_tokens_key(user_id),
+        token_hash,
+        user_id,
+        lifetime,
+    )
+}
+
+/// Gets the ID of the user of the password reset token with the given hash, without using it.
+pub fn get_reset_token(token_hash: &[u8]) -> Result<Option<i32>, Error> {
+    let cache_con = CONNECTION_POOL.get()?;
+    Ok(cache_con.get(reset_token_key(token_hash))?)
+}
+
+/// Gets and removes the password reset token with the given hash, returning the ID of its user.
+///
+/// This is done atomically, so that a token can only be used once.
+pub fn take_reset_token(token_hash: &[u8]) -> Result<Option<i32>, Error> {
+    let cache_con = CONNECTION_POOL.get()?;
+    let key = reset_token_key(token_hash);
+    let (user_id, _): (Option<i32>, i32) = redis::pipe()
+        .atomic()
+        .get(&key)
+        .del(&key)
+        .query(&*cache_con)?;
+    Ok(user_id)
+}
+
+/// Removes all the password reset tokens of a user.
+pub fn remove_reset_tokens(user_id: i32) -> Result<(), Error> {
+    remove_user_tokens(&user_reset_tokens_key(user_id), reset_token_key)
+}
+
+/// Gets the cache key of an account page token.
+fn page_token_key(token_hash: &[u8]) -> String {
+    format!("user:page_token:{}", hex::encode(token_hash))
+}
+
+/// Gets the cache key of the set of account page token hashes of a user.
+fn user_page_tokens_key(user_id: i32) -> String {
+    format!("user:page_tokens:{}", user_id)
+}
+
+/// Stores a new account page token with the given hash, for the given user.
+///
+/// The hash is also added to the page tokens of the user, so that they can all be removed.
+pub fn set_page_token(token_hash: &[u8], user_id: i32, lifetime: Duration) -> Result<(), Error> {
+    set_user_token(
+        &page_token_key(token_hash),
+        &user_page_tokens_key(user_id),
+        token_hash,
+        user_id,
+        lifetime,
+    )
+}
+
+/// Gets the ID of the user of the account page token with the given hash, if it has not expired.
+pub fn get_page_token(token_hash: &[u8]) -> Result<Option<i32>, Error> {
+    let cache_con = CONNECTION_POOL.get()?;
+    Ok(cache_con.get(page_token_key(token_hash))?)
+}
+
+/// Removes all the account page tokens of a user.
+pub fn remove_page_tokens(user_id: i32) -> Result<(), Error> {
+    remove_user_tokens(&user_page_tokens_key(user_id), page_token_key)
+}
+
+/// Stores a token of a user with the given key, and adds its hash to the set of token hashes of
+/// the user with the given key.
+///
+/// The set expires with the last token added to it.
+fn set_user_token(
+    key: &str,
+    user_key: &str,
+    token_hash: &[u8],
+    user_id: i32,
+    lifetime: Duration,
+) -> Result<(), Error> {
+    let cache_con = CONNECTION_POOL.get()?;
+    let lifetime = lifetime.num_seconds() as usize;
+    let _: () = redis::pipe()
+        .atomic()
+        .set_ex(key, user_id, lifetime)
+        .ignore()
+        .sadd(user_key, token_hash)
+        .ignore()
+        .expire(user_key, lifetime)
+        .ignore()
+        .query(&*cache_con)?;
+    Ok(())
+}
+
+/// Removes all the tokens in the set of token hashes of a user with the given key, getting the
+/// key of each token from its hash.
+///
+/// Only the hashes read are removed from the set, so that a token stored in the meantime is kept
+/// consistent.
+fn remove_user_tokens<F>(user_key: &str, token_key: F) -> Result<(), Error>
+where
+    F: Fn(&[u8]) -> String,
+{
+    let cache_con = CONNECTION_POOL.get()?;
+    let token_hashes: Vec<Vec<u8>> = cache_con.smembers(user_key)?;
+    if token_hashes.is_empty() {
+        return Ok(());
+    }
+
+    let keys = token_hashes
+        .iter()
+        .map(|token_hash| token_key(token_hash))
+        .collect::<Vec<_>>();
+    let _: () = redis::pipe()
+        .atomic()
+        .del(keys)
+        .ignore()
+        .srem(user_key, token_hashes)
+        .ignore()
+        .query(&*cache_con)?;
+    Ok(())
+}
+
+/// Gets the cache key of the pending two-factor enrolment of a user.
+fn totp_enrolment_key(user_id: i32) -> String {
+    format!("user:totp_enrolment:{}", user_id)
+}
+
+/// Stores the TOTP secret of a pending two-factor enrolment, until the user confirms it.
+///
+/// The secret must be encrypted with `crypto::seal()`. Starting a new enrolment replaces the
+/// previous one.
+pub fn set_totp_enrolment(user_id: i32, secret: &[u8], lifetime: Duration) -> Result<(), Error> {
+    let cache_con = CONNECTION_POOL.get()?;
+    let _: () = cache_con.set_ex(
+        totp_enrolment_key(user_id),
+        secret,
+        lifetime.num_seconds() as usize,
+    )?;
+    Ok(())
+}
+
+/// Gets the TOTP secret of the pending two-factor enrolment of a user, if it has not expired.
+pub fn get_totp_enrolment(user_id: i32) -> Result<Option<Vec<u8>>, Error> {
+    let cache_con = CONNECTION_POOL.get()?;
+    Ok(cache_con.get(totp_enrolment_key(user_id))?)
+}
+
+/// Removes the pending two-factor enrolment of a user, once it has been confirmed.
+pub fn remove_totp_enrolment(user_id: i32) -> Result<(), Error> {
+    let cache_con = CONNECTION_POOL.get()?;
+    let _: () = cache_con.del(totp_enrolment_key(user_id))?;
+    Ok(())
+}
+
+/// Sign-in link, as stored in the cache.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct MagicLink {
+    /// ID of the user signing in.
+    user_id: i32,
+    /// ID of the application that requested the link, if it was requested through the API.
+    app_id: Option<Uuid>,
+    /// Scopes requested by the application.
+    scopes: Vec<String>,
+}
+
+impl MagicLink {
+    /// Creates a new sign-in link structure.
+    pub fn new(user_id: i32, app_id: Option<Uuid>, scopes: Vec<String>) -> MagicLink {
+        MagicLink {
+            user_id,
+            app_id,
+            scopes,
+        }
+    }
+
+    /// Gets the ID of the user signing in.
+    pub fn user_id(&self) -> i32 {
+        self.user_id
+    }
+
+    /// Gets the ID of the application that requested the link, if any.
+    pub fn app_id(&self) -> Option<Uuid> {
+        self.app_id
+    }
+
+    /// Gets the scopes requested by the application.
+    pub fn scopes(&self) -> &[String] {
+        &self.scopes
+    }
+}
+
+/// Gets the cache key of a sign-in link token.
+fn magic_link_key(token_hash: &[u8]) -> String {
+    format!("user:magic_link:{}", hex::encode(token_hash))
+}
+
+/// Stores a new sign-in link with the given token hash.
+pub fn set_magic_link(
+    token_hash: &[u8],
+    link: &MagicLink,
+    lifetime: Duration,
+) -> Result<(), Error> {
+    let cache_con = CONNECTION_POOL.get()?;
+    let _: () = cache_con.set_ex(
+        magic_link_key(token_hash),
+        serde_json::to_string(link)?,
+        lifetime.num_seconds() as usize,
+    )?;
+    Ok(())
+}
+
+/// Gets the sign-in link with the given token hash, without using it.
+pub fn get_magic_link(token_hash: &[u8]) -> Result<Option<MagicLink>, Error> {
+    let cache_con = CONNECTION_POOL.get()?;
+    let cached: Option<String> = cache_con.get(magic_link_key(token_hash))?;
+    match cached {
+        Some(json) => Ok(Some(serde_json::from_str(&json)?)),
+        None => Ok(None),
+    }
+}
+
+/// Gets and removes the sign-in link with the given token hash.
+///
+/// This is done atomically, so that a link can only be used once.
+pub fn take_magic_link(token_hash: &[u8]) -> Result<Option<MagicLink>, Error> {
+    let cache_con = CONNECTION_POOL.get()?;
+    let key = magic_link_key(token_hash);
+    let (cached, _): (Option<String>, i32) = redis::pipe()
+        .atomic()
+        .get(&key)
+        .del(&key)
+        .query(&*cache_con)?;
+
+    match cached {
+        Some(json) => Ok(Some(serde_json::from_str(&json)?)),
+        None => Ok(None),
+    }
+}
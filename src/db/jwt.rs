@@ -0,0 +1,94 @@
+//! JSON Web Token related database methods.
+
+use chrono::{DateTime, Utc};
+use failure::Error;
+use diesel;
+use diesel::prelude::*;
+use diesel::Connection as DieselConnection;
+
+use super::models::jwt::{NewSigningKey, SigningKey, SigningKeySecret};
+use super::schema::oauth_signing_keys;
+use super::Connection;
+
+/// Transaction-level advisory lock that serializes the signing key rotations.
+const ROTATION_LOCK: &str = "SELECT pg_advisory_xact_lock(7341029318264113)";
+
+/// Gets the current signing key, the newest one, if there is any.
+pub fn get_current_signing_key(db_con: &Connection) -> Result<Option<SigningKey>, Error> {
+    Ok(oauth_signing_keys::table
+        .filter(oauth_signing_keys::retirement.is_null())
+        .order(oauth_signing_keys::creation.desc())
+        .first(db_con)
+        .optional()?)
+}
+
+/// Gets the signing keys that are current or were retired after the given date.
+pub fn get_published_signing_keys(
+    db_con: &Connection,
+    retired_after: DateTime<Utc>,
+) -> Result<Vec<SigningKey>, Error> {
+    Ok(oauth_signing_keys::table
+        .filter(
+            oauth_signing_keys::retirement
+                .is_null()
+                .or(oauth_signing_keys::retirement.gt(retired_after)),
+        )
+        .order(oauth_signing_keys::creation.desc())
+        .load(db_con)?)
+}
+
+/// Stores a new signing key, retiring the current one, unless the current key was created after
+/// the given date.
+///
+/// Rotations are serialized with an advisory lock, so that workers that find an old key at the
+/// same time don't create several keys: the ones that wait for the lock get the key created by
+/// the first one. The unique index on the current key enforces it too.
+pub fn rotate_signing_key(
+    db_con: &Connection,
+    key: &NewSigningKey,
+    created_before: DateTime<Utc>,
+) -> Result<SigningKey, Error> {
+    db_con.transaction(|| {
+        let _ = diesel::sql_query(ROTATION_LOCK).execute(db_con)?;
+        if let Some(current) = get_current_signing_key(db_con)? {
+            if current.creation() >= created_before {
+                return Ok(current);
+            }
+        }
+
+        let _ = diesel::update(
+            oauth_signing_keys::table.filter(oauth_signing_keys::retirement.is_null()),
+        ).set(oauth_signing_keys::retirement.eq(Utc::now()))
+            .execute(db_con)?;
+
+        Ok(diesel::insert_into(oauth_signing_keys::table)
+            .values(key)
+            .get_result(db_con)?)
+    })
+}
+
+/// Gets the signing keys whose private keys are not encrypted with the given master key.
+pub fn get_signing_keys_to_reencrypt(
+    db_con: &Connection,
+    master_key_id: &str,
+) -> Result<Vec<SigningKey>, Error> {
+    Ok(oauth_signing_keys::table
+        .filter(
+            oauth_signing_keys::master_key_id
+                .is_null()
+                .or(oauth_signing_keys::master_key_id.ne(master_key_id)),
+        )
+        .load(db_con)?)
+}
+
+/// Replaces the encrypted private key of a signing key.
+pub fn update_signing_key_secret(
+    db_con: &Connection,
+    id: &str,
+    secret: &SigningKeySecret,
+) -> Result<(), Error> {
+    let _ = diesel::update(oauth_signing_keys::table.find(id))
+        .set(secret)
+        .execute(db_con)?;
+    Ok(())
+}
@@ -5,7 +5,10 @@
 
 pub mod models;
 pub mod cache;
+pub mod audit;
+pub mod jwt;
 pub mod oauth;
+pub mod user;
 
 use std::env;
 
@@ -16,7 +19,7 @@ use r2d2_diesel::ConnectionManager;
 /// Type of database connection.
 ///
 /// Change this to other databases such as MySQL/MariaDB.
-type Connection = PgConnection;
+pub type Connection = PgConnection;
 
 lazy_static!{
     /// Main database connection pool.
@@ -0,0 +1,87 @@
+//! Audit log database models.
+
+use std::net::IpAddr;
+
+use super::super::schema::audit_log;
+
+/// Security related event of a user account.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AuditEvent {
+    /// The user logged in with the password.
+    LoginSucceeded,
+    /// A login attempt failed because of an invalid username or password.
+    LoginFailed,
+    /// A client address was locked out of the account after too many failed login attempts.
+    AccountLocked,
+    /// Every login attempt on the account was delayed after too many failed attempts from any
+    /// address.
+    AccountThrottled,
+    /// The account was unlocked with the link sent by email.
+    AccountUnlocked,
+    /// A rotated refresh token was used again, and its whole family was revoked.
+    RefreshTokenReused,
+    /// The user registered a new account.
+    UserRegistered,
+    /// The user verified its email address, activating the account.
+    EmailVerified,
+    /// A password reset link was sent to the user.
+    PasswordResetRequested,
+    /// The user reset its password with the link sent by email.
+    PasswordReset,
+    /// The user enabled two-factor authentication.
+    TwoFactorEnabled,
+    /// The user disabled two-factor authentication.
+    TwoFactorDisabled,
+    /// An administrator disabled the two-factor authentication of the user.
+    TwoFactorReset,
+    /// The user logged in with a recovery code instead of a TOTP code.
+    RecoveryCodeUsed,
+    /// A sign-in link was sent to the user.
+    MagicLinkRequested,
+}
+
+impl AuditEvent {
+    /// Gets the name of the event, as stored in the database.
+    pub fn as_str(&self) -> &'static str {
+        match *self {
+            AuditEvent::LoginSucceeded => "login_succeeded",
+            AuditEvent::LoginFailed => "login_failed",
+            AuditEvent::AccountLocked => "account_locked",
+            AuditEvent::AccountThrottled => "account_throttled",
+            AuditEvent::AccountUnlocked => "account_unlocked",
+            AuditEvent::RefreshTokenReused => "refresh_token_reused",
+            AuditEvent::UserRegistered => "user_registered",
+            AuditEvent::EmailVerified => "email_verified",
+            AuditEvent::PasswordResetRequested => "password_reset_requested",
+            AuditEvent::PasswordReset => "password_reset",
+            AuditEvent::TwoFactorEnabled => "two_factor_enabled",
+            AuditEvent::TwoFactorDisabled => "two_factor_disabled",
+            AuditEvent::TwoFactorReset => "two_factor_reset",
+            AuditEvent::RecoveryCodeUsed => "recovery_code_used",
+            AuditEvent::MagicLinkRequested => "magic_link_requested",
+        }
+    }
+}
+
+/// New audit log entry.
+#[derive(Debug, Insertable)]
+#[table_name = "audit_log"]
+pub struct NewAuditEntry {
+    /// ID of the user the event belongs to, if it's known.
+    user_id: Option<i32>,
+    /// Name of the event.
+    event: &'static str,
+    /// Client IP address of the request that caused the event, if any.
+    ip_address: Option<String>,
+}
+
+impl NewAuditEntry {
+    /// Creates a new audit log entry structure.
+    pub fn new(user_id: Option<i32>, event: AuditEvent, ip: Option<IpAddr>) -> NewAuditEntry {
+        NewAuditEntry {
+            user_id,
+            event: event.as_str(),
+            ip_address: ip.map(|ip| ip.to_string()),
+        }
+    }
+}
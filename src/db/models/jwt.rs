@@ -0,0 +1,137 @@
+//! JSON Web Token database models.
+
+use chrono::{DateTime, Utc};
+use failure::Error;
+
+use crypto::DataKey;
+
+use super::super::schema::oauth_signing_keys;
+
+/// JWT signing key.
+#[derive(Debug, Clone, Queryable, Identifiable)]
+#[table_name = "oauth_signing_keys"]
+pub struct SigningKey {
+    /// Key ID.
+    id: String,
+    /// Creation timestamp.
+    creation: DateTime<Utc>,
+    /// Retirement timestamp, if a newer key has replaced it.
+    retirement: Option<DateTime<Utc>>,
+    /// PKCS#8 encoded Ed25519 key pair, encrypted with the data key.
+    private_key: Vec<u8>,
+    /// Ed25519 public key.
+    public_key: Vec<u8>,
+    /// Data key of the private key, encrypted with the master key.
+    ///
+    /// Keys stored before encryption was introduced don't have a data key, and they are stored
+    /// in plain text until they get re-encrypted.
+    secret_key: Option<Vec<u8>>,
+    /// ID of the master key that encrypts the data key.
+    master_key_id: Option<String>,
+}
+
+impl SigningKey {
+    /// Gets the key ID.
+    pub fn id(&self) -> &str {
+        &self.id
+    }
+
+    /// Gets the creation timestamp.
+    pub fn creation(&self) -> DateTime<Utc> {
+        self.creation
+    }
+
+    /// Gets the retirement timestamp, if a newer key has replaced it.
+    pub fn retirement(&self) -> Option<DateTime<Utc>> {
+        self.retirement
+    }
+
+    /// Gets the decrypted PKCS#8 encoded key pair.
+    pub fn private_key(&self) -> Result<Vec<u8>, Error> {
+        match (self.master_key_id.as_ref(), self.secret_key.as_ref()) {
+            (Some(master_key_id), Some(secret_key)) => {
+                DataKey::unwrap(master_key_id, secret_key, &key_context(&self.id, "secret_key"))?
+                    .decrypt(&self.private_key, &key_context(&self.id, "private_key"))
+            }
+            // Keys stored before encryption was introduced.
+            (None, None) => Ok(self.private_key.clone()),
+            _ => bail!("the private key of signing key {} has an incomplete data key", self.id),
+        }
+    }
+
+    /// Gets the public key.
+    pub fn public_key(&self) -> &[u8] {
+        &self.public_key
+    }
+
+    /// Gets the ID of the master key that encrypts the private key, if it's encrypted.
+    pub fn master_key_id(&self) -> Option<&str> {
+        self.master_key_id.as_ref().map(String::as_str)
+    }
+}
+
+/// Gets the context the private key of a signing key is encrypted with, binding it to the key
+/// and the column it's stored in.
+fn key_context(key_id: &str, column: &str) -> String {
+    format!("oauth_signing_keys.{}:{}", column, key_id)
+}
+
+/// Structure to create a new signing key.
+#[derive(Debug, Insertable)]
+#[table_name = "oauth_signing_keys"]
+pub struct NewSigningKey {
+    /// Key ID.
+    id: String,
+    /// PKCS#8 encoded Ed25519 key pair, encrypted with the data key.
+    private_key: Vec<u8>,
+    /// Ed25519 public key.
+    public_key: Vec<u8>,
+    /// Data key of the private key, encrypted with the master key.
+    secret_key: Vec<u8>,
+    /// ID of the master key that encrypts the data key.
+    master_key_id: String,
+}
+
+impl NewSigningKey {
+    /// Creates a new signing key structure, encrypting the private key with a new data key.
+    pub fn new(
+        id: String,
+        private_key: &[u8],
+        public_key: Vec<u8>,
+    ) -> Result<NewSigningKey, Error> {
+        let secret = SigningKeySecret::new(&id, private_key)?;
+        Ok(NewSigningKey {
+            id,
+            private_key: secret.private_key,
+            public_key,
+            secret_key: secret.secret_key,
+            master_key_id: secret.master_key_id,
+        })
+    }
+}
+
+/// Encrypted private key of a signing key, to re-encrypt it.
+#[derive(Debug, AsChangeset)]
+#[table_name = "oauth_signing_keys"]
+pub struct SigningKeySecret {
+    /// PKCS#8 encoded Ed25519 key pair, encrypted with the data key.
+    private_key: Vec<u8>,
+    /// Data key of the private key, encrypted with the master key.
+    secret_key: Vec<u8>,
+    /// ID of the master key that encrypts the data key.
+    master_key_id: String,
+}
+
+impl SigningKeySecret {
+    /// Encrypts the given private key of the signing key with a new data key.
+    pub fn new(key_id: &str, private_key: &[u8]) -> Result<SigningKeySecret, Error> {
+        let data_key = DataKey::generate()?;
+        let (master_key_id, secret_key) = data_key.wrap(&key_context(key_id, "secret_key"))?;
+
+        Ok(SigningKeySecret {
+            private_key: data_key.encrypt(private_key, &key_context(key_id, "private_key"))?,
+            secret_key,
+            master_key_id,
+        })
+    }
+}
@@ -1,3 +1,6 @@
 //! Database models.
 
+pub mod audit;
+pub mod jwt;
 pub mod oauth;
+pub mod user;
@@ -2,8 +2,12 @@
 
 use uuid::Uuid;
 use chrono::{DateTime, Utc};
+use failure::Error;
 
-use super::super::schema::oauth_apps;
+use crypto::{self, DataKey};
+
+use super::super::schema::{oauth_access_tokens, oauth_apps, oauth_redirect_uris,
+                           oauth_refresh_tokens};
 
 /// OAuth application.
 #[derive(Debug, Queryable, Identifiable)]
@@ -23,12 +27,27 @@ pub struct Application {
     description: String,
     /// Optional URL of the application.
     url: Option<String>,
-    /// Application's secret.
+    /// Application's secret, encrypted with the data key.
     api_secret: Vec<u8>,
     /// Hourly request limit.
     hourly_limit: i32,
     /// Manager ID.
     manager: i32,
+    /// Wether the application is a first party application or not.
+    first_party: bool,
+    /// Scopes the application can request for itself with the client credentials grant.
+    client_scopes: Vec<String>,
+    /// Application's secret before the last rotation, encrypted with the data key.
+    previous_api_secret: Option<Vec<u8>>,
+    /// Time until which the previous secret is still accepted.
+    previous_secret_expiration: Option<DateTime<Utc>>,
+    /// Data key of the secrets, encrypted with the master key.
+    ///
+    /// Secrets stored before encryption was introduced don't have a data key, and they are
+    /// stored in plain text until they get re-encrypted.
+    secret_key: Option<Vec<u8>>,
+    /// ID of the master key that encrypts the data key.
+    master_key_id: Option<String>,
 }
 
 impl Application {
@@ -71,9 +90,55 @@ impl Application {
         }
     }
 
-    /// Gets the API secret.
-    pub fn api_secret(&self) -> &[u8] {
-        &self.api_secret
+    /// Gets the decrypted API secret.
+    pub fn api_secret(&self) -> Result<Vec<u8>, Error> {
+        self.decrypt(&self.api_secret, "api_secret")
+    }
+
+    /// Gets the decrypted API secret before the last rotation, if there is one.
+    pub fn previous_api_secret(&self) -> Result<Option<Vec<u8>>, Error> {
+        match self.previous_api_secret {
+            Some(ref secret) => Ok(Some(self.decrypt(secret, "previous_api_secret")?)),
+            None => Ok(None),
+        }
+    }
+
+    /// Gets the decrypted secrets that are currently accepted to sign requests.
+    ///
+    /// After a rotation, the previous secret is accepted along with the current one until it
+    /// expires.
+    pub fn valid_api_secrets(&self) -> Result<Vec<Vec<u8>>, Error> {
+        let mut secrets = vec![self.api_secret()?];
+        match self.previous_secret_expiration {
+            Some(expiration) if expiration > Utc::now() => {
+                secrets.extend(self.previous_api_secret()?);
+            }
+            _ => {}
+        }
+        Ok(secrets)
+    }
+
+    /// Gets the ID of the master key that encrypts the secrets, if they are encrypted.
+    pub fn master_key_id(&self) -> Option<&str> {
+        self.master_key_id.as_ref().map(String::as_str)
+    }
+
+    /// Decrypts the secret of the application stored in the given column.
+    fn decrypt(&self, secret: &[u8], column: &str) -> Result<Vec<u8>, Error> {
+        match (self.master_key_id.as_ref(), self.secret_key.as_ref()) {
+            (Some(master_key_id), Some(secret_key)) => {
+                DataKey::unwrap(master_key_id, secret_key, &secret_context(self.id, "secret_key"))?
+                    .decrypt(secret, &secret_context(self.id, column))
+            }
+            // Secrets stored before encryption was introduced.
+            (None, None) => Ok(secret.to_vec()),
+            _ => bail!("the secrets of application {} have an incomplete data key", self.id),
+        }
+    }
+
+    /// Gets the time until which the previous secret is still accepted, if there is one.
+    pub fn previous_secret_expiration(&self) -> Option<DateTime<Utc>> {
+        self.previous_secret_expiration
     }
 
     /// Gets the hourly request limit.
@@ -86,22 +151,518 @@ impl Application {
     pub fn manager_id(&self) -> i32 {
         self.manager
     }
+
+    /// Gets wether the application is a first party application or not.
+    ///
+    /// Only first party applications can authenticate users with their password.
+    pub fn is_first_party(&self) -> bool {
+        self.first_party
+    }
+
+    /// Gets the scopes the application can request for itself with the client credentials grant.
+    pub fn client_scopes(&self) -> &[String] {
+        &self.client_scopes
+    }
+}
+
+/// Gets the context the secrets of an application are encrypted with, binding them to the
+/// application and the column they are stored in.
+fn secret_context(app_id: Uuid, column: &str) -> String {
+    format!("oauth_apps.{}:{}", column, app_id)
 }
 
 /// Structure to create a new applicaation.
 #[derive(Debug, Insertable)]
 #[table_name = "oauth_apps"]
 pub struct NewApplication {
+    /// Application ID, generated beforehand to encrypt the secret for it.
+    id: Uuid,
+    /// Wether the application is active or not.
+    active: bool,
     /// Application name.
     name: String,
     /// Application description.
     description: String,
     /// Optional URL of the application.
     url: Option<String>,
-    /// Application's secret.
+    /// Application's secret, encrypted with the data key.
     api_secret: Vec<u8>,
     /// Hourly request limit.
     hourly_limit: i32,
     /// Manager ID.
     manager: i32,
+    /// Scopes the application can request for itself with the client credentials grant.
+    client_scopes: Vec<String>,
+    /// Data key of the secret, encrypted with the master key.
+    secret_key: Vec<u8>,
+    /// ID of the master key that encrypts the data key.
+    master_key_id: String,
+}
+
+impl NewApplication {
+    /// Creates a new active application structure, managed by the given user.
+    ///
+    /// The API secret gets encrypted with a new data key.
+    pub fn new(
+        name: String,
+        description: String,
+        url: Option<String>,
+        api_secret: &[u8],
+        hourly_limit: i32,
+        manager: i32,
+        client_scopes: Vec<String>,
+    ) -> Result<NewApplication, Error> {
+        let id = crypto::random_uuid()?;
+        let data_key = DataKey::generate()?;
+        let (master_key_id, secret_key) = data_key.wrap(&secret_context(id, "secret_key"))?;
+
+        Ok(NewApplication {
+            id,
+            active: true,
+            name,
+            description,
+            url,
+            api_secret: data_key.encrypt(api_secret, &secret_context(id, "api_secret"))?,
+            hourly_limit,
+            manager,
+            client_scopes,
+            secret_key,
+            master_key_id,
+        })
+    }
+}
+
+/// Encrypted secrets of an application, to replace the current ones.
+#[derive(Debug, AsChangeset)]
+#[table_name = "oauth_apps"]
+#[changeset_options(treat_none_as_null = "true")]
+pub struct ApplicationSecrets {
+    /// Application's secret, encrypted with the data key.
+    api_secret: Vec<u8>,
+    /// Application's previous secret, encrypted with the data key.
+    previous_api_secret: Option<Vec<u8>>,
+    /// Time until which the previous secret is still accepted.
+    previous_secret_expiration: Option<DateTime<Utc>>,
+    /// Data key of the secrets, encrypted with the master key.
+    secret_key: Vec<u8>,
+    /// ID of the master key that encrypts the data key.
+    master_key_id: String,
+}
+
+impl ApplicationSecrets {
+    /// Encrypts the given secrets of the application with a new data key.
+    pub fn new(
+        app_id: Uuid,
+        api_secret: &[u8],
+        previous_api_secret: Option<&[u8]>,
+        previous_secret_expiration: Option<DateTime<Utc>>,
+    ) -> Result<ApplicationSecrets, Error> {
+        let data_key = DataKey::generate()?;
+        let (master_key_id, secret_key) = data_key.wrap(&secret_context(app_id, "secret_key"))?;
+        let previous_api_secret = match previous_api_secret {
+            Some(secret) => Some(
+                data_key.encrypt(secret, &secret_context(app_id, "previous_api_secret"))?,
+            ),
+            None => None,
+        };
+
+        Ok(ApplicationSecrets {
+            api_secret: data_key.encrypt(api_secret, &secret_context(app_id, "api_secret"))?,
+            previous_api_secret,
+            previous_secret_expiration,
+            secret_key,
+            master_key_id,
+        })
+    }
+}
+
+/// Changes to the editable fields of an application.
+///
+/// Fields set to `None` are left untouched. The URL can be removed by setting it to `Some(None)`.
+#[derive(Debug, AsChangeset)]
+#[table_name = "oauth_apps"]
+pub struct ApplicationChanges {
+    /// New application name.
+    name: Option<String>,
+    /// New application description.
+    description: Option<String>,
+    /// New URL of the application.
+    url: Option<Option<String>>,
+    /// New hourly request limit.
+    hourly_limit: Option<i32>,
+    /// New scopes the application can request for itself.
+    client_scopes: Option<Vec<String>>,
+}
+
+impl ApplicationChanges {
+    /// Creates a new application changes structure.
+    pub fn new(
+        name: Option<String>,
+        description: Option<String>,
+        url: Option<Option<String>>,
+        hourly_limit: Option<i32>,
+        client_scopes: Option<Vec<String>>,
+    ) -> ApplicationChanges {
+        ApplicationChanges {
+            name,
+            description,
+            url,
+            hourly_limit,
+            client_scopes,
+        }
+    }
+}
+
+/// Redirect URI of an application, to insert it in the database.
+#[derive(Debug, Insertable)]
+#[table_name = "oauth_redirect_uris"]
+pub struct NewRedirectUri<'a> {
+    /// ID of the application.
+    app_id: Uuid,
+    /// Registered URI.
+    uri: &'a str,
+}
+
+impl<'a> NewRedirectUri<'a> {
+    /// Creates a new redirect URI structure.
+    pub fn new(app_id: Uuid, uri: &'a str) -> NewRedirectUri<'a> {
+        NewRedirectUri { app_id, uri }
+    }
+}
+
+/// OAuth refresh token.
+#[derive(Debug, Queryable, Identifiable)]
+#[table_name = "oauth_refresh_tokens"]
+pub struct RefreshToken {
+    /// Refresh token ID.
+    id: i32,
+    /// SHA-256 hash of the token.
+    token_hash: Vec<u8>,
+    /// Creation timestamp.
+    creation: DateTime<Utc>,
+    /// Expiration timestamp.
+    expiration: DateTime<Utc>,
+    /// ID of the user the token was issued to.
+    user_id: i32,
+    /// ID of the application the token was issued for.
+    app_id: Uuid,
+    /// Scopes granted to the token.
+    scopes: Vec<String>,
+    /// ID of the family of the token.
+    family: Uuid,
+    /// Rotation timestamp, if the token has been replaced by a new one.
+    rotated: Option<DateTime<Utc>>,
+    /// Creation timestamp of the first token of the family.
+    session_creation: DateTime<Utc>,
+    /// User agent of the last request that used the session.
+    user_agent: Option<String>,
+}
+
+impl RefreshToken {
+    /// Gets the refresh token ID.
+    pub fn id(&self) -> i32 {
+        self.id
+    }
+
+    /// Gets the SHA-256 hash of the token.
+    pub fn token_hash(&self) -> &[u8] {
+        &self.token_hash
+    }
+
+    /// Gets the creation timestamp.
+    pub fn creation(&self) -> DateTime<Utc> {
+        self.creation
+    }
+
+    /// Gets the expiration timestamp.
+    pub fn expiration(&self) -> DateTime<Utc> {
+        self.expiration
+    }
+
+    /// Gets the ID of the user the token was issued to.
+    pub fn user_id(&self) -> i32 {
+        self.user_id
+    }
+
+    /// Gets the ID of the application the token was issued for.
+    pub fn app_id(&self) -> Uuid {
+        self.app_id
+    }
+
+    /// Gets the scopes granted to the token.
+    pub fn scopes(&self) -> &[String] {
+        &self.scopes
+    }
+
+    /// Gets the ID of the family of the token.
+    ///
+    /// All the refresh tokens that come from the same login, through rotations, belong to the
+    /// same family.
+    pub fn family(&self) -> Uuid {
+        self.family
+    }
+
+    /// Gets the rotation timestamp, if the token has been replaced by a new one.
+    pub fn rotated(&self) -> Option<DateTime<Utc>> {
+        self.rotated
+    }
+
+    /// Checks if the token has been replaced by a new one, and cannot be used anymore.
+    pub fn is_rotated(&self) -> bool {
+        self.rotated.is_some()
+    }
+
+    /// Checks if the token can still be used at the given time: it has neither expired nor been
+    /// rotated.
+    pub fn is_active(&self, now: DateTime<Utc>) -> bool {
+        self.expiration > now && !self.is_rotated()
+    }
+
+    /// Gets the creation timestamp of the session, the one of the first token of the family.
+    pub fn session_creation(&self) -> DateTime<Utc> {
+        self.session_creation
+    }
+
+    /// Gets the user agent of the last request that used the session, if it was sent.
+    pub fn user_agent(&self) -> Option<&str> {
+        self.user_agent.as_ref().map(String::as_str)
+    }
+}
+
+/// Structure to create a new refresh token.
+#[derive(Debug, Insertable)]
+#[table_name = "oauth_refresh_tokens"]
+pub struct NewRefreshToken<'a> {
+    /// SHA-256 hash of the token.
+    token_hash: &'a [u8],
+    /// Expiration timestamp.
+    expiration: DateTime<Utc>,
+    /// ID of the user the token is issued to.
+    user_id: i32,
+    /// ID of the application the token is issued for.
+    app_id: Uuid,
+    /// Scopes granted to the token.
+    scopes: Vec<String>,
+    /// ID of the family of the token, a new one if not present.
+    family: Option<Uuid>,
+    /// Creation timestamp of the session, the current time if not present.
+    session_creation: Option<DateTime<Utc>>,
+    /// User agent of the request.
+    user_agent: Option<&'a str>,
+}
+
+impl<'a> NewRefreshToken<'a> {
+    /// Creates a new refresh token structure, issued after a login.
+    ///
+    /// The token starts a new family, a new session of the user.
+    pub fn new(
+        token_hash: &'a [u8],
+        expiration: DateTime<Utc>,
+        user_id: i32,
+        app_id: Uuid,
+        scopes: Vec<String>,
+        user_agent: Option<&'a str>,
+    ) -> NewRefreshToken<'a> {
+        NewRefreshToken {
+            token_hash,
+            expiration,
+            user_id,
+            app_id,
+            scopes,
+            family: None,
+            session_creation: None,
+            user_agent,
+        }
+    }
+
+    /// Creates a new refresh token structure, to replace the given token after rotating it.
+    ///
+    /// The token belongs to the same family and session, with the same scopes.
+    pub fn rotation(
+        token_hash: &'a [u8],
+        expiration: DateTime<Utc>,
+        previous: &RefreshToken,
+        user_agent: Option<&'a str>,
+    ) -> NewRefreshToken<'a> {
+        NewRefreshToken {
+            token_hash,
+            expiration,
+            user_id: previous.user_id(),
+            app_id: previous.app_id(),
+            scopes: previous.scopes().to_vec(),
+            family: Some(previous.family()),
+            session_creation: Some(previous.session_creation()),
+            user_agent,
+        }
+    }
+}
+
+/// OAuth access token.
+#[derive(Debug, Queryable, Identifiable)]
+#[table_name = "oauth_access_tokens"]
+pub struct AccessToken {
+    /// Access token ID.
+    id: i32,
+    /// SHA-256 hash of the token.
+    token_hash: Vec<u8>,
+    /// Creation timestamp.
+    creation: DateTime<Utc>,
+    /// Expiration timestamp.
+    expiration: DateTime<Utc>,
+    /// ID of the refresh token this access token was derived from, if any.
+    refresh_token: Option<i32>,
+    /// ID of the user the token was issued to, if any.
+    user_id: Option<i32>,
+    /// ID of the application the token was issued for.
+    app_id: Uuid,
+    /// Scopes of the token.
+    scopes: Vec<String>,
+}
+
+impl AccessToken {
+    /// Gets the access token ID.
+    pub fn id(&self) -> i32 {
+        self.id
+    }
+
+    /// Gets the SHA-256 hash of the token.
+    pub fn token_hash(&self) -> &[u8] {
+        &self.token_hash
+    }
+
+    /// Gets the creation timestamp.
+    pub fn creation(&self) -> DateTime<Utc> {
+        self.creation
+    }
+
+    /// Gets the expiration timestamp.
+    pub fn expiration(&self) -> DateTime<Utc> {
+        self.expiration
+    }
+
+    /// Gets the ID of the refresh token this access token was derived from.
+    ///
+    /// Tokens issued with the client credentials grant don't have a refresh token.
+    pub fn refresh_token_id(&self) -> Option<i32> {
+        self.refresh_token
+    }
+
+    /// Gets the ID of the user the token was issued to.
+    ///
+    /// Tokens issued with the client credentials grant don't have a user, they represent the
+    /// application itself.
+    pub fn user_id(&self) -> Option<i32> {
+        self.user_id
+    }
+
+    /// Gets the ID of the application the token was issued for.
+    pub fn app_id(&self) -> Uuid {
+        self.app_id
+    }
+
+    /// Gets the scopes of the token.
+    pub fn scopes(&self) -> &[String] {
+        &self.scopes
+    }
+}
+
+/// Structure to create a new access token.
+#[derive(Debug, Insertable)]
+#[table_name = "oauth_access_tokens"]
+pub struct NewAccessToken<'a> {
+    /// SHA-256 hash of the token.
+    token_hash: &'a [u8],
+    /// Expiration timestamp.
+    expiration: DateTime<Utc>,
+    /// ID of the refresh token this access token is derived from, if any.
+    refresh_token: Option<i32>,
+    /// ID of the user the token is issued to, if any.
+    user_id: Option<i32>,
+    /// ID of the application the token is issued for.
+    app_id: Uuid,
+    /// Scopes of the token.
+    scopes: Vec<String>,
+}
+
+impl<'a> NewAccessToken<'a> {
+    /// Creates a new access token structure.
+    ///
+    /// The scopes should be a subset of the scopes granted to the refresh token.
+    pub fn new(
+        token_hash: &'a [u8],
+        expiration: DateTime<Utc>,
+        refresh_token: &RefreshToken,
+        scopes: Vec<String>,
+    ) -> NewAccessToken<'a> {
+        NewAccessToken {
+            token_hash,
+            expiration,
+            refresh_token: Some(refresh_token.id()),
+            user_id: Some(refresh_token.user_id()),
+            app_id: refresh_token.app_id(),
+            scopes,
+        }
+    }
+
+    /// Creates a new access token structure for an application, without a user.
+    ///
+    /// The scopes should be a subset of the client scopes of the application.
+    pub fn for_application(
+        token_hash: &'a [u8],
+        expiration: DateTime<Utc>,
+        app_id: Uuid,
+        scopes: Vec<String>,
+    ) -> NewAccessToken<'a> {
+        NewAccessToken {
+            token_hash,
+            expiration,
+            refresh_token: None,
+            user_id: None,
+            app_id,
+            scopes,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::{Duration, Utc};
+    use uuid::Uuid;
+
+    use super::RefreshToken;
+
+    /// Creates a refresh token that expires in one day.
+    fn refresh_token() -> RefreshToken {
+        let now = Utc::now();
+        RefreshToken {
+            id: 1,
+            token_hash: vec![0; 32],
+            creation: now,
+            expiration: now + Duration::days(1),
+            user_id: 1,
+            app_id: Uuid::nil(),
+            scopes: vec!["profile:read".to_owned()],
+            family: Uuid::nil(),
+            rotated: None,
+            session_creation: now,
+            user_agent: None,
+        }
+    }
+
+    #[test]
+    fn rotated_refresh_tokens_are_not_active() {
+        let mut token = refresh_token();
+        assert!(token.is_active(Utc::now()));
+
+        token.rotated = Some(Utc::now());
+        assert!(token.is_rotated());
+        assert!(!token.is_active(Utc::now()));
+    }
+
+    #[test]
+    fn expired_refresh_tokens_are_not_active() {
+        let token = refresh_token();
+        assert!(!token.is_active(token.expiration()));
+        assert!(!token.is_active(Utc::now() + Duration::days(2)));
+    }
 }
@@ -0,0 +1,257 @@
+//! User database models.
+
+use chrono::{DateTime, Utc};
+use failure::Error;
+
+use crypto::DataKey;
+
+use super::super::schema::{user_login_failures, user_recovery_codes, users};
+
+/// User.
+#[derive(Debug, Queryable, Identifiable)]
+#[table_name = "users"]
+pub struct User {
+    /// User ID.
+    id: i32,
+    /// Wether the user is active or not.
+    active: Option<bool>,
+    /// Creation timestamp.
+    creation: DateTime<Utc>,
+    /// Last activity timestamp.
+    last_active: Option<DateTime<Utc>>,
+    /// User email.
+    email: String,
+    /// Username.
+    username: String,
+    /// Encoded password hash.
+    password: Vec<u8>,
+    /// Number of consecutive failed login attempts, from any client address.
+    failed_logins: i32,
+    /// Timestamp of the last failed login attempt.
+    last_failed_login: Option<DateTime<Utc>>,
+    /// TOTP secret, encrypted with the data key, if two-factor authentication is enabled.
+    totp_secret: Option<Vec<u8>>,
+    /// Data key of the TOTP secret, encrypted with the master key.
+    totp_secret_key: Option<Vec<u8>>,
+    /// ID of the master key that encrypts the data key.
+    totp_master_key_id: Option<String>,
+    /// Last TOTP time step used to log in, so that codes cannot be used twice.
+    totp_last_step: Option<i64>,
+}
+
+impl User {
+    /// Gets the user ID.
+    pub fn id(&self) -> i32 {
+        self.id
+    }
+
+    /// Gets wether the user is active or not.
+    pub fn is_active(&self) -> bool {
+        self.active.unwrap_or(false)
+    }
+
+    /// Gets the creation timestamp.
+    pub fn creation(&self) -> DateTime<Utc> {
+        self.creation
+    }
+
+    /// Gets the last activity timestamp, if the user has ever been active.
+    pub fn last_active(&self) -> Option<DateTime<Utc>> {
+        self.last_active
+    }
+
+    /// Gets the user email.
+    pub fn email(&self) -> &str {
+        &self.email
+    }
+
+    /// Gets the username.
+    pub fn username(&self) -> &str {
+        &self.username
+    }
+
+    /// Gets the encoded password hash.
+    pub fn password(&self) -> &[u8] {
+        &self.password
+    }
+
+    /// Gets the number of consecutive failed login attempts, from any client address.
+    pub fn failed_logins(&self) -> i32 {
+        self.failed_logins
+    }
+
+    /// Gets the timestamp of the last failed login attempt, if any.
+    pub fn last_failed_login(&self) -> Option<DateTime<Utc>> {
+        self.last_failed_login
+    }
+
+    /// Checks if the user has enabled two-factor authentication.
+    pub fn has_two_factor(&self) -> bool {
+        self.totp_secret.is_some()
+    }
+
+    /// Gets the decrypted TOTP secret, if two-factor authentication is enabled.
+    pub fn totp_secret(&self) -> Result<Option<Vec<u8>>, Error> {
+        match (
+            self.totp_secret.as_ref(),
+            self.totp_secret_key.as_ref(),
+            self.totp_master_key_id.as_ref(),
+        ) {
+            (Some(secret), Some(secret_key), Some(master_key_id)) => Ok(Some(
+                DataKey::unwrap(master_key_id, secret_key, &totp_context(self.id, "secret_key"))?
+                    .decrypt(secret, &totp_context(self.id, "secret"))?,
+            )),
+            (None, _, _) => Ok(None),
+            _ => bail!("the TOTP secret of user {} has no data key", self.id),
+        }
+    }
+
+    /// Gets the ID of the master key that encrypts the TOTP secret, if there is one.
+    pub fn totp_master_key_id(&self) -> Option<&str> {
+        self.totp_master_key_id.as_ref().map(String::as_str)
+    }
+
+    /// Gets the last TOTP time step used to log in, if any.
+    pub fn totp_last_step(&self) -> Option<i64> {
+        self.totp_last_step
+    }
+}
+
+/// Failed login attempts of a user from a client address.
+#[derive(Debug, Queryable, Identifiable)]
+#[table_name = "user_login_failures"]
+#[primary_key(user_id, ip_address)]
+pub struct LoginFailures {
+    /// User ID.
+    user_id: i32,
+    /// Client IP address, its /64 network for IPv6, or empty if it's unknown.
+    ip_address: String,
+    /// Number of consecutive failed login attempts.
+    failed_logins: i32,
+    /// Timestamp of the last failed login attempt.
+    last_failed_login: DateTime<Utc>,
+    /// Timestamp until which the address is locked out of the account.
+    locked_until: Option<DateTime<Utc>>,
+}
+
+impl LoginFailures {
+    /// Gets the number of consecutive failed login attempts.
+    pub fn failed_logins(&self) -> i32 {
+        self.failed_logins
+    }
+
+    /// Gets the timestamp of the last failed login attempt.
+    pub fn last_failed_login(&self) -> DateTime<Utc> {
+        self.last_failed_login
+    }
+
+    /// Gets the timestamp until which the address is locked out of the account, if it has been
+    /// locked.
+    pub fn locked_until(&self) -> Option<DateTime<Utc>> {
+        self.locked_until
+    }
+}
+
+/// Structure to start counting the failed login attempts of a user from a client address.
+#[derive(Debug, Insertable)]
+#[table_name = "user_login_failures"]
+pub struct NewLoginFailures<'a> {
+    /// User ID.
+    user_id: i32,
+    /// Client IP address, its /64 network for IPv6, or empty if it's unknown.
+    ip_address: &'a str,
+}
+
+impl<'a> NewLoginFailures<'a> {
+    /// Creates a new failed login attempts structure, without any failure.
+    pub fn new(user_id: i32, ip_address: &'a str) -> NewLoginFailures<'a> {
+        NewLoginFailures {
+            user_id,
+            ip_address,
+        }
+    }
+}
+
+/// Structure to create a new user.
+///
+/// New users are not active until they verify their email address.
+#[derive(Debug, Insertable)]
+#[table_name = "users"]
+pub struct NewUser<'a> {
+    /// User email.
+    email: &'a str,
+    /// Username.
+    username: &'a str,
+    /// Encoded password hash.
+    password: Vec<u8>,
+}
+
+impl<'a> NewUser<'a> {
+    /// Creates a new user structure, with the encoded hash of its password.
+    pub fn new(email: &'a str, username: &'a str, password: Vec<u8>) -> NewUser<'a> {
+        NewUser {
+            email,
+            username,
+            password,
+        }
+    }
+}
+
+/// Gets the context the TOTP secret of a user is encrypted with, binding it to the user and the
+/// column it's stored in.
+fn totp_context(user_id: i32, column: &str) -> String {
+    format!("users.totp_{}:{}", column, user_id)
+}
+
+/// Encrypted TOTP secret of a user, to replace the current one.
+#[derive(Debug, AsChangeset)]
+#[table_name = "users"]
+#[changeset_options(treat_none_as_null = "true")]
+pub struct TotpSecret {
+    /// TOTP secret, encrypted with the data key.
+    totp_secret: Option<Vec<u8>>,
+    /// Data key of the secret, encrypted with the master key.
+    totp_secret_key: Option<Vec<u8>>,
+    /// ID of the master key that encrypts the data key.
+    totp_master_key_id: Option<String>,
+}
+
+impl TotpSecret {
+    /// Encrypts the given TOTP secret of the user with a new data key.
+    pub fn new(user_id: i32, secret: &[u8]) -> Result<TotpSecret, Error> {
+        let data_key = DataKey::generate()?;
+        let (master_key_id, secret_key) = data_key.wrap(&totp_context(user_id, "secret_key"))?;
+
+        Ok(TotpSecret {
+            totp_secret: Some(data_key.encrypt(secret, &totp_context(user_id, "secret"))?),
+            totp_secret_key: Some(secret_key),
+            totp_master_key_id: Some(master_key_id),
+        })
+    }
+
+    /// Removes the TOTP secret, disabling two-factor authentication.
+    pub fn none() -> TotpSecret {
+        TotpSecret {
+            totp_secret: None,
+            totp_secret_key: None,
+            totp_master_key_id: None,
+        }
+    }
+}
+
+/// New two-factor recovery code of a user.
+#[derive(Debug, Insertable)]
+#[table_name = "user_recovery_codes"]
+pub struct NewRecoveryCode<'a> {
+    /// User ID.
+    user_id: i32,
+    /// SHA-256 hash of the code.
+    code_hash: &'a [u8],
+}
+
+impl<'a> NewRecoveryCode<'a> {
+    /// Creates a new recovery code structure.
+    pub fn new(user_id: i32, code_hash: &'a [u8]) -> NewRecoveryCode<'a> {
+        NewRecoveryCode { user_id, code_hash }
+    }
+}
@@ -1,10 +1,442 @@
 //! OAuth related database methods.
 
+use chrono::Utc;
 use failure::Error;
+use diesel::{self, select};
+use diesel::dsl::exists;
+use diesel::prelude::*;
+use diesel::Connection as DieselConnection;
+use uuid::Uuid;
 
-use super::models::oauth::Application;
+use super::models::oauth::{AccessToken, Application, ApplicationChanges, ApplicationSecrets,
+                           NewAccessToken, NewApplication, NewRedirectUri, NewRefreshToken,
+                           RefreshToken};
+use super::schema::{oauth_access_tokens, oauth_apps, oauth_redirect_uris, oauth_refresh_tokens};
 use super::Connection;
 
-pub fn get_application(db_con: &Connection, app_id: u64) -> Result<Option<Application>, Error> {
-    unimplemented!()
+/// Gets the active application with the given ID, if it exists.
+pub fn get_application(db_con: &Connection, app_id: Uuid) -> Result<Option<Application>, Error> {
+    Ok(oauth_apps::table
+        .find(app_id)
+        .filter(oauth_apps::active.eq(true))
+        .first(db_con)
+        .optional()?)
+}
+
+/// Gets the active application with the given ID, locking it until the end of the current
+/// transaction.
+pub fn get_application_for_update(
+    db_con: &Connection,
+    app_id: Uuid,
+) -> Result<Option<Application>, Error> {
+    Ok(oauth_apps::table
+        .find(app_id)
+        .filter(oauth_apps::active.eq(true))
+        .for_update()
+        .first(db_con)
+        .optional()?)
+}
+
+/// Stores a new application, with its redirect URIs.
+pub fn insert_application(
+    db_con: &Connection,
+    app: &NewApplication,
+    redirect_uris: &[String],
+) -> Result<Application, Error> {
+    db_con.transaction(|| {
+        let app: Application = diesel::insert_into(oauth_apps::table)
+            .values(app)
+            .get_result(db_con)?;
+        insert_redirect_uris(db_con, app.id(), redirect_uris)?;
+
+        Ok(app)
+    })
+}
+
+/// Gets all the applications managed by the given user, including inactive ones.
+pub fn get_managed_applications(
+    db_con: &Connection,
+    manager_id: i32,
+) -> Result<Vec<Application>, Error> {
+    Ok(oauth_apps::table
+        .filter(oauth_apps::manager.eq(manager_id))
+        .order(oauth_apps::creation.asc())
+        .load(db_con)?)
+}
+
+/// Gets the application with the given ID, if it exists and is managed by the given user.
+pub fn get_managed_application(
+    db_con: &Connection,
+    app_id: Uuid,
+    manager_id: i32,
+) -> Result<Option<Application>, Error> {
+    Ok(oauth_apps::table
+        .find(app_id)
+        .filter(oauth_apps::manager.eq(manager_id))
+        .first(db_con)
+        .optional()?)
+}
+
+/// Updates the application with the given ID, if it's managed by the given user.
+///
+/// The redirect URIs are replaced if new ones are given. Returns the updated application, or
+/// `None` if the user does not manage it.
+pub fn update_application(
+    db_con: &Connection,
+    app_id: Uuid,
+    manager_id: i32,
+    changes: &ApplicationChanges,
+    redirect_uris: Option<&[String]>,
+) -> Result<Option<Application>, Error> {
+    db_con.transaction(|| {
+        let app = diesel::update(
+            oauth_apps::table
+                .find(app_id)
+                .filter(oauth_apps::manager.eq(manager_id)),
+        ).set((changes, oauth_apps::last_update.eq(Utc::now())))
+            .get_result::<Application>(db_con)
+            .optional()?;
+
+        if let (Some(_), Some(redirect_uris)) = (app.as_ref(), redirect_uris) {
+            let _ = diesel::delete(
+                oauth_redirect_uris::table.filter(oauth_redirect_uris::app_id.eq(app_id)),
+            ).execute(db_con)?;
+            insert_redirect_uris(db_con, app_id, redirect_uris)?;
+        }
+
+        Ok(app)
+    })
+}
+
+/// Sets wether the application with the given ID is a first party application.
+///
+/// Returns the updated application, or `None` if it does not exist.
+pub fn update_application_first_party(
+    db_con: &Connection,
+    app_id: Uuid,
+    first_party: bool,
+) -> Result<Option<Application>, Error> {
+    Ok(diesel::update(oauth_apps::table.find(app_id))
+        .set((
+            oauth_apps::first_party.eq(first_party),
+            oauth_apps::last_update.eq(Utc::now()),
+        ))
+        .get_result(db_con)
+        .optional()?)
+}
+
+/// Replaces the encrypted secrets of the application with the given ID.
+///
+/// Returns the updated application, or `None` if it does not exist.
+pub fn update_application_secrets(
+    db_con: &Connection,
+    app_id: Uuid,
+    secrets: &ApplicationSecrets,
+) -> Result<Option<Application>, Error> {
+    Ok(diesel::update(oauth_apps::table.find(app_id))
+        .set((secrets, oauth_apps::last_update.eq(Utc::now())))
+        .get_result(db_con)
+        .optional()?)
+}
+
+/// Gets the applications whose secrets are not encrypted with the given master key.
+pub fn get_applications_to_reencrypt(
+    db_con: &Connection,
+    master_key_id: &str,
+) -> Result<Vec<Application>, Error> {
+    Ok(oauth_apps::table
+        .filter(
+            oauth_apps::master_key_id
+                .is_null()
+                .or(oauth_apps::master_key_id.ne(master_key_id)),
+        )
+        .load(db_con)?)
+}
+
+/// Deactivates the application with the given ID, if it's managed by the given user.
+///
+/// All the tokens issued for the application get revoked. The hashes of the revoked access
+/// tokens are returned, so that they can be removed from the cache, or `None` if the user does
+/// not manage the application.
+pub fn deactivate_application(
+    db_con: &Connection,
+    app_id: Uuid,
+    manager_id: i32,
+) -> Result<Option<Vec<Vec<u8>>>, Error> {
+    db_con.transaction(|| {
+        let updated = diesel::update(
+            oauth_apps::table
+                .find(app_id)
+                .filter(oauth_apps::manager.eq(manager_id)),
+        ).set((
+            oauth_apps::active.eq(false),
+            oauth_apps::last_update.eq(Utc::now()),
+        ))
+            .execute(db_con)?;
+        if updated == 0 {
+            return Ok(None);
+        }
+
+        let access_token_hashes = diesel::delete(
+            oauth_access_tokens::table.filter(oauth_access_tokens::app_id.eq(app_id)),
+        ).returning(oauth_access_tokens::token_hash)
+            .get_results(db_con)?;
+        let _ = diesel::delete(
+            oauth_refresh_tokens::table.filter(oauth_refresh_tokens::app_id.eq(app_id)),
+        ).execute(db_con)?;
+
+        Ok(Some(access_token_hashes))
+    })
+}
+
+/// Stores the given redirect URIs of an application.
+fn insert_redirect_uris(
+    db_con: &Connection,
+    app_id: Uuid,
+    redirect_uris: &[String],
+) -> Result<(), Error> {
+    let redirect_uris = redirect_uris
+        .iter()
+        .map(|uri| NewRedirectUri::new(app_id, uri))
+        .collect::<Vec<_>>();
+    let _ = diesel::insert_into(oauth_redirect_uris::table)
+        .values(&redirect_uris)
+        .execute(db_con)?;
+
+    Ok(())
+}
+
+/// Gets the redirect URIs registered for the given applications, as `(app_id, uri)` pairs.
+pub fn get_redirect_uris(
+    db_con: &Connection,
+    app_ids: &[Uuid],
+) -> Result<Vec<(Uuid, String)>, Error> {
+    Ok(oauth_redirect_uris::table
+        .select((oauth_redirect_uris::app_id, oauth_redirect_uris::uri))
+        .filter(oauth_redirect_uris::app_id.eq_any(app_ids))
+        .order(oauth_redirect_uris::id.asc())
+        .load(db_con)?)
+}
+
+/// Checks if the given redirect URI is registered for the given application.
+pub fn is_redirect_uri_registered(
+    db_con: &Connection,
+    app_id: Uuid,
+    uri: &str,
+) -> Result<bool, Error> {
+    Ok(select(exists(
+        oauth_redirect_uris::table
+            .filter(oauth_redirect_uris::app_id.eq(app_id))
+            .filter(oauth_redirect_uris::uri.eq(uri)),
+    )).get_result(db_con)?)
+}
+
+/// Stores a new refresh token.
+pub fn insert_refresh_token(
+    db_con: &Connection,
+    token: &NewRefreshToken,
+) -> Result<RefreshToken, Error> {
+    Ok(diesel::insert_into(oauth_refresh_tokens::table)
+        .values(token)
+        .get_result(db_con)?)
+}
+
+/// Stores a new access token.
+pub fn insert_access_token(
+    db_con: &Connection,
+    token: &NewAccessToken,
+) -> Result<AccessToken, Error> {
+    Ok(diesel::insert_into(oauth_access_tokens::table)
+        .values(token)
+        .get_result(db_con)?)
+}
+
+/// Gets the refresh token with the given hash, if it exists.
+pub fn get_refresh_token(
+    db_con: &Connection,
+    token_hash: &[u8],
+) -> Result<Option<RefreshToken>, Error> {
+    Ok(oauth_refresh_tokens::table
+        .filter(oauth_refresh_tokens::token_hash.eq(token_hash))
+        .first(db_con)
+        .optional()?)
+}
+
+/// Gets the access token with the given hash, if it exists.
+pub fn get_access_token(
+    db_con: &Connection,
+    token_hash: &[u8],
+) -> Result<Option<AccessToken>, Error> {
+    Ok(oauth_access_tokens::table
+        .filter(oauth_access_tokens::token_hash.eq(token_hash))
+        .first(db_con)
+        .optional()?)
+}
+
+/// Marks the refresh token with the given ID as rotated, and stores the token that replaces it.
+///
+/// This is done atomically, so that a token can only be rotated once, even with concurrent
+/// requests. Returns `None` if the token had already been rotated.
+///
+/// The rotated tokens of the user that have expired are deleted at the same time, they are no
+/// longer needed to detect reuse since they would be rejected anyway.
+pub fn rotate_refresh_token(
+    db_con: &Connection,
+    token_id: i32,
+    new_token: &NewRefreshToken,
+) -> Result<Option<RefreshToken>, Error> {
+    db_con.transaction(|| {
+        let now = Utc::now();
+        let user_id = match diesel::update(
+            oauth_refresh_tokens::table
+                .find(token_id)
+                .filter(oauth_refresh_tokens::rotated.is_null()),
+        ).set(oauth_refresh_tokens::rotated.eq(now))
+            .returning(oauth_refresh_tokens::user_id)
+            .get_result::<i32>(db_con)
+            .optional()?
+        {
+            Some(user_id) => user_id,
+            None => return Ok(None),
+        };
+
+        let _ = diesel::delete(
+            oauth_refresh_tokens::table
+                .filter(oauth_refresh_tokens::user_id.eq(user_id))
+                .filter(oauth_refresh_tokens::rotated.is_not_null())
+                .filter(oauth_refresh_tokens::expiration.lt(now)),
+        ).execute(db_con)?;
+
+        Ok(Some(diesel::insert_into(oauth_refresh_tokens::table)
+            .values(new_token)
+            .get_result(db_con)?))
+    })
+}
+
+/// Revokes the refresh token with the given hash, if it was issued for the given application.
+///
+/// The whole family of the token gets revoked, with all the access tokens derived from it. The
+/// hashes of the revoked access tokens are returned, so that they can be removed from the cache.
+pub fn revoke_refresh_token(
+    db_con: &Connection,
+    token_hash: &[u8],
+    app_id: Uuid,
+) -> Result<Option<Vec<Vec<u8>>>, Error> {
+    db_con.transaction(|| {
+        match oauth_refresh_tokens::table
+            .select(oauth_refresh_tokens::family)
+            .filter(oauth_refresh_tokens::token_hash.eq(token_hash))
+            .filter(oauth_refresh_tokens::app_id.eq(app_id))
+            .first::<Uuid>(db_con)
+            .optional()?
+        {
+            Some(family) => Ok(Some(revoke_refresh_token_family(db_con, family)?)),
+            None => Ok(None),
+        }
+    })
+}
+
+/// Revokes all the refresh tokens of a family, with all the access tokens derived from them.
+///
+/// The hashes of the revoked access tokens are returned, so that they can be removed from the
+/// cache.
+pub fn revoke_refresh_token_family(
+    db_con: &Connection,
+    family: Uuid,
+) -> Result<Vec<Vec<u8>>, Error> {
+    db_con.transaction(|| {
+        let refresh_token_ids = oauth_refresh_tokens::table
+            .select(oauth_refresh_tokens::id.nullable())
+            .filter(oauth_refresh_tokens::family.eq(family));
+
+        let access_token_hashes = oauth_access_tokens::table
+            .select(oauth_access_tokens::token_hash)
+            .filter(oauth_access_tokens::refresh_token.eq_any(refresh_token_ids))
+            .load(db_con)?;
+
+        // Access tokens are removed in cascade.
+        let _ = diesel::delete(
+            oauth_refresh_tokens::table.filter(oauth_refresh_tokens::family.eq(family)),
+        ).execute(db_con)?;
+
+        Ok(access_token_hashes)
+    })
+}
+
+/// Gets the current sessions of a user, newest first, with the name of their application.
+///
+/// Each session is the current refresh token of a family, the one that has not been rotated.
+pub fn get_user_sessions(
+    db_con: &Connection,
+    user_id: i32,
+) -> Result<Vec<(RefreshToken, String)>, Error> {
+    Ok(oauth_refresh_tokens::table
+        .inner_join(oauth_apps::table)
+        .select((oauth_refresh_tokens::all_columns, oauth_apps::name))
+        .filter(oauth_refresh_tokens::user_id.eq(user_id))
+        .filter(oauth_refresh_tokens::rotated.is_null())
+        .filter(oauth_refresh_tokens::expiration.gt(Utc::now()))
+        .order(oauth_refresh_tokens::session_creation.desc())
+        .load(db_con)?)
+}
+
+/// Revokes a session of a user, the refresh token family with the given ID.
+///
+/// The hashes of the revoked access tokens are returned, so that they can be removed from the
+/// cache, or `None` if the user does not have the session.
+pub fn revoke_user_session(
+    db_con: &Connection,
+    user_id: i32,
+    family: Uuid,
+) -> Result<Option<Vec<Vec<u8>>>, Error> {
+    db_con.transaction(|| {
+        let found: bool = select(exists(
+            oauth_refresh_tokens::table
+                .filter(oauth_refresh_tokens::family.eq(family))
+                .filter(oauth_refresh_tokens::user_id.eq(user_id)),
+        )).get_result(db_con)?;
+        if found {
+            Ok(Some(revoke_refresh_token_family(db_con, family)?))
+        } else {
+            Ok(None)
+        }
+    })
+}
+
+/// Revokes all the sessions of a user, with all their access tokens.
+///
+/// Access tokens issued to applications with the client credentials grant are not affected. The
+/// hashes of the revoked access tokens are returned, so that they can be removed from the cache.
+pub fn revoke_user_sessions(db_con: &Connection, user_id: i32) -> Result<Vec<Vec<u8>>, Error> {
+    db_con.transaction(|| {
+        let access_token_hashes = oauth_access_tokens::table
+            .select(oauth_access_tokens::token_hash)
+            .filter(oauth_access_tokens::user_id.eq(user_id))
+            .filter(oauth_access_tokens::refresh_token.is_not_null())
+            .load(db_con)?;
+
+        // Access tokens are removed in cascade.
+        let _ = diesel::delete(
+            oauth_refresh_tokens::table.filter(oauth_refresh_tokens::user_id.eq(user_id)),
+        ).execute(db_con)?;
+
+        Ok(access_token_hashes)
+    })
+}
+
+/// Revokes the access token with the given hash, if it was issued for the given application.
+///
+/// Returns wether the token was revoked or not.
+pub fn revoke_access_token(
+    db_con: &Connection,
+    token_hash: &[u8],
+    app_id: Uuid,
+) -> Result<bool, Error> {
+    let deleted = diesel::delete(
+        oauth_access_tokens::table
+            .filter(oauth_access_tokens::token_hash.eq(token_hash))
+            .filter(oauth_access_tokens::app_id.eq(app_id)),
+    ).execute(db_con)?;
+
+    Ok(deleted > 0)
 }
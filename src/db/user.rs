@@ -0,0 +1,391 @@
+//! User related database methods.
+
+use chrono::{DateTime, Duration, Utc};
+use failure::Error;
+use diesel::{self, select};
+use diesel::dsl::exists;
+use diesel::prelude::*;
+use diesel::result::{DatabaseErrorKind, Error as DieselError};
+use diesel::Connection as DieselConnection;
+
+use super::models::user::{LoginFailures, NewLoginFailures, NewRecoveryCode, NewUser, TotpSecret,
+                          User};
+use super::schema::{user_login_failures, user_recovery_codes, users};
+use super::Connection;
+use self::functions::lower;
+
+/// SQL functions used in the queries.
+mod functions {
+    use diesel::sql_types::Text;
+
+    sql_function!(lower, lower_t, (x: Text) -> Text);
+}
+
+/// Name of the unique constraint of the email addresses.
+const EMAIL_CONSTRAINT: &str = "users_email_key";
+/// Name of the unique index of the lowercase usernames.
+const USERNAME_CONSTRAINT: &str = "users_username_lower_key";
+
+/// Field of a new user that is already taken by another user.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum UserConflict {
+    /// The username, regardless of its case.
+    Username,
+    /// The email address.
+    Email,
+}
+
+/// Gets the user with the given ID, if it exists.
+pub fn get_user(db_con: &Connection, user_id: i32) -> Result<Option<User>, Error> {
+    Ok(users::table.find(user_id).first(db_con).optional()?)
+}
+
+/// Gets the user with the given username, if it exists, regardless of its case.
+pub fn get_user_by_username(db_con: &Connection, username: &str) -> Result<Option<User>, Error> {
+    Ok(users::table
+        .filter(lower(users::username).eq(username.to_lowercase()))
+        .first(db_con)
+        .optional()?)
+}
+
+/// Gets the user with the given email, if it exists.
+pub fn get_user_by_email(db_con: &Connection, email: &str) -> Result<Option<User>, Error> {
+    Ok(users::table
+        .filter(users::email.eq(email))
+        .first(db_con)
+        .optional()?)
+}
+
+/// Checks if a user with the given username exists, regardless of its case.
+pub fn username_exists(db_con: &Connection, username: &str) -> Result<bool, Error> {
+    Ok(select(exists(
+        users::table.filter(lower(users::username).eq(username.to_lowercase())),
+    )).get_result(db_con)?)
+}
+
+/// Checks if a user with the given email exists.
+pub fn email_exists(db_con: &Connection, email: &str) -> Result<bool, Error> {
+    Ok(select(exists(users::table.filter(users::email.eq(email)))).get_result(db_con)?)
+}
+
+/// Stores a new user.
+///
+/// Returns the conflicting field if the username or the email address are already taken, even
+/// if a concurrent registration took them after checking it.
+pub fn insert_user(
+    db_con: &Connection,
+    user: &NewUser,
+) -> Result<Result<User, UserConflict>, Error> {
+    match diesel::insert_into(users::table)
+        .values(user)
+        .get_result(db_con)
+    {
+        Ok(user) => Ok(Ok(user)),
+        Err(e) => {
+            if let DieselError::DatabaseError(DatabaseErrorKind::UniqueViolation, ref info) = e {
+                match info.constraint_name() {
+                    Some(EMAIL_CONSTRAINT) => return Ok(Err(UserConflict::Email)),
+                    Some(USERNAME_CONSTRAINT) => return Ok(Err(UserConflict::Username)),
+                    _ => {}
+                }
+            }
+            Err(e.into())
+        }
+    }
+}
+
+/// Deletes the user with the given ID, if it has not been activated yet.
+///
+/// Returns `false` if the user does not exist or is already active.
+pub fn delete_inactive_user(db_con: &Connection, user_id: i32) -> Result<bool, Error> {
+    let deleted = diesel::delete(
+        users::table
+            .find(user_id)
+            .filter(users::active.eq(false)),
+    ).execute(db_con)?;
+    Ok(deleted == 1)
+}
+
+/// Activates the user with the given ID, if its email is still the given one.
+///
+/// Returns `false` if the user does not exist, its email has changed, or it was already active.
+pub fn activate_user(db_con: &Connection, user_id: i32, email: &str) -> Result<bool, Error> {
+    let updated = diesel::update(
+        users::table
+            .find(user_id)
+            .filter(users::email.eq(email))
+            .filter(users::active.eq(false)),
+    ).set(users::active.eq(true))
+        .execute(db_con)?;
+    Ok(updated == 1)
+}
+
+/// Changes the password of a user.
+///
+/// The failed login attempts are reset, and the account is unlocked if it was locked.
+pub fn set_password(db_con: &Connection, user_id: i32, password: &[u8]) -> Result<(), Error> {
+    db_con.transaction(|| {
+        let _ = diesel::update(users::table.find(user_id))
+            .set((
+                users::password.eq(password),
+                users::failed_logins.eq(0),
+                users::last_failed_login.eq(None::<DateTime<Utc>>),
+            ))
+            .execute(db_con)?;
+        let _ = diesel::delete(
+            user_login_failures::table.filter(user_login_failures::user_id.eq(user_id)),
+        ).execute(db_con)?;
+        Ok(())
+    })
+}
+
+/// Gets the failed login attempts of a user from the given client address, if there are any.
+pub fn get_login_failures(
+    db_con: &Connection,
+    user_id: i32,
+    ip_address: &str,
+) -> Result<Option<LoginFailures>, Error> {
+    Ok(user_login_failures::table
+        .find((user_id, ip_address))
+        .first(db_con)
+        .optional()?)
+}
+
+/// Records a failed login attempt of a user from the given client address.
+///
+/// Failed attempts before `reset_before` are no longer counted, neither for the address nor for
+/// the whole account. If the attempt reaches the maximum number of consecutive failures of the
+/// address, the address gets locked out of the account for the given time, and its counter
+/// starts again. The expired failures of the other addresses of the user are removed. Returns
+/// wether this attempt locked the address out, and the number of consecutive failed attempts of
+/// the whole account.
+pub fn record_failed_login(
+    db_con: &Connection,
+    user_id: i32,
+    ip_address: &str,
+    reset_before: DateTime<Utc>,
+    max_failures: i32,
+    lockout: Duration,
+) -> Result<(bool, i32), Error> {
+    db_con.transaction(|| {
+        let now = Utc::now();
+        let user: User = users::table.find(user_id).for_update().first(db_con)?;
+        let account_failures = match user.last_failed_login() {
+            Some(last_failed_login) if last_failed_login >= reset_before => {
+                user.failed_logins() + 1
+            }
+            _ => 1,
+        };
+        let _ = diesel::update(users::table.find(user_id))
+            .set((
+                users::failed_logins.eq(account_failures),
+                users::last_failed_login.eq(now),
+            ))
+            .execute(db_con)?;
+
+        let _ = diesel::delete(
+            user_login_failures::table
+                .filter(user_login_failures::user_id.eq(user_id))
+                .filter(user_login_failures::ip_address.ne(ip_address))
+                .filter(user_login_failures::last_failed_login.lt(reset_before))
+                .filter(
+                    user_login_failures::locked_until
+                        .is_null()
+                        .or(user_login_failures::locked_until.lt(now)),
+                ),
+        ).execute(db_con)?;
+
+        let _ = diesel::insert_into(user_login_failures::table)
+            .values(&NewLoginFailures::new(user_id, ip_address))
+            .on_conflict_do_nothing()
+            .execute(db_con)?;
+        let failures: LoginFailures = user_login_failures::table
+            .find((user_id, ip_address))
+            .for_update()
+            .first(db_con)?;
+        let failed_logins = if failures.last_failed_login() >= reset_before {
+            failures.failed_logins() + 1
+        } else {
+            1
+        };
+
+        let target = user_login_failures::table.find((user_id, ip_address));
+        if failed_logins >= max_failures {
+            let _ = diesel::update(target)
+                .set((
+                    user_login_failures::failed_logins.eq(0),
+                    user_login_failures::last_failed_login.eq(now),
+                    user_login_failures::locked_until.eq(now + lockout),
+                ))
+                .execute(db_con)?;
+            Ok((true, account_failures))
+        } else {
+            let _ = diesel::update(target)
+                .set((
+                    user_login_failures::failed_logins.eq(failed_logins),
+                    user_login_failures::last_failed_login.eq(now),
+                ))
+                .execute(db_con)?;
+            Ok((false, account_failures))
+        }
+    })
+}
+
+/// Resets the failed login attempts of a user, from the given client address and for the whole
+/// account, after a successful login.
+pub fn reset_failed_logins(
+    db_con: &Connection,
+    user_id: i32,
+    ip_address: &str,
+) -> Result<(), Error> {
+    db_con.transaction(|| {
+        let _ = diesel::update(users::table.find(user_id))
+            .set(users::failed_logins.eq(0))
+            .execute(db_con)?;
+        let _ = diesel::delete(user_login_failures::table.find((user_id, ip_address)))
+            .execute(db_con)?;
+        Ok(())
+    })
+}
+
+/// Unlocks the account of a user, resetting the failed login attempts of the whole account and
+/// of all the client addresses.
+///
+/// Returns `false` if the account was neither locked for any address nor delayed, after the given
+/// number of consecutive failed attempts of the whole account.
+pub fn unlock_user(db_con: &Connection, user_id: i32, delayed_after: i32) -> Result<bool, Error> {
+    db_con.transaction(|| {
+        let address_locked: bool = select(exists(
+            user_login_failures::table
+                .filter(user_login_failures::user_id.eq(user_id))
+                .filter(user_login_failures::locked_until.gt(Utc::now())),
+        )).get_result(db_con)?;
+        let account_delayed: bool = select(exists(
+            users::table
+                .find(user_id)
+                .filter(users::failed_logins.ge(delayed_after)),
+        )).get_result(db_con)?;
+
+        let _ = diesel::update(users::table.find(user_id))
+            .set(users::failed_logins.eq(0))
+            .execute(db_con)?;
+        let _ = diesel::delete(
+            user_login_failures::table.filter(user_login_failures::user_id.eq(user_id)),
+        ).execute(db_con)?;
+        Ok(address_locked || account_delayed)
+    })
+}
+
+/// Enables two-factor authentication for a user, replacing its recovery codes.
+///
+/// The given step is stored as the last used one, since its code was used to confirm the
+/// enrolment. Returns `false` if two-factor authentication was already enabled.
+pub fn enable_two_factor(
+    db_con: &Connection,
+    user_id: i32,
+    secret: &TotpSecret,
+    step: i64,
+    recovery_code_hashes: &[Vec<u8>],
+) -> Result<bool, Error> {
+    db_con.transaction(|| {
+        let updated = diesel::update(
+            users::table
+                .find(user_id)
+                .filter(users::totp_secret.is_null()),
+        ).set((secret, users::totp_last_step.eq(step)))
+            .execute(db_con)?;
+        if updated == 0 {
+            return Ok(false);
+        }
+
+        let _ = diesel::delete(
+            user_recovery_codes::table.filter(user_recovery_codes::user_id.eq(user_id)),
+        ).execute(db_con)?;
+        let codes = recovery_code_hashes
+            .iter()
+            .map(|hash| NewRecoveryCode::new(user_id, hash))
+            .collect::<Vec<_>>();
+        let _ = diesel::insert_into(user_recovery_codes::table)
+            .values(&codes)
+            .execute(db_con)?;
+
+        Ok(true)
+    })
+}
+
+/// Disables two-factor authentication for a user, removing its recovery codes.
+///
+/// Returns `false` if two-factor authentication was not enabled.
+pub fn disable_two_factor(db_con: &Connection, user_id: i32) -> Result<bool, Error> {
+    db_con.transaction(|| {
+        let _ = diesel::delete(
+            user_recovery_codes::table.filter(user_recovery_codes::user_id.eq(user_id)),
+        ).execute(db_con)?;
+        let updated = diesel::update(
+            users::table
+                .find(user_id)
+                .filter(users::totp_secret.is_not_null()),
+        ).set((&TotpSecret::none(), users::totp_last_step.eq(None::<i64>)))
+            .execute(db_con)?;
+
+        Ok(updated == 1)
+    })
+}
+
+/// Marks a TOTP time step as used by a user.
+///
+/// Returns `false` if the step, or a later one, had already been used, so that a code cannot be
+/// used twice.
+pub fn use_totp_step(db_con: &Connection, user_id: i32, step: i64) -> Result<bool, Error> {
+    let updated = diesel::update(
+        users::table.find(user_id).filter(
+            users::totp_last_step
+                .is_null()
+                .or(users::totp_last_step.lt(step)),
+        ),
+    ).set(users::totp_last_step.eq(step))
+        .execute(db_con)?;
+    Ok(updated == 1)
+}
+
+/// Uses the recovery code with the given hash of a user, removing it.
+///
+/// Returns `false` if the user does not have that recovery code.
+pub fn use_recovery_code(
+    db_con: &Connection,
+    user_id: i32,
+    code_hash: &[u8],
+) -> Result<bool, Error> {
+    let deleted = diesel::delete(
+        user_recovery_codes::table
+            .filter(user_recovery_codes::user_id.eq(user_id))
+            .filter(user_recovery_codes::code_hash.eq(code_hash)),
+    ).execute(db_con)?;
+    Ok(deleted == 1)
+}
+
+/// Gets the users whose TOTP secrets are not encrypted with the given master key.
+pub fn get_users_to_reencrypt(
+    db_con: &Connection,
+    master_key_id: &str,
+) -> Result<Vec<User>, Error> {
+    Ok(users::table
+        .filter(users::totp_secret.is_not_null())
+        .filter(users::totp_master_key_id.ne(master_key_id))
+        .load(db_con)?)
+}
+
+/// Replaces the TOTP secret of a user that has two-factor authentication enabled.
+pub fn update_totp_secret(
+    db_con: &Connection,
+    user_id: i32,
+    secret: &TotpSecret,
+) -> Result<(), Error> {
+    let _ = diesel::update(
+        users::table
+            .find(user_id)
+            .filter(users::totp_secret.is_not_null()),
+    ).set(secret)
+        .execute(db_con)?;
+    Ok(())
+}
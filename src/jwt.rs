@@ -0,0 +1,274 @@
+//! JSON Web Token module.
+//!
+//! Access tokens can be issued as JWTs signed with Ed25519 (`EdDSA`, RFC 8037), so that resource
+//! servers can verify them offline with the public keys published in `/.well-known/jwks.json`.
+//! Signing keys are rotated automatically, and retired keys are still published until all the
+//! tokens they signed have expired. The private keys are stored with envelope encryption, like
+//! the API secrets of the applications.
+//!
+//! ID tokens can also be signed with RSA (`RS256`), the algorithm that all the OpenID Connect
+//! relying parties support, if an RSA key is configured. That key is also published, and it
+//! must be rotated manually.
+
+use base64;
+use chrono::{DateTime, Duration, Utc};
+use failure::Error;
+use hex;
+use ring::signature::Ed25519KeyPair;
+use rocket::State;
+use serde::Serialize;
+use serde_json;
+use untrusted;
+use uuid::Uuid;
+
+use api::v1::error::ApiError;
+use api::v1::oauth::{Cacheable, ACCESS_TOKEN_LIFETIME_MINUTES};
+use compress::CompressedJson;
+use config::OAuthConfig;
+use crypto::{self, RsaSigningKey};
+use db::{self, CONNECTION_POOL};
+use db::models::jwt::{NewSigningKey, SigningKey, SigningKeySecret};
+
+/// Time clients can cache the published keys, in seconds.
+const JWKS_CACHE_SECONDS: i64 = 300;
+
+/// JWT header.
+#[derive(Debug, Serialize)]
+struct Header<'a> {
+    alg: &'static str,
+    typ: &'static str,
+    kid: &'a str,
+}
+
+/// Claims of a JWT access token.
+#[derive(Debug, Serialize)]
+pub struct AccessTokenClaims<'a> {
+    iss: &'a str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    sub: Option<String>,
+    aud: &'a str,
+    client_id: Uuid,
+    scope: String,
+    iat: i64,
+    exp: i64,
+    jti: String,
+}
+
+impl<'a> AccessTokenClaims<'a> {
+    /// Creates the claims of a new access token.
+    ///
+    /// Application tokens, issued with the client credentials grant, don't have a subject.
+    pub fn new<S: AsRef<str>>(
+        config: &'a OAuthConfig,
+        app_id: Uuid,
+        user_id: Option<i32>,
+        scopes: &[S],
+        expiration: i64,
+    ) -> Result<AccessTokenClaims<'a>, Error> {
+        Ok(AccessTokenClaims {
+            iss: config.issuer(),
+            sub: user_id.map(|id| id.to_string()),
+            aud: config.audience(),
+            client_id: app_id,
+            scope: scopes
+                .iter()
+                .map(AsRef::as_ref)
+                .collect::<Vec<_>>()
+                .join(" "),
+            iat: Utc::now().timestamp(),
+            exp: expiration,
+            jti: hex::encode(crypto::random_token()?),
+        })
+    }
+}
+
+/// Encodes a byte slice with URL-safe Base64 without padding, as JWTs require.
+fn base64url(bytes: &[u8]) -> String {
+    base64::encode_config(bytes, base64::URL_SAFE_NO_PAD)
+}
+
+/// Encodes the given claims in a JWT signed with the current signing key.
+pub fn encode<C: Serialize>(config: &OAuthConfig, claims: &C) -> Result<String, Error> {
+    let db_con = CONNECTION_POOL.get()?;
+    let key = current_signing_key(&db_con, config)?;
+    let key_pair = Ed25519KeyPair::from_pkcs8(untrusted::Input::from(&key.private_key()?))
+        .map_err(|_| format_err!("invalid signing key `{}`", key.id()))?;
+
+    sign("EdDSA", key.id(), claims, |signing_input| {
+        Ok(key_pair.sign(signing_input).as_ref().to_vec())
+    })
+}
+
+/// Encodes the given claims in a JWT signed with the given RSA key (`RS256`).
+pub fn encode_rs256<C: Serialize>(key: &RsaSigningKey, claims: &C) -> Result<String, Error> {
+    sign("RS256", key.id(), claims, |signing_input| key.sign(signing_input))
+}
+
+/// Builds a JWT with the given claims, signing it with the given function.
+fn sign<C, F>(alg: &'static str, kid: &str, claims: &C, sign: F) -> Result<String, Error>
+where
+    C: Serialize,
+    F: FnOnce(&[u8]) -> Result<Vec<u8>, Error>,
+{
+    let header = Header {
+        alg,
+        typ: "JWT",
+        kid,
+    };
+    let signing_input = format!(
+        "{}.{}",
+        base64url(&serde_json::to_vec(&header)?),
+        base64url(&serde_json::to_vec(claims)?)
+    );
+    let signature = sign(signing_input.as_bytes())?;
+
+    Ok(format!("{}.{}", signing_input, base64url(&signature)))
+}
+
+/// Gets the current signing key, rotating it if it's older than the configured lifetime.
+fn current_signing_key(
+    db_con: &db::Connection,
+    config: &OAuthConfig,
+) -> Result<SigningKey, Error> {
+    let created_after = Utc::now() - config.signing_key_lifetime();
+    match db::jwt::get_current_signing_key(db_con)? {
+        Some(ref key) if key.creation() > created_after => Ok(key.clone()),
+        _ => rotate_signing_key_created_before(db_con, created_after),
+    }
+}
+
+/// Generates a new signing key, retiring the current one.
+pub fn rotate_signing_key(db_con: &db::Connection) -> Result<SigningKey, Error> {
+    rotate_signing_key_created_before(db_con, Utc::now())
+}
+
+/// Generates a new signing key, retiring the current one, unless another worker already
+/// replaced it with a key created after the given date.
+fn rotate_signing_key_created_before(
+    db_con: &db::Connection,
+    created_before: DateTime<Utc>,
+) -> Result<SigningKey, Error> {
+    let private_key = crypto::generate_signing_key()?;
+    let public_key = Ed25519KeyPair::from_pkcs8(untrusted::Input::from(&private_key))
+        .map_err(|_| format_err!("invalid generated signing key"))?
+        .public_key_bytes()
+        .to_vec();
+
+    db::jwt::rotate_signing_key(
+        db_con,
+        &NewSigningKey::new(
+            hex::encode(crypto::random_token()?),
+            &private_key,
+            public_key,
+        )?,
+        created_before,
+    )
+}
+
+/// Re-encrypts the private signing keys that are not encrypted with the current master key,
+/// returning the number of re-encrypted keys.
+///
+/// Keys encrypted with an older master key can only be re-encrypted if it's configured as the
+/// previous master key. Keys stored in plain text get encrypted.
+pub fn reencrypt_signing_keys() -> Result<usize, Error> {
+    let db_con = CONNECTION_POOL.get()?;
+    let keys = db::jwt::get_signing_keys_to_reencrypt(&db_con, crypto::master_key_id())?;
+
+    for key in &keys {
+        db::jwt::update_signing_key_secret(
+            &db_con,
+            key.id(),
+            &SigningKeySecret::new(key.id(), &key.private_key()?)?,
+        )?;
+    }
+
+    Ok(keys.len())
+}
+
+/// JSON Web Key Set (RFC 7517, section 5).
+#[derive(Debug, Serialize)]
+pub struct JsonWebKeySet {
+    keys: Vec<JsonWebKey>,
+}
+
+/// Public JSON Web Key (RFC 7517, section 4).
+#[derive(Debug, Serialize)]
+#[serde(tag = "kty")]
+pub enum JsonWebKey {
+    /// Ed25519 key (RFC 8037, section 2).
+    #[serde(rename = "OKP")]
+    Okp {
+        /// Curve, `Ed25519`.
+        crv: &'static str,
+        /// Use of the key, `sig`.
+        #[serde(rename = "use")]
+        key_use: &'static str,
+        /// Signature algorithm, `EdDSA`.
+        alg: &'static str,
+        /// Key ID.
+        kid: String,
+        /// Public key.
+        x: String,
+    },
+    /// RSA key (RFC 7518, section 6.3.1).
+    #[serde(rename = "RSA")]
+    Rsa {
+        /// Use of the key, `sig`.
+        #[serde(rename = "use")]
+        key_use: &'static str,
+        /// Signature algorithm, `RS256`.
+        alg: &'static str,
+        /// Key ID.
+        kid: String,
+        /// Modulus.
+        n: String,
+        /// Public exponent.
+        e: String,
+    },
+}
+
+impl<'a> From<&'a SigningKey> for JsonWebKey {
+    fn from(key: &SigningKey) -> JsonWebKey {
+        JsonWebKey::Okp {
+            crv: "Ed25519",
+            key_use: "sig",
+            alg: "EdDSA",
+            kid: key.id().to_owned(),
+            x: base64url(key.public_key()),
+        }
+    }
+}
+
+impl<'a> From<&'a RsaSigningKey> for JsonWebKey {
+    fn from(key: &RsaSigningKey) -> JsonWebKey {
+        JsonWebKey::Rsa {
+            key_use: "sig",
+            alg: "RS256",
+            kid: key.id().to_owned(),
+            n: base64url(key.modulus()),
+            e: base64url(key.public_exponent()),
+        }
+    }
+}
+
+/// Public keys to verify JWT access tokens and ID tokens.
+#[get("/.well-known/jwks.json")]
+pub fn jwks(
+    config: State<OAuthConfig>,
+) -> Result<Cacheable<CompressedJson<JsonWebKeySet>>, ApiError> {
+    let db_con = CONNECTION_POOL.get()?;
+    let keys = db::jwt::get_published_signing_keys(
+        &db_con,
+        Utc::now() - Duration::minutes(ACCESS_TOKEN_LIFETIME_MINUTES),
+    )?;
+
+    Ok(Cacheable::new(
+        CompressedJson::new(JsonWebKeySet {
+            keys: keys.iter()
+                .map(JsonWebKey::from)
+                .chain(config.rsa_key().map(JsonWebKey::from))
+                .collect(),
+        }),
+        JWKS_CACHE_SECONDS,
+    ))
+}
@@ -12,16 +12,27 @@
         unused_extern_crates)]
 #![allow(unused_imports, unused_extern_crates)]
 
+extern crate argon2;
+extern crate base64;
 #[macro_use]
 extern crate failure;
 extern crate flate2;
+extern crate handlebars;
+extern crate hex;
 #[macro_use]
 extern crate lazy_static;
+extern crate lettre;
+extern crate lettre_email;
+#[macro_use]
+extern crate log;
+extern crate native_tls;
+extern crate ring;
 extern crate rocket;
 extern crate rocket_contrib;
 extern crate serde;
 #[macro_use]
 extern crate serde_derive;
+extern crate serde_json;
 
 // For databases:
 extern crate chrono;
@@ -33,19 +44,42 @@ extern crate r2d2;
 extern crate r2d2_diesel;
 extern crate r2d2_redis;
 extern crate redis;
+extern crate untrusted;
 extern crate uuid;
 
 mod db;
+mod account;
 mod compress;
+mod crypto;
+mod totp;
+#[macro_use]
+pub mod rate_limit;
 pub mod api;
+pub mod config;
+pub mod jwt;
+pub mod mail;
+pub mod oidc;
+
+pub use crypto::load_master_keys;
 
 use std::path::{Path, PathBuf};
 
-use rocket::response::NamedFile;
+use chrono::{TimeZone, Utc};
+use failure::Error;
+use uuid::Uuid;
+use rocket::State;
+use rocket::request::Form;
+use rocket::response::{NamedFile, Redirect};
 use rocket::http::ContentType;
+use rocket::http::uri::URI;
 use rocket_contrib::Template;
 
+use api::v1::oauth::{Authorization, AuthorizationError, AuthorizationRequest, PasswordLogin};
+use api::v1::sessions::{self, SessionInfo};
 use compress::*;
+use config::OAuthConfig;
+use mail::MailService;
+use rate_limit::IpThrottle;
 
 /// Homepage.
 #[get("/")]
@@ -81,6 +115,937 @@ pub fn homepage() -> CompressedTemplate {
     CompressedTemplate::new(Template::render("homepage", &context))
 }
 
+/// Context structure for the OAuth authorization page.
+#[derive(Debug, Serialize)]
+struct AuthorizeContext {
+    /// Title of the page.
+    title: String,
+    /// The short representation of the language.
+    lang_short: String,
+    /// CSS style for the page.
+    css: &'static str,
+    /// Name of the application requesting access, if the request is valid.
+    app_name: Option<String>,
+    /// Descriptions of the requested scopes.
+    scopes: Vec<&'static str>,
+    /// Query string of the authorization request, to send it back with the consent form.
+    query: String,
+    /// Error to show to the user, if any.
+    error: Option<&'static str>,
+}
+
+/// Renders the OAuth authorization page.
+///
+/// If no authorization is given, the page will only show the error.
+fn authorize_page(
+    authorization: Option<&Authorization>,
+    uri: &URI,
+    error: Option<&'static str>,
+) -> CompressedTemplate {
+    let context = AuthorizeContext {
+        title: "Authorize application".to_owned(),
+        lang_short: "en".to_owned(),
+        css: include_str!("../static/css/_compiled/authorize.css"),
+        app_name: authorization.map(|a| a.application().name().to_owned()),
+        scopes: authorization.map_or_else(Vec::new, |a| {
+            a.scope().iter().map(|scope| scope.description()).collect()
+        }),
+        query: uri.query().unwrap_or("").to_owned(),
+        error,
+    };
+    CompressedTemplate::new(Template::render("authorize", &context))
+}
+
+/// OAuth authorization page, where users consent the access of an application.
+#[get("/authorize?<request>")]
+#[cfg_attr(feature = "cargo-clippy", allow(needless_pass_by_value))]
+pub fn authorize(
+    request: AuthorizationRequest,
+    uri: &URI,
+) -> Result<CompressedTemplate, Redirect> {
+    match request.validate() {
+        Ok(authorization) => Ok(authorize_page(Some(&authorization), uri, None)),
+        Err(AuthorizationError::InvalidClient(error)) => Ok(authorize_page(None, uri, Some(error))),
+        Err(AuthorizationError::Redirect(url)) => Err(Redirect::to(&url)),
+        Err(AuthorizationError::Server(_)) => {
+            // TODO log error.
+            Ok(authorize_page(None, uri, Some("Unknown error")))
+        }
+    }
+}
+
+/// OAuth authorization consent form.
+#[derive(Debug, FromForm)]
+pub struct ConsentForm {
+    /// Username of the user giving consent.
+    username: String,
+    /// Password of the user giving consent.
+    password: String,
+    /// Two-factor authentication code, if the user has it enabled.
+    second_factor: Option<String>,
+    /// Decision of the user, `approve` or `deny`.
+    decision: String,
+}
+
+/// Gets the two-factor authentication code of a login form, if the field was filled in.
+fn second_factor_code(code: &Option<String>) -> Option<&str> {
+    match *code {
+        Some(ref code) if !code.trim().is_empty() => Some(code.as_str()),
+        _ => None,
+    }
+}
+
+/// OAuth authorization consent, redirecting the user back to the application.
+///
+/// Password attempts are throttled by client IP address.
+#[post("/authorize?<request>", data = "<consent>")]
+#[cfg_attr(feature = "cargo-clippy", allow(needless_pass_by_value))]
+pub fn authorize_consent(
+    request: AuthorizationRequest,
+    uri: &URI,
+    mail: State<MailService>,
+    throttle: Result<IpThrottle<PasswordLogin>, &'static str>,
+    consent: Form<ConsentForm>,
+) -> Result<Redirect, CompressedTemplate> {
+    let authorization = match request.validate() {
+        Ok(authorization) => authorization,
+        Err(AuthorizationError::InvalidClient(error)) => {
+            return Err(authorize_page(None, uri, Some(error)))
+        }
+        Err(AuthorizationError::Redirect(url)) => return Ok(Redirect::to(&url)),
+        Err(AuthorizationError::Server(_)) => {
+            // TODO log error.
+            return Err(authorize_page(None, uri, Some("Unknown error")));
+        }
+    };
+
+    let consent = consent.get();
+    if consent.decision != "approve" {
+        return Ok(Redirect::to(&authorization.deny()));
+    }
+    let ip = match throttle {
+        Ok(throttle) => throttle.ip(),
+        Err(error) => return Err(authorize_page(Some(&authorization), uri, Some(error))),
+    };
+
+    let user = match db::CONNECTION_POOL
+        .get()
+        .map_err(Error::from)
+        .and_then(|db_con| {
+            account::authenticate_user(
+                &db_con,
+                &mail,
+                &consent.username,
+                &consent.password,
+                second_factor_code(&consent.second_factor),
+                Some(ip),
+            )
+        }) {
+        Ok(Ok(user)) => user,
+        Ok(Err(e)) => {
+            return Err(authorize_page(Some(&authorization), uri, Some(e.description())))
+        }
+        Err(_) => {
+            // TODO log error.
+            return Err(authorize_page(Some(&authorization), uri, Some("Unknown error")));
+        }
+    };
+
+    match authorization.grant(user.id()) {
+        Ok(url) => Ok(Redirect::to(&url)),
+        Err(_) => {
+            // TODO log error.
+            Err(authorize_page(None, uri, Some("Unknown error")))
+        }
+    }
+}
+
+/// Context structure for the account unlock page.
+#[derive(Debug, Serialize)]
+struct UnlockContext {
+    /// Title of the page.
+    title: String,
+    /// The short representation of the language.
+    lang_short: String,
+    /// CSS style for the page.
+    css: &'static str,
+    /// Unlock token from the email, to send it back with the unlock form.
+    token: Option<String>,
+    /// Result message to show to the user, if the form has been sent.
+    message: Option<&'static str>,
+}
+
+/// Renders the account unlock page.
+///
+/// If no token is given, the page will only show the message.
+fn unlock_page(token: Option<String>, message: Option<&'static str>) -> CompressedTemplate {
+    let context = UnlockContext {
+        title: "Unlock account".to_owned(),
+        lang_short: "en".to_owned(),
+        css: include_str!("../static/css/_compiled/account.css"),
+        token,
+        message,
+    };
+    CompressedTemplate::new(Template::render("unlock", &context))
+}
+
+/// Account unlock form, with the token sent in the unlock email.
+#[derive(Debug, FromForm)]
+pub struct UnlockForm {
+    /// Unlock token.
+    token: String,
+}
+
+/// Account unlock page, linked from the email sent when an account gets locked.
+///
+/// The account is only unlocked after confirming it with the form, so that email clients that
+/// open the links in advance don't unlock it.
+#[get("/unlock?<form>")]
+pub fn unlock(form: UnlockForm) -> CompressedTemplate {
+    unlock_page(Some(form.token), None)
+}
+
+/// Unlocks an account with the token sent in the unlock email.
+#[post("/unlock", data = "<form>")]
+#[cfg_attr(feature = "cargo-clippy", allow(needless_pass_by_value))]
+pub fn unlock_confirm(
+    throttle: Result<IpThrottle, &'static str>,
+    form: Form<UnlockForm>,
+) -> CompressedTemplate {
+    let ip = match throttle {
+        Ok(throttle) => throttle.ip(),
+        Err(error) => return unlock_page(None, Some(error)),
+    };
+
+    match db::CONNECTION_POOL
+        .get()
+        .map_err(Error::from)
+        .and_then(|db_con| account::unlock_account(&db_con, &form.get().token, Some(ip)))
+    {
+        Ok(true) => unlock_page(None, Some("Your account has been unlocked, you can log in now")),
+        Ok(false) => unlock_page(None, Some("The unlock link is not valid or has expired")),
+        Err(_) => {
+            // TODO log error.
+            unlock_page(None, Some("Unknown error"))
+        }
+    }
+}
+
+/// Context structure for the registration page.
+#[derive(Debug, Serialize)]
+struct RegisterContext {
+    /// Title of the page.
+    title: String,
+    /// The short representation of the language.
+    lang_short: String,
+    /// CSS style for the page.
+    css: &'static str,
+    /// Wether the user has been registered.
+    registered: bool,
+    /// Message to show to the user, if the form has been sent.
+    message: Option<&'static str>,
+}
+
+/// Renders the registration page.
+fn register_page(registered: bool, message: Option<&'static str>) -> CompressedTemplate {
+    let context = RegisterContext {
+        title: "Register".to_owned(),
+        lang_short: "en".to_owned(),
+        css: include_str!("../static/css/_compiled/account.css"),
+        registered,
+        message,
+    };
+    CompressedTemplate::new(Template::render("register", &context))
+}
+
+/// Registration form.
+#[derive(Debug, FromForm)]
+pub struct RegisterForm {
+    /// Username of the new user.
+    username: String,
+    /// Email address of the new user.
+    email: String,
+    /// Password of the new user.
+    password: String,
+}
+
+/// Registration page.
+#[get("/register")]
+pub fn register_form() -> CompressedTemplate {
+    register_page(false, None)
+}
+
+/// Registers a new user, sending the email verification link.
+#[post("/register", data = "<form>")]
+#[cfg_attr(feature = "cargo-clippy", allow(needless_pass_by_value))]
+pub fn register(
+    mail: State<MailService>,
+    throttle: Result<IpThrottle<PasswordLogin>, &'static str>,
+    form: Form<RegisterForm>,
+) -> CompressedTemplate {
+    let ip = match throttle {
+        Ok(throttle) => throttle.ip(),
+        Err(error) => return register_page(false, Some(error)),
+    };
+
+    let form = form.get();
+    match db::CONNECTION_POOL.get().map_err(Error::from).and_then(|db_con| {
+        account::register_user(
+            &db_con,
+            &mail,
+            &form.username,
+            &form.email,
+            &form.password,
+            Some(ip),
+        )
+    }) {
+        Ok(Ok(_)) => register_page(
+            true,
+            Some("We have sent you an email, follow its link to verify your email address"),
+        ),
+        Ok(Err(e)) => register_page(false, Some(e.description())),
+        Err(_) => {
+            // TODO log error.
+            register_page(false, Some("Unknown error"))
+        }
+    }
+}
+
+/// Context structure for the email verification page.
+#[derive(Debug, Serialize)]
+struct VerifyEmailContext {
+    /// Title of the page.
+    title: String,
+    /// The short representation of the language.
+    lang_short: String,
+    /// CSS style for the page.
+    css: &'static str,
+    /// Signed link parameters, to send them back with the verification form.
+    link: Option<VerifyEmailForm>,
+    /// Result message to show to the user, if the form has been sent.
+    message: Option<&'static str>,
+}
+
+/// Renders the email verification page.
+///
+/// If no link parameters are given, the page will only show the message.
+fn verify_email_page(
+    link: Option<VerifyEmailForm>,
+    message: Option<&'static str>,
+) -> CompressedTemplate {
+    let context = VerifyEmailContext {
+        title: "Verify email address".to_owned(),
+        lang_short: "en".to_owned(),
+        css: include_str!("../static/css/_compiled/account.css"),
+        link,
+        message,
+    };
+    CompressedTemplate::new(Template::render("verify_email", &context))
+}
+
+/// Email verification form, with the parameters of the signed link sent by email.
+#[derive(Debug, Serialize, FromForm)]
+pub struct VerifyEmailForm {
+    /// ID of the user.
+    user: i32,
+    /// Expiration of the link, as a UNIX timestamp.
+    expires: i64,
+    /// Signature of the link.
+    signature: String,
+}
+
+/// Email verification page, linked from the email sent when registering.
+///
+/// The email address is only verified after confirming it with the form, so that email clients
+/// that open the links in advance don't activate the account.
+#[get("/verify_email?<form>")]
+pub fn verify_email(form: VerifyEmailForm) -> CompressedTemplate {
+    verify_email_page(Some(form), None)
+}
+
+/// Verifies the email address of a user, activating the account.
+#[post("/verify_email", data = "<form>")]
+#[cfg_attr(feature = "cargo-clippy", allow(needless_pass_by_value))]
+pub fn verify_email_confirm(
+    throttle: Result<IpThrottle, &'static str>,
+    form: Form<VerifyEmailForm>,
+) -> CompressedTemplate {
+    let ip = match throttle {
+        Ok(throttle) => throttle.ip(),
+        Err(error) => return verify_email_page(None, Some(error)),
+    };
+
+    let form = form.get();
+    match db::CONNECTION_POOL.get().map_err(Error::from).and_then(|db_con| {
+        account::verify_email(&db_con, form.user, form.expires, &form.signature, Some(ip))
+    }) {
+        Ok(true) => verify_email_page(
+            None,
+            Some("Your email address has been verified, you can log in now"),
+        ),
+        Ok(false) => {
+            verify_email_page(None, Some("The verification link is not valid or has expired"))
+        }
+        Err(_) => {
+            // TODO log error.
+            verify_email_page(None, Some("Unknown error"))
+        }
+    }
+}
+
+/// Context structure for the forgotten password page.
+#[derive(Debug, Serialize)]
+struct ForgotPasswordContext {
+    /// Title of the page.
+    title: String,
+    /// The short representation of the language.
+    lang_short: String,
+    /// CSS style for the page.
+    css: &'static str,
+    /// Result message to show to the user, if the form has been sent.
+    message: Option<&'static str>,
+}
+
+/// Renders the forgotten password page.
+///
+/// If a message is given, the page will only show the message.
+fn forgot_password_page(message: Option<&'static str>) -> CompressedTemplate {
+    let context = ForgotPasswordContext {
+        title: "Forgot password".to_owned(),
+        lang_short: "en".to_owned(),
+        css: include_str!("../static/css/_compiled/account.css"),
+        message,
+    };
+    CompressedTemplate::new(Template::render("forgot_password", &context))
+}
+
+/// Forgotten password form.
+#[derive(Debug, FromForm)]
+pub struct ForgotPasswordForm {
+    /// Email address of the user.
+    email: String,
+}
+
+/// Forgotten password page, to request a password reset link.
+#[get("/forgot_password")]
+pub fn forgot_password() -> CompressedTemplate {
+    forgot_password_page(None)
+}
+
+/// Sends a password reset link to the user with the given email address, if it exists.
+#[post("/forgot_password", data = "<form>")]
+#[cfg_attr(feature = "cargo-clippy", allow(needless_pass_by_value))]
+pub fn forgot_password_request(
+    mail: State<MailService>,
+    throttle: Result<IpThrottle, &'static str>,
+    form: Form<ForgotPasswordForm>,
+) -> CompressedTemplate {
+    let ip = match throttle {
+        Ok(throttle) => throttle.ip(),
+        Err(error) => return forgot_password_page(Some(error)),
+    };
+
+    match account::request_password_reset(&mail, &form.get().email, Some(ip)) {
+        Ok(()) => forgot_password_page(Some(
+            "If there is an account with that email address, we have sent it a link to reset \
+             the password",
+        )),
+        Err(_) => {
+            // TODO log error.
+            forgot_password_page(Some("Unknown error"))
+        }
+    }
+}
+
+/// Context structure for the password reset page.
+#[derive(Debug, Serialize)]
+struct ResetPasswordContext {
+    /// Title of the page.
+    title: String,
+    /// The short representation of the language.
+    lang_short: String,
+    /// CSS style for the page.
+    css: &'static str,
+    /// Reset token from the email, to send it back with the reset form.
+    token: Option<String>,
+    /// Message to show to the user, if any.
+    message: Option<&'static str>,
+}
+
+/// Renders the password reset page.
+///
+/// If no token is given, the page will only show the message.
+fn reset_password_page(token: Option<String>, message: Option<&'static str>) -> CompressedTemplate {
+    let context = ResetPasswordContext {
+        title: "Reset password".to_owned(),
+        lang_short: "en".to_owned(),
+        css: include_str!("../static/css/_compiled/account.css"),
+        token,
+        message,
+    };
+    CompressedTemplate::new(Template::render("reset_password", &context))
+}
+
+/// Password reset link parameters, with the token sent in the password reset email.
+#[derive(Debug, FromForm)]
+pub struct ResetTokenForm {
+    /// Reset token.
+    token: String,
+}
+
+/// Password reset page, linked from the password reset email.
+///
+/// The token is only used when the form is sent, so that email clients that open the links in
+/// advance don't use it.
+#[get("/reset_password?<form>")]
+pub fn reset_password(form: ResetTokenForm) -> CompressedTemplate {
+    match account::is_valid_reset_token(&form.token) {
+        Ok(true) => reset_password_page(Some(form.token), None),
+        Ok(false) => reset_password_page(
+            None,
+            Some(account::PasswordResetError::InvalidToken.description()),
+        ),
+        Err(_) => {
+            // TODO log error.
+            reset_password_page(None, Some("Unknown error"))
+        }
+    }
+}
+
+/// Password reset form.
+#[derive(Debug, FromForm)]
+pub struct ResetPasswordForm {
+    /// Reset token.
+    token: String,
+    /// New password of the user.
+    password: String,
+}
+
+/// Resets the password of the user, logging it out of all its sessions.
+#[post("/reset_password", data = "<form>")]
+#[cfg_attr(feature = "cargo-clippy", allow(needless_pass_by_value))]
+pub fn reset_password_confirm(
+    throttle: Result<IpThrottle<PasswordLogin>, &'static str>,
+    form: Form<ResetPasswordForm>,
+) -> CompressedTemplate {
+    let ip = match throttle {
+        Ok(throttle) => throttle.ip(),
+        Err(error) => return reset_password_page(None, Some(error)),
+    };
+
+    let form = form.into_inner();
+    match db::CONNECTION_POOL.get().map_err(Error::from).and_then(|db_con| {
+        account::reset_password(&db_con, &form.token, &form.password, Some(ip))
+    }) {
+        Ok(Ok(())) => reset_password_page(
+            None,
+            Some("Your password has been changed, and you have been logged out of all sessions"),
+        ),
+        Ok(Err(e @ account::PasswordResetError::WeakPassword)) => {
+            reset_password_page(Some(form.token), Some(e.description()))
+        }
+        Ok(Err(e)) => reset_password_page(None, Some(e.description())),
+        Err(_) => {
+            // TODO log error.
+            reset_password_page(None, Some("Unknown error"))
+        }
+    }
+}
+
+/// Session row of the sessions page.
+#[derive(Debug, Serialize)]
+struct SessionRow {
+    /// Session ID.
+    id: String,
+    /// Name of the application of the session.
+    app_name: String,
+    /// Creation date of the session.
+    creation: String,
+    /// Date of the last use of the session.
+    last_use: String,
+    /// User agent of the last request that used the session, if any.
+    user_agent: Option<String>,
+}
+
+impl<'a> From<&'a SessionInfo> for SessionRow {
+    fn from(session: &SessionInfo) -> SessionRow {
+        let format_date = |timestamp: i64| {
+            Utc.timestamp(timestamp, 0)
+                .format("%Y-%m-%d %H:%M UTC")
+                .to_string()
+        };
+
+        SessionRow {
+            id: session.id().to_string(),
+            app_name: session.app_name().to_owned(),
+            creation: format_date(session.creation()),
+            last_use: format_date(session.last_use()),
+            user_agent: session.user_agent().map(str::to_owned),
+        }
+    }
+}
+
+/// Context structure for the sessions page.
+#[derive(Debug, Serialize)]
+struct SessionsContext {
+    /// Title of the page.
+    title: String,
+    /// The short representation of the language.
+    lang_short: String,
+    /// CSS style for the page.
+    css: &'static str,
+    /// Account page token, if the user has logged in.
+    page_token: Option<String>,
+    /// Current sessions of the user.
+    sessions: Vec<SessionRow>,
+    /// Message to show to the user, if any.
+    message: Option<&'static str>,
+}
+
+/// Renders the sessions page.
+///
+/// If no page token is given, the page will show the login form, and the sessions otherwise.
+fn sessions_page(
+    page_token: Option<String>,
+    sessions: &[SessionInfo],
+    message: Option<&'static str>,
+) -> CompressedTemplate {
+    let context = SessionsContext {
+        title: "Sessions".to_owned(),
+        lang_short: "en".to_owned(),
+        css: include_str!("../static/css/_compiled/account.css"),
+        page_token,
+        sessions: sessions.iter().map(SessionRow::from).collect(),
+        message,
+    };
+    CompressedTemplate::new(Template::render("sessions", &context))
+}
+
+/// Renders the sessions page with the current sessions of the user.
+fn user_sessions_page(
+    db_con: &db::Connection,
+    page_token: String,
+    user_id: i32,
+    message: Option<&'static str>,
+) -> Result<CompressedTemplate, Error> {
+    let sessions = sessions::user_sessions(db_con, user_id)?;
+    Ok(sessions_page(Some(page_token), &sessions, message))
+}
+
+/// Sessions page, where users can see and revoke their sessions, after logging in.
+#[get("/sessions")]
+pub fn sessions_login() -> CompressedTemplate {
+    sessions_page(None, &[], None)
+}
+
+/// Login form of the account pages.
+#[derive(Debug, FromForm)]
+pub struct LoginForm {
+    /// Username of the user.
+    username: String,
+    /// Password of the user.
+    password: String,
+    /// Two-factor authentication code, if the user has it enabled.
+    second_factor: Option<String>,
+}
+
+/// Logs in to the sessions page, showing the sessions of the user.
+///
+/// Password attempts are throttled by client IP address.
+#[post("/sessions", data = "<login>")]
+#[cfg_attr(feature = "cargo-clippy", allow(needless_pass_by_value))]
+pub fn sessions_list(
+    mail: State<MailService>,
+    throttle: Result<IpThrottle<PasswordLogin>, &'static str>,
+    login: Form<LoginForm>,
+) -> CompressedTemplate {
+    let ip = match throttle {
+        Ok(throttle) => throttle.ip(),
+        Err(error) => return sessions_page(None, &[], Some(error)),
+    };
+
+    let login = login.get();
+    let page = db::CONNECTION_POOL
+        .get()
+        .map_err(Error::from)
+        .and_then(|db_con| {
+            match account::authenticate_user(
+                &db_con,
+                &mail,
+                &login.username,
+                &login.password,
+                second_factor_code(&login.second_factor),
+                Some(ip),
+            )? {
+                Ok(user) => {
+                    let page_token = account::create_page_token(user.id())?;
+                    user_sessions_page(&db_con, page_token, user.id(), None)
+                }
+                Err(e) => Ok(sessions_page(None, &[], Some(e.description()))),
+            }
+        });
+
+    page.unwrap_or_else(|_| {
+        // TODO log error.
+        sessions_page(None, &[], Some("Unknown error"))
+    })
+}
+
+/// Context structure for the email login page.
+#[derive(Debug, Serialize)]
+struct LoginContext {
+    /// Title of the page.
+    title: String,
+    /// The short representation of the language.
+    lang_short: String,
+    /// CSS style for the page.
+    css: &'static str,
+    /// Result message to show to the user, if the form has been sent.
+    message: Option<&'static str>,
+}
+
+/// Renders the email login page.
+///
+/// If a message is given, the page will only show the message.
+fn login_page(message: Option<&'static str>) -> CompressedTemplate {
+    let context = LoginContext {
+        title: "Log in with email".to_owned(),
+        lang_short: "en".to_owned(),
+        css: include_str!("../static/css/_compiled/account.css"),
+        message,
+    };
+    CompressedTemplate::new(Template::render("login", &context))
+}
+
+/// Message shown when sign-in links are not enabled.
+const MAGIC_LINK_DISABLED: &str = "Logging in with an email link is not enabled";
+
+/// Email login form.
+#[derive(Debug, FromForm)]
+pub struct MagicLinkRequestForm {
+    /// Email address of the user.
+    email: String,
+}
+
+/// Email login page, to request a sign-in link instead of using the password.
+#[get("/login")]
+#[cfg_attr(feature = "cargo-clippy", allow(needless_pass_by_value))]
+pub fn login(config: State<OAuthConfig>) -> CompressedTemplate {
+    if config.magic_link_login() {
+        login_page(None)
+    } else {
+        login_page(Some(MAGIC_LINK_DISABLED))
+    }
+}
+
+/// Sends a sign-in link to the user with the given email address, if it exists.
+#[post("/login", data = "<form>")]
+#[cfg_attr(feature = "cargo-clippy", allow(needless_pass_by_value))]
+pub fn login_request(
+    config: State<OAuthConfig>,
+    mail: State<MailService>,
+    throttle: Result<IpThrottle<PasswordLogin>, &'static str>,
+    form: Form<MagicLinkRequestForm>,
+) -> CompressedTemplate {
+    if !config.magic_link_login() {
+        return login_page(Some(MAGIC_LINK_DISABLED));
+    }
+    let ip = match throttle {
+        Ok(throttle) => throttle.ip(),
+        Err(error) => return login_page(Some(error)),
+    };
+
+    match account::send_magic_link(&mail, &form.get().email, None, Vec::new(), Some(ip)) {
+        Ok(()) => login_page(Some(
+            "If there is an account with that email address, we have sent it a link to log in",
+        )),
+        Err(_) => {
+            // TODO log error.
+            login_page(Some("Unknown error"))
+        }
+    }
+}
+
+/// Context structure for the sign-in link page.
+#[derive(Debug, Serialize)]
+struct MagicLinkContext {
+    /// Title of the page.
+    title: String,
+    /// The short representation of the language.
+    lang_short: String,
+    /// CSS style for the page.
+    css: &'static str,
+    /// Token of the sign-in link, to send it back with the login form.
+    token: Option<String>,
+    /// Message to show to the user, if any.
+    message: Option<&'static str>,
+}
+
+/// Renders the sign-in link page.
+///
+/// If no token is given, the page will only show the message.
+fn magic_link_page(token: Option<String>, message: Option<&'static str>) -> CompressedTemplate {
+    let context = MagicLinkContext {
+        title: "Log in".to_owned(),
+        lang_short: "en".to_owned(),
+        css: include_str!("../static/css/_compiled/account.css"),
+        token,
+        message,
+    };
+    CompressedTemplate::new(Template::render("magic_link", &context))
+}
+
+/// Sign-in link parameters, with the token sent in the sign-in email.
+#[derive(Debug, FromForm)]
+pub struct MagicLinkTokenForm {
+    /// Sign-in token.
+    token: String,
+}
+
+/// Sign-in link page, linked from the sign-in email.
+///
+/// The token is only used when the form is sent, so that email clients that open the links in
+/// advance don't use it.
+#[get("/magic_link?<form>")]
+#[cfg_attr(feature = "cargo-clippy", allow(needless_pass_by_value))]
+pub fn magic_link(config: State<OAuthConfig>, form: MagicLinkTokenForm) -> CompressedTemplate {
+    if !config.magic_link_login() {
+        return magic_link_page(None, Some(MAGIC_LINK_DISABLED));
+    }
+
+    match account::is_valid_magic_link(&form.token) {
+        Ok(true) => magic_link_page(Some(form.token), None),
+        Ok(false) => magic_link_page(
+            None,
+            Some(account::LoginError::InvalidMagicLink.description()),
+        ),
+        Err(_) => {
+            // TODO log error.
+            magic_link_page(None, Some("Unknown error"))
+        }
+    }
+}
+
+/// Sign-in link login form.
+#[derive(Debug, FromForm)]
+pub struct MagicLinkLoginForm {
+    /// Sign-in token.
+    token: String,
+    /// Two-factor authentication code, if the user has it enabled.
+    second_factor: Option<String>,
+}
+
+/// Logs in to the sessions page with a sign-in link, showing the sessions of the user.
+///
+/// As with password logins on the login page, this does not create a web session: the server
+/// keeps no cookies, and the account pages are only authorized by the short-lived page token
+/// they embed. Links requested by applications point to their own redirect URI instead.
+///
+/// Attempts are throttled by client IP address, as password logins.
+#[post("/magic_link", data = "<form>")]
+#[cfg_attr(feature = "cargo-clippy", allow(needless_pass_by_value))]
+pub fn magic_link_login(
+    config: State<OAuthConfig>,
+    mail: State<MailService>,
+    throttle: Result<IpThrottle<PasswordLogin>, &'static str>,
+    form: Form<MagicLinkLoginForm>,
+) -> CompressedTemplate {
+    if !config.magic_link_login() {
+        return magic_link_page(None, Some(MAGIC_LINK_DISABLED));
+    }
+    let form = form.into_inner();
+    let ip = match throttle {
+        Ok(throttle) => throttle.ip(),
+        Err(error) => return magic_link_page(Some(form.token), Some(error)),
+    };
+
+    let page = db::CONNECTION_POOL
+        .get()
+        .map_err(Error::from)
+        .and_then(|db_con| {
+            match account::magic_link_login(
+                &db_con,
+                &mail,
+                &form.token,
+                second_factor_code(&form.second_factor),
+                None,
+                Some(ip),
+            )? {
+                Ok((user, _)) => {
+                    let page_token = account::create_page_token(user.id())?;
+                    user_sessions_page(&db_con, page_token, user.id(), None)
+                }
+                Err(e @ account::LoginError::SecondFactorRequired)
+                | Err(e @ account::LoginError::InvalidSecondFactor) => {
+                    Ok(magic_link_page(Some(form.token.clone()), Some(e.description())))
+                }
+                Err(e) => Ok(magic_link_page(None, Some(e.description()))),
+            }
+        });
+
+    page.unwrap_or_else(|_| {
+        // TODO log error.
+        magic_link_page(None, Some("Unknown error"))
+    })
+}
+
+/// Session revocation form of the sessions page.
+#[derive(Debug, FromForm)]
+pub struct RevokeSessionForm {
+    /// Account page token.
+    page_token: String,
+    /// ID of the session to revoke, or `all` to revoke all of them.
+    session: String,
+}
+
+/// Revokes one or all the sessions of the user, from the sessions page.
+#[post("/sessions/revoke", data = "<form>")]
+#[cfg_attr(feature = "cargo-clippy", allow(needless_pass_by_value))]
+pub fn sessions_revoke(form: Form<RevokeSessionForm>) -> CompressedTemplate {
+    let form = form.into_inner();
+    let page = db::CONNECTION_POOL
+        .get()
+        .map_err(Error::from)
+        .and_then(|db_con| {
+            let user_id = match account::page_token_user(&form.page_token)? {
+                Some(user_id) => user_id,
+                None => {
+                    return Ok(sessions_page(
+                        None,
+                        &[],
+                        Some("The page has expired, please log in again"),
+                    ))
+                }
+            };
+
+            let message = if form.session == "all" {
+                // This removes the page token too.
+                sessions::revoke_all_sessions(&db_con, user_id)?;
+                return Ok(sessions_page(
+                    None,
+                    &[],
+                    Some("You have been logged out of all your sessions"),
+                ));
+            } else {
+                let revoked = match form.session.parse::<Uuid>() {
+                    Ok(session_id) => sessions::revoke_session(&db_con, user_id, session_id)?,
+                    Err(_) => false,
+                };
+                if revoked {
+                    "The session has been revoked"
+                } else {
+                    "Session not found"
+                }
+            };
+            user_sessions_page(&db_con, form.page_token, user_id, Some(message))
+        });
+
+    page.unwrap_or_else(|_| {
+        // TODO log error.
+        sessions_page(None, &[], Some("Unknown error"))
+    })
+}
+
 /// Image.
 #[get("/img/<file..>")]
 pub fn image(file: PathBuf) -> Option<NamedFile> {
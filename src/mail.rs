@@ -0,0 +1,419 @@
+//! Email module.
+//!
+//! Emails are composed from the Handlebars templates in `templates/email/`: every email has an
+//! HTML template in `html/` and a plain text template in `text/`, with the same name. They are
+//! then sent with the mailer backend selected in the configuration:
+//!
+//! - `smtp`: sends the emails through an SMTP server, with `STARTTLS` unless `smtp_security` is
+//!   `"none"`. The credentials are read from the `SMTP_USERNAME` and `SMTP_PASSWORD`
+//!   environment variables.
+//! - `file`: writes each email to a file in a directory, for development.
+//! - `memory`: keeps the emails in memory, for tests.
+
+use std::fmt;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, MutexGuard};
+
+use failure::Error;
+use handlebars::{self, Handlebars};
+use lettre::{ClientSecurity, ClientTlsParameters, EmailTransport, SmtpTransport};
+use lettre::file::FileEmailTransport;
+use lettre::smtp::DEFAULT_TLS_PROTOCOLS;
+use lettre::smtp::authentication::Credentials;
+use lettre_email::{Email, EmailBuilder};
+use native_tls::TlsConnector;
+use serde::Serialize;
+
+use config::{MailBackend, MailConfig, SmtpConfig, SmtpSecurity};
+
+/// Email message, rendered and ready to be sent.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Message {
+    /// Sender of the email.
+    from: String,
+    /// Recipient of the email.
+    to: String,
+    /// Subject of the email.
+    subject: String,
+    /// HTML body of the email.
+    html: String,
+    /// Plain text body of the email.
+    text: String,
+}
+
+impl Message {
+    /// Gets the sender of the email.
+    pub fn from(&self) -> &str {
+        &self.from
+    }
+
+    /// Gets the recipient of the email.
+    pub fn to(&self) -> &str {
+        &self.to
+    }
+
+    /// Gets the subject of the email.
+    pub fn subject(&self) -> &str {
+        &self.subject
+    }
+
+    /// Gets the HTML body of the email.
+    pub fn html(&self) -> &str {
+        &self.html
+    }
+
+    /// Gets the plain text body of the email.
+    pub fn text(&self) -> &str {
+        &self.text
+    }
+
+    /// Builds the MIME email, with both bodies as alternatives.
+    fn to_email(&self) -> Result<Email, Error> {
+        Ok(EmailBuilder::new()
+            .to(self.to.as_str())
+            .from(self.from.as_str())
+            .subject(self.subject.as_str())
+            .alternative(self.html.as_str(), self.text.as_str())
+            .build()?)
+    }
+}
+
+/// Mailer backend, that delivers rendered messages.
+pub trait Mailer: fmt::Debug + Send + Sync {
+    /// Sends the given message.
+    fn send(&self, message: &Message) -> Result<(), Error>;
+}
+
+/// Mailer that sends the emails through an SMTP server.
+pub struct SmtpMailer {
+    /// SMTP server.
+    server: String,
+    /// Transport to the server, built once with the configured security and credentials.
+    transport: Mutex<SmtpTransport>,
+}
+
+impl SmtpMailer {
+    /// Creates a new SMTP mailer with the given configuration.
+    pub fn new(config: &SmtpConfig) -> Result<SmtpMailer, Error> {
+        let security = match config.security() {
+            SmtpSecurity::StartTls => {
+                let mut tls_builder = TlsConnector::builder()?;
+                tls_builder.supported_protocols(DEFAULT_TLS_PROTOCOLS)?;
+                ClientSecurity::Required(ClientTlsParameters::new(
+                    config.server().to_owned(),
+                    tls_builder.build()?,
+                ))
+            }
+            SmtpSecurity::Unencrypted => ClientSecurity::None,
+        };
+
+        let mut builder = SmtpTransport::builder((config.server(), config.port()), security)?;
+        if let Some((username, password)) = config.credentials() {
+            let credentials = Credentials::new(username.to_owned(), password.to_owned());
+            builder = builder.credentials(credentials);
+        }
+
+        Ok(SmtpMailer {
+            server: config.server().to_owned(),
+            transport: Mutex::new(builder.build()),
+        })
+    }
+}
+
+impl fmt::Debug for SmtpMailer {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("SmtpMailer")
+            .field("server", &self.server)
+            .finish()
+    }
+}
+
+impl Mailer for SmtpMailer {
+    fn send(&self, message: &Message) -> Result<(), Error> {
+        let email = message.to_email()?;
+        let mut transport = self.transport
+            .lock()
+            .map_err(|_| format_err!("the SMTP transport mutex was poisoned"))?;
+        let _ = transport.send(&email)?;
+        Ok(())
+    }
+}
+
+/// Mailer that writes each email to a file in a directory.
+#[derive(Debug, Clone)]
+pub struct FileMailer {
+    /// Directory of the emails.
+    dir: PathBuf,
+}
+
+impl FileMailer {
+    /// Creates a new file mailer, creating the directory if it does not exist.
+    pub fn new<P: Into<PathBuf>>(dir: P) -> Result<FileMailer, Error> {
+        let dir = dir.into();
+        fs::create_dir_all(&dir)?;
+        Ok(FileMailer { dir })
+    }
+}
+
+impl Mailer for FileMailer {
+    fn send(&self, message: &Message) -> Result<(), Error> {
+        FileEmailTransport::new(&self.dir).send(&message.to_email()?)?;
+        Ok(())
+    }
+}
+
+/// Mailer that keeps the sent emails in memory.
+#[derive(Debug, Default)]
+pub struct MemoryMailer {
+    /// Sent messages, in order.
+    messages: Mutex<Vec<Message>>,
+}
+
+impl MemoryMailer {
+    /// Creates a new, empty, memory mailer.
+    pub fn new() -> MemoryMailer {
+        MemoryMailer::default()
+    }
+
+    /// Gets the messages sent until now.
+    pub fn messages(&self) -> Vec<Message> {
+        self.lock().clone()
+    }
+
+    /// Takes the messages sent until now, leaving the mailer empty.
+    pub fn take_messages(&self) -> Vec<Message> {
+        self.lock().drain(..).collect()
+    }
+
+    /// Locks the message list.
+    ///
+    /// A poisoned lock is recovered, since the list is always left in a consistent state.
+    fn lock(&self) -> MutexGuard<Vec<Message>> {
+        self.messages
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+}
+
+impl Mailer for MemoryMailer {
+    fn send(&self, message: &Message) -> Result<(), Error> {
+        self.lock().push(message.clone());
+        Ok(())
+    }
+}
+
+/// Email service, that composes the emails from templates and sends them with a mailer.
+///
+/// Cloning it is cheap, the templates and the mailer are shared, so that emails can be sent from
+/// other threads.
+#[derive(Clone)]
+pub struct MailService {
+    /// Sender of the emails.
+    from: String,
+    /// Base URL of the links in the emails.
+    base_url: String,
+    /// HTML templates.
+    html_templates: Arc<Handlebars>,
+    /// Plain text templates, without HTML escaping.
+    text_templates: Arc<Handlebars>,
+    /// Mailer backend.
+    mailer: Arc<Mailer>,
+    /// Memory mailer backend, if it's the configured one, so that the sent emails can be read.
+    memory_mailer: Option<Arc<MemoryMailer>>,
+}
+
+impl fmt::Debug for MailService {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("MailService")
+            .field("from", &self.from)
+            .field("base_url", &self.base_url)
+            .field("mailer", &self.mailer)
+            .finish()
+    }
+}
+
+impl MailService {
+    /// Creates the email service with the mailer backend selected in the configuration.
+    pub fn new(config: &MailConfig) -> Result<MailService, Error> {
+        match *config.backend() {
+            MailBackend::Smtp(ref smtp_config) => {
+                MailService::with_mailer(config, Arc::new(SmtpMailer::new(smtp_config)?))
+            }
+            MailBackend::File(ref dir) => {
+                MailService::with_mailer(config, Arc::new(FileMailer::new(dir.as_path())?))
+            }
+            MailBackend::Memory => {
+                let memory_mailer = Arc::new(MemoryMailer::new());
+                let mut service = MailService::with_mailer(config, memory_mailer.clone())?;
+                service.memory_mailer = Some(memory_mailer);
+                Ok(service)
+            }
+        }
+    }
+
+    /// Creates the email service with the given mailer backend.
+    ///
+    /// This allows tests to keep a reference to a `MemoryMailer` to check the sent emails.
+    pub fn with_mailer(config: &MailConfig, mailer: Arc<Mailer>) -> Result<MailService, Error> {
+        let mut html_templates = Handlebars::new();
+        load_templates(&mut html_templates, &config.template_dir().join("html"))?;
+
+        let mut text_templates = Handlebars::new();
+        text_templates.register_escape_fn(handlebars::no_escape);
+        load_templates(&mut text_templates, &config.template_dir().join("text"))?;
+
+        Ok(MailService {
+            from: config.from().to_owned(),
+            base_url: config.base_url().to_owned(),
+            html_templates: Arc::new(html_templates),
+            text_templates: Arc::new(text_templates),
+            mailer,
+            memory_mailer: None,
+        })
+    }
+
+    /// Gets the base URL of the links in the emails, without a trailing slash.
+    pub fn base_url(&self) -> &str {
+        &self.base_url
+    }
+
+    /// Gets the memory mailer backend, if it's the one selected in the configuration, to read
+    /// the sent emails.
+    pub fn memory_mailer(&self) -> Option<&MemoryMailer> {
+        self.memory_mailer.as_ref().map(|mailer| &**mailer)
+    }
+
+    /// Renders the email template with the given name and context, and sends it to the given
+    /// address.
+    pub fn send<C>(
+        &self,
+        to: &str,
+        subject: &str,
+        template: &str,
+        context: &C,
+    ) -> Result<(), Error>
+    where
+        C: Serialize,
+    {
+        let message = Message {
+            from: self.from.clone(),
+            to: to.to_owned(),
+            subject: subject.to_owned(),
+            html: self.html_templates.render(template, context)?,
+            text: self.text_templates.render(template, context)?,
+        };
+        self.mailer.send(&message)
+    }
+}
+
+/// Registers all the `.hbs` templates in the given directory, named after their file stem.
+fn load_templates(registry: &mut Handlebars, dir: &Path) -> Result<(), Error> {
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        if path.extension().map_or(true, |extension| extension != "hbs") {
+            continue;
+        }
+        if let Some(name) = path.file_stem().and_then(|stem| stem.to_str()) {
+            registry.register_template_file(name, &path)?;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use rocket::config::{Config, Environment};
+
+    use config::MailConfig;
+    use super::{MailService, MemoryMailer};
+
+    /// Context with the variables of all the email templates.
+    #[derive(Debug, Serialize)]
+    struct Context {
+        username: &'static str,
+        minutes: i64,
+        hours: i64,
+        attempts: i32,
+        link: &'static str,
+    }
+
+    const CONTEXT: Context = Context {
+        username: "<Jane & Joe>",
+        minutes: 15,
+        hours: 48,
+        attempts: 5,
+        link: "https://example.com/link?user=1&signature=abc",
+    };
+
+    /// Gets the email configuration with the memory backend and the templates of the repository.
+    fn mail_config() -> MailConfig {
+        let config = Config::build(Environment::Development)
+            .extra("mail_backend", "memory")
+            .extra("mail_from", "Sender <sender@example.com>")
+            .finalize()
+            .unwrap();
+        MailConfig::from_config(&config).unwrap()
+    }
+
+    #[test]
+    fn renders_all_the_templates() {
+        let mailer = Arc::new(MemoryMailer::new());
+        let service = MailService::with_mailer(&mail_config(), mailer.clone()).unwrap();
+
+        for &(template, value) in &[
+            ("magic_link", "15 minutes"),
+            ("reset_password", "15 minutes"),
+            ("unlock", "5 failed login attempts"),
+            ("verify_email", "48 hours"),
+        ] {
+            service
+                .send("user@example.com", "Subject", template, &CONTEXT)
+                .unwrap();
+
+            let messages = mailer.take_messages();
+            assert_eq!(messages.len(), 1, "{}", template);
+            let message = &messages[0];
+            assert_eq!(message.from(), "Sender <sender@example.com>");
+            assert_eq!(message.to(), "user@example.com");
+            assert_eq!(message.subject(), "Subject");
+
+            // HTML bodies are escaped, plain text bodies are not.
+            assert!(message.html().contains("&lt;Jane &amp; Joe&gt;"), "{}", template);
+            assert!(message.html().contains("&amp;signature"), "{}", template);
+            assert!(message.text().contains("Hello <Jane & Joe>,"), "{}", template);
+            assert!(message.text().contains(CONTEXT.link), "{}", template);
+
+            let text = message.text().replace('\n', " ");
+            let html = message.html().split_whitespace().collect::<Vec<_>>().join(" ");
+            assert!(text.contains(value), "{}", template);
+            assert!(html.contains(value), "{}", template);
+        }
+    }
+
+    #[test]
+    fn reads_the_configured_memory_mailer() {
+        let service = MailService::new(&mail_config()).unwrap();
+        service
+            .send("user@example.com", "Subject", "verify_email", &CONTEXT)
+            .unwrap();
+
+        let mailer = service.memory_mailer().unwrap();
+        assert_eq!(mailer.messages().len(), 1);
+        assert_eq!(mailer.take_messages()[0].to(), "user@example.com");
+        assert!(mailer.messages().is_empty());
+    }
+
+    #[test]
+    fn fails_with_unknown_templates() {
+        let service = MailService::new(&mail_config()).unwrap();
+        assert!(
+            service
+                .send("user@example.com", "Subject", "unknown", &CONTEXT)
+                .is_err()
+        );
+        assert!(service.memory_mailer().unwrap().messages().is_empty());
+    }
+}
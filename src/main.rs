@@ -25,6 +25,8 @@ fn main() {
 
     let server = rocket::ignite()
         .attach(Template::fairing())
+        .attach(config::fairing())
+        .attach(api::v1::error::fairing())
         .mount(
             "/",
             routes![
@@ -36,12 +38,63 @@ fn main() {
                 css,
                 js,
                 homepage,
+                authorize,
+                authorize_consent,
+                unlock,
+                unlock_confirm,
+                register_form,
+                register,
+                verify_email,
+                verify_email_confirm,
+                forgot_password,
+                forgot_password_request,
+                reset_password,
+                reset_password_confirm,
+                sessions_login,
+                sessions_list,
+                sessions_revoke,
+                login,
+                login_request,
+                magic_link,
+                magic_link_login,
+                jwt::jwks,
+                oidc::configuration,
             ],
         )
         .mount(
             "api/v1",
-            routes![api::v1::oauth::refresh_token, api::v1::oauth::access_token,],
-        );
+            routes![
+                api::v1::oauth::refresh_token,
+                api::v1::oauth::request_magic_link,
+                api::v1::oauth::magic_link_token,
+                api::v1::oauth::access_token,
+                api::v1::oauth::token,
+                api::v1::oauth::token_form,
+                api::v1::oauth::revoke,
+                api::v1::oauth::introspect,
+                api::v1::user::register,
+                api::v1::user::request_password_reset,
+                api::v1::user::profile,
+                api::v1::user::userinfo,
+                api::v1::apps::list,
+                api::v1::apps::register,
+                api::v1::apps::get,
+                api::v1::apps::update,
+                api::v1::apps::deactivate,
+                api::v1::apps::rotate_secret,
+                api::v1::sessions::list,
+                api::v1::sessions::revoke,
+                api::v1::sessions::revoke_all,
+                api::v1::two_factor::start_enrolment,
+                api::v1::two_factor::enable,
+                api::v1::two_factor::disable,
+            ],
+        )
+        .catch(errors![
+            api::v1::error::unauthorized,
+            api::v1::error::forbidden,
+            rate_limit::too_many_requests,
+        ]);
 
     #[cfg(feature = "source_maps")]
     let error = {
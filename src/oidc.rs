@@ -0,0 +1,134 @@
+//! OpenID Connect module.
+//!
+//! The server is an OpenID Connect provider on top of the OAuth authorization code flow. When the
+//! `openid` scope is granted, the token endpoint also responds with an ID token, signed with the
+//! configured RSA key (`RS256`) or, if there is none, with the same keys as the JWT access tokens,
+//! and the user information is available in the user info endpoint. Relying parties can find all
+//! the endpoints in the discovery document.
+//!
+//! Standard relying parties can't sign their requests, so they authenticate in the token endpoint
+//! with their API secret, in HTTP Basic authentication (`client_secret_basic`).
+
+use chrono::{Duration, Utc};
+use failure::Error;
+use rocket::State;
+use uuid::Uuid;
+
+use api::v1::oauth::{Cacheable, ACCESS_TOKEN_LIFETIME_MINUTES};
+use api::v1::scope::Scope;
+use compress::CompressedJson;
+use config::OAuthConfig;
+use jwt;
+
+/// Lifetime of ID tokens, in minutes.
+///
+/// Retired signing keys are only published for the lifetime of access tokens, so ID tokens
+/// cannot live longer.
+const ID_TOKEN_LIFETIME_MINUTES: i64 = ACCESS_TOKEN_LIFETIME_MINUTES;
+/// Time clients can cache the discovery document, in seconds.
+const DISCOVERY_CACHE_SECONDS: i64 = 3600;
+
+/// Claims of an ID token (OpenID Connect Core, section 2).
+#[derive(Debug, Serialize)]
+pub struct IdTokenClaims<'a> {
+    iss: &'a str,
+    sub: String,
+    aud: Uuid,
+    iat: i64,
+    exp: i64,
+    auth_time: i64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    nonce: Option<&'a str>,
+}
+
+/// Issues a new ID token for a user, for the given application.
+///
+/// The authentication time and the nonce come from the authorization request.
+pub fn id_token(
+    config: &OAuthConfig,
+    user_id: i32,
+    app_id: Uuid,
+    auth_time: i64,
+    nonce: Option<&str>,
+) -> Result<String, Error> {
+    let now = Utc::now();
+    let claims = IdTokenClaims {
+        iss: config.issuer(),
+        sub: user_id.to_string(),
+        aud: app_id,
+        iat: now.timestamp(),
+        exp: (now + Duration::minutes(ID_TOKEN_LIFETIME_MINUTES)).timestamp(),
+        auth_time,
+        nonce,
+    };
+
+    match config.rsa_key() {
+        Some(key) => jwt::encode_rs256(key, &claims),
+        None => jwt::encode(config, &claims),
+    }
+}
+
+/// OpenID provider metadata (OpenID Connect Discovery, section 3).
+#[derive(Debug, Serialize)]
+pub struct ProviderMetadata {
+    issuer: String,
+    authorization_endpoint: String,
+    token_endpoint: String,
+    token_endpoint_auth_methods_supported: &'static [&'static str],
+    userinfo_endpoint: String,
+    jwks_uri: String,
+    revocation_endpoint: String,
+    introspection_endpoint: String,
+    scopes_supported: Vec<&'static str>,
+    response_types_supported: &'static [&'static str],
+    grant_types_supported: &'static [&'static str],
+    subject_types_supported: &'static [&'static str],
+    id_token_signing_alg_values_supported: &'static [&'static str],
+    code_challenge_methods_supported: &'static [&'static str],
+    claims_supported: &'static [&'static str],
+}
+
+/// OpenID Connect discovery document.
+#[get("/.well-known/openid-configuration")]
+pub fn configuration(config: State<OAuthConfig>) -> Cacheable<CompressedJson<ProviderMetadata>> {
+    let issuer = config.issuer();
+    Cacheable::new(
+        CompressedJson::new(ProviderMetadata {
+            issuer: issuer.to_owned(),
+            authorization_endpoint: format!("{}/authorize", issuer),
+            token_endpoint: format!("{}/api/v1/token", issuer),
+            token_endpoint_auth_methods_supported: &["client_secret_basic"],
+            userinfo_endpoint: format!("{}/api/v1/userinfo", issuer),
+            jwks_uri: format!("{}/.well-known/jwks.json", issuer),
+            revocation_endpoint: format!("{}/api/v1/revoke", issuer),
+            introspection_endpoint: format!("{}/api/v1/introspect", issuer),
+            scopes_supported: Scope::ALL
+                .iter()
+                .filter(|scope| !scope.is_application_scope())
+                .map(Scope::as_str)
+                .collect(),
+            response_types_supported: &["code"],
+            grant_types_supported: &["authorization_code", "client_credentials"],
+            subject_types_supported: &["public"],
+            id_token_signing_alg_values_supported: if config.rsa_key().is_some() {
+                &["RS256"]
+            } else {
+                &["EdDSA"]
+            },
+            code_challenge_methods_supported: &["S256"],
+            claims_supported: &[
+                "iss",
+                "sub",
+                "aud",
+                "iat",
+                "exp",
+                "auth_time",
+                "nonce",
+                "email",
+                "email_verified",
+                "preferred_username",
+            ],
+        }),
+        DISCOVERY_CACHE_SECONDS,
+    )
+}
@@ -0,0 +1,546 @@
+//! Rate limiting module.
+//!
+//! Rate limits are stored in Redis, so that they are shared between all the server instances.
+//! Responses to rate limited requests should be wrapped in `RateLimited`, that adds the
+//! `X-RateLimit-Limit`, `X-RateLimit-Remaining` and `X-RateLimit-Reset` headers, and the
+//! `Retry-After` header if the limit has been exceeded.
+//!
+//! Application requests are counted in buckets, each of them with a share of the hourly limit of
+//! the application, and each route can have a different cost. The bucket and cost of a route are
+//! declared with the `rate_limit_tier!` macro, and used as the parameter of the `Application`
+//! request guard:
+//!
+//! ```ignore
+//! rate_limit_tier!(
+//!     /// Report exports, that have to read a lot of data.
+//!     ReportExport => Default, cost 5
+//! );
+//!
+//! #[get("/reports/export")]
+//! pub fn export_report(application: Application<ReportExport>, ...) -> ... { ... }
+//! ```
+//!
+//! Routes that can be used without authentication, such as the login forms, should also be
+//! throttled by client IP address with the `IpThrottle` request guard, that uses the same tiers.
+//! The hourly limit of client IP addresses is not shared: it applies to each bucket.
+
+use std::cmp;
+use std::marker::PhantomData;
+use std::net::{IpAddr, Ipv6Addr};
+
+use chrono::{Duration, Utc};
+use failure::Error;
+use hex;
+use rocket::{Outcome, Request, State};
+use rocket::http::Status;
+use rocket::request::{self, FromRequest};
+use rocket::response::{Responder, Response};
+use uuid::Uuid;
+
+use api::v1::error::{ApiError, GuardError};
+use config::RateLimitConfig;
+use crypto;
+use db;
+use db::cache::rate_limit::RateLimitState;
+
+/// Rate limit bucket of application requests.
+///
+/// Each bucket gets its own share of the hourly limit, so that cheap requests are not blocked by
+/// expensive ones. To add a new bucket, add its variant here and register it in `Bucket::ALL`,
+/// `Bucket::as_str()` and `Bucket::share()`, keeping the sum of the shares at 100.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Bucket {
+    /// Data endpoints.
+    Default,
+    /// Token endpoints, that authenticate users and issue tokens.
+    Tokens,
+}
+
+impl Bucket {
+    /// All the available buckets.
+    pub const ALL: &'static [Bucket] = &[Bucket::Default, Bucket::Tokens];
+
+    /// Gets the name of the bucket, used in its cache key.
+    pub fn as_str(&self) -> &'static str {
+        match *self {
+            Bucket::Default => "default",
+            Bucket::Tokens => "tokens",
+        }
+    }
+
+    /// Gets the percentage of the hourly limit that the requests of the bucket can use.
+    pub fn share(&self) -> i32 {
+        match *self {
+            Bucket::Default => 80,
+            Bucket::Tokens => 20,
+        }
+    }
+
+    /// Gets the part of the given hourly limit that the requests of the bucket can use.
+    ///
+    /// It's at least one request, so that every bucket can be used.
+    pub fn limit(&self, hourly_limit: i32) -> i32 {
+        cmp::max(
+            (i64::from(hourly_limit) * i64::from(self.share()) / 100) as i32,
+            1,
+        )
+    }
+}
+
+/// Rate limit tier of a route: the bucket its requests are counted in, and their cost.
+///
+/// A request never costs more than the limit of its bucket, so that small limits don't block the
+/// expensive routes completely.
+pub trait RateLimitTier {
+    /// Bucket the requests are counted in.
+    const BUCKET: Bucket;
+    /// Number of requests each request counts as.
+    const COST: i32;
+}
+
+/// Defines a rate limit tier to be used with the `Application` request guard.
+macro_rules! rate_limit_tier {
+    ($(#[$attr:meta])* $name:ident => $bucket:ident, cost $cost:expr) => {
+        $(#[$attr])*
+        #[derive(Debug, Clone, Copy)]
+        pub struct $name;
+
+        impl $crate::rate_limit::RateLimitTier for $name {
+            const BUCKET: $crate::rate_limit::Bucket = $crate::rate_limit::Bucket::$bucket;
+            const COST: i32 = $cost;
+        }
+    };
+}
+
+rate_limit_tier!(
+    /// Default tier, where each request counts as one in the default bucket.
+    Standard => Default, cost 1
+);
+
+/// Period of the hourly limits.
+fn hourly_period() -> Duration {
+    Duration::hours(1)
+}
+
+/// Gets the rate limit key of a bucket of an application.
+fn application_key(app_id: Uuid, bucket: Bucket) -> String {
+    format!("rate_limit:app:{}:{}", app_id, bucket.as_str())
+}
+
+/// Gets the rate limit key of a bucket of a client IP address.
+///
+/// IPv6 clients usually get a whole /64 network, so they are limited per network. IPv4 addresses
+/// mapped to IPv6 are limited as IPv4 addresses.
+fn ip_key(ip: IpAddr, bucket: Bucket) -> String {
+    format!("rate_limit:ip:{}:{}", throttled_address(ip), bucket.as_str())
+}
+
+/// Gets the rate limit key of the emails a client IP address requested for an email address.
+///
+/// Each client has its own limit for each address, so that others cannot use up the emails a
+/// user can request for its own address. The address is hashed, so that it's not stored in the
+/// cache.
+fn email_key(email: &str, ip: Option<IpAddr>) -> String {
+    format!(
+        "rate_limit:email:{}:{}",
+        hex::encode(crypto::token_hash(email.as_bytes())),
+        requester(ip)
+    )
+}
+
+/// Gets the rate limit key of the emails a client IP address requested for any address.
+fn email_requester_key(ip: Option<IpAddr>) -> String {
+    format!("rate_limit:email_requester:{}", requester(ip))
+}
+
+/// Gets the name of the requester of an email in its rate limit keys: its throttled address, or
+/// `unknown` if the client address is not known.
+fn requester(ip: Option<IpAddr>) -> String {
+    ip.map_or_else(
+        || "unknown".to_owned(),
+        |ip| throttled_address(ip).to_string(),
+    )
+}
+
+/// Gets the address whose limit is used for a client IP address: the address itself for IPv4,
+/// and its /64 network for IPv6.
+pub fn throttled_address(ip: IpAddr) -> IpAddr {
+    match ip {
+        IpAddr::V4(_) => ip,
+        IpAddr::V6(ip) => match ip.to_ipv4() {
+            Some(ipv4) if ip.segments()[..6] == [0, 0, 0, 0, 0, 0xffff] => IpAddr::V4(ipv4),
+            _ => {
+                let segments = ip.segments();
+                IpAddr::V6(Ipv6Addr::new(
+                    segments[0],
+                    segments[1],
+                    segments[2],
+                    segments[3],
+                    0,
+                    0,
+                    0,
+                    0,
+                ))
+            }
+        },
+    }
+}
+
+/// Counts a request of the tier `T` against the limit with the given key, if it's allowed.
+///
+/// The cost of the request is capped to the limit.
+fn consume<T: RateLimitTier>(key: &str, limit: i32) -> Result<RateLimit, Error> {
+    let state =
+        db::cache::rate_limit::consume(key, limit, hourly_period(), cmp::min(T::COST, limit))?;
+    Ok(RateLimit::from_state(limit, &state))
+}
+
+/// Gets the number of seconds of a duration, rounded up.
+fn ceil_seconds(duration: Duration) -> i64 {
+    (duration.num_milliseconds() + 999) / 1000
+}
+
+/// State of a rate limit after a request.
+#[derive(Debug, Clone, Copy)]
+pub struct RateLimit {
+    /// Maximum number of requests in the period.
+    limit: i32,
+    /// Requests that can still be made right away.
+    remaining: i32,
+    /// UNIX timestamp when the limit will be fully restored.
+    reset: i64,
+    /// Seconds until the request can be retried, if the limit has been exceeded.
+    retry_after: Option<i64>,
+}
+
+impl RateLimit {
+    /// Creates the rate limit information from its state in the cache.
+    fn from_state(limit: i32, state: &RateLimitState) -> RateLimit {
+        RateLimit {
+            limit,
+            remaining: state.remaining(),
+            reset: Utc::now().timestamp() + ceil_seconds(state.reset_after()),
+            retry_after: if state.is_allowed() {
+                None
+            } else {
+                Some(ceil_seconds(state.retry_after()))
+            },
+        }
+    }
+
+    /// Counts a request of an application against the share of its hourly limit of the bucket of
+    /// the given tier, if it's allowed.
+    ///
+    /// The returned rate limit is exceeded if the request is not allowed.
+    pub fn consume_application<T: RateLimitTier>(
+        app_id: Uuid,
+        hourly_limit: i32,
+    ) -> Result<RateLimit, Error> {
+        consume::<T>(
+            &application_key(app_id, T::BUCKET),
+            T::BUCKET.limit(hourly_limit),
+        )
+    }
+
+    /// Checks the share of the hourly limit of a bucket of an application, without counting any
+    /// request.
+    pub fn peek_application(
+        app_id: Uuid,
+        hourly_limit: i32,
+        bucket: Bucket,
+    ) -> Result<RateLimit, Error> {
+        let limit = bucket.limit(hourly_limit);
+        let state =
+            db::cache::rate_limit::peek(&application_key(app_id, bucket), limit, hourly_period())?;
+        Ok(RateLimit::from_state(limit, &state))
+    }
+
+    /// Counts a request of a client IP address against the hourly limit of the bucket of the
+    /// given tier, if it's allowed.
+    ///
+    /// The returned rate limit is exceeded if the request is not allowed.
+    pub fn consume_ip<T: RateLimitTier>(ip: IpAddr, hourly_limit: i32) -> Result<RateLimit, Error> {
+        consume::<T>(&ip_key(ip, T::BUCKET), hourly_limit)
+    }
+
+    /// Counts an email requested by a client IP address for the given address against their
+    /// hourly limits, if it's allowed.
+    ///
+    /// This keeps the emails requested by others, such as password reset links, from flooding
+    /// the inbox of a user. Each client has a limit for each address, and a limit for all the
+    /// addresses, so that a client cannot use up the limit of others. The returned rate limit is
+    /// exceeded if the email must not be sent.
+    pub fn consume_email(
+        email: &str,
+        ip: Option<IpAddr>,
+        hourly_limit: i32,
+        requester_hourly_limit: i32,
+    ) -> Result<RateLimit, Error> {
+        let requester_limit =
+            consume::<Standard>(&email_requester_key(ip), requester_hourly_limit)?;
+        if requester_limit.is_exceeded() {
+            return Ok(requester_limit);
+        }
+        consume::<Standard>(&email_key(email, ip), hourly_limit)
+    }
+
+    /// Checks the hourly limit of a bucket of a client IP address, without counting any request.
+    pub fn peek_ip(ip: IpAddr, hourly_limit: i32, bucket: Bucket) -> Result<RateLimit, Error> {
+        let state =
+            db::cache::rate_limit::peek(&ip_key(ip, bucket), hourly_limit, hourly_period())?;
+        Ok(RateLimit::from_state(hourly_limit, &state))
+    }
+
+    /// Gets the maximum number of requests in the period.
+    pub fn limit(&self) -> i32 {
+        self.limit
+    }
+
+    /// Gets the number of requests that can still be made right away.
+    pub fn remaining(&self) -> i32 {
+        self.remaining
+    }
+
+    /// Gets the UNIX timestamp when the limit will be fully restored.
+    pub fn reset(&self) -> i64 {
+        self.reset
+    }
+
+    /// Checks if the limit has been exceeded.
+    pub fn is_exceeded(&self) -> bool {
+        self.retry_after.is_some()
+    }
+}
+
+/// Response with rate limit headers.
+#[derive(Debug)]
+pub struct RateLimited<R> {
+    /// Inner response.
+    response: R,
+    /// Rate limit of the request, if it's known.
+    rate_limit: Option<RateLimit>,
+}
+
+impl<R> RateLimited<R> {
+    /// Creates a new response with rate limit headers.
+    pub fn new(response: R, rate_limit: RateLimit) -> RateLimited<R> {
+        RateLimited {
+            response,
+            rate_limit: Some(rate_limit),
+        }
+    }
+}
+
+impl<'r, R> Responder<'r> for RateLimited<R>
+where
+    R: Responder<'r>,
+{
+    fn respond_to(self, request: &Request) -> Result<Response<'r>, Status> {
+        let mut response = self.response.respond_to(request)?;
+        if let Some(rate_limit) = self.rate_limit {
+            let _ = response.set_raw_header("X-RateLimit-Limit", rate_limit.limit.to_string());
+            let _ = response.set_raw_header(
+                "X-RateLimit-Remaining",
+                rate_limit.remaining.to_string(),
+            );
+            let _ = response.set_raw_header("X-RateLimit-Reset", rate_limit.reset.to_string());
+            if let Some(retry_after) = rate_limit.retry_after {
+                let _ = response.set_raw_header("Retry-After", retry_after.to_string());
+            }
+        }
+        Ok(response)
+    }
+}
+
+/// Gets the IP address of the client of a request.
+///
+/// If the request comes from a trusted proxy, the `X-Forwarded-For` header is read from right to
+/// left, skipping the trusted proxies, and the first address that is not trusted is the client.
+pub fn client_ip(request: &Request, config: &RateLimitConfig) -> Option<IpAddr> {
+    let mut client = request.remote()?.ip();
+    if config.is_trusted_proxy(client) {
+        let forwarded = request
+            .headers()
+            .get("X-Forwarded-For")
+            .flat_map(|header| header.split(','))
+            .collect::<Vec<_>>();
+        for address in forwarded.iter().rev() {
+            match address.trim().parse() {
+                Ok(ip) => {
+                    client = ip;
+                    if !config.is_trusted_proxy(ip) {
+                        break;
+                    }
+                }
+                Err(_) => break,
+            }
+        }
+    }
+    Some(client)
+}
+
+/// Client IP address request guard.
+///
+/// It fails with a `400 Bad Request` status if the request does not come from a network
+/// connection.
+#[derive(Debug, Clone, Copy)]
+pub struct ClientIp(IpAddr);
+
+impl ClientIp {
+    /// Gets the IP address of the client.
+    pub fn ip(&self) -> IpAddr {
+        self.0
+    }
+}
+
+impl<'a, 'r> FromRequest<'a, 'r> for ClientIp {
+    type Error = &'static str;
+
+    fn from_request(request: &'a Request<'r>) -> request::Outcome<Self, Self::Error> {
+        let config = match request.guard::<State<RateLimitConfig>>() {
+            Outcome::Success(config) => config,
+            _ => return Outcome::Failure((Status::InternalServerError, "Unknown error")),
+        };
+        match client_ip(request, &config) {
+            Some(ip) => Outcome::Success(ClientIp(ip)),
+            None => {
+                // Failure: the request does not come from a network connection.
+                Outcome::Failure((Status::BadRequest, "Client address not found"))
+            }
+        }
+    }
+}
+
+/// Client IP address throttle request guard.
+///
+/// The request is counted in the hourly limit of the client IP address, with the bucket and
+/// cost of the rate limit tier `T`. It fails with a `429 Too Many Requests` status if the limit
+/// has been exceeded.
+#[derive(Debug, Clone, Copy)]
+pub struct IpThrottle<T = Standard> {
+    /// IP address of the client.
+    ip: IpAddr,
+    /// Hourly rate limit of the client, after counting the request.
+    rate_limit: RateLimit,
+    /// Rate limit tier.
+    tier: PhantomData<T>,
+}
+
+impl<T> IpThrottle<T> {
+    /// Gets the IP address of the client.
+    pub fn ip(&self) -> IpAddr {
+        self.ip
+    }
+
+    /// Gets the hourly rate limit of the client, after counting the request.
+    pub fn rate_limit(&self) -> RateLimit {
+        self.rate_limit
+    }
+}
+
+impl<'a, 'r, T> FromRequest<'a, 'r> for IpThrottle<T>
+where
+    T: RateLimitTier,
+{
+    type Error = &'static str;
+
+    fn from_request(request: &'a Request<'r>) -> request::Outcome<Self, Self::Error> {
+        let config = match request.guard::<State<RateLimitConfig>>() {
+            Outcome::Success(config) => config,
+            _ => return Outcome::Failure((Status::InternalServerError, "Unknown error")),
+        };
+        let ip = match client_ip(request, &config) {
+            Some(ip) => ip,
+            None => {
+                // Failure: the request does not come from a network connection.
+                return Outcome::Failure((Status::BadRequest, "Client address not found"));
+            }
+        };
+
+        throttle_ip::<T>(ip, &config).map(|rate_limit| IpThrottle {
+            ip,
+            rate_limit,
+            tier: PhantomData,
+        })
+    }
+}
+
+/// Counts a request of a client IP address against its hourly limit, in the bucket and with the
+/// cost of the rate limit tier `T`.
+///
+/// It fails with a `429 Too Many Requests` status if the limit has been exceeded.
+pub fn throttle_ip<T>(
+    ip: IpAddr,
+    config: &RateLimitConfig,
+) -> request::Outcome<RateLimit, &'static str>
+where
+    T: RateLimitTier,
+{
+    match RateLimit::consume_ip::<T>(ip, config.ip_hourly_limit()) {
+        Ok(rate_limit) if rate_limit.is_exceeded() => {
+            // Failure: too many requests.
+            GuardError::new(ApiError::too_many_requests())
+                .with_rate_limit(rate_limit)
+                .fail("Too many requests, please try again later")
+        }
+        Ok(rate_limit) => Outcome::Success(rate_limit),
+        Err(_) => {
+            // TODO log error.
+            Outcome::Failure((Status::InternalServerError, "Unknown error"))
+        }
+    }
+}
+
+/// Catcher for `429 Too Many Requests` errors.
+///
+/// The rate limit headers are the ones of the limit the request exceeded, stored by the request
+/// guard that rejected it.
+#[error(429)]
+pub fn too_many_requests(_request: &Request) -> GuardError {
+    GuardError::take(Status::TooManyRequests)
+        .unwrap_or_else(|| GuardError::new(ApiError::too_many_requests()))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::IpAddr;
+
+    use super::{throttled_address, Bucket};
+
+    #[test]
+    fn buckets_share_the_whole_limit() {
+        assert_eq!(Bucket::ALL.iter().map(Bucket::share).sum::<i32>(), 100);
+        assert_eq!(
+            Bucket::ALL
+                .iter()
+                .map(|bucket| bucket.limit(1_000))
+                .sum::<i32>(),
+            1_000
+        );
+    }
+
+    #[test]
+    fn buckets_allow_at_least_one_request() {
+        for bucket in Bucket::ALL {
+            assert_eq!(bucket.limit(1), 1);
+        }
+        assert_eq!(Bucket::Tokens.limit(10), 2);
+        assert_eq!(Bucket::Default.limit(10), 8);
+    }
+
+    #[test]
+    fn throttles_ipv6_clients_per_network() {
+        assert_eq!(
+            throttled_address("2001:db8:1:2:3:4:5:6".parse().unwrap()),
+            "2001:db8:1:2::".parse::<IpAddr>().unwrap()
+        );
+        assert_eq!(
+            throttled_address("::ffff:192.0.2.1".parse().unwrap()),
+            "192.0.2.1".parse::<IpAddr>().unwrap()
+        );
+        assert_eq!(
+            throttled_address("192.0.2.1".parse().unwrap()),
+            "192.0.2.1".parse::<IpAddr>().unwrap()
+        );
+    }
+}
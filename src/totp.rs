@@ -0,0 +1,177 @@
+//! TOTP module.
+//!
+//! Time-based one-time passwords (RFC 6238) are used for two-factor authentication. They use the
+//! default parameters of authenticator apps: HMAC-SHA1, 6 digits and 30 second steps. Codes from
+//! the previous and the next step are accepted too, to allow for some clock skew.
+
+use chrono::{DateTime, Utc};
+use failure::Error;
+use ring::{constant_time, digest, hmac};
+use rocket::http::uri::URI;
+
+use crypto;
+
+/// Length of the TOTP secrets, in bytes.
+const SECRET_LEN: usize = 20;
+/// Number of digits of the codes.
+const DIGITS: usize = 6;
+/// Duration of each time step, in seconds.
+const STEP_SECONDS: i64 = 30;
+/// Number of steps before and after the current one whose codes are accepted.
+const ALLOWED_SKEW_STEPS: i64 = 1;
+/// Base 32 alphabet (RFC 4648).
+const BASE32_ALPHABET: &[u8] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZ234567";
+
+/// Generates a new random TOTP secret.
+pub fn generate_secret() -> Result<Vec<u8>, Error> {
+    let mut secret = vec![0u8; SECRET_LEN];
+    crypto::fill_random(&mut secret)?;
+    Ok(secret)
+}
+
+/// Encodes a secret in base 32 without padding, as authenticator apps expect it.
+pub fn base32(secret: &[u8]) -> String {
+    let mut encoded = String::with_capacity((secret.len() * 8 + 4) / 5);
+    let mut buffer = 0u16;
+    let mut bits = 0;
+    for &byte in secret {
+        buffer = (buffer << 8) | u16::from(byte);
+        bits += 8;
+        while bits >= 5 {
+            bits -= 5;
+            encoded.push(char::from(BASE32_ALPHABET[usize::from((buffer >> bits) & 0x1f)]));
+        }
+    }
+    if bits > 0 {
+        encoded.push(char::from(BASE32_ALPHABET[usize::from((buffer << (5 - bits)) & 0x1f)]));
+    }
+    encoded
+}
+
+/// Gets the provisioning URI of a secret, to show it as a QR code to authenticator apps.
+pub fn provisioning_uri(issuer: &str, account: &str, secret: &[u8]) -> String {
+    format!(
+        "otpauth://totp/{issuer}:{account}?secret={secret}&issuer={issuer}&algorithm=SHA1&\
+         digits={digits}&period={period}",
+        issuer = URI::percent_encode(issuer),
+        account = URI::percent_encode(account),
+        secret = base32(secret),
+        digits = DIGITS,
+        period = STEP_SECONDS,
+    )
+}
+
+/// Gets the time step of the given time.
+fn step_at(time: DateTime<Utc>) -> i64 {
+    time.timestamp() / STEP_SECONDS
+}
+
+/// Computes the code of the given step (HOTP, RFC 4226).
+fn code(secret: &[u8], step: i64) -> String {
+    let mut counter = [0u8; 8];
+    for (i, byte) in counter.iter_mut().enumerate() {
+        *byte = (step >> (56 - 8 * i)) as u8;
+    }
+
+    let key = hmac::SigningKey::new(&digest::SHA1, secret);
+    let signature = hmac::sign(&key, &counter);
+    let hash = signature.as_ref();
+    let offset = usize::from(hash[hash.len() - 1] & 0x0f);
+    let binary = (u32::from(hash[offset]) & 0x7f) << 24 | u32::from(hash[offset + 1]) << 16
+        | u32::from(hash[offset + 2]) << 8 | u32::from(hash[offset + 3]);
+
+    format!("{:0width$}", binary % 10u32.pow(DIGITS as u32), width = DIGITS)
+}
+
+/// Checks if the given string has the format of a TOTP code.
+pub fn is_code(code: &str) -> bool {
+    code.len() == DIGITS && code.bytes().all(|byte| byte.is_ascii_digit())
+}
+
+/// Verifies a TOTP code at the given time, returning the step it belongs to if it's valid.
+///
+/// The caller must check that the step has not been used before.
+pub fn verify(secret: &[u8], candidate: &str, time: DateTime<Utc>) -> Option<i64> {
+    if !is_code(candidate) {
+        return None;
+    }
+
+    let current = step_at(time);
+    (current - ALLOWED_SKEW_STEPS..current + ALLOWED_SKEW_STEPS + 1).find(|&step| {
+        constant_time::verify_slices_are_equal(code(secret, step).as_bytes(), candidate.as_bytes())
+            .is_ok()
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::{TimeZone, Utc};
+
+    use super::{base32, code, step_at, verify};
+
+    /// Secret of the test vectors of RFC 4226 and RFC 6238, for HMAC-SHA1.
+    const SECRET: &[u8] = b"12345678901234567890";
+
+    #[test]
+    fn computes_hotp_codes() {
+        // RFC 4226, appendix D.
+        let codes = [
+            "755224", "287082", "359152", "969429", "338314", "254676", "287922", "162583",
+            "399871", "520489",
+        ];
+        for (step, expected) in codes.iter().enumerate() {
+            assert_eq!(code(SECRET, step as i64), *expected);
+        }
+    }
+
+    #[test]
+    fn computes_totp_codes() {
+        // RFC 6238, appendix B, with the last 6 of the 8 digits.
+        for &(time, expected) in &[
+            (59, "287082"),
+            (1_111_111_109, "081804"),
+            (1_111_111_111, "050471"),
+            (1_234_567_890, "005924"),
+            (2_000_000_000, "279037"),
+            (20_000_000_000, "353130"),
+        ] {
+            assert_eq!(code(SECRET, step_at(Utc.timestamp(time, 0))), expected);
+        }
+    }
+
+    #[test]
+    fn accepts_codes_of_adjacent_steps() {
+        let time = Utc.timestamp(1_111_111_109, 0);
+        let step = step_at(time);
+        assert_eq!(verify(SECRET, "081804", time), Some(step));
+        assert_eq!(verify(SECRET, &code(SECRET, step - 1), time), Some(step - 1));
+        assert_eq!(verify(SECRET, &code(SECRET, step + 1), time), Some(step + 1));
+        assert_eq!(verify(SECRET, &code(SECRET, step - 2), time), None);
+        assert_eq!(verify(SECRET, &code(SECRET, step + 2), time), None);
+    }
+
+    #[test]
+    fn rejects_malformed_codes() {
+        let time = Utc.timestamp(59, 0);
+        assert_eq!(verify(SECRET, "28708", time), None);
+        assert_eq!(verify(SECRET, "2870820", time), None);
+        assert_eq!(verify(SECRET, "28708a", time), None);
+        assert_eq!(verify(SECRET, " 287082", time), None);
+    }
+
+    #[test]
+    fn encodes_base32() {
+        // RFC 4648, section 10, without padding.
+        for &(input, expected) in &[
+            ("", ""),
+            ("f", "MY"),
+            ("fo", "MZXQ"),
+            ("foo", "MZXW6"),
+            ("foob", "MZXW6YQ"),
+            ("fooba", "MZXW6YTB"),
+            ("foobar", "MZXW6YTBOI"),
+        ] {
+            assert_eq!(base32(input.as_bytes()), expected);
+        }
+    }
+}